@@ -2,6 +2,8 @@
 #include <stdint.h>
 #include <stdlib.h>
 #include <stdbool.h>
+#include <stdio.h>
+#include <stddef.h>
 
 
 #ifdef __cplusplus
@@ -80,6 +82,79 @@ typedef struct GifskiSettings {
   int16_t repeat;
 } GifskiSettings;
 
+/**
+ * Settings for `gifski_new2`. Zero in every field means default.
+ *
+ * New fields are only ever appended to this struct, so always set `size` to `sizeof(GifskiSettings2)`.
+ */
+typedef struct GifskiSettings2 {
+  /**
+   * Must be set to `sizeof(GifskiSettings2)`
+   */
+  uint32_t size;
+  /**
+   * Resize to max this width if non-0.
+   */
+  uint32_t width;
+  /**
+   * Resize to max this height if width is non-0. Note that aspect ratio is not preserved.
+   */
+  uint32_t height;
+  /**
+   * 1-100, but useful range is 50-100. 0 for the default (100).
+   */
+  uint8_t quality;
+  /**
+   * Lower quality, but faster encode.
+   */
+  bool fast;
+  /**
+   * If negative, looping is disabled. The number of times the sequence is repeated. 0 to loop forever.
+   */
+  int16_t repeat;
+  /**
+   * 2-256, or 0 for 256.
+   */
+  uint32_t max_colors;
+  /**
+   * One of `GIFSKI_DITHERING_*` values.
+   */
+  int dithering;
+  /**
+   * 0-1, for ordered and diffusion dithering.
+   */
+  float dithering_strength;
+  /**
+   * Lossy LZW compression level, like gifsicle's `--lossy`. 0 derives it from `quality`.
+   */
+  uint32_t loss;
+  /**
+   * If non-0, frames with PSNR below this (in dB) after lossy compression are compressed again with less loss.
+   */
+  float lossy_min_psnr;
+  /**
+   * Use less loss on mostly-static frames, and more on frames with lots of motion.
+   */
+  bool adaptive_loss;
+  /**
+   * Write interlaced frames.
+   */
+  bool interlace;
+  /**
+   * Slower encoding, but slightly smaller files.
+   */
+  bool extra;
+  /**
+   * Make the same GIF from the same input and settings on any machine.
+   */
+  bool deterministic;
+  /**
+   * Megabytes that frames waiting for encoding may use, or 0 for no limit.
+   * Also how much bounce keeps in memory before frames go to a temporary file (0 for about 1GB).
+   */
+  uint32_t memory_limit_mb;
+} GifskiSettings2;
+
 enum GifskiError {
   GIFSKI_OK = 0,
   /** one of input arguments was NULL */
@@ -116,6 +191,55 @@ enum GifskiError {
 
 typedef enum GifskiError GifskiError;
 
+enum GifskiDithering {
+  /** depends on quality */
+  GIFSKI_DITHERING_AUTO = 0,
+  /** nearest palette color, may have banding */
+  GIFSKI_DITHERING_NONE,
+  /** regular pattern, compresses better */
+  GIFSKI_DITHERING_ORDERED,
+  /** Floyd-Steinberg error diffusion */
+  GIFSKI_DITHERING_DIFFUSION,
+};
+
+typedef enum GifskiDithering GifskiDithering;
+
+enum GifskiColorspace {
+  /** standard-definition video */
+  GIFSKI_COLORSPACE_BT601 = 0,
+  /** HD video */
+  GIFSKI_COLORSPACE_BT709,
+  /** UHD video */
+  GIFSKI_COLORSPACE_BT2020,
+};
+
+typedef enum GifskiColorspace GifskiColorspace;
+
+enum GifskiFeature {
+  /** lossy LZW compression */
+  GIFSKI_FEATURE_GIFSICLE = 1,
+  /** `gifski_add_frame_png_file` */
+  GIFSKI_FEATURE_PNG,
+  /** multi-threaded quantization */
+  GIFSKI_FEATURE_OPENMP,
+  /** denoiser debug images, see `GIFSKI_DUMP_DIR` */
+  GIFSKI_FEATURE_DUMP,
+};
+
+typedef enum GifskiFeature GifskiFeature;
+
+/**
+ * Version of the library, e.g. `"1.4.4"`. The string is static and must not be freed.
+ */
+const char *gifski_version_string(void);
+
+/**
+ * Returns `true` if this build of the library has been compiled with the given feature.
+ *
+ * Unknown features are reported as missing, so it's safe to query features added in newer versions.
+ */
+bool gifski_has_feature(GifskiFeature feature);
+
 /**
  * Call to start the process
  *
@@ -125,6 +249,17 @@ typedef enum GifskiError GifskiError;
  */
 gifski *gifski_new(const GifskiSettings *settings);
 
+/**
+ * Same as `gifski_new`, but with the extensible `GifskiSettings2` struct, which has more options.
+ *
+ * ```c
+ * gifski *g = gifski_new2(&(GifskiSettings2){ .size = sizeof(GifskiSettings2), .quality = 90, .max_colors = 128 });
+ * ```
+ *
+ * Returns `NULL` if the settings are invalid, or if the library is older than the header the caller has been compiled with.
+ */
+gifski *gifski_new2(const GifskiSettings2 *settings);
+
 /**
  * Adds a frame to the animation. This function is asynchronous.
  *
@@ -146,6 +281,25 @@ GifskiError gifski_add_frame_png_file(gifski *handle,
                                       const char *file_path,
                                       double presentation_timestamp);
 
+#ifdef _WIN32
+/**
+ * Same as `gifski_add_frame_png_file`, but the path is a NUL-terminated UTF-16 string,
+ * so it works with any file name regardless of the code page.
+ */
+GifskiError gifski_add_frame_png_file_w(gifski *handle,
+                                        uint32_t frame_number,
+                                        const wchar_t *file_path,
+                                        double presentation_timestamp);
+#else
+/**
+ * Same as `gifski_add_frame_png_file`, but the path is used as-is, without requiring it to be valid UTF-8.
+ */
+GifskiError gifski_add_frame_png_file_bytes(gifski *handle,
+                                            uint32_t frame_number,
+                                            const char *file_path,
+                                            double presentation_timestamp);
+#endif
+
 /**
  * Adds a frame to the animation. This function is asynchronous.
  *
@@ -170,6 +324,22 @@ GifskiError gifski_add_frame_rgba(gifski *handle,
                                   const unsigned char *pixels,
                                   double presentation_timestamp);
 
+/** Same as `gifski_add_frame_rgba`, but with a map of areas that need better quality,
+such as the mouse pointer or click highlights in screen recordings.
+
+`importance` is an array width×height bytes large. 0 means no preference, 255 is the most important.
+It can only add quality, and areas that haven't changed since the previous frame stay unchanged.
+
+Both arrays are copied, so you can free/reuse them immediately.
+*/
+GifskiError gifski_add_frame_rgba_with_importance(gifski *handle,
+                                                  uint32_t frame_number,
+                                                  uint32_t width,
+                                                  uint32_t height,
+                                                  const unsigned char *pixels,
+                                                  const uint8_t *importance,
+                                                  double presentation_timestamp);
+
 /** Same as `gifski_add_frame_rgba`, but with bytes per row arg */
 GifskiError gifski_add_frame_rgba_stride(gifski *handle,
                                   uint32_t frame_number,
@@ -179,6 +349,23 @@ GifskiError gifski_add_frame_rgba_stride(gifski *handle,
                                   const unsigned char *pixels,
                                   double presentation_timestamp);
 
+/** Same as `gifski_add_frame_rgba_stride`, but the pixels aren't copied by this function.
+Instead, gifski keeps the buffer until the frame is processed, and then calls `free_fn(user_data)`,
+which can `free()` the buffer or return it to a pool.
+
+The buffer must not be modified until `free_fn` is called. `free_fn` is called exactly once, including when this function fails,
+but it may be called from another thread. `free_fn` can be `NULL`, but then the buffer must stay valid until `gifski_finish` returns.
+*/
+GifskiError gifski_add_frame_rgba_nocopy(gifski *handle,
+                                         uint32_t frame_number,
+                                         uint32_t width,
+                                         uint32_t height,
+                                         uint32_t bytes_per_row,
+                                         const unsigned char *pixels,
+                                         double presentation_timestamp,
+                                         void (*free_fn)(void *user_data),
+                                         void *user_data);
+
 /** Same as `gifski_add_frame_rgba_stride`, except it expects components in ARGB order.
 
 Bytes per row must be multiple of 4, and greater or equal width×4.
@@ -213,6 +400,70 @@ GifskiError gifski_add_frame_rgb(gifski *handle,
                                  const unsigned char *pixels,
                                  double presentation_timestamp);
 
+/** Same as `gifski_add_frame_rgba_stride`, except it expects components in BGRA order,
+which is what `CGBitmapContext` and Windows screen capture usually produce.
+
+Bytes per row must be multiple of 4, and greater or equal width×4.
+
+Colors are in sRGB, uncorrelated BGRA, with alpha byte last.
+*/
+GifskiError gifski_add_frame_bgra(gifski *handle,
+                                  uint32_t frame_number,
+                                  uint32_t width,
+                                  uint32_t height,
+                                  uint32_t bytes_per_row,
+                                  const unsigned char *pixels,
+                                  double presentation_timestamp);
+
+/** Same as `gifski_add_frame_rgba_stride`, except it expects BGR components (3 bytes per pixel).
+
+Bytes per row must be multiple of 3, and greater or equal width×3.
+
+Colors are in sRGB, blue byte first.
+*/
+GifskiError gifski_add_frame_bgr(gifski *handle,
+                                 uint32_t frame_number,
+                                 uint32_t width,
+                                 uint32_t height,
+                                 uint32_t bytes_per_row,
+                                 const unsigned char *pixels,
+                                 double presentation_timestamp);
+
+/** Same as `gifski_add_frame_rgba_stride`, except it expects a single gray channel (1 byte per pixel).
+
+Bytes per row must be greater or equal width.
+*/
+GifskiError gifski_add_frame_gray(gifski *handle,
+                                  uint32_t frame_number,
+                                  uint32_t width,
+                                  uint32_t height,
+                                  uint32_t bytes_per_row,
+                                  const unsigned char *pixels,
+                                  double presentation_timestamp);
+
+/** Planar YUV 4:2:0 frame, as produced by video decoders.
+
+`u` and `v` planes have half the width and height of the `y` plane (rounded up).
+Each plane has its own bytes per row, which must be greater or equal its width.
+
+If `full_range` is false, luma is in the 16-235 range.
+
+Planes are converted to RGB before this function returns, without copying them first.
+*/
+GifskiError gifski_add_frame_yuv420(gifski *handle,
+                                    uint32_t frame_number,
+                                    uint32_t width,
+                                    uint32_t height,
+                                    const unsigned char *y,
+                                    uint32_t y_bytes_per_row,
+                                    const unsigned char *u,
+                                    uint32_t u_bytes_per_row,
+                                    const unsigned char *v,
+                                    uint32_t v_bytes_per_row,
+                                    GifskiColorspace colorspace,
+                                    bool full_range,
+                                    double presentation_timestamp);
+
 /**
  * Get a callback for frame processed, and abort processing if desired.
  *
@@ -230,6 +481,132 @@ GifskiError gifski_add_frame_rgb(gifski *handle,
  */
 void gifski_set_progress_callback(gifski *handle, int (*progress_callback)(void *user_data), void *user_data);
 
+/**
+ * Same as `gifski_set_progress_callback`, but the callback also gets the frame number (1..),
+ * its presentation timestamp in seconds, and number of bytes of the GIF written so far,
+ * which can be used to estimate the final file size.
+ *
+ * The callback must return `1` to continue processing, or `0` to abort.
+ *
+ * Only one progress callback can be set. This function must be called before `gifski_set_file_output()` to take effect.
+ *
+ * Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+ */
+GifskiError gifski_set_progress_callback2(gifski *handle,
+                                          int (*progress_callback)(uint32_t frame_number, double presentation_timestamp, uint64_t bytes_written, void *user_data),
+                                          void *user_data);
+
+/**
+ * Get a callback for errors, instead of having them printed to stderr.
+ *
+ * The callback gets the `GifskiError` code, the number of the frame that caused the error
+ * (or `-1` if the error isn't about a particular frame), a human-readable message, and `user_data`.
+ * The message is valid only during the call.
+ *
+ * The callback must be thread-safe (it may be called from another thread).
+ * It must remain valid at all times, until `gifski_finish` completes.
+ *
+ * Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+ */
+GifskiError gifski_set_error_callback(gifski *handle,
+                                      void (*error_callback)(GifskiError code, int64_t frame_number, const char *message, void *user_data),
+                                      void *user_data);
+
+/**
+ * Choose how colors that aren't in the palette are approximated.
+ *
+ * `strength` (0-1) applies to `GIFSKI_DITHERING_ORDERED` and `GIFSKI_DITHERING_DIFFUSION` only.
+ *
+ * This function must be called before `gifski_set_file_output()` to take effect.
+ *
+ * Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+ */
+GifskiError gifski_set_dithering(gifski *handle, GifskiDithering mode, float strength);
+
+/**
+ * Instead of dithering semi-transparent pixels to fully transparent or opaque,
+ * blend them with this color, making the animation fully opaque.
+ *
+ * Applies to frames added after this call.
+ *
+ * Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+ */
+GifskiError gifski_set_matte_color(gifski *handle, uint8_t r, uint8_t g, uint8_t b);
+
+/**
+ * Play the frames forwards, and then backwards. Frames are kept until the end, and once they take more than
+ * `memory_limit_mb` of `GifskiSettings2` (or about 1GB without it) the rest go to a temporary file.
+ *
+ * This function must be called before `gifski_set_file_output()` to take effect.
+ *
+ * Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+ */
+GifskiError gifski_set_bounce(gifski *handle, bool bounce);
+
+/**
+ * Write interlaced frames. Some viewers display them progressively while loading, but they compress slightly worse.
+ *
+ * Same as `interlace` in `GifskiSettings2`, for handles from `gifski_new()`.
+ *
+ * This function must be called before `gifski_set_file_output()` to take effect.
+ *
+ * Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+ */
+GifskiError gifski_set_interlace(gifski *handle, bool interlace);
+
+/**
+ * Details of a written frame, see `gifski_set_frame_stats_callback`
+ */
+typedef struct GifskiFrameStats {
+  /** 1.. */
+  uint32_t frame_number;
+  /** in 1/100ths of a second */
+  uint16_t delay;
+  /** number of colors, including transparency */
+  uint16_t palette_size;
+  /** bytes added to the file by this frame */
+  uint64_t compressed_size;
+} GifskiFrameStats;
+
+/**
+ * Get a callback after each frame is written, with its delay, palette size, and compressed size.
+ * Frames skipped by the encoder aren't reported.
+ *
+ * The `stats` pointer is valid only during the call. `user_data` can be `NULL`.
+ *
+ * The callback must be thread-safe (it will be called from another thread).
+ *
+ * This function must be called before `gifski_set_file_output()` to take effect.
+ *
+ * Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+ */
+GifskiError gifski_set_frame_stats_callback(gifski *handle,
+                                            void (*stats_callback)(const GifskiFrameStats *stats, void *user_data),
+                                            void *user_data);
+
+/**
+ * Limit number of colors in each frame's palette to `max_colors` (2-256). Fewer colors give smaller, but uglier files.
+ *
+ * One of the colors is reserved for transparency in all frames except the first one.
+ *
+ * This function must be called before `gifski_set_file_output()` to take effect.
+ *
+ * Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+ */
+GifskiError gifski_set_max_colors(gifski *handle, uint32_t max_colors);
+
+/**
+ * Use exactly these colors for the whole animation, instead of finding the best palette for each frame.
+ *
+ * `colors` is an array of `count` RGB colors (3 bytes per color, red byte first).
+ * At most 255 colors are allowed, because one palette entry is needed for transparency.
+ *
+ * This function must be called before `gifski_set_file_output()` to take effect.
+ *
+ * Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+ */
+GifskiError gifski_set_fixed_palette(gifski *handle, const unsigned char *colors, uint32_t count);
+
 /**
  * Start writing to the file at `destination_path` (overwrites if needed).
  * The file path must be ASCII or valid UTF-8.
@@ -241,6 +618,19 @@ void gifski_set_progress_callback(gifski *handle, int (*progress_callback)(void
  */
 GifskiError gifski_set_file_output(gifski *handle, const char *destination_path);
 
+#ifdef _WIN32
+/**
+ * Same as `gifski_set_file_output`, but the path is a NUL-terminated UTF-16 string,
+ * so it works with any file name regardless of the code page.
+ */
+GifskiError gifski_set_file_output_w(gifski *handle, const wchar_t *destination_path);
+#else
+/**
+ * Same as `gifski_set_file_output`, but the path is used as-is, without requiring it to be valid UTF-8.
+ */
+GifskiError gifski_set_file_output_bytes(gifski *handle, const char *destination_path);
+#endif
+
 /**
  * Start writing via callback (any buffer, file, whatever you want). This has to be called before any frames are added.
  * This call will not block.
@@ -260,6 +650,28 @@ GifskiError gifski_set_write_callback(gifski *handle,
                                       int (*write_callback)(size_t buffer_length, const uint8_t *buffer, void *user_data),
                                       void *user_data);
 
+#ifndef _WIN32
+/**
+ * Start writing to an already-open file descriptor, e.g. one given to a sandboxed app.
+ *
+ * The descriptor is not closed by gifski. It must remain open until `gifski_finish` completes.
+ * This call will not block.
+ *
+ * Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+ */
+GifskiError gifski_set_fd_output(gifski *handle, int fd);
+#endif
+
+/**
+ * Start writing to a stdio stream opened for writing in binary mode (`"wb"`).
+ *
+ * The stream is flushed, but not closed by gifski. It must remain open until `gifski_finish` completes.
+ * This call will not block.
+ *
+ * Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+ */
+GifskiError gifski_set_stream_output(gifski *handle, FILE *stream);
+
 /**
  * The last step:
  *  - stops accepting any more frames (gifski_add_frame_* calls are blocked)
@@ -0,0 +1,179 @@
+//! `--format apng` writes the frames as they are shown in the GIF to an animated PNG instead
+
+use crate::apng_source::{write_chunk, SIGNATURE};
+use crate::BinResult;
+use gifski::Repeat;
+use imgref::*;
+use rgb::*;
+use std::io::Write;
+
+/// Frames are compressed as they arrive, but the file is written at the end, because its header has the number of frames
+pub struct ApngWriter {
+    repeat: Repeat,
+    /// Canvas after the last frame
+    previous: Option<ImgVec<RGBA8>>,
+    frames: u32,
+    /// fcTL, IDAT and fdAT chunks share one sequence of numbers
+    sequence: u32,
+    /// Chunks of all frames so far
+    chunks: Vec<u8>,
+    /// Frames arrive in a callback, so the first error is kept for later
+    error: Option<String>,
+}
+
+impl ApngWriter {
+    pub fn new(repeat: Repeat) -> Self {
+        Self {
+            repeat,
+            previous: None,
+            frames: 0,
+            sequence: 0,
+            chunks: Vec::new(),
+            error: None,
+        }
+    }
+
+    /// `delay` is in 1/100ths of a second
+    pub fn add(&mut self, frame: ImgRef<'_, RGBA8>, delay: u16) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(e) = self.add_frame(frame, delay) {
+            self.error = Some(e.to_string());
+        }
+    }
+
+    fn add_frame(&mut self, frame: ImgRef<'_, RGBA8>, delay: u16) -> BinResult<()> {
+        // Only the area that changed is stored. It replaces the pixels under it, and stays on the canvas.
+        let (left, top, width, height) = match &self.previous {
+            Some(previous) if (previous.width(), previous.height()) != (frame.width(), frame.height()) => {
+                Err("Frames of different sizes can't be in one APNG")?
+            },
+            Some(previous) => changed_area(previous.as_ref(), frame).unwrap_or((0, 0, 1, 1)),
+            None => (0, 0, frame.width(), frame.height()),
+        };
+        let area = frame.sub_image(left, top, width, height);
+        let (buf, _, _) = area.to_contiguous_buf();
+        let mut encoder = lodepng::Encoder::new();
+        // all frames must have the color type of the header
+        encoder.set_auto_convert(false);
+        let png = encoder.encode(&buf, width, height).map_err(|e| format!("Can't compress the frame: {}", e))?;
+
+        let mut fctl = self.sequence.to_be_bytes().to_vec();
+        for &n in &[width, height, left, top] {
+            fctl.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        // no dispose, and no blending
+        fctl.extend_from_slice(&[(delay >> 8) as u8, delay as u8, 0, 100, 0, 0]);
+        write_chunk(&mut self.chunks, b"fcTL", &fctl);
+        self.sequence += 1;
+
+        for data in image_data(&png) {
+            if self.frames == 0 {
+                write_chunk(&mut self.chunks, b"IDAT", data);
+            } else {
+                write_chunk(&mut self.chunks, b"fdAT", &[&self.sequence.to_be_bytes()[..], data].concat());
+                self.sequence += 1;
+            }
+        }
+        self.frames += 1;
+        self.previous = Some(ImgVec::new(frame.to_contiguous_buf().0.into_owned(), frame.width(), frame.height()));
+        Ok(())
+    }
+
+    /// Returns the size of the file
+    pub fn finish(&mut self, mut out: impl Write) -> BinResult<u64> {
+        if let Some(e) = self.error.take() {
+            Err(e)?;
+        }
+        let (width, height) = match &self.previous {
+            Some(frame) => (frame.width() as u32, frame.height() as u32),
+            None => Err("No frames to write")?,
+        };
+        let plays = match self.repeat {
+            Repeat::Infinite => 0,
+            Repeat::Finite(repeats) => u32::from(repeats) + 1,
+        };
+        let mut png = SIGNATURE.to_vec();
+        // 8-bit RGBA
+        write_chunk(&mut png, b"IHDR", &[&width.to_be_bytes()[..], &height.to_be_bytes(), &[8, 6, 0, 0, 0]].concat());
+        write_chunk(&mut png, b"acTL", &[self.frames.to_be_bytes(), plays.to_be_bytes()].concat());
+        png.append(&mut self.chunks);
+        write_chunk(&mut png, b"IEND", &[]);
+        out.write_all(&png)?;
+        out.flush()?;
+        Ok(png.len() as u64)
+    }
+}
+
+/// Bodies of IDAT chunks of a PNG file
+fn image_data(png: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let mut pos = SIGNATURE.len();
+    std::iter::from_fn(move || {
+        while pos + 12 <= png.len() {
+            let len = crate::apng_source::be32(&png[pos..]) as usize;
+            let tag = &png[pos + 4..pos + 8];
+            let body = png.get(pos + 8..pos + 8 + len)?;
+            pos += 12 + len;
+            if tag == b"IDAT" {
+                return Some(body);
+            }
+        }
+        None
+    })
+}
+
+/// Left, top, width and height of the pixels that differ
+fn changed_area(previous: ImgRef<'_, RGBA8>, frame: ImgRef<'_, RGBA8>) -> Option<(usize, usize, usize, usize)> {
+    let mut area: Option<(usize, usize, usize, usize)> = None;
+    for (y, (old, new)) in previous.rows().zip(frame.rows()).enumerate() {
+        let left = match old.iter().zip(new).position(|(a, b)| a != b) {
+            Some(x) => x,
+            None => continue,
+        };
+        let right = old.iter().zip(new).rposition(|(a, b)| a != b).unwrap_or(left) + 1;
+        area = Some(match area {
+            Some((l, t, r, _)) => (l.min(left), t, r.max(right), y + 1),
+            None => (left, y, right, y + 1),
+        });
+    }
+    area.map(|(left, top, right, bottom)| (left, top, right - left, bottom - top))
+}
+
+#[test]
+fn frames_survive_round_trip() {
+    use crate::source::Fps;
+
+    let red = RGBA8::new(255, 0, 0, 255);
+    let clear = RGBA8::new(0, 0, 0, 0);
+    let first = ImgVec::new(vec![red; 16], 4, 4);
+    let mut second = first.clone();
+    second[(1usize, 2usize)] = clear;
+    second[(2usize, 1usize)] = RGBA8::new(0, 0, 255, 128);
+
+    let mut writer = ApngWriter::new(Repeat::Finite(2));
+    writer.add(first.as_ref(), 10);
+    // nothing changed
+    writer.add(first.as_ref(), 20);
+    writer.add(second.as_ref(), 30);
+    let mut png = Vec::new();
+    let size = writer.finish(&mut png).unwrap();
+    assert_eq!(size, png.len() as u64);
+
+    let chunks = crate::apng_source::test_chunks(&png);
+    let actl = &chunks.iter().find(|(tag, _)| tag == b"acTL").unwrap().1;
+    assert_eq!(&[0, 0, 0, 3, 0, 0, 0, 3], &actl[..]);
+    let areas: Vec<_> = chunks.iter().filter(|(tag, _)| tag == b"fcTL").map(|(_, fctl)| fctl[4..20].to_vec()).collect();
+    assert_eq!(&[0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0, 1], &areas[2][..]);
+
+    let decoder = crate::apng_source::ApngDecoder::parse(&png[SIGNATURE.len()..], &Fps { fps: 10., speed: 1., speed_ranges: Vec::new() }).unwrap().unwrap();
+    let mut canvases = Vec::new();
+    decoder.composite(|_, canvas| {
+        canvases.push(canvas.clone());
+        Ok(())
+    }).unwrap();
+    assert_eq!(3, canvases.len());
+    assert!(canvases[0] == first);
+    assert!(canvases[1] == first);
+    assert!(canvases[2] == second);
+}
@@ -0,0 +1,376 @@
+use crate::source::Fps;
+use crate::source::Source;
+use crate::BinResult;
+use gifski::Collector;
+use imgref::*;
+use rgb::*;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+pub const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Animated PNG. Each frame is rebuilt as a standalone PNG file for lodepng, and composited here.
+pub struct ApngDecoder {
+    /// IHDR chunk's data
+    header: Vec<u8>,
+    /// PLTE, tRNS, etc. that apply to all frames
+    shared_chunks: Vec<([u8; 4], Vec<u8>)>,
+    frames: Vec<ApngFrame>,
+    rate: Fps,
+}
+
+struct ApngFrame {
+    width: u32,
+    height: u32,
+    left: u32,
+    top: u32,
+    /// in seconds
+    delay: f64,
+    dispose_op: u8,
+    blend_op: u8,
+    /// IDAT or fdAT contents
+    data: Vec<u8>,
+}
+
+impl ApngDecoder {
+    /// `None` if the file isn't an animated PNG
+    pub fn open(path: &Path, rate: &Fps) -> BinResult<Option<Self>> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        };
+        let mut signature = [0; 8];
+        if file.read_exact(&mut signature).is_err() || signature != SIGNATURE {
+            return Ok(None);
+        }
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        Self::parse(&data, rate).map_err(|e| format!("Can't read {}: {}", path.display(), e).into())
+    }
+
+    pub fn parse(data: &[u8], rate: &Fps) -> BinResult<Option<Self>> {
+        let mut header = None;
+        let mut is_animated = false;
+        let mut shared_chunks = Vec::new();
+        let mut frames = Vec::<ApngFrame>::new();
+        // IDAT is the first frame only if there was fcTL before it
+        let mut idat_is_frame = None;
+        // fcTL and fdAT chunks are numbered, so that chunks in the wrong order are noticed
+        let mut next_sequence = 0;
+        let mut sequence = |body: &[u8]| -> BinResult<()> {
+            if body.len() < 4 || be32(body) != next_sequence {
+                Err("chunks are out of order")?;
+            }
+            next_sequence += 1;
+            Ok(())
+        };
+
+        let mut pos = 0;
+        while pos + 12 <= data.len() {
+            let len = be32(&data[pos..]) as usize;
+            let tag = [data[pos + 4], data[pos + 5], data[pos + 6], data[pos + 7]];
+            let body = data.get(pos + 8..pos + 8 + len).ok_or("truncated file")?;
+            let crc = data.get(pos + 8 + len..pos + 12 + len).ok_or("truncated file")?;
+            if be32(crc) != crc32(&tag, body) {
+                Err(format!("bad checksum of {} chunk", String::from_utf8_lossy(&tag)))?;
+            }
+            pos += 12 + len;
+            match &tag {
+                b"IHDR" => header = Some(body.to_vec()),
+                b"acTL" => is_animated = true,
+                b"fcTL" => {
+                    if body.len() < 26 {
+                        Err("bad fcTL chunk")?;
+                    }
+                    sequence(body)?;
+                    let delay_den = match u16::from_be_bytes([body[22], body[23]]) {
+                        0 => 100.,
+                        den => f64::from(den),
+                    };
+                    frames.push(ApngFrame {
+                        width: be32(&body[4..]),
+                        height: be32(&body[8..]),
+                        left: be32(&body[12..]),
+                        top: be32(&body[16..]),
+                        delay: f64::from(u16::from_be_bytes([body[20], body[21]])) / delay_den,
+                        dispose_op: body[24],
+                        blend_op: body[25],
+                        data: Vec::new(),
+                    });
+                },
+                b"IDAT" => {
+                    if *idat_is_frame.get_or_insert(!frames.is_empty()) {
+                        frames.last_mut().ok_or("bad IDAT chunk")?.data.extend_from_slice(body);
+                    }
+                },
+                b"fdAT" => {
+                    sequence(body)?;
+                    frames.last_mut().ok_or("bad fdAT chunk")?.data.extend_from_slice(body.get(4..).ok_or("bad fdAT chunk")?);
+                },
+                b"IEND" => break,
+                _ => {
+                    if idat_is_frame.is_none() {
+                        shared_chunks.push((tag, body.to_vec()));
+                    }
+                },
+            }
+        }
+        if !is_animated {
+            return Ok(None);
+        }
+        let header = header.filter(|h| h.len() == 13).ok_or("missing IHDR chunk")?;
+        if frames.is_empty() {
+            Err("no frames")?;
+        }
+        if let Some(n) = frames.iter().position(|frame| frame.data.is_empty()) {
+            Err(format!("frame {} has no image data", n + 1))?;
+        }
+        Ok(Some(Self { header, shared_chunks, frames, rate: rate.clone() }))
+    }
+
+    /// Summary for `gifski info`, with a line per frame
+    pub fn info(&self) -> String {
+        let duration: f64 = self.frames.iter().map(|frame| frame.delay).sum();
+        let mut info = format!("APNG, {}x{}, {} frames, {:.2}s",
+            be32(&self.header[0..]), be32(&self.header[4..]), self.frames.len(), duration);
+        for (i, frame) in self.frames.iter().enumerate() {
+            let dispose = match frame.dispose_op {
+                1 => "background",
+                2 => "previous",
+                _ => "none",
+            };
+            let blend = if frame.blend_op == 1 { "over" } else { "source" };
+            info += &format!("\n  #{} {:.3}s {}x{}+{}+{}, dispose {}, blend {}",
+                i + 1, frame.delay, frame.width, frame.height, frame.left, frame.top, dispose, blend);
+        }
+        info
+    }
+
+    fn decode_frame(&self, frame: &ApngFrame) -> BinResult<ImgVec<RGBA8>> {
+        let mut header = self.header.clone();
+        header[0..4].copy_from_slice(&frame.width.to_be_bytes());
+        header[4..8].copy_from_slice(&frame.height.to_be_bytes());
+
+        let mut png = SIGNATURE.to_vec();
+        write_chunk(&mut png, b"IHDR", &header);
+        for (tag, body) in &self.shared_chunks {
+            write_chunk(&mut png, tag, body);
+        }
+        write_chunk(&mut png, b"IDAT", &frame.data);
+        write_chunk(&mut png, b"IEND", &[]);
+        let image = lodepng::decode32(&png)?;
+        Ok(ImgVec::new(image.buffer, image.width, image.height))
+    }
+
+    /// Applies each frame's blend op, and then its dispose op after `each` has seen the result
+    pub fn composite(&self, mut each: impl FnMut(usize, &ImgVec<RGBA8>) -> BinResult<()>) -> BinResult<()> {
+        let width = be32(&self.header[0..]) as usize;
+        let height = be32(&self.header[4..]) as usize;
+        let mut canvas = ImgVec::new(vec![RGBA8::default(); width * height], width, height);
+
+        for (i, frame) in self.frames.iter().enumerate() {
+            let image = self.decode_frame(frame)?;
+            let (left, top) = (frame.left as usize, frame.top as usize);
+            if left + image.width() > width || top + image.height() > height {
+                Err(format!("Frame {} is outside of the image", i + 1))?;
+            }
+            let previous = if frame.dispose_op == 2 { Some(canvas.clone()) } else { None };
+
+            let mut area = canvas.sub_image_mut(left, top, image.width(), image.height());
+            for (dst_row, src_row) in area.rows_mut().zip(image.rows()) {
+                for (dst, src) in dst_row.iter_mut().zip(src_row) {
+                    *dst = if frame.blend_op == 1 { blend_over(*dst, *src) } else { *src };
+                }
+            }
+
+            each(i, &canvas)?;
+
+            match (frame.dispose_op, previous) {
+                (1, _) => {
+                    let mut area = canvas.sub_image_mut(left, top, image.width(), image.height());
+                    area.pixels_mut().for_each(|px| *px = RGBA8::default());
+                },
+                (2, Some(previous)) => canvas = previous,
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Source for ApngDecoder {
+    fn total_frames(&self) -> u64 {
+        self.frames.len() as u64
+    }
+
+    fn collect(&mut self, dest: &mut Collector) -> BinResult<()> {
+        let (frames, rate) = (&self.frames, &self.rate);
+        let mut input_time = 0.;
+        self.composite(|i, canvas| {
+            let pts = rate.output_time(input_time);
+            dest.add_frame_rgba(i, canvas.clone(), pts)?;
+            input_time += frames[i].delay;
+            if i + 1 == frames.len() {
+                dest.set_last_frame_duration(rate.output_time(input_time) - pts);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Alpha compositing of non-premultiplied pixels
+fn blend_over(dst: RGBA8, src: RGBA8) -> RGBA8 {
+    let src_a = f32::from(src.a) / 255.;
+    let dst_a = f32::from(dst.a) / 255. * (1. - src_a);
+    let out_a = src_a + dst_a;
+    if out_a <= 0. {
+        return RGBA8::default();
+    }
+    let blend = |s: u8, d: u8| ((f32::from(s) * src_a + f32::from(d) * dst_a) / out_a).round() as u8;
+    RGBA8::new(blend(src.r, dst.r), blend(src.g, dst.g), blend(src.b, dst.b), (out_a * 255.).round() as u8)
+}
+
+pub fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+pub fn write_chunk(png: &mut Vec<u8>, tag: &[u8; 4], body: &[u8]) {
+    png.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    png.extend_from_slice(tag);
+    png.extend_from_slice(body);
+    png.extend_from_slice(&crc32(tag, body).to_be_bytes());
+}
+
+fn crc32(tag: &[u8], body: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for &byte in tag.iter().chain(body) {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Tags and bodies of PNG chunks, after the signature
+#[cfg(test)]
+pub fn test_chunks(png: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+    let mut chunks = Vec::new();
+    let mut pos = SIGNATURE.len();
+    while pos + 12 <= png.len() {
+        let len = be32(&png[pos..]) as usize;
+        chunks.push(([png[pos + 4], png[pos + 5], png[pos + 6], png[pos + 7]], png[pos + 8..pos + 8 + len].to_vec()));
+        pos += 12 + len;
+    }
+    chunks
+}
+
+/// Chunks after the signature, like `parse()` takes them
+#[cfg(test)]
+fn test_png(chunks: &[([u8; 4], Vec<u8>)]) -> Vec<u8> {
+    let mut png = Vec::new();
+    for (tag, body) in chunks {
+        write_chunk(&mut png, tag, body);
+    }
+    png
+}
+
+/// 4x4 animation. Each frame is `(left, top, width, height, dispose_op, blend_op, color)`.
+/// The first frame is in IDAT, and the rest in fdAT.
+#[cfg(test)]
+fn test_apng(frames: &[(u32, u32, u32, u32, u8, u8, RGBA8)]) -> Vec<([u8; 4], Vec<u8>)> {
+    let mut chunks = vec![(*b"IHDR", [&4_u32.to_be_bytes()[..], &4_u32.to_be_bytes(), &[8, 6, 0, 0, 0]].concat())];
+    chunks.push((*b"acTL", [(frames.len() as u32).to_be_bytes(), 0_u32.to_be_bytes()].concat()));
+    let mut sequence = 0_u32;
+    for (i, &(left, top, width, height, dispose_op, blend_op, color)) in frames.iter().enumerate() {
+        let mut fctl = sequence.to_be_bytes().to_vec();
+        for n in [width, height, left, top] {
+            fctl.extend_from_slice(&n.to_be_bytes());
+        }
+        // 1/10s
+        fctl.extend_from_slice(&[0, 1, 0, 10, dispose_op, blend_op]);
+        chunks.push((*b"fcTL", fctl));
+        sequence += 1;
+
+        let mut encoder = lodepng::Encoder::new();
+        encoder.set_auto_convert(false);
+        let png = encoder.encode(&vec![color; (width * height) as usize], width as usize, height as usize).unwrap();
+        let data: Vec<u8> = test_chunks(&png).into_iter().filter(|(tag, _)| tag == b"IDAT").flat_map(|(_, body)| body).collect();
+        if i == 0 {
+            chunks.push((*b"IDAT", data));
+        } else {
+            chunks.push((*b"fdAT", [&sequence.to_be_bytes()[..], &data].concat()));
+            sequence += 1;
+        }
+    }
+    chunks.push((*b"IEND", Vec::new()));
+    chunks
+}
+
+#[cfg(test)]
+fn test_rate() -> Fps {
+    Fps { fps: 10., speed: 1., speed_ranges: Vec::new() }
+}
+
+#[test]
+fn composites_with_dispose_and_blend_ops() {
+    let red = RGBA8::new(255, 0, 0, 255);
+    let blue = RGBA8::new(0, 0, 255, 128);
+    let green = RGBA8::new(0, 255, 0, 255);
+    let clear = RGBA8::default();
+    let png = test_png(&test_apng(&[
+        (0, 0, 4, 4, 0, 0, red),
+        // blended over red, and then the previous canvas comes back
+        (0, 0, 2, 2, 2, 1, blue),
+        // replaces red, and then becomes transparent
+        (2, 2, 2, 2, 1, 0, green),
+        // blending a transparent pixel changes nothing
+        (0, 0, 1, 1, 0, 1, clear),
+    ]));
+    let decoder = ApngDecoder::parse(&png, &test_rate()).unwrap().unwrap();
+    assert_eq!(4, decoder.total_frames());
+    assert!(decoder.frames.iter().all(|frame| frame.delay == 0.1));
+
+    let mut canvases = Vec::new();
+    decoder.composite(|_, canvas| {
+        canvases.push(canvas.clone());
+        Ok(())
+    }).unwrap();
+    let px = |frame: usize, x: usize, y: usize| canvases[frame][(x, y)];
+    assert!(canvases[0].pixels().all(|px| px == red));
+    assert_eq!(RGBA8::new(127, 0, 128, 255), px(1, 1, 1));
+    assert_eq!(red, px(1, 2, 2));
+    assert_eq!(red, px(2, 0, 0), "disposed to previous");
+    assert_eq!(green, px(2, 3, 3));
+    assert_eq!(red, px(3, 0, 0));
+    assert_eq!(clear, px(3, 3, 3), "disposed to background");
+    assert_eq!(red, px(3, 1, 3));
+}
+
+#[test]
+fn rejects_bad_chunks() {
+    let red = RGBA8::new(255, 0, 0, 255);
+    let chunks = test_apng(&[(0, 0, 4, 4, 0, 0, red), (1, 1, 2, 2, 0, 0, red)]);
+    assert!(ApngDecoder::parse(&test_png(&chunks), &test_rate()).unwrap().is_some());
+
+    let mut png = test_png(&chunks);
+    let last = png.len() - 13;
+    png[last] ^= 1;
+    let err = ApngDecoder::parse(&png, &test_rate()).err().unwrap();
+    assert!(err.to_string().contains("checksum"), "{}", err);
+
+    let fdat = chunks.iter().rposition(|(tag, _)| tag == b"fdAT").unwrap();
+    let mut swapped = chunks.clone();
+    swapped.swap(fdat - 1, fdat);
+    let err = ApngDecoder::parse(&test_png(&swapped), &test_rate()).err().unwrap();
+    assert!(err.to_string().contains("out of order"), "{}", err);
+
+    let mut missing = chunks.clone();
+    missing.remove(fdat);
+    let err = ApngDecoder::parse(&test_png(&missing), &test_rate()).err().unwrap();
+    assert!(err.to_string().contains("no image data"), "{}", err);
+
+    let still: Vec<_> = chunks.into_iter().filter(|(tag, _)| tag != b"acTL").collect();
+    assert!(ApngDecoder::parse(&test_png(&still), &test_rate()).unwrap().is_none());
+}
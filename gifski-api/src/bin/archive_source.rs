@@ -0,0 +1,212 @@
+use crate::source::{Fps, Source};
+use crate::BinResult;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+#[cfg(feature = "archive")]
+use crate::source::Retimer;
+#[cfg(feature = "archive")]
+use gifski::Collector;
+#[cfg(feature = "archive")]
+use std::collections::HashMap;
+#[cfg(feature = "archive")]
+use std::io::{Seek, SeekFrom};
+
+/// `None` if the file isn't a zip or tar archive
+#[cfg(feature = "archive")]
+pub fn open(path: &Path, rate: &Fps) -> BinResult<Option<Box<dyn Source + Send>>> {
+    Ok(match ArchiveDecoder::open(path, rate)? {
+        Some(decoder) => Some(Box::new(decoder)),
+        None => None,
+    })
+}
+
+#[cfg(not(feature = "archive"))]
+#[cold]
+pub fn open(path: &Path, _: &Fps) -> BinResult<Option<Box<dyn Source + Send>>> {
+    if archive_kind(path).is_none() {
+        return Ok(None);
+    }
+    Err(format!(r"{} is an archive, and reading frames from zip and tar files is disabled in this executable.
+
+To enable it you need to recompile gifski from source with:
+cargo build --release --features=archive
+", path.display()))?
+}
+
+/// PNG frames in a zip or tar file, read without extracting it
+#[cfg(feature = "archive")]
+struct ArchiveDecoder {
+    archive: Archive,
+    /// Names of PNG files in the archive, in natural order
+    names: Vec<String>,
+    rate: Fps,
+}
+
+#[cfg(feature = "archive")]
+enum Archive {
+    Zip(zip::ZipArchive<File>),
+    /// Tar has no index, so the position and size of each file are noted while scanning it once
+    Tar(File, HashMap<String, (u64, u64)>),
+}
+
+#[cfg(feature = "archive")]
+impl ArchiveDecoder {
+    fn open(path: &Path, rate: &Fps) -> BinResult<Option<Self>> {
+        let is_zip = match archive_kind(path) {
+            Some(kind) => kind == Kind::Zip,
+            None => return Ok(None),
+        };
+        let err = |e: &dyn std::fmt::Display| format!("Can't read {}: {}", path.display(), e);
+        let file = File::open(path).map_err(|e| err(&e))?;
+        let (archive, mut names): (_, Vec<String>) = if is_zip {
+            let zip = zip::ZipArchive::new(file).map_err(|e| err(&e))?;
+            let names = zip.file_names().filter(|name| is_png_name(name)).map(String::from).collect();
+            (Archive::Zip(zip), names)
+        } else {
+            let mut frames = HashMap::new();
+            for entry in tar::Archive::new(&file).entries().map_err(|e| err(&e))? {
+                let entry = entry.map_err(|e| err(&e))?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let name = entry.path().map_err(|e| err(&e))?.to_string_lossy().into_owned();
+                if is_png_name(&name) {
+                    frames.insert(name, (entry.raw_file_position(), entry.size()));
+                }
+            }
+            let names = frames.keys().cloned().collect();
+            (Archive::Tar(file, frames), names)
+        };
+        if names.is_empty() {
+            Err(format!("{} has no PNG files", path.display()))?;
+        }
+        names.sort_by(|a, b| natord::compare(a, b));
+        Ok(Some(Self { archive, names, rate: rate.clone() }))
+    }
+
+    /// Input time of a frame, as if the files were a video
+    fn input_time(&self, i: usize) -> f64 {
+        i as f64 / f64::from(self.rate.fps)
+    }
+
+    fn read(&mut self, name: &str) -> BinResult<Vec<u8>> {
+        Ok(match &mut self.archive {
+            Archive::Zip(zip) => {
+                let mut data = Vec::new();
+                zip.by_name(name)
+                    .map_err(|e| format!("Can't read {}: {}", name, e))?
+                    .read_to_end(&mut data)?;
+                data
+            },
+            Archive::Tar(file, frames) => {
+                let (position, size) = frames.get(name).copied().ok_or_else(|| format!("Can't find {}", name))?;
+                let mut data = Vec::with_capacity(size as usize);
+                file.seek(SeekFrom::Start(position))?;
+                file.take(size).read_to_end(&mut data)?;
+                data
+            },
+        })
+    }
+}
+
+#[cfg(feature = "archive")]
+impl Source for ArchiveDecoder {
+    fn total_frames(&self) -> u64 {
+        let duration = self.rate.output_time(self.input_time(self.names.len()));
+        ((duration * f64::from(self.rate.fps)).ceil() as u64).min(self.names.len() as u64)
+    }
+
+    fn collect(&mut self, dest: &mut Collector) -> BinResult<()> {
+        let names = std::mem::take(&mut self.names);
+        let rate = self.rate.clone();
+        let mut retimer = Retimer::new(&rate, 0.);
+        let mut n = 0;
+        for (i, name) in names.iter().enumerate() {
+            if let Some(pts) = retimer.pts(self.input_time(i)) {
+                let image = lodepng::decode32(&self.read(name)?)
+                    .map_err(|e| format!("Can't load {}: {}", name, e))?;
+                dest.add_frame_rgba(n, imgref::ImgVec::new(image.buffer, image.width, image.height), pts)?;
+                n += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Kind {
+    Zip,
+    Tar,
+}
+
+/// Checks the signature, since archives of frames are often named after the animation
+fn archive_kind(path: &Path) -> Option<Kind> {
+    let mut header = Vec::with_capacity(262);
+    File::open(path).and_then(|file| file.take(262).read_to_end(&mut header)).ok()?;
+    if header.starts_with(b"PK\x03\x04") {
+        Some(Kind::Zip)
+    } else if header.get(257..262) == Some(&b"ustar"[..]) {
+        Some(Kind::Tar)
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "archive")]
+fn is_png_name(name: &str) -> bool {
+    // macOS adds resource forks as ._name.png
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    !file_name.starts_with("._") && Path::new(file_name).extension().map_or(false, |ext| ext.eq_ignore_ascii_case("png"))
+}
+
+/// Small PNG files named so that natural order differs from the order in the archive, and a file that isn't a frame
+#[cfg(all(test, feature = "archive"))]
+fn test_files() -> Vec<(&'static str, Vec<u8>)> {
+    let png = |shade: u8| lodepng::encode32(&[rgb::RGBA8::new(shade, shade, shade, 255); 4], 2, 2).unwrap();
+    vec![("frames/frame10.png", png(30)), ("frames/frame2.png", png(20)), ("frames/._frame3.png", vec![0; 10]), ("frames/readme.txt", b"hi".to_vec()), ("frames/frame1.png", png(10))]
+}
+
+#[cfg(all(test, feature = "archive"))]
+fn check_frames(path: &Path) {
+    let rate = Fps { fps: 10., speed: 1., speed_ranges: Vec::new() };
+    let mut decoder = ArchiveDecoder::open(path, &rate).unwrap().unwrap();
+    let _ = std::fs::remove_file(path);
+    assert_eq!(["frames/frame1.png", "frames/frame2.png", "frames/frame10.png"], &decoder.names[..]);
+    assert_eq!(3, decoder.total_frames());
+    for (name, shade) in decoder.names.clone().iter().zip([10, 20, 30]) {
+        let image = lodepng::decode32(decoder.read(name).unwrap()).unwrap();
+        assert_eq!((2, 2), (image.width, image.height));
+        assert!(image.buffer.iter().all(|px| px.r == shade && px.a == 255));
+    }
+}
+
+#[cfg(feature = "archive")]
+#[test]
+fn reads_zip() {
+    let path = std::env::temp_dir().join(format!("gifski-archive-test-{}.zip", std::process::id()));
+    let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+    for (name, data) in test_files() {
+        zip.start_file(name, zip::write::FileOptions::default()).unwrap();
+        std::io::Write::write_all(&mut zip, &data).unwrap();
+    }
+    zip.finish().unwrap();
+    check_frames(&path);
+}
+
+#[cfg(feature = "archive")]
+#[test]
+fn reads_tar() {
+    let path = std::env::temp_dir().join(format!("gifski-archive-test-{}.tar", std::process::id()));
+    let mut tar = tar::Builder::new(File::create(&path).unwrap());
+    for (name, data) in test_files() {
+        let mut header = tar::Header::new_ustar();
+        header.set_size(data.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, name, &data[..]).unwrap();
+    }
+    tar.finish().unwrap();
+    drop(tar);
+    check_frames(&path);
+}
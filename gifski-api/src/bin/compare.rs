@@ -0,0 +1,141 @@
+use crate::exit_code::ExitCode;
+use crate::BinResult;
+use clap::{App, Arg};
+use imgref::*;
+use rgb::*;
+use std::fs::File;
+use std::path::Path;
+
+/// `gifski compare a.gif b.gif`
+pub fn compare_main() -> BinResult<()> {
+    let matches = App::new("gifski compare")
+        .about("Compares how two GIFs look when played, frame by frame, \n\
+                e.g. to see what changing a setting does to quality")
+        .arg(Arg::with_name("A")
+            .help("GIF file")
+            .empty_values(false)
+            .required(true))
+        .arg(Arg::with_name("B")
+            .help("GIF file of the same size")
+            .empty_values(false)
+            .required(true))
+        // skips "gifski", so that "compare" is the name of the command
+        .get_matches_from_safe(wild::args_os().skip(1))
+        .unwrap_or_else(|e| {
+            if e.use_stderr() {
+                eprintln!("{}", e.message);
+                std::process::exit(ExitCode::BadArguments as i32);
+            }
+            e.exit()
+        });
+    let a = matches.value_of_os("A").map(Path::new).unwrap();
+    let b = matches.value_of_os("B").map(Path::new).unwrap();
+    compare(a, b)
+}
+
+/// Frames of both files are compared for as long as they're both on screen,
+/// so GIFs with different frame rates can be compared too.
+fn compare(a_path: &Path, b_path: &Path) -> BinResult<()> {
+    let mut a = Player::open(a_path)?;
+    let mut b = Player::open(b_path)?;
+    if a.screen.pixels.width() != b.screen.pixels.width() || a.screen.pixels.height() != b.screen.pixels.height() {
+        Err(format!("The GIFs have different sizes: {}x{} and {}x{}",
+            a.screen.pixels.width(), a.screen.pixels.height(), b.screen.pixels.width(), b.screen.pixels.height()))?;
+    }
+    if !a.next_frame()? || !b.next_frame()? {
+        Err("A GIF has no frames")?;
+    }
+
+    // in 1/100ths of a second, like GIF delays
+    let mut time = 0;
+    let mut total_error = 0.;
+    let mut worst: Option<(f64, u32)> = None;
+    loop {
+        let end = a.frame_end.min(b.frame_end);
+        // zero-delay frames are never seen
+        if end > time {
+            let mse = mean_squared_error(a.screen.pixels.as_ref(), b.screen.pixels.as_ref());
+            println!("{:>8.2}s-{:.2}s  a #{} b #{}  {}",
+                f64::from(time) / 100., f64::from(end) / 100., a.frame_number, b.frame_number, format_psnr(mse));
+            total_error += mse * f64::from(end - time);
+            if worst.map_or(true, |(worst_mse, _)| mse > worst_mse) {
+                worst = Some((mse, time));
+            }
+            time = end;
+        }
+        let a_more = a.frame_end > time || a.next_frame()?;
+        let b_more = b.frame_end > time || b.next_frame()?;
+        if !a_more || !b_more {
+            if a_more || b_more {
+                println!("Only the first {:.2}s are compared, because one GIF is shorter", f64::from(time) / 100.);
+            }
+            break;
+        }
+    }
+
+    if time == 0 {
+        Err("The GIFs have no frames with a delay")?;
+    }
+    println!("Overall {}", format_psnr(total_error / f64::from(time)));
+    if let Some((mse, at)) = worst {
+        println!("Worst {} at {:.2}s", format_psnr(mse), f64::from(at) / 100.);
+    }
+    Ok(())
+}
+
+/// Plays the GIF frame by frame
+struct Player {
+    decoder: gif::Decoder<File>,
+    screen: gif_dispose::Screen,
+    frame_number: usize,
+    /// When the current frame stops being shown, in 1/100ths of a second
+    frame_end: u32,
+}
+
+impl Player {
+    fn open(path: &Path) -> BinResult<Self> {
+        let file = File::open(path)
+            .map_err(|e| format!("Can't open {}: {}", path.display(), e))?;
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let decoder = options.read_info(file)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+        let screen = gif_dispose::Screen::new_decoder(&decoder);
+        Ok(Self { decoder, screen, frame_number: 0, frame_end: 0 })
+    }
+
+    /// `false` after the last frame
+    fn next_frame(&mut self) -> BinResult<bool> {
+        match self.decoder.read_next_frame()? {
+            Some(frame) => {
+                self.screen.blit_frame(frame)?;
+                self.frame_number += 1;
+                self.frame_end += u32::from(frame.delay);
+                Ok(true)
+            },
+            None => Ok(false),
+        }
+    }
+}
+
+/// Colors are premultiplied by alpha, and channels are weighted by how much the eye cares about them
+fn mean_squared_error(a: ImgRef<'_, RGBA8>, b: ImgRef<'_, RGBA8>) -> f64 {
+    let premultiplied = |px: RGBA8| {
+        let a = u32::from(px.a);
+        RGBA::new(u32::from(px.r) * a / 255, u32::from(px.g) * a / 255, u32::from(px.b) * a / 255, a).map(|c| c as i32)
+    };
+    let sum: u64 = a.pixels().zip(b.pixels()).map(|(a, b)| {
+        let (a, b) = (premultiplied(a), premultiplied(b));
+        ((a.r - b.r).pow(2) * 2 + (a.g - b.g).pow(2) * 3 + (a.b - b.b).pow(2) + (a.a - b.a).pow(2) * 3) as u64
+    }).sum();
+    sum as f64 / (9 * a.width() * a.height()).max(1) as f64
+}
+
+/// Higher is better. Above 40dB differences are hard to see.
+fn format_psnr(mse: f64) -> String {
+    if mse > 0. {
+        format!("PSNR {:.2}dB", 10. * (255. * 255. / mse).log10())
+    } else {
+        "identical".to_string()
+    }
+}
@@ -0,0 +1,34 @@
+use crate::source::*;
+use crate::BinResult;
+use gifski::Collector;
+
+/// Inputs played one after another
+pub struct ConcatSource {
+    sources: Vec<Box<dyn Source + Send>>,
+    /// output rate
+    fps: f32,
+}
+
+impl ConcatSource {
+    pub fn new(sources: Vec<Box<dyn Source + Send>>, fps: f32) -> Self {
+        Self { sources, fps }
+    }
+}
+
+impl Source for ConcatSource {
+    fn total_frames(&self) -> u64 {
+        let totals = self.sources.iter().map(|source| source.total_frames());
+        // 0 means unknown, and then the sum is unknown too
+        if totals.clone().any(|total| total == 0) { 0 } else { totals.sum() }
+    }
+
+    fn collect(&mut self, dest: &mut Collector) -> BinResult<()> {
+        for (i, source) in self.sources.iter_mut().enumerate() {
+            if i > 0 {
+                dest.start_next_segment(1. / f64::from(self.fps));
+            }
+            source.collect(dest)?;
+        }
+        Ok(())
+    }
+}
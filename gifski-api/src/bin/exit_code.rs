@@ -0,0 +1,96 @@
+use crate::BinResult;
+use std::error::Error;
+use std::fmt;
+
+/// Process exit status for each kind of failure, so that scripts can tell them apart. Listed in `--help`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ExitCode {
+    /// Encoding failed for any other reason
+    Other = 1,
+    BadArguments = 2,
+    InputNotFound = 3,
+    DecodeFailed = 4,
+    WriteFailed = 5,
+    Aborted = 6,
+}
+
+pub const EXIT_CODES_HELP: &str = "EXIT CODES:
+    1  encoding failed
+    2  invalid arguments
+    3  input file not found
+    4  input could not be decoded
+    5  output could not be written
+    6  aborted";
+
+impl ExitCode {
+    /// Errors are tagged where they're created, because the same error type can come from the input or the output
+    pub fn of(err: &(dyn Error + 'static)) -> Self {
+        match err.downcast_ref::<Failure>() {
+            Some(failure) => failure.code,
+            None => Self::Other,
+        }
+    }
+
+    pub fn of_encoding(err: &gifski::Error) -> Self {
+        match err {
+            gifski::Error::Aborted => Self::Aborted,
+            // reading of the input is done by the caller, so this is the output
+            gifski::Error::Io(_) => Self::WriteFailed,
+            gifski::Error::NoFrames | gifski::Error::PNG(_) | gifski::Error::GifDecode(_) | gifski::Error::WrongSize(_) => Self::DecodeFailed,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Error tagged with the exit code it should cause
+#[derive(Debug)]
+pub struct Failure {
+    code: ExitCode,
+    error: Box<dyn Error + Send + Sync>,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.error.fmt(f)
+    }
+}
+
+impl Error for Failure {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.source()
+    }
+}
+
+pub trait WithExitCode<T> {
+    /// Keeps the exit code if the error already has one
+    fn exit_code(self, code: ExitCode) -> BinResult<T>;
+}
+
+impl<T, E: Into<Box<dyn Error + Send + Sync>>> WithExitCode<T> for Result<T, E> {
+    fn exit_code(self, code: ExitCode) -> BinResult<T> {
+        self.map_err(|err| {
+            let error = err.into();
+            if error.is::<Failure>() {
+                error
+            } else {
+                Box::new(Failure { code, error }) as Box<dyn Error + Send + Sync>
+            }
+        })
+    }
+}
+
+#[test]
+fn untagged_errors_are_generic_failures() {
+    let untagged: Box<dyn Error + Send + Sync> = "oops".into();
+    assert_eq!(ExitCode::Other, ExitCode::of(&*untagged));
+
+    let tagged = Err::<(), _>("bad").exit_code(ExitCode::BadArguments);
+    // the first code is kept
+    let retagged = tagged.exit_code(ExitCode::DecodeFailed);
+    assert_eq!(ExitCode::BadArguments, ExitCode::of(&*retagged.unwrap_err()));
+
+    let io = gifski::Error::Io(std::io::ErrorKind::Other.into());
+    assert_eq!(ExitCode::WriteFailed, ExitCode::of_encoding(&io));
+    let temp = gifski::Error::TempFile(std::io::ErrorKind::Other.into());
+    assert_eq!(ExitCode::Other, ExitCode::of_encoding(&temp));
+}
@@ -0,0 +1,55 @@
+use crate::BinResult;
+use imgref::*;
+use rgb::*;
+use std::path::{Path, PathBuf};
+
+/// Name of the list of exported frames, in the format of `--from-manifest`
+const MANIFEST_FILE_NAME: &str = "manifest.txt";
+
+/// Saves frames as they are shown in the GIF, as numbered PNG files with a manifest of their durations
+pub struct FrameExporter {
+    dir: PathBuf,
+    frames: usize,
+    manifest: String,
+    /// Frames arrive in a callback, so the first error is kept for later
+    error: Option<String>,
+}
+
+impl FrameExporter {
+    pub fn new(dir: &Path) -> BinResult<Self> {
+        std::fs::create_dir_all(dir)
+            .map_err(|e| format!("Can't create {}: {}", dir.display(), e))?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            frames: 0,
+            manifest: String::new(),
+            error: None,
+        })
+    }
+
+    /// `delay` is in 1/100ths of a second
+    pub fn add(&mut self, frame: ImgRef<'_, RGBA8>, delay: u16) {
+        if self.error.is_some() {
+            return;
+        }
+        self.frames += 1;
+        let file_name = format!("{:05}.png", self.frames);
+        let path = self.dir.join(&file_name);
+        let (buf, width, height) = frame.to_contiguous_buf();
+        if let Err(e) = lodepng::encode32_file(&path, &buf, width, height) {
+            self.error = Some(format!("Can't write {}: {}", path.display(), e));
+            return;
+        }
+        self.manifest += &format!("{} {}\n", file_name, u32::from(delay) * 10);
+    }
+
+    pub fn finish(&mut self) -> BinResult<()> {
+        if let Some(e) = self.error.take() {
+            Err(e)?;
+        }
+        let path = self.dir.join(MANIFEST_FILE_NAME);
+        std::fs::write(&path, &self.manifest)
+            .map_err(|e| format!("Can't write {}: {}", path.display(), e))?;
+        Ok(())
+    }
+}
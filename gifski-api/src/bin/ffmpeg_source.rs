@@ -8,8 +8,16 @@ use std::path::Path;
 
 pub struct FfmpegDecoder {
     input_context: ffmpeg::format::context::Input,
+    stream_index: usize,
+    /// Clockwise, 0, 90, 180 or 270 degrees
+    rotation: i32,
+    /// Pipes can't seek
+    seekable: bool,
+    /// User's filtergraph, applied after rotation
+    video_filter: Option<String>,
     frames: u64,
     rate: Fps,
+    trim: Trim,
     settings: Settings,
 }
 
@@ -23,29 +31,87 @@ impl Source for FfmpegDecoder {
 }
 
 impl FfmpegDecoder {
-    pub fn new(path: &Path, rate: Fps, settings: Settings) -> BinResult<Self> {
+    /// `video_stream` counts only video streams, from 0. By default it's the one FFmpeg thinks is the main one.
+    /// `video_filter` is in FFmpeg's filtergraph syntax, as in `ffmpeg -vf`.
+    /// The path `-` reads the video from stdin.
+    pub fn new(path: &Path, video_stream: Option<usize>, video_filter: Option<&str>, rate: Fps, trim: Trim, settings: Settings) -> BinResult<Self> {
         ffmpeg::init().map_err(|e| format!("Unable to initialize ffmpeg: {}", e))?;
-        let input_context = ffmpeg::format::input(&path)
-            .map_err(|e| format!("Unable to open video file {}: {}", path.display(), e))?;
-        // take fps override into account
-        let filter_fps = rate.fps / rate.speed;
-        let stream = input_context.streams().best(ffmpeg::media::Type::Video).ok_or("The file has no video tracks")?;
+        let seekable = path != Path::new("-");
+        let input_context = if seekable {
+            ffmpeg::format::input(&path)
+                .map_err(|e| format!("Unable to open video file {}: {}", path.display(), e))?
+        } else {
+            ffmpeg::format::input(&"pipe:0")
+                .map_err(|e| format!("Unable to read video from stdin: {}", e))?
+        };
+        let stream = match video_stream {
+            None => input_context.streams().best(ffmpeg::media::Type::Video).ok_or("The file has no video tracks")?,
+            Some(n) => input_context.streams()
+                .filter(|stream| stream.codec().medium() == ffmpeg::media::Type::Video)
+                .nth(n)
+                .ok_or_else(|| format!("The file has no video stream {} (they're counted from 0)", n))?,
+        };
+        let stream_index = stream.index();
+        let rotation = display_rotation(&stream);
         let time_base = stream.time_base().numerator() as f64 / stream.time_base().denominator() as f64;
-        let frames = (stream.duration() as f64 * time_base * filter_fps as f64).ceil() as u64;
+        let duration = stream.duration() as f64 * time_base;
+        let end = trim.end.map_or(duration, |end| end.min(duration));
+        // take fps and speed overrides into account
+        let duration = rate.output_time(end) - rate.output_time(trim.start);
+        let frames = (duration.max(0.) * rate.fps as f64).ceil() as u64;
         Ok(Self {
             input_context,
+            stream_index,
+            rotation,
+            seekable,
+            video_filter: video_filter.map(String::from),
             frames,
             rate,
+            trim,
             settings,
         })
     }
 
+    /// Summary for `gifski info`
+    pub fn info(&self) -> BinResult<String> {
+        let format = self.input_context.format();
+        let stream = self.input_context.stream(self.stream_index).ok_or("The video stream is missing")?;
+        let codec = stream.codec();
+        let codec_id = codec.id();
+        let video = codec.decoder().video().map_err(|e| format!("Unable to decode the video: {}", e))?;
+        let fps = stream.avg_frame_rate();
+        let fps = if fps.denominator() != 0 { fps.numerator() as f64 / fps.denominator() as f64 } else { 0. };
+        let time_base = stream.time_base().numerator() as f64 / stream.time_base().denominator() as f64;
+        let mut info = format!("{} ({}), {:?} video stream {}, {}x{}, {:.3} fps, {} frames, {:.2}s",
+            format.description(), format.name(), codec_id, self.stream_index,
+            video.width(), video.height(), fps, stream.frames(), stream.duration() as f64 * time_base);
+        if self.rotation != 0 {
+            info += &format!(", rotated {}°", self.rotation);
+        }
+        Ok(info)
+    }
+
     pub fn collect_frames(&mut self, dest: &mut Collector) -> BinResult<()> {
-        let (stream_index, mut decoder, mut filter) = {
-            let filter_fps = self.rate.fps / self.rate.speed;
-            let stream = self.input_context.streams().best(ffmpeg::media::Type::Video).ok_or("The file has no video tracks")?;
+        if self.trim.start > 0. && self.seekable {
+            // in AV_TIME_BASE units; lands on a keyframe before the start, and the trim filter drops the rest
+            let ts = (self.trim.start * 1_000_000.) as i64;
+            self.input_context.seek(ts, ..ts)
+                .map_err(|e| format!("Unable to seek to {}s: {}", self.trim.start, e))?;
+        }
 
-            let decoder = stream.codec().decoder().video().map_err(|e| format!("Unable to decode the codec used in the video: {}", e))?;
+        let (stream_index, time_base, mut decoder, mut filter) = {
+            let filter_fps = self.rate.input_fps();
+            let stream = self.input_context.stream(self.stream_index).ok_or("The file has no video tracks")?;
+
+            let mut decoder = stream.codec().decoder();
+            if self.settings.threads > 0 {
+                decoder.set_threading(ffmpeg::threading::Config {
+                    kind: ffmpeg::threading::Type::Frame,
+                    count: self.settings.threads.into(),
+                    safe: false,
+                });
+            }
+            let decoder = decoder.video().map_err(|e| format!("Unable to decode the codec used in the video: {}", e))?;
 
             let (dest_width, dest_height) = self.settings.dimensions_for_image(decoder.width() as _, decoder.height() as _);
 
@@ -64,13 +130,37 @@ impl FfmpegDecoder {
             let mut filter = ffmpeg::filter::Graph::new();
             filter.add(&ffmpeg::filter::find("buffer").ok_or("ffmpeg format error")?, "in", &buffer_args)?;
             filter.add(&ffmpeg::filter::find("buffersink").ok_or("ffmpeg format error")?, "out", "")?;
-            filter.output("in", 0)?.input("out", 0)?.parse(&format!("fps=fps={},format=rgba", filter_fps))?;
+            let trim = match (self.trim.start, self.trim.end) {
+                (start, Some(end)) => format!("trim=start={}:end={},setpts=PTS-STARTPTS,", start, end),
+                (start, None) if start > 0. => format!("trim=start={},setpts=PTS-STARTPTS,", start),
+                _ => String::new(),
+            };
+            // phones record sideways, and only tell players to rotate the video
+            let rotate = match self.rotation {
+                90 => "transpose=clock,",
+                180 => "hflip,vflip,",
+                270 => "transpose=cclock,",
+                _ => "",
+            };
+            let user_filter = self.video_filter.as_ref().map(|f| format!("{},", f)).unwrap_or_default();
+            filter.output("in", 0)?.input("out", 0)?.parse(&format!("{}{}{}fps=fps={},format=rgba", trim, rotate, user_filter, filter_fps))
+                .map_err(|e| if user_filter.is_empty() { e.to_string() } else { format!("Invalid --vf filtergraph: {}", e) })?;
             filter.validate()?;
-            (stream.index(), decoder, filter)
+            let time_base = stream.time_base().numerator() as f64 / stream.time_base().denominator() as f64;
+            (stream.index(), time_base, decoder, filter)
         };
 
 
-        let mut add_frame = |rgba_frame: &ffmpeg::util::frame::Video, pts: f64, pos: i64| -> BinResult<()> {
+        // the filter's output is sampled at a constant rate, and speed ranges drop some of the frames
+        let input_frame_step = 1.0 / self.rate.input_fps();
+        let input_start = self.trim.start;
+        let mut retimer = Retimer::new(&self.rate, input_start);
+        let mut n = 0;
+        let mut add_frame = |rgba_frame: &ffmpeg::util::frame::Video, i: i64| -> BinResult<()> {
+            let pts = match retimer.pts(input_start + input_frame_step * i as f64) {
+                Some(pts) => pts,
+                None => return Ok(()),
+            };
             let stride = rgba_frame.stride(0) as usize;
             if stride % 4 != 0 {
                 Err("incompatible video")?;
@@ -81,14 +171,18 @@ impl FfmpegDecoder {
                 rgba_frame.height() as usize,
                 stride / 4,
             );
-            Ok(dest.add_frame_rgba(pos as usize, rgba_frame, pts)?)
+            dest.add_frame_rgba(n, rgba_frame, pts)?;
+            n += 1;
+            Ok(())
         };
 
         let mut vid_frame = ffmpeg::util::frame::Video::empty();
         let mut filt_frame = ffmpeg::util::frame::Video::empty();
         let mut i = 0;
         let mut pts_last_packet = 0;
-        let pts_frame_step = 1.0 / self.rate.fps as f64;
+
+        // stop decoding after the end, with a margin for frames that are decoded out of order
+        let last_pts = self.trim.end.map(|end| ((end + 1.) / time_base) as i64);
 
         let packets = self.input_context.packets().filter_map(|(s, packet)| {
             if s.index() != stream_index {
@@ -99,6 +193,10 @@ impl FfmpegDecoder {
                 Some(packet)
             }
         })
+        .take_while(|packet| match (last_pts, packet.pts()) {
+            (Some(last), Some(pts)) => pts <= last,
+            _ => true,
+        })
         // extra packet to flush remaining frames
         .chain(std::iter::once(ffmpeg::Packet::empty()));
 
@@ -109,7 +207,7 @@ impl FfmpegDecoder {
                 let mut out = filter.get("out").ok_or("ffmpeg format error")?;
                 let mut out = out.sink();
                 while let Ok(..) = out.frame(&mut filt_frame) {
-                    add_frame(&filt_frame, pts_frame_step * i as f64, i)?;
+                    add_frame(&filt_frame, i)?;
                     i += 1;
                 }
             }
@@ -120,9 +218,33 @@ impl FfmpegDecoder {
         let mut out = filter.get("out").ok_or("ffmpeg format error")?;
         let mut out = out.sink();
         while let Ok(..) = out.frame(&mut filt_frame) {
-            add_frame(&filt_frame, pts_frame_step * i as f64, i)?;
+            add_frame(&filt_frame, i)?;
             i += 1;
         }
         Ok(())
     }
 }
+
+/// Clockwise rotation the video should be displayed with, rounded to 0, 90, 180 or 270 degrees
+fn display_rotation(stream: &ffmpeg::format::stream::Stream<'_>) -> i32 {
+    let degrees = stream.metadata().get("rotate").and_then(|r| r.parse::<f64>().ok())
+        .or_else(|| stream.side_data()
+            .find(|side_data| side_data.kind() == ffmpeg::codec::packet::side_data::Type::DisplayMatrix)
+            .and_then(|side_data| display_matrix_rotation(side_data.data())))
+        .unwrap_or(0.);
+    ((degrees / 90.).round() as i32 * 90).rem_euclid(360)
+}
+
+/// Angle of a 3x3 matrix of 16.16 fixed-point numbers, same as FFmpeg's `av_display_rotation_get()` but clockwise
+fn display_matrix_rotation(data: &[u8]) -> Option<f64> {
+    if data.len() < 9 * 4 {
+        return None;
+    }
+    let m = |i: usize| f64::from(i32::from_ne_bytes([data[i * 4], data[i * 4 + 1], data[i * 4 + 2], data[i * 4 + 3]])) / 65536.;
+    let scale0 = m(0).hypot(m(3));
+    let scale1 = m(1).hypot(m(4));
+    if scale0 == 0. || scale1 == 0. {
+        return None;
+    }
+    Some((m(1) / scale1).atan2(m(0) / scale0).to_degrees())
+}
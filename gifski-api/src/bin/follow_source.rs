@@ -0,0 +1,152 @@
+use crate::png::add_frame_file;
+use crate::tonemap::ToneMap;
+use crate::source::*;
+use crate::BinResult;
+use gifski::Collector;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Image files that keep being added while encoding, e.g. by a screen recorder
+pub struct FollowDecoder {
+    watched: Watched,
+    rate: Fps,
+    /// Stops when no new frames have appeared for this long
+    idle_timeout: Duration,
+    /// Stops when this file appears
+    sentinel: Option<PathBuf>,
+    tone_map: ToneMap,
+}
+
+enum Watched {
+    /// All images in it, in natural order of names. Files that appear later are added
+    /// even if their names sort before files already added.
+    Dir { dir: PathBuf, added: HashSet<PathBuf> },
+    /// Numbered files, like frame%04d.png
+    Pattern { prefix: String, width: usize, suffix: String, next: Option<u64> },
+}
+
+impl FollowDecoder {
+    pub fn new(target: &str, start_number: Option<u64>, rate: Fps, idle_timeout: Duration, sentinel: Option<PathBuf>, tone_map: ToneMap) -> BinResult<Self> {
+        let watched = if let Some((prefix, width, suffix)) = crate::parse_pattern(target) {
+            Watched::Pattern { prefix: prefix.into(), width, suffix: suffix.into(), next: start_number }
+        } else if Path::new(target).is_dir() {
+            Watched::Dir { dir: target.into(), added: HashSet::new() }
+        } else {
+            Err(format!("--follow needs a directory or a pattern like frame%04d.png, not {}", target))?
+        };
+        Ok(Self { watched, rate, idle_timeout, sentinel, tone_map })
+    }
+
+    /// New files in order. The last one may still be being written.
+    fn poll(&mut self) -> BinResult<Vec<PathBuf>> {
+        let sentinel = &self.sentinel;
+        Ok(match &mut self.watched {
+            Watched::Dir { dir, added } => {
+                let mut files = Vec::new();
+                for entry in std::fs::read_dir(dir)? {
+                    let path = entry?.path();
+                    if is_image(&path) && sentinel.as_ref() != Some(&path) && !added.contains(&path) {
+                        files.push(path);
+                    }
+                }
+                files.sort_by(|a, b| natord::compare(&a.to_string_lossy(), &b.to_string_lossy()));
+                files
+            },
+            Watched::Pattern { prefix, width, suffix, next } => {
+                let path = |n: u64| PathBuf::from(format!("{}{:0width$}{}", prefix, n, suffix, width = *width));
+                if next.is_none() {
+                    // numbering may start at 0 or 1
+                    *next = (0..=4).find(|&n| path(n).exists());
+                }
+                match *next {
+                    Some(start) => (start..).map(path).take_while(|p| p.exists()).collect(),
+                    None => Vec::new(),
+                }
+            },
+        })
+    }
+
+    fn mark_added(&mut self, path: &Path) {
+        match &mut self.watched {
+            Watched::Dir { added, .. } => {
+                added.insert(path.to_owned());
+            },
+            Watched::Pattern { next, .. } => *next = next.map(|n| n + 1),
+        }
+    }
+}
+
+impl Source for FollowDecoder {
+    /// Unknown until the end
+    fn total_frames(&self) -> u64 {
+        0
+    }
+
+    fn collect(&mut self, dest: &mut Collector) -> BinResult<()> {
+        let rate = self.rate.clone();
+        let mut retimer = Retimer::new(&rate, 0.);
+        let mut i = 0;
+        let mut n = 0;
+        let mut last_added = Instant::now();
+        // size of the last file seen, to add it only after it stops growing
+        let mut pending: Option<(PathBuf, u64)> = None;
+        loop {
+            let finished = self.sentinel.as_ref().map_or(false, |s| s.exists());
+            let files = self.poll()?;
+            let count = files.len();
+            for (pos, path) in files.into_iter().enumerate() {
+                let size = path.metadata().map(|m| m.len()).unwrap_or(0);
+                let complete = finished || pos + 1 < count || pending.as_ref().map_or(false, |(p, s)| *p == path && *s == size && size > 0);
+                if !complete {
+                    pending = Some((path, size));
+                    break;
+                }
+                if let Some(pts) = retimer.pts(i as f64 / f64::from(rate.fps)) {
+                    add_frame_file(dest, n, path.clone(), pts, self.tone_map)?;
+                    n += 1;
+                }
+                i += 1;
+                self.mark_added(&path);
+                last_added = Instant::now();
+                pending = None;
+            }
+            if finished || last_added.elapsed() >= self.idle_timeout {
+                break;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+        Ok(())
+    }
+}
+
+pub fn is_image(path: &Path) -> bool {
+    path.is_file() && path.extension().and_then(|e| e.to_str()).map_or(false, |ext| {
+        ["png", "jpg", "jpeg", "gif", "bmp", "tif", "tiff", "webp", "exr"].iter().any(|known| ext.eq_ignore_ascii_case(known))
+    })
+}
+
+#[test]
+fn follows_files_added_out_of_order() {
+    let dir = std::env::temp_dir().join(format!("gifski-follow-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let write = |name: &str| std::fs::write(dir.join(name), b"png").unwrap();
+    write("frame2.png");
+    write("frame10.png");
+    write("notes.txt");
+    let rate = Fps { fps: 10., speed: 1., speed_ranges: Vec::new() };
+    let mut decoder = FollowDecoder::new(&dir.to_string_lossy(), None, rate, Duration::from_secs(1), None, ToneMap::Auto).unwrap();
+    let files = decoder.poll().unwrap();
+    assert_eq!(vec![dir.join("frame2.png"), dir.join("frame10.png")], files);
+    for path in &files {
+        decoder.mark_added(path);
+    }
+    // written late, e.g. by another thread of the recorder
+    write("frame1.png");
+    write("frame11.png");
+    let files = decoder.poll().unwrap();
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_eq!(vec![dir.join("frame1.png"), dir.join("frame11.png")], files);
+}
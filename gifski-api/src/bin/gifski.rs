@@ -1,32 +1,60 @@
 #[macro_use] extern crate clap;
 
 use std::ffi::OsStr;
-use gifski::{Settings, Repeat};
+use gifski::{Crop, Dithering, Look, LossyMode, MatteMode, Pad, Settings, Repeat};
 
+mod apng_output;
+mod apng_source;
+mod archive_source;
+mod color;
+mod compare;
+mod concat_source;
+mod exit_code;
+mod export_frames;
+mod follow_source;
+mod html_preview;
+mod info;
 #[cfg(feature = "video")]
 mod ffmpeg_source;
+#[cfg(all(feature = "h264", not(feature = "video")))]
+mod mp4_source;
+mod json_progress;
+mod overlay;
+mod palette_file;
 mod png;
+mod progress_bar;
+mod raw_source;
+mod resume;
 mod source;
+mod tonemap;
+mod timestamps;
+mod y4m_source;
+use crate::exit_code::{ExitCode, WithExitCode};
 use crate::source::*;
 
-use gifski::progress::{NoProgress, ProgressBar, ProgressReporter};
+use gifski::progress::{FrameStats, NoProgress, ProgressReporter, StageTimes};
 
 pub type BinResult<T, E = Box<dyn std::error::Error + Send + Sync>> = Result<T, E>;
 
-use clap::{App, AppSettings, Arg};
+use clap::{App, AppSettings, Arg, ArgMatches};
+use rgb::{RGB8, RGBA8};
 
 use std::env;
 use std::fmt;
 use std::fs::File;
 use std::io;
+use std::io::Read;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[cfg(feature = "video")]
-const VIDEO_FRAMES_ARG_HELP: &str = "one video file supported by FFmpeg, or multiple PNG image files";
-#[cfg(not(feature = "video"))]
-const VIDEO_FRAMES_ARG_HELP: &str = "PNG image files";
+const VIDEO_FRAMES_ARG_HELP: &str = "one video file supported by FFmpeg, or multiple PNG image files or a directory of them, or a pattern like frame%04d.png";
+#[cfg(all(feature = "h264", not(feature = "video")))]
+const VIDEO_FRAMES_ARG_HELP: &str = "one H.264 video in an MP4 file, or multiple PNG image files or a directory of them, or a pattern like frame%04d.png";
+#[cfg(not(any(feature = "video", feature = "h264")))]
+const VIDEO_FRAMES_ARG_HELP: &str = "PNG image files or a directory of them, or a pattern like frame%04d.png, or one Y4M video file";
 
 fn main() {
     if let Err(e) = bin_main() {
@@ -34,12 +62,18 @@ fn main() {
         if let Some(e) = e.source() {
             eprintln!("error: {}", e);
         }
-        std::process::exit(1);
+        std::process::exit(ExitCode::of(&*e) as i32);
     }
 }
 
 #[allow(clippy::float_cmp)]
 fn bin_main() -> BinResult<()> {
+    // not clap subcommands, because clap would reject files named like "info.png" as misspelled subcommands
+    match env::args_os().nth(1) {
+        Some(arg) if arg == "info" => return info::info_main().exit_code(ExitCode::DecodeFailed),
+        Some(arg) if arg == "compare" => return compare::compare_main().exit_code(ExitCode::DecodeFailed),
+        _ => {},
+    }
     let matches = App::new(crate_name!())
                         .version(crate_version!())
                         .about("https://gif.ski by Kornel Lesiński")
@@ -47,6 +81,7 @@ fn bin_main() -> BinResult<()> {
                         .setting(AppSettings::DeriveDisplayOrder)
                         .setting(AppSettings::ArgRequiredElseHelp)
                         .setting(AppSettings::AllowNegativeNumbers)
+                        .after_help(exit_code::EXIT_CODES_HELP)
                         .arg(Arg::with_name("output")
                             .long("output")
                             .short("o")
@@ -54,7 +89,14 @@ fn bin_main() -> BinResult<()> {
                             .empty_values(false)
                             .takes_value(true)
                             .value_name("a.gif")
-                            .required(true))
+                            .required_unless_one(&["estimate", "export-frames"]))
+                        .arg(Arg::with_name("format")
+                            .long("format")
+                            .takes_value(true)
+                            .possible_values(&["gif", "apng"])
+                            .help("File format of the output. By default it's APNG if \n\
+                                   the output file name ends with .png or .apng, \n\
+                                   and GIF otherwise"))
                         .arg(Arg::with_name("fps")
                             .long("fps")
                             .short("r")
@@ -70,8 +112,51 @@ fn bin_main() -> BinResult<()> {
                             .long("fast-forward")
                             .help("Multiply speed of video by a factor\n(no effect when using PNG files as input)")
                             .empty_values(false)
+                            .number_of_values(1)
+                            .multiple(true)
                             .value_name("x")
                             .default_value("1"))
+                        .arg(Arg::with_name("speed-map")
+                            .long("speed-map")
+                            .takes_value(true)
+                            .value_name("0-2s:1x,2-4s:4x")
+                            .help("Play these parts of the input (in seconds) at \n\
+                                   a different speed"))
+                        .arg(Arg::with_name("start")
+                            .long("start")
+                            .help("Skip this many seconds of the video")
+                            .takes_value(true)
+                            .number_of_values(1)
+                            .multiple(true)
+                            .value_name("sec"))
+                        .arg(Arg::with_name("duration")
+                            .long("duration")
+                            .help("Use only this many seconds of the video")
+                            .takes_value(true)
+                            .number_of_values(1)
+                            .multiple(true)
+                            .conflicts_with("end")
+                            .value_name("sec"))
+                        .arg(Arg::with_name("end")
+                            .long("end")
+                            .help("Stop at this time of the video, in seconds")
+                            .takes_value(true)
+                            .number_of_values(1)
+                            .multiple(true)
+                            .value_name("sec"))
+                        .arg(Arg::with_name("video-stream")
+                            .long("video-stream")
+                            .help("Which of the video's video streams to use, counting from 0. \n\
+                                   By default it's the main one")
+                            .takes_value(true)
+                            .value_name("N"))
+                        .arg(Arg::with_name("vf")
+                            .long("vf")
+                            .help("FFmpeg filters to apply to the video before fps \n\
+                                   conversion, e.g. \"crop=640:480,eq=saturation=1.5\"")
+                            .takes_value(true)
+                            .conflicts_with_all(&["raw-video", "follow"])
+                            .value_name("filters"))
                         .arg(Arg::with_name("fast")
                             .long("fast")
                             .help("3 times faster encoding, but 10% lower quality and \nlarger file size"))
@@ -81,12 +166,285 @@ fn bin_main() -> BinResult<()> {
                             .value_name("1-100")
                             .takes_value(true)
                             .help("Lower quality may give smaller file"))
+                        .arg(Arg::with_name("colors")
+                            .long("colors")
+                            .takes_value(true)
+                            .value_name("2-256")
+                            .help("Use at most this many colors per frame"))
+                        .arg(Arg::with_name("dither")
+                            .long("dither")
+                            .takes_value(true)
+                            .value_name("mode[:strength]")
+                            .help("none, ordered or diffusion, optionally with strength \n\
+                                   0-1 (e.g. diffusion:0.5). By default depends on quality"))
+                        .arg(Arg::with_name("matte")
+                            .long("matte")
+                            .takes_value(true)
+                            .value_name("color")
+                            .help("Blend transparent pixels with this color, e.g. white, \n\
+                                   #ffffff or #ffffff80, instead of dithering them"))
+                        .arg(Arg::with_name("matte-mode")
+                            .long("matte-mode")
+                            .takes_value(true)
+                            .possible_values(&["semi", "flatten"])
+                            .requires("matte")
+                            .help("Blend only semi-transparent pixels (default), or \n\
+                                   flatten all transparency"))
+                        .arg(Arg::with_name("fixed-color")
+                            .long("fixed-color")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1)
+                            .value_name("color")
+                            .help("Always include this color in the palette, e.g. for \n\
+                                   a logo. Can be used multiple times"))
+                        .arg(Arg::with_name("fixed-palette")
+                            .long("fixed-palette")
+                            .takes_value(true)
+                            .value_name("palette.gpl")
+                            .help("Load fixed colors from a GIMP (.gpl), Adobe (.act) \n\
+                                   or hex list (.hex) palette file"))
+                        .arg(Arg::with_name("fixed-palette-mode")
+                            .long("fixed-palette-mode")
+                            .takes_value(true)
+                            .possible_values(&["include", "exact"])
+                            .requires("fixed-palette")
+                            .help("Add the palette's colors to the best colors for each \n\
+                                   frame (default), or use exactly the palette's colors"))
+                        .arg(Arg::with_name("lossy")
+                            .long("lossy")
+                            .takes_value(true)
+                            .value_name("0-200")
+                            .help("Use this amount of lossy compression instead of \n\
+                                   deriving it from quality"))
+                        .arg(Arg::with_name("adaptive-loss")
+                            .long("adaptive-loss")
+                            .help("With quality below 100, compress static frames less \n\
+                                   and high-motion frames more"))
+                        .arg(Arg::with_name("min-psnr")
+                            .long("min-psnr")
+                            .takes_value(true)
+                            .value_name("dB")
+                            .help("With quality below 100, compress frames with less \n\
+                                   loss if they'd be worse than this (e.g. 35)"))
+                        .arg(Arg::with_name("extra")
+                            .long("extra")
+                            .help("Slower encoding, but slightly smaller files"))
+                        .arg(Arg::with_name("interlace")
+                            .long("interlace")
+                            .help("Interlaced frames, which some viewers show progressively \n\
+                                   while loading. Files are slightly larger"))
+                        .arg(Arg::with_name("dedup-threshold")
+                            .long("dedup-threshold")
+                            .takes_value(true)
+                            .value_name("0-255")
+                            .help("Merge consecutive frames if no pixel differs by \n\
+                                   more than this. Helps with noisy recordings"))
+                        .arg(Arg::with_name("brightness")
+                            .long("brightness")
+                            .takes_value(true)
+                            .value_name("-100-100")
+                            .allow_hyphen_values(true)
+                            .help("Make frames brighter or darker (0 is unchanged)"))
+                        .arg(Arg::with_name("contrast")
+                            .long("contrast")
+                            .takes_value(true)
+                            .value_name("-100-100")
+                            .allow_hyphen_values(true)
+                            .help("Increase or reduce contrast (0 is unchanged)"))
+                        .arg(Arg::with_name("gamma")
+                            .long("gamma")
+                            .takes_value(true)
+                            .value_name("0.1-10")
+                            .help("Above 1 brightens dark areas, below 1 darkens them"))
+                        .arg(Arg::with_name("grayscale")
+                            .long("grayscale")
+                            .help("Make frames black-and-white. Makes files smaller"))
+                        .arg(Arg::with_name("sepia")
+                            .long("sepia")
+                            .conflicts_with("grayscale")
+                            .help("Make frames look like an old photo"))
+                        .arg(Arg::with_name("sharpen")
+                            .long("sharpen")
+                            .takes_value(true)
+                            .value_name("0-100")
+                            .help("Sharpen frames after resizing. Helps downscaled text"))
+                        .arg(Arg::with_name("blur")
+                            .long("blur")
+                            .takes_value(true)
+                            .value_name("radius")
+                            .help("Blur frames after resizing, by this many pixels. \n\
+                                   Slight blur (e.g. 0.5) makes noisy videos smaller"))
+                        .arg(Arg::with_name("pad")
+                            .long("pad")
+                            .takes_value(true)
+                            .value_name("w:h[:color]")
+                            .help("Add borders to make frames this aspect ratio, e.g. \n\
+                                   1:1 for square. Transparent unless color is given"))
+                        .arg(Arg::with_name("deband")
+                            .long("deband")
+                            .help("Smooth out banding in gradients, e.g. in screen \n\
+                                   recordings of dark apps"))
+                        .arg(Arg::with_name("stabilize")
+                            .long("stabilize")
+                            .help("Reduce camera shake of handheld videos. Crops 5% \n\
+                                   off each edge"))
+                        .arg(Arg::with_name("bounce")
+                            .long("bounce")
+                            .help("Play the animation forwards, and then backwards"))
+                        .arg(Arg::with_name("end-pause")
+                            .long("end-pause")
+                            .help("Show the last frame for this many seconds longer")
+                            .takes_value(true)
+                            .value_name("sec"))
+                        .arg(Arg::with_name("loop-crossfade")
+                            .long("loop-crossfade")
+                            .help("Blend this many seconds of the beginning into the end, \n\
+                                   so that the animation doesn't jump when it loops")
+                            .takes_value(true)
+                            .value_name("sec")
+                            .conflicts_with("bounce"))
+                        .arg(Arg::with_name("reverse")
+                            .long("reverse")
+                            .help("Play the animation backwards"))
+                        .arg(Arg::with_name("crop")
+                            .long("crop")
+                            .takes_value(true)
+                            .value_name("X,Y,WxH")
+                            .help("Use only this area of the input frames.\n\
+                                   Cropping is done before resizing"))
+                        .arg(Arg::with_name("overlay")
+                            .long("overlay")
+                            .takes_value(true)
+                            .value_name("logo.png[:position[:opacity]]")
+                            .help("Draw this image on every frame, e.g. logo.png:top-left:0.5\n\
+                                   Positions are top-left, top-right, bottom-left, bottom-right"))
+                        .arg(Arg::with_name("text")
+                            .long("text")
+                            .takes_value(true)
+                            .value_name("caption")
+                            .help("Draw this text on every frame"))
+                        .arg(Arg::with_name("timestamp")
+                            .long("timestamp")
+                            .help("Draw the time of each frame on it"))
+                        .arg(Arg::with_name("text-position")
+                            .long("text-position")
+                            .takes_value(true)
+                            .value_name("corner")
+                            .default_value("bottom-left")
+                            .possible_values(&["top-left", "top-right", "bottom-left", "bottom-right"])
+                            .help("Where to draw --text and --timestamp"))
+                        .arg(Arg::with_name("text-size")
+                            .long("text-size")
+                            .takes_value(true)
+                            .value_name("px")
+                            .help("Font size for --text and --timestamp"))
+                        .arg(Arg::with_name("font")
+                            .long("font")
+                            .takes_value(true)
+                            .value_name("font.ttf")
+                            .help("Font file for --text and --timestamp"))
+                        .arg(Arg::with_name("poster")
+                            .long("poster")
+                            .takes_value(true)
+                            .value_name("still.png")
+                            .help("Also save a frame as a PNG file, e.g. for a preview"))
+                        .arg(Arg::with_name("export-frames")
+                            .long("export-frames")
+                            .takes_value(true)
+                            .value_name("dir")
+                            .conflicts_with_all(&["estimate", "resume", "max-size"])
+                            .help("Also save frames as they are shown in the GIF, \n\
+                                   including --lossy artifacts, as PNG files with \n\
+                                   a manifest.txt of their durations. \n\
+                                   Without --output, no GIF is compressed"))
+                        .arg(Arg::with_name("html")
+                            .long("html")
+                            .takes_value(true)
+                            .value_name("preview.html")
+                            .requires("output")
+                            .conflicts_with_all(&["estimate", "optimize"])
+                            .help("Also write a web page with the GIF embedded in it, \n\
+                                   its size, dimensions and settings"))
+                        .arg(Arg::with_name("poster-at")
+                            .long("poster-at")
+                            .takes_value(true)
+                            .value_name("sec")
+                            .requires("poster")
+                            .help("Time of the frame to use for --poster (default 0)"))
+                        .arg(Arg::with_name("max-size")
+                            .long("max-size")
+                            .takes_value(true)
+                            .value_name("8MB")
+                            .help("Lower quality as needed to make the file fit in this \n\
+                                   size. Decodes once, but may encode multiple times"))
+                        .arg(Arg::with_name("estimate")
+                            .long("estimate")
+                            .conflicts_with("max-size")
+                            .help("Encode without writing the file, and print its size \n\
+                                   and duration"))
+                        .arg(Arg::with_name("resume")
+                            .long("resume")
+                            .requires("output")
+                            .conflicts_with_all(&["max-size", "estimate", "poster"])
+                            .help("Write to a .part file, and if it's there from an \n\
+                                   interrupted run with the same options, continue it"))
+                        .arg(Arg::with_name("optimize")
+                            .long("optimize")
+                            .conflicts_with_all(&["max-size", "estimate", "resume", "files-from", "from-manifest", "raw-video", "follow", "export-frames"])
+                            .help("Make an existing GIF smaller without quantizing it \n\
+                                   again. Applies --lossy or --quality, if given"))
+                        .arg(Arg::with_name("benchmark")
+                            .long("benchmark")
+                            .help("Print how much time each stage of encoding took"))
+                        .arg(Arg::with_name("stats")
+                            .long("stats")
+                            .help("Print details of the result, such as skipped frames \n\
+                                   and average palette size"))
+                        .arg(Arg::with_name("threads")
+                            .long("threads")
+                            .takes_value(true)
+                            .value_name("num")
+                            .help("Use at most this many threads for video decoding \n\
+                                   and quantization. Diffing, remapping and writing \n\
+                                   use one thread each regardless"))
+                        .arg(Arg::with_name("deterministic")
+                            .long("deterministic")
+                            .conflicts_with("follow")
+                            .help("Make the same GIF from the same input and options on \n\
+                                   any machine, at the cost of slower quantization"))
+                        .arg(Arg::with_name("memory-limit")
+                            .long("memory-limit")
+                            .takes_value(true)
+                            .value_name("MB")
+                            .help("Slow down reading frames instead of letting frames \n\
+                                   waiting between encoding stages use more memory than this"))
+                        .arg(Arg::with_name("reuse-palettes")
+                            .long("reuse-palettes")
+                            .help("Use the previous frame's palette again when the colors \n\
+                                   barely change. Faster for steady videos"))
+                        .arg(Arg::with_name("denoise-half-size")
+                            .long("denoise-half-size")
+                            .help("Compare frames at half the size to find changed pixels. \n\
+                                   Uses less memory for 4K video, but doesn't denoise"))
+                        .arg(Arg::with_name("queue-depth")
+                            .long("queue-depth")
+                            .takes_value(true)
+                            .value_name("frames")
+                            .help("Frames that can wait between encoding stages. \n\
+                                   Higher uses more memory, but may be faster on many cores"))
                         .arg(Arg::with_name("width")
                             .long("width")
                             .short("W")
                             .takes_value(true)
                             .value_name("px")
                             .help("Maximum width.\nBy default anims are limited to about 800x600"))
+                        .arg(Arg::with_name("scale")
+                            .long("scale")
+                            .takes_value(true)
+                            .value_name("percent")
+                            .conflicts_with_all(&["width", "height"])
+                            .help("Resize to this percentage of the input size, e.g. 50%"))
                         .arg(Arg::with_name("height")
                             .long("height")
                             .short("H")
@@ -96,6 +454,49 @@ fn bin_main() -> BinResult<()> {
                         .arg(Arg::with_name("nosort")
                             .long("nosort")
                             .help("Use files exactly in the order given, rather than \nsorted"))
+                        .arg(Arg::with_name("start-number")
+                            .long("start-number")
+                            .takes_value(true)
+                            .value_name("num")
+                            .help("First number for a frame%04d.png input pattern \n\
+                                   (by default the first file found from 0 to 4)"))
+                        .arg(Arg::with_name("end-number")
+                            .long("end-number")
+                            .takes_value(true)
+                            .value_name("num")
+                            .help("Last number for a frame%04d.png input pattern"))
+                        .arg(Arg::with_name("frames")
+                            .long("frames")
+                            .takes_value(true)
+                            .value_name("first-last")
+                            .help("Use only this range of the sorted PNG files, e.g. \n\
+                                   100-400 (counting from 1, inclusive)"))
+                        .arg(Arg::with_name("tonemap")
+                            .long("tonemap")
+                            .takes_value(true)
+                            .value_name("auto|clip|reinhard|aces|hable")
+                            .help("How to fit colors of EXR and 16-bit PNG frames into \n\
+                                   8 bits (needs the hdr feature)"))
+                        .arg(Arg::with_name("pts-from-filename")
+                            .long("pts-from-filename")
+                            .conflicts_with_all(&["from-manifest", "pts-from-exif"])
+                            .help("Show PNG files at times in milliseconds from \n\
+                                   their names, e.g. frame_000123ms.png"))
+                        .arg(Arg::with_name("pts-from-exif")
+                            .long("pts-from-exif")
+                            .conflicts_with("from-manifest")
+                            .help("Show photos at the times they were taken"))
+                        .arg(Arg::with_name("take-every")
+                            .long("take-every")
+                            .takes_value(true)
+                            .value_name("N")
+                            .help("Use only every Nth PNG file, showing each for N frames"))
+                        .arg(Arg::with_name("progress")
+                            .long("progress")
+                            .takes_value(true)
+                            .possible_values(&["bar", "json"])
+                            .help("Show progress as a bar, or as JSON lines on stderr \n\
+                                   (even if --quiet) for other programs to read"))
                         .arg(Arg::with_name("quiet")
                             .long("quiet")
                             .short("q")
@@ -105,24 +506,155 @@ fn bin_main() -> BinResult<()> {
                             .min_values(1)
                             .empty_values(false)
                             .use_delimiter(false)
-                            .required(true))
+                            .required_unless_one(&["from-manifest", "files-from", "raw-video", "input"]))
+                        .arg(Arg::with_name("input")
+                            .long("input")
+                            .takes_value(true)
+                            .number_of_values(1)
+                            .multiple(true)
+                            .value_name("file")
+                            .conflicts_with_all(&["FILE", "files-from", "from-manifest", "raw-video", "follow", "speed-map", "frames", "take-every"])
+                            .help("A video to add to the animation. Repeat to play several \n\
+                                   videos one after another. --start, --duration, --end \n\
+                                   and --fast-forward after it apply only to it"))
+                        .arg(Arg::with_name("raw-video")
+                            .long("raw-video")
+                            .takes_value(true)
+                            .value_name("WxH:rgba[:fps]")
+                            .conflicts_with_all(&["FILE", "files-from", "from-manifest", "max-size"])
+                            .help("Read uncompressed frames from stdin. Pixel formats \n\
+                                   are rgba, bgra and rgb"))
+                        .arg(Arg::with_name("files-from")
+                            .long("files-from")
+                            .takes_value(true)
+                            .value_name("list.txt")
+                            .help("Read paths of input files from this file (\"-\" means \n\
+                                   stdin), one per line or separated by NUL"))
+                        .arg(Arg::with_name("from-manifest")
+                            .long("from-manifest")
+                            .takes_value(true)
+                            .value_name("frames.txt")
+                            .conflicts_with_all(&["FILE", "files-from", "frames", "take-every", "speed-map"])
+                            .help("Read PNG files from a list where each line is \n\
+                                   a path and how long to show it, in milliseconds"))
+                        .arg(Arg::with_name("follow")
+                            .long("follow")
+                            .conflicts_with_all(&["files-from", "from-manifest", "raw-video", "frames", "take-every", "pts-from-filename", "pts-from-exif"])
+                            .help("Keep adding frames from a directory or a frame%04d.png \n\
+                                   pattern as they're created, e.g. by a screen recorder"))
+                        .arg(Arg::with_name("follow-timeout")
+                            .long("follow-timeout")
+                            .takes_value(true)
+                            .value_name("sec")
+                            .requires("follow")
+                            .help("With --follow, finish after no new frames have \n\
+                                   appeared for this long (default 10)"))
+                        .arg(Arg::with_name("follow-until")
+                            .long("follow-until")
+                            .takes_value(true)
+                            .value_name("file")
+                            .requires("follow")
+                            .help("With --follow, finish when this file appears"))
                         .arg(Arg::with_name("repeat")
                             .long("repeat")
                             .help("Number of times the animation is repeated (-1 none, 0 forever or <value> repetitions")
                             .takes_value(true)
                             .value_name("num"))
-                        .get_matches_from(wild::args_os());
+                        .get_matches_from_safe(wild::args_os())
+                        .unwrap_or_else(|e| {
+                            if e.use_stderr() {
+                                eprintln!("{}", e.message);
+                                std::process::exit(ExitCode::BadArguments as i32);
+                            }
+                            e.exit()
+                        });
 
-    let mut frames: Vec<_> = matches.values_of("FILE").ok_or("Missing files")?.collect();
+    let mut frames: Vec<_> = matches.values_of("FILE").map(|files| files.map(String::from).collect()).unwrap_or_default();
+    if let Some(list_path) = matches.value_of_os("files-from") {
+        frames.extend(read_file_list(list_path).exit_code(ExitCode::InputNotFound)?);
+    }
+    let follow = matches.is_present("follow");
+    if follow && frames.len() != 1 {
+        Err("--follow needs one directory or frame%04d.png pattern").exit_code(ExitCode::BadArguments)?;
+    }
+    if !follow {
+        frames = expand_dirs(frames).exit_code(ExitCode::InputNotFound)?;
+    }
+    if frames.len() == 1 && !follow && !Path::new(&frames[0]).exists() {
+        if let Some(pattern) = parse_pattern(&frames[0]) {
+            let start = parse_opt(matches.value_of("start-number")).map_err(|_| "Invalid start number").exit_code(ExitCode::BadArguments)?;
+            let end = parse_opt(matches.value_of("end-number")).map_err(|_| "Invalid end number").exit_code(ExitCode::BadArguments)?;
+            frames = expand_pattern(pattern, start, end).exit_code(ExitCode::BadArguments)?;
+        }
+    }
     if !matches.is_present("nosort") {
         frames.sort_by(|a, b| natord::compare(a, b));
     }
-    let frames: Vec<_> = frames.into_iter().map(PathBuf::from).collect();
+    if let Some(range) = matches.value_of("frames") {
+        if frames.len() == 1 {
+            Err("--frames applies to PNG files as input, use --start and --end for videos").exit_code(ExitCode::BadArguments)?;
+        }
+        let (first, last) = parse_frame_range(range).exit_code(ExitCode::BadArguments)?;
+        if first > frames.len() {
+            Err(format!("--frames starts at {}, but there are only {} files", first, frames.len())).exit_code(ExitCode::BadArguments)?;
+        }
+        frames.truncate(last);
+        frames.drain(..first - 1);
+    }
+    let take_every = parse_opt(matches.value_of("take-every")).map_err(|_| "Invalid --take-every").exit_code(ExitCode::BadArguments)?.unwrap_or(1_usize);
+    if take_every == 0 {
+        Err("--take-every must be at least 1").exit_code(ExitCode::BadArguments)?;
+    }
+    if take_every > 1 && frames.len() == 1 {
+        Err("--take-every applies to PNG files as input, use fps for videos").exit_code(ExitCode::BadArguments)?;
+    }
+    let frames: Vec<_> = frames.into_iter().step_by(take_every).map(PathBuf::from).collect();
+    let (frames, durations) = match matches.value_of_os("from-manifest") {
+        Some(path) => {
+            let (frames, durations): (Vec<_>, Vec<_>) = png::read_manifest(Path::new(path)).exit_code(ExitCode::InputNotFound)?.into_iter().unzip();
+            (frames, Some(durations))
+        },
+        None if frames.is_empty() && !matches.is_present("raw-video") => Err("Missing files").exit_code(ExitCode::BadArguments)?,
+        None => (frames, None),
+    };
+    let (frames, durations) = if matches.is_present("pts-from-filename") || matches.is_present("pts-from-exif") {
+        let timestamps = if matches.is_present("pts-from-exif") {
+            timestamps::from_exif(&frames).exit_code(ExitCode::BadArguments)?
+        } else {
+            timestamps::from_filenames(&frames).exit_code(ExitCode::BadArguments)?
+        };
+        let (frames, durations) = timestamps::durations(frames, timestamps);
+        (frames, Some(durations))
+    } else {
+        (frames, durations)
+    };
 
-    let output_path = DestPath::new(matches.value_of_os("output").ok_or("Missing output")?);
-    let width = parse_opt(matches.value_of("width")).map_err(|_| "Invalid width")?;
-    let height = parse_opt(matches.value_of("height")).map_err(|_| "Invalid height")?;
-    let repeat_int = parse_opt(matches.value_of("repeat")).map_err(|_| "Invalid repeat count")?.unwrap_or(0) as i16;
+    let estimate = matches.is_present("estimate");
+    let benchmark = matches.is_present("benchmark");
+    let stats = matches.is_present("stats");
+    let output_path = match matches.value_of_os("output") {
+        Some(path) => DestPath::new(path),
+        None if estimate => DestPath::Stdout,
+        // frames are exported instead of the GIF
+        None if matches.is_present("export-frames") => DestPath::None,
+        None => Err("Missing output").exit_code(ExitCode::BadArguments)?,
+    };
+    let format = match matches.value_of("format") {
+        Some("apng") => OutputFormat::Apng,
+        Some(_) => OutputFormat::Gif,
+        None => match output_path {
+            DestPath::Path(p) if p.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("png") || ext.eq_ignore_ascii_case("apng")) => OutputFormat::Apng,
+            _ => OutputFormat::Gif,
+        },
+    };
+    if format == OutputFormat::Apng {
+        if let Some(gif_only) = ["lossy", "max-size", "estimate", "resume", "optimize"].iter().find(|&&name| matches.is_present(name)) {
+            Err(format!("--{} applies only to GIF output", gif_only)).exit_code(ExitCode::BadArguments)?;
+        }
+    }
+    let width = parse_opt(matches.value_of("width")).map_err(|_| "Invalid width").exit_code(ExitCode::BadArguments)?;
+    let height = parse_opt(matches.value_of("height")).map_err(|_| "Invalid height").exit_code(ExitCode::BadArguments)?;
+    let repeat_int = parse_opt(matches.value_of("repeat")).map_err(|_| "Invalid repeat count").exit_code(ExitCode::BadArguments)?.unwrap_or(0) as i16;
     let repeat;
     match repeat_int {
         -1 => repeat = Repeat::Finite(0),
@@ -133,79 +665,623 @@ fn bin_main() -> BinResult<()> {
     let settings = Settings {
         width,
         height,
-        quality: parse_opt(matches.value_of("quality")).map_err(|_| "Invalid quality")?.unwrap_or(100),
+        scale: matches.value_of("scale").map(parse_scale).transpose().exit_code(ExitCode::BadArguments)?,
+        quality: parse_opt(matches.value_of("quality")).map_err(|_| "Invalid quality").exit_code(ExitCode::BadArguments)?.unwrap_or(100),
         fast: matches.is_present("fast"),
         repeat,
+        adaptive_loss: matches.is_present("adaptive-loss"),
+        interlace: matches.is_present("interlace"),
+        lossy_min_psnr: matches.value_of("min-psnr").map(|s| s.parse()).transpose().map_err(|_| "PSNR must be a number").exit_code(ExitCode::BadArguments)?,
+        optimize_frames: matches.is_present("extra"),
+        lossy: match parse_opt(matches.value_of("lossy")).map_err(|_| "Invalid lossy value").exit_code(ExitCode::BadArguments)? {
+            Some(loss) => LossyMode::Loss(loss),
+            // APNG has no LZW to make lossy
+            None if format == OutputFormat::Apng => LossyMode::Loss(0),
+            None => LossyMode::Quality,
+        },
+        max_colors: match parse_opt(matches.value_of("colors")).map_err(|_| "Invalid number of colors").exit_code(ExitCode::BadArguments)? {
+            Some(colors @ 2..=256) => colors,
+            Some(_) => Err("Number of colors must be between 2 and 256").exit_code(ExitCode::BadArguments)?,
+            None => 256,
+        },
+        dithering: matches.value_of("dither").map(parse_dither).transpose().exit_code(ExitCode::BadArguments)?.unwrap_or(Dithering::Auto),
+        bounce: matches.is_present("bounce"),
+        crop: matches.value_of("crop").map(parse_crop).transpose().exit_code(ExitCode::BadArguments)?,
+        reverse: matches.is_present("reverse"),
+        loop_end_delay: match matches.value_of("end-pause").map(|s| s.parse::<f32>()) {
+            Some(Ok(sec)) if sec >= 0. => sec,
+            Some(_) => Err("End pause must be a positive number of seconds").exit_code(ExitCode::BadArguments)?,
+            None => 0.,
+        },
+        loop_crossfade: match matches.value_of("loop-crossfade").map(|s| s.trim_end_matches('s').parse::<f32>()) {
+            Some(Ok(sec)) if sec >= 0. => sec,
+            Some(_) => Err("Loop crossfade must be a positive number of seconds").exit_code(ExitCode::BadArguments)?,
+            None => 0.,
+        },
+        threads: parse_opt(matches.value_of("threads")).map_err(|_| "Invalid number of threads").exit_code(ExitCode::BadArguments)?.unwrap_or(0),
+        duplicate_threshold: parse_opt(matches.value_of("dedup-threshold")).map_err(|_| "Dedup threshold must be 0-255").exit_code(ExitCode::BadArguments)?.unwrap_or(0),
+        stabilize: matches.is_present("stabilize"),
+        deband: matches.is_present("deband"),
+        brightness: parse_adjustment(matches.value_of("brightness")).map_err(|_| "Brightness must be -100 to 100").exit_code(ExitCode::BadArguments)?,
+        contrast: parse_adjustment(matches.value_of("contrast")).map_err(|_| "Contrast must be -100 to 100").exit_code(ExitCode::BadArguments)?,
+        gamma: match matches.value_of("gamma").map(|s| s.parse::<f32>()) {
+            Some(Ok(gamma)) if (0.1..=10.).contains(&gamma) => gamma,
+            Some(_) => Err("Gamma must be 0.1 to 10").exit_code(ExitCode::BadArguments)?,
+            None => 1.,
+        },
+        look: if matches.is_present("grayscale") {
+            Look::Grayscale
+        } else if matches.is_present("sepia") {
+            Look::Sepia
+        } else {
+            Look::Original
+        },
+        sharpen: match parse_opt(matches.value_of("sharpen")) {
+            Ok(Some(amount)) if amount <= 100 => amount,
+            Ok(None) => 0,
+            _ => Err("Sharpen must be 0-100").exit_code(ExitCode::BadArguments)?,
+        },
+        blur: match matches.value_of("blur").map(|s| s.parse::<f32>()) {
+            Some(Ok(radius)) if (0. ..=100.).contains(&radius) => radius,
+            Some(_) => Err("Blur must be a radius in pixels, 0-100").exit_code(ExitCode::BadArguments)?,
+            None => 0.,
+        },
+        pad: matches.value_of("pad").map(parse_pad).transpose().exit_code(ExitCode::BadArguments)?,
+        deterministic: matches.is_present("deterministic"),
+        memory_limit_mb: parse_opt(matches.value_of("memory-limit")).map_err(|_| "Memory limit must be a number of megabytes").exit_code(ExitCode::BadArguments)?.unwrap_or(0),
+        denoise_half_size: matches.is_present("denoise-half-size"),
+        reuse_palettes: matches.is_present("reuse-palettes"),
+        queue_depth: parse_opt(matches.value_of("queue-depth")).map_err(|_| "Queue depth must be a number of frames (1-255)").exit_code(ExitCode::BadArguments)?.unwrap_or(0),
     };
-    let quiet = matches.is_present("quiet") || output_path == DestPath::Stdout;
-    let fps: f32 = matches.value_of("fps").ok_or("Missing fps")?.parse().map_err(|_| "FPS must be a number")?;
-    let speed: f32 = matches.value_of("fast-forward").ok_or("Missing speed")?.parse().map_err(|_| "Speed must be a number")?;
+    let quiet = matches.is_present("quiet") || (output_path == DestPath::Stdout && !estimate);
+    if matches.is_present("optimize") {
+        // without --repeat, the loop count of the input is kept
+        let repeat = if matches.is_present("repeat") { Some(settings.repeat) } else { None };
+        return optimize_gif(&frames, &output_path, settings.gifsicle_loss(), repeat, quiet);
+    }
+    if output_path == DestPath::Stdout && matches.is_present("resume") {
+        Err("--resume needs an output file, not stdout").exit_code(ExitCode::BadArguments)?;
+    }
+    let progress = match matches.value_of("progress") {
+        Some("json") => ProgressMode::Json,
+        _ if quiet => ProgressMode::None,
+        _ => ProgressMode::Bar,
+    };
+    let fps: f32 = matches.value_of("fps").ok_or("Missing fps").exit_code(ExitCode::BadArguments)?.parse().map_err(|_| "FPS must be a number").exit_code(ExitCode::BadArguments)?;
+    // the kept frames are shown for longer, so that the animation plays at the same speed
+    let fps = fps / take_every as f32;
+    let speed: f32 = matches.value_of("fast-forward").ok_or("Missing speed").exit_code(ExitCode::BadArguments)?.parse().map_err(|_| "Speed must be a number").exit_code(ExitCode::BadArguments)?;
 
-    let rate = source::Fps { speed, fps };
+    let speed_ranges = matches.value_of("speed-map").map(parse_speed_map).transpose().exit_code(ExitCode::BadArguments)?.unwrap_or_default();
+    let rate = source::Fps { speed, fps, speed_ranges };
+
+    let parse_seconds = |name| -> BinResult<Option<f64>> {
+        match matches.value_of(name).map(|s| s.parse::<f64>()) {
+            Some(Ok(sec)) if sec >= 0. => Ok(Some(sec)),
+            Some(_) => Err(format!("--{} must be a positive number of seconds", name).into()),
+            None => Ok(None),
+        }
+    };
+    let (start, end) = if matches.is_present("input") {
+        // each input has its own, see parse_segments
+        (0., None)
+    } else {
+        let start = parse_seconds("start").exit_code(ExitCode::BadArguments)?.unwrap_or(0.);
+        let end = match (parse_seconds("duration").exit_code(ExitCode::BadArguments)?, parse_seconds("end").exit_code(ExitCode::BadArguments)?) {
+            (Some(duration), _) => Some(start + duration),
+            (None, end) => end,
+        };
+        (start, end)
+    };
+    if end.map_or(false, |end| end <= start) {
+        Err("The end must be after the start").exit_code(ExitCode::BadArguments)?;
+    }
+    let trim = source::Trim { start, end };
 
     if settings.quality < 20 {
         if settings.quality < 1 {
-            Err("Quality too low")?;
+            Err("Quality too low").exit_code(ExitCode::BadArguments)?;
         } else if !quiet {
             eprintln!("warning: quality {} will give really bad results", settings.quality);
         }
     } else if settings.quality > 100 {
-        Err("Quality 100 is maximum")?;
+        Err("Quality 100 is maximum").exit_code(ExitCode::BadArguments)?;
     }
 
     if fps > 100.0 {
-        Err("100 fps is maximum")?;
+        Err("100 fps is maximum").exit_code(ExitCode::BadArguments)?;
     }
     else if !quiet && fps > 50.0 {
         eprintln!("warning: web browsers support max 50 fps");
     }
 
-    check_if_paths_exist(&frames)?;
+    let max_size = matches.value_of("max-size").map(parse_size).transpose().exit_code(ExitCode::BadArguments)?;
+    let mut overlays = overlay::Overlays::default();
+    if let Some(arg) = matches.value_of("overlay") {
+        overlays.image = Some(overlay::Image::from_arg(arg).exit_code(ExitCode::BadArguments)?);
+    }
+    if matches.is_present("text") || matches.is_present("timestamp") {
+        let size = match matches.value_of("text-size").map(|s| s.parse::<f32>()) {
+            Some(Ok(size)) if size >= 1. => Some(size),
+            Some(_) => Err("Text size must be a number of pixels").exit_code(ExitCode::BadArguments)?,
+            None => None,
+        };
+        overlays.text = Some(overlay::Text::new(
+            matches.value_of("text").map(String::from),
+            matches.is_present("timestamp"),
+            matches.value_of("text-position").unwrap_or("bottom-left").parse().exit_code(ExitCode::BadArguments)?,
+            matches.value_of_os("font").map(Path::new),
+            size,
+        ).exit_code(ExitCode::BadArguments)?);
+    }
+    let poster = match matches.value_of_os("poster") {
+        Some(path) => Some((parse_seconds("poster-at").exit_code(ExitCode::BadArguments)?.unwrap_or(0.), Path::new(path))),
+        None => None,
+    };
+    let matte_mode = match matches.value_of("matte-mode") {
+        Some("flatten") => MatteMode::Flatten,
+        _ => MatteMode::SemiTransparent,
+    };
+    let mut fixed_colors = matches.values_of("fixed-color").into_iter().flatten()
+        .map(|c| color::parse_color(c).map(|c| c.rgb()))
+        .collect::<BinResult<Vec<_>>>().exit_code(ExitCode::BadArguments)?;
+    let mut fixed_palette = Vec::new();
+    if let Some(path) = matches.value_of_os("fixed-palette") {
+        let colors = palette_file::read_palette(Path::new(path)).exit_code(ExitCode::InputNotFound)?;
+        if matches.value_of("fixed-palette-mode") == Some("exact") {
+            if !fixed_colors.is_empty() {
+                Err("--fixed-color can't be added to --fixed-palette-mode exact, add the color to the palette file instead").exit_code(ExitCode::BadArguments)?;
+            }
+            fixed_palette = colors;
+        } else {
+            fixed_colors.extend(colors);
+        }
+    }
+    let mut options = EncodeOptions {
+        poster,
+        export_frames: matches.value_of_os("export-frames").map(Path::new),
+        fixed_palette,
+        fixed_colors,
+        overlays,
+        matte: matches.value_of("matte").map(color::parse_color).transpose().exit_code(ExitCode::BadArguments)?.map(|color| (color, matte_mode)),
+        progress,
+        journal: None,
+        max_size,
+        format,
+    };
 
-    let mut decoder = if frames.len() == 1 {
-        get_video_decoder(&frames[0], rate, settings)?
-    } else {
-        if speed != 1.0 {
-            Err("Speed doesn't apply to PNG files as input, use fps only")?;
+    let segments = parse_segments(&matches).exit_code(ExitCode::BadArguments)?;
+    let frames = if segments.is_empty() { frames } else { segments.iter().map(|segment| segment.path.clone()).collect() };
+    let from_stdin = frames.iter().any(|path| path == Path::new("-"));
+    if !follow && !from_stdin {
+        check_if_paths_exist(&frames).exit_code(ExitCode::InputNotFound)?;
+    }
+    let input_size: u64 = frames.iter().filter_map(|path| path.metadata().ok()).map(|meta| meta.len()).sum();
+    let report = |totals: &Totals| {
+        if stats {
+            print_stats(totals, input_size);
+        }
+        if benchmark {
+            print_benchmark(totals);
         }
-        Box::new(png::Lodecoder::new(frames, &rate))
+    };
+    let html_path = matches.value_of_os("html").map(Path::new);
+    if html_path.is_some() && !matches!(output_path, DestPath::Path(_)) {
+        Err("--html needs the GIF to be written to a file").exit_code(ExitCode::BadArguments)?;
+    }
+    let write_html = |gif: &[u8], totals: &Totals, settings: &Settings| -> BinResult<()> {
+        let (html_path, gif_path) = match (html_path, &output_path) {
+            (Some(html_path), DestPath::Path(gif_path)) => (html_path, gif_path),
+            _ => return Ok(()),
+        };
+        let title = gif_path.file_name().unwrap_or(gif_path.as_os_str()).to_string_lossy();
+        let mut quality = settings.quality.to_string();
+        if settings.gifsicle_loss() > 0 {
+            quality += &format!(", lossy {}", settings.gifsicle_loss());
+        }
+        let command = env::args_os().map(|arg| arg.to_string_lossy().into_owned()).collect::<Vec<_>>().join(" ");
+        html_preview::write(html_path, gif, &title, &[
+            ("Size", format!("{}KB", (gif.len() + 1023) / 1024)),
+            ("Frames", totals.frames_written.to_string()),
+            ("Duration", format!("{:.2}s", totals.duration)),
+            ("Quality", quality),
+            ("Colors", settings.max_colors.to_string()),
+            ("Command", command),
+        ]).exit_code(ExitCode::WriteFailed)
     };
 
-    let mut pb;
-    let mut nopb = NoProgress {};
-    let progress: &mut dyn ProgressReporter = if quiet {
-        &mut nopb
-    } else {
-        pb = ProgressBar::new(decoder.total_frames());
-        pb.show_speed = false;
-        pb.show_percent = false;
-        pb.format(" #_. ");
-        pb.message("Frame ");
-        pb.set_max_refresh_rate(Some(Duration::from_millis(250)));
-        &mut pb
+    if !quiet && frames.iter().any(|path| path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg"))) {
+        eprintln!("warning: JPEG compression artifacts look bad in GIFs and make them much larger.\n\
+                   Export the frames as PNG instead if you can.");
+    }
+
+    if segments.is_empty() && (frames.len() > 1 || durations.is_some()) {
+        if speed != 1.0 {
+            Err("Speed doesn't apply to PNG files as input, use fps only").exit_code(ExitCode::BadArguments)?;
+        }
+        if start > 0. || end.is_some() {
+            Err("Start and duration don't apply to PNG files as input").exit_code(ExitCode::BadArguments)?;
+        }
+        if matches.is_present("vf") {
+            Err("--vf applies only to video files").exit_code(ExitCode::BadArguments)?;
+        }
+    }
+    let tone_map = matches.value_of("tonemap").map(str::parse).transpose().exit_code(ExitCode::BadArguments)?.unwrap_or(tonemap::ToneMap::Auto);
+    let video_stream = parse_opt(matches.value_of("video-stream")).map_err(|_| "Invalid video stream number").exit_code(ExitCode::BadArguments)?;
+    let make_decoder = |settings: Settings| -> BinResult<Box<dyn Source + Send>> {
+        Ok(if !segments.is_empty() {
+            let sources = segments.iter().map(|segment| {
+                let rate = source::Fps { speed: segment.speed, ..rate.clone() };
+                single_file_decoder(&segment.path, video_stream, matches.value_of("vf"), rate, segment.trim, settings)
+            }).collect::<BinResult<Vec<_>>>()?;
+            Box::new(concat_source::ConcatSource::new(sources, rate.fps))
+        } else if follow {
+            if trim.start > 0. || trim.end.is_some() {
+                Err("Start and duration don't apply to --follow").exit_code(ExitCode::BadArguments)?;
+            }
+            let timeout = matches.value_of("follow-timeout").map(|t| t.parse::<f64>()).transpose().map_err(|_| "Invalid --follow-timeout").exit_code(ExitCode::BadArguments)?.unwrap_or(10.);
+            if !(timeout > 0. && timeout < 1e9) {
+                Err("--follow-timeout must be a positive number of seconds").exit_code(ExitCode::BadArguments)?;
+            }
+            let start = parse_opt(matches.value_of("start-number")).map_err(|_| "Invalid start number").exit_code(ExitCode::BadArguments)?;
+            Box::new(follow_source::FollowDecoder::new(&frames[0].to_string_lossy(), start, rate.clone(), Duration::from_secs_f64(timeout), matches.value_of_os("follow-until").map(PathBuf::from), tone_map).exit_code(ExitCode::InputNotFound)?)
+        } else if let Some(spec) = matches.value_of("raw-video") {
+            Box::new(raw_source::RawDecoder::new(spec, rate.clone(), trim).exit_code(ExitCode::BadArguments)?)
+        } else if let Some(durations) = &durations {
+            Box::new(png::Lodecoder::with_durations(frames.clone(), durations.clone(), &rate, tone_map))
+        } else if frames.len() == 1 {
+            single_file_decoder(&frames[0], video_stream, matches.value_of("vf"), rate.clone(), trim, settings)?
+        } else {
+            Box::new(png::Lodecoder::new(frames.clone(), &rate, tone_map))
+        })
     };
 
-    let (mut collector, writer) = gifski::new(settings)?;
-    let decode_thread = thread::Builder::new().name("decode".into()).spawn(move || {
-        decoder.collect(&mut collector)
-    })?;
+    if estimate {
+        let totals = encode(make_decoder(settings)?, settings, Some(io::sink()), &options, "")?;
+        println!("Estimated size: {}KB, duration: {:.2}s", (totals.bytes_written + 1023) / 1024, totals.duration);
+        report(&totals);
+        return Ok(());
+    }
 
-    match output_path {
+    let done_msg = match (&output_path, options.export_frames) {
+        (DestPath::None, Some(dir)) => format!("gifski exported frames to {}", dir.display()),
+        _ => format!("gifski created {}", output_path),
+    };
+    let decoder = make_decoder(settings)?;
+    let totals = match output_path {
+        DestPath::Path(p) if matches.is_present("resume") => {
+            let (journal, file) = resume::Journal::open(p, env::args_os()).exit_code(ExitCode::WriteFailed)?;
+            if journal.frames > 0 && !quiet {
+                eprintln!("Continuing after frame {}", journal.frames);
+            }
+            options.journal = Some(journal);
+            let totals = encode(decoder, settings, Some(file), &options, &done_msg)?;
+            if let Some(journal) = &options.journal {
+                journal.finish(p).exit_code(ExitCode::WriteFailed)?;
+            }
+            totals
+        },
         DestPath::Path(p) => {
             let file = File::create(p)
-                .map_err(|e| format!("Can't write to {}: {}", p.display(), e))?;
-            writer.write(file, progress)?;
+                .map_err(|e| format!("Can't write to {}: {}", p.display(), e)).exit_code(ExitCode::WriteFailed)?;
+            encode(decoder, settings, Some(file), &options, &done_msg)?
         },
         DestPath::Stdout => {
-            writer.write(io::stdout().lock(), progress)?;
+            encode(decoder, settings, Some(io::stdout().lock()), &options, &done_msg)?
+        },
+        DestPath::None => {
+            encode(decoder, settings, None::<io::Sink>, &options, &done_msg)?
         },
     };
-    decode_thread.join().map_err(|_| "thread died?")??;
-    progress.done(&format!("gifski created {}", output_path));
+    report(&totals);
+    if let (Some(_), DestPath::Path(p)) = (html_path, &output_path) {
+        let gif = std::fs::read(p)
+            .map_err(|e| format!("Can't read {}: {}", p.display(), e)).exit_code(ExitCode::WriteFailed)?;
+        let settings = Settings { quality: totals.quality.unwrap_or(settings.quality), ..settings };
+        write_html(&gif, &totals, &settings)?;
+    }
+
+    Ok(())
+}
 
+/// Recompresses a GIF, keeping its palettes and pixels
+#[cfg(feature = "gifsicle")]
+fn optimize_gif(input: &[PathBuf], output_path: &DestPath<'_>, loss: u32, repeat: Option<Repeat>, quiet: bool) -> BinResult<()> {
+    let input = match input {
+        [path] => path,
+        _ => Err("--optimize takes one GIF file as input").exit_code(ExitCode::BadArguments)?,
+    };
+    let data = std::fs::read(input)
+        .map_err(|e| format!("Can't read {}: {}", input.display(), e)).exit_code(ExitCode::InputNotFound)?;
+    let mut gif = Vec::new();
+    if let Err(err) = gifski::recompress(&data[..], &mut gif, loss, repeat) {
+        let code = ExitCode::of_encoding(&err);
+        return Err(err).exit_code(code);
+    }
+    match output_path {
+        DestPath::Path(p) => {
+            std::fs::write(p, &gif)
+                .map_err(|e| format!("Can't write to {}: {}", p.display(), e)).exit_code(ExitCode::WriteFailed)?;
+        },
+        DestPath::Stdout => {
+            io::Write::write_all(&mut io::stdout().lock(), &gif).exit_code(ExitCode::WriteFailed)?;
+        },
+        DestPath::None => {},
+    }
+    if !quiet {
+        eprintln!("gifski optimized {} ({}KB, {:.0}% of the original)", output_path, (gif.len() + 1023) / 1024, gif.len() as f64 * 100. / data.len().max(1) as f64);
+    }
     Ok(())
 }
 
+#[cfg(not(feature = "gifsicle"))]
+#[cold]
+fn optimize_gif(_: &[PathBuf], _: &DestPath<'_>, _: u32, _: Option<Repeat>, _: bool) -> BinResult<()> {
+    Err("--optimize needs gifsicle, which is disabled in this executable. Build with --features=gifsicle").exit_code(ExitCode::BadArguments)?
+}
+
+/// Summary of the result, for tuning settings and for bug reports
+fn print_stats(totals: &Totals, input_size: u64) {
+    let frames_in = totals.stage_times.frames;
+    let frames_out = totals.frames_written;
+    eprintln!("frames in:            {}", frames_in);
+    eprintln!("frames out:           {}", frames_out);
+    eprintln!("frames skipped:       {}", frames_in.saturating_sub(frames_out));
+    if frames_out > 0 {
+        eprintln!("average palette size: {:.1}", totals.palette_sizes as f64 / frames_out as f64);
+        eprintln!("average frame delay:  {:.3}s", totals.duration / frames_out as f64);
+    }
+    eprintln!("duration:             {:.2}s", totals.duration);
+    if input_size > 0 {
+        eprintln!("size:                 {}KB ({:.1}% of {}KB of input files)",
+            (totals.bytes_written + 1023) / 1024, totals.bytes_written as f64 * 100. / input_size as f64, (input_size + 1023) / 1024);
+    } else {
+        eprintln!("size:                 {}KB", (totals.bytes_written + 1023) / 1024);
+    }
+}
+
+/// Goes to stderr, because the GIF may be written to stdout
+fn print_benchmark(totals: &Totals) {
+    let times = &totals.stage_times;
+    let frames = times.frames as f64;
+    eprintln!("{:<10}{:>10}{:>12}", "stage", "time", "frames/s");
+    for &(name, time) in &[
+        ("decode", times.decode),
+        ("resize", times.resize),
+        ("denoise", times.denoise),
+        ("quantize", times.quantize),
+        ("remap", times.remap),
+        ("optimize", times.optimize),
+        ("lzw", times.lzw),
+        ("write", times.write),
+    ] {
+        let secs = time.as_secs_f64();
+        if secs > 0. {
+            eprintln!("{:<10}{:>9.3}s{:>12.1}", name, secs, frames / secs);
+        } else {
+            eprintln!("{:<10}{:>9.3}s{:>12}", name, secs, "-");
+        }
+    }
+    let secs = totals.elapsed.as_secs_f64();
+    eprintln!("{:<10}{:>9.3}s{:>12.1}", "total", secs, frames / secs.max(0.001));
+    eprintln!("{} frames. Stages run in parallel, so their times add up to more than the total.", times.frames);
+}
+
+/// Size and length of an encoded animation
+#[derive(Default)]
+struct Totals {
+    bytes_written: u64,
+    /// In seconds
+    duration: f64,
+    /// Not counting skipped frames
+    frames_written: usize,
+    /// Sum of colors in all written frames
+    palette_sizes: u64,
+    /// Wall time of the whole encode
+    elapsed: Duration,
+    stage_times: StageTimes,
+    /// Set if `--max-size` lowered the quality
+    quality: Option<u8>,
+}
+
+/// Passes progress through, and keeps track of totals
+struct CountingReporter<'a> {
+    progress: &'a mut dyn ProgressReporter,
+    totals: Totals,
+    journal: Option<resume::Journal>,
+}
+
+impl ProgressReporter for CountingReporter<'_> {
+    fn increase(&mut self) -> bool {
+        self.progress.increase()
+    }
+
+    fn frame_written(&mut self, ordinal_frame_number: usize, presentation_timestamp: f64, bytes_written: u64) -> bool {
+        self.totals.bytes_written = bytes_written;
+        self.progress.frame_written(ordinal_frame_number, presentation_timestamp, bytes_written)
+    }
+
+    fn frame_stats(&mut self, stats: &FrameStats) {
+        self.totals.duration += f64::from(stats.delay) / 100.;
+        self.totals.frames_written += 1;
+        self.totals.palette_sizes += u64::from(stats.palette_size);
+        if let Some(journal) = &mut self.journal {
+            journal.frame_written(stats.ordinal_frame_number as usize, stats.compressed_size);
+        }
+        self.progress.frame_stats(stats);
+    }
+
+    fn stage_times(&mut self, times: &StageTimes) {
+        self.totals.stage_times = times.clone();
+        self.progress.stage_times(times);
+    }
+
+    fn retry(&mut self, size: u64, next_quality: u8) {
+        self.totals = Totals { quality: Some(next_quality), ..Totals::default() };
+        self.progress.retry(size, next_quality);
+    }
+
+    fn done(&mut self, msg: &str) {
+        self.progress.done(msg);
+    }
+}
+
+/// Everything else that applies to encoding, besides `Settings`
+struct EncodeOptions<'a> {
+    /// Timestamp of the frame, and where to save it
+    poster: Option<(f64, &'a Path)>,
+    /// For `--export-frames`
+    export_frames: Option<&'a Path>,
+    overlays: overlay::Overlays,
+    matte: Option<(RGBA8, MatteMode)>,
+    /// The only colors used, if not empty
+    fixed_palette: Vec<RGB8>,
+    /// Added to the best colors for each frame
+    fixed_colors: Vec<RGB8>,
+    progress: ProgressMode,
+    /// For `--resume`
+    journal: Option<resume::Journal>,
+    /// For `--max-size`
+    max_size: Option<u64>,
+    format: OutputFormat,
+}
+
+/// For `--format`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum OutputFormat {
+    Gif,
+    /// Frames as they are shown in the GIF, written by `apng_output`
+    Apng,
+}
+
+/// How to show progress of encoding
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum ProgressMode {
+    None,
+    Bar,
+    Json,
+}
+
+/// Without `out`, frames are only processed for `--export-frames`
+fn encode(mut decoder: Box<dyn Source + Send>, settings: Settings, out: Option<impl io::Write>, options: &EncodeOptions<'_>, done_msg: &str) -> BinResult<Totals> {
+    let mut pb;
+    let mut json;
+    let mut nopb = NoProgress {};
+    let progress: &mut dyn ProgressReporter = match options.progress {
+        ProgressMode::Json => {
+            json = json_progress::JsonProgress::new(decoder.total_frames());
+            &mut json
+        },
+        // the number of frames may be unknown
+        ProgressMode::Bar if decoder.total_frames() > 0 => {
+            pb = progress_bar::ProgressBar::new(decoder.total_frames());
+            &mut pb
+        },
+        _ => &mut nopb,
+    };
+
+    let start = Instant::now();
+    let (mut collector, mut writer) = gifski::new(settings).exit_code(ExitCode::Other)?;
+    if let Some((color, mode)) = options.matte {
+        collector.set_matte(color, mode);
+    }
+    if !options.fixed_palette.is_empty() {
+        writer.set_fixed_palette(options.fixed_palette.clone()).exit_code(ExitCode::BadArguments)?;
+    }
+    if !options.fixed_colors.is_empty() {
+        writer.add_fixed_colors(&options.fixed_colors).exit_code(ExitCode::BadArguments)?;
+    }
+    if !options.overlays.is_empty() {
+        let mut overlays = options.overlays.clone();
+        collector.set_frame_filter(move |frame, pts| overlays.draw(frame, pts));
+    }
+    let poster_frame = Arc::new(Mutex::new(None));
+    if let Some((pts, _)) = options.poster {
+        let poster_frame = poster_frame.clone();
+        writer.set_poster_callback(pts, move |frame| {
+            *poster_frame.lock().unwrap() = Some(frame);
+        });
+    }
+    let exporter = match options.export_frames {
+        Some(dir) => Some(Arc::new(Mutex::new(export_frames::FrameExporter::new(dir).exit_code(ExitCode::WriteFailed)?))),
+        None => None,
+    };
+    let apng = match (options.format, &out) {
+        (OutputFormat::Apng, Some(_)) => Some(Arc::new(Mutex::new(apng_output::ApngWriter::new(settings.repeat)))),
+        _ => None,
+    };
+    if exporter.is_some() || apng.is_some() {
+        let (callback_exporter, callback_apng) = (exporter.clone(), apng.clone());
+        writer.set_output_frame_callback(move |frame, delay| {
+            if let Some(exporter) = &callback_exporter {
+                exporter.lock().unwrap().add(frame, delay);
+            }
+            if let Some(apng) = &callback_apng {
+                apng.lock().unwrap().add(frame, delay);
+            }
+        });
+    }
+    let decode_thread = thread::Builder::new().name("decode".into()).spawn(move || {
+        decoder.collect(&mut collector)
+    }).exit_code(ExitCode::Other)?;
+    if let Some(journal) = &options.journal {
+        writer.resume_after(journal.frames);
+    }
+    if let Some(max_size) = options.max_size {
+        writer.set_max_size(max_size);
+    }
+    let mut reporter = CountingReporter { progress, totals: Totals::default(), journal: options.journal.clone() };
+    let mut out = out;
+    let written = match out.as_mut() {
+        Some(out) if apng.is_none() => writer.write(out, &mut reporter),
+        _ => writer.write_frames_only(&mut reporter),
+    };
+    let decoded = decode_thread.join().map_err(|_| "thread died?").exit_code(ExitCode::Other)?;
+    if let Err(err) = written {
+        // the decoder's error explains why there were no frames
+        if let gifski::Error::NoFrames = err {
+            decoded.exit_code(ExitCode::DecodeFailed)?;
+        }
+        if let gifski::Error::TooLarge(_) = err {
+            Err(format!("{}. Try a smaller width or lower fps", err)).exit_code(ExitCode::Other)?;
+        }
+        let code = ExitCode::of_encoding(&err);
+        return Err(err).exit_code(code);
+    }
+    decoded.exit_code(ExitCode::DecodeFailed)?;
+    if let Some((_, path)) = options.poster {
+        let frame = poster_frame.lock().unwrap().take().ok_or("No frame for the poster").exit_code(ExitCode::Other)?;
+        let (buf, width, height) = frame.into_contiguous_buf();
+        lodepng::encode32_file(path, &buf, width, height)
+            .map_err(|e| format!("Can't write to {}: {}", path.display(), e)).exit_code(ExitCode::WriteFailed)?;
+    }
+    if let Some(exporter) = exporter {
+        exporter.lock().unwrap().finish().exit_code(ExitCode::WriteFailed)?;
+    }
+    if let (Some(apng), Some(out)) = (apng, out) {
+        reporter.totals.bytes_written = apng.lock().unwrap().finish(out).exit_code(ExitCode::WriteFailed)?;
+    }
+    reporter.done(done_msg);
+    reporter.totals.elapsed = start.elapsed();
+    Ok(reporter.totals)
+}
+
+/// Bytes, or with KB/MB suffix (1024-based, so that the result fits under limits either way)
+fn parse_size(s: &str) -> BinResult<u64> {
+    let upper = s.trim().to_ascii_uppercase();
+    let (num, multiplier) = if let Some(num) = upper.strip_suffix("MB").or_else(|| upper.strip_suffix('M')) {
+        (num, 1024. * 1024.)
+    } else if let Some(num) = upper.strip_suffix("KB").or_else(|| upper.strip_suffix('K')) {
+        (num, 1024.)
+    } else {
+        (upper.strip_suffix('B').unwrap_or(&upper), 1.)
+    };
+    match num.trim().parse::<f64>() {
+        Ok(n) if n > 0. => Ok((n * multiplier) as u64),
+        _ => Err(format!("Invalid size '{}'. Use e.g. 8MB or 500KB", s).into()),
+    }
+}
+
 fn check_if_paths_exist(paths: &[PathBuf]) -> BinResult<()> {
     for path in paths {
         if !path.exists() {
@@ -228,10 +1304,241 @@ fn parse_opt<T: ::std::str::FromStr<Err = ::std::num::ParseIntError>>(s: Option<
     }
 }
 
+/// Newline or NUL-separated paths, e.g. from `find -print0`
+fn read_file_list(path: &OsStr) -> BinResult<Vec<String>> {
+    let mut list = Vec::new();
+    if path == "-" {
+        io::stdin().lock().read_to_end(&mut list)?;
+    } else {
+        let path = Path::new(path);
+        File::open(path).and_then(|mut f| f.read_to_end(&mut list))
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    }
+    let list = String::from_utf8(list).map_err(|_| "The list of files must be in UTF-8")?;
+    let separator = if list.contains('\0') { '\0' } else { '\n' };
+    Ok(list.split(separator)
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect())
+}
+
+/// Directories replaced with the images in them, in natural order of names
+fn expand_dirs(paths: Vec<String>) -> BinResult<Vec<String>> {
+    let mut expanded = Vec::with_capacity(paths.len());
+    for path in paths {
+        if !Path::new(&path).is_dir() {
+            expanded.push(path);
+            continue;
+        }
+        let mut images = Vec::new();
+        for entry in std::fs::read_dir(&path).map_err(|e| format!("Can't read {}: {}", path, e))? {
+            let image = entry?.path();
+            if follow_source::is_image(&image) {
+                images.push(image.into_os_string().into_string().map_err(|name| format!("File name {:?} isn't UTF-8", name))?);
+            }
+        }
+        if images.is_empty() {
+            Err(format!("The directory {} has no image files", path))?;
+        }
+        images.sort_by(|a, b| natord::compare(a, b));
+        expanded.extend(images);
+    }
+    Ok(expanded)
+}
+
+/// ffmpeg-style frame%04d.png split into prefix, number width and suffix
+fn parse_pattern(s: &str) -> Option<(&str, usize, &str)> {
+    let pos = s.find('%')?;
+    let rest = &s[pos + 1..];
+    let digits = rest.find(|c: char| !c.is_ascii_digit())?;
+    if !rest[digits..].starts_with('d') {
+        return None;
+    }
+    let width = if digits > 0 { rest[..digits].parse().ok()? } else { 0 };
+    Some((&s[..pos], width, &rest[digits + 1..]))
+}
+
+/// Paths of consecutively numbered files that exist
+fn expand_pattern((prefix, width, suffix): (&str, usize, &str), start: Option<u64>, end: Option<u64>) -> BinResult<Vec<String>> {
+    let path = |n: u64| format!("{}{:0width$}{}", prefix, n, suffix, width = width);
+    let start = match start {
+        Some(start) => start,
+        None => (0..=4).find(|&n| Path::new(&path(n)).exists())
+            .ok_or_else(|| format!("No files match the pattern {}%0{}d{}", prefix, width, suffix))?,
+    };
+    let end = end.unwrap_or(u64::MAX);
+    let frames: Vec<_> = (start..=end).map(path).take_while(|p| Path::new(p).exists()).collect();
+    if frames.is_empty() {
+        Err(format!("File {} doesn't exist", path(start)))?;
+    }
+    Ok(frames)
+}
+
+/// first-last, 1-based and inclusive. "first-" is until the end.
+fn parse_frame_range(s: &str) -> BinResult<(usize, usize)> {
+    let err = || format!("Invalid frame range '{}'. Use first-last, e.g. 100-400", s);
+    let mut parts = s.splitn(2, '-');
+    let first: usize = parts.next().unwrap_or_default().trim().parse().map_err(|_| err())?;
+    let last: usize = match parts.next().map(str::trim) {
+        Some("") => usize::MAX,
+        Some(last) => last.parse().map_err(|_| err())?,
+        None => first,
+    };
+    if first == 0 || last < first {
+        Err(err())?;
+    }
+    Ok((first, last))
+}
+
+/// One of the videos given with `--input`
+struct Segment {
+    path: PathBuf,
+    trim: source::Trim,
+    speed: f32,
+}
+
+/// Options given after an `--input` apply only to it, and ones before the first `--input` apply to all of them.
+/// Empty if there's no `--input`.
+fn parse_segments(matches: &ArgMatches<'_>) -> BinResult<Vec<Segment>> {
+    const SEGMENT_OPTIONS: [&str; 4] = ["start", "duration", "end", "fast-forward"];
+    let inputs: Vec<_> = match (matches.indices_of("input"), matches.values_of_os("input")) {
+        (Some(indices), Some(paths)) => indices.zip(paths).collect(),
+        _ => {
+            if let Some(name) = SEGMENT_OPTIONS.iter().find(|&&name| matches.occurrences_of(name) > 1) {
+                Err(format!("--{} can be given more than once only with multiple --input", name))?;
+            }
+            return Ok(Vec::new());
+        },
+    };
+    // start, duration, end and speed of each input, before the defaults are filled in
+    let mut options = vec![[None; 4]; inputs.len()];
+    for (option, &name) in SEGMENT_OPTIONS.iter().enumerate() {
+        // the default speed isn't an occurrence
+        if matches.occurrences_of(name) == 0 {
+            continue;
+        }
+        let (indices, values) = match (matches.indices_of(name), matches.values_of(name)) {
+            (Some(indices), Some(values)) => (indices, values),
+            _ => continue,
+        };
+        for (index, value) in indices.zip(values) {
+            let value = value.parse::<f64>().ok().filter(|&v| v >= 0.)
+                .ok_or_else(|| format!("--{} must be a positive number, not '{}'", name, value))?;
+            match inputs.iter().rposition(|&(input_index, _)| input_index < index) {
+                Some(segment) => options[segment][option] = Some(value),
+                None => options.iter_mut().filter(|o| o[option].is_none()).for_each(|o| o[option] = Some(value)),
+            }
+        }
+    }
+    inputs.iter().zip(options).map(|(&(_, path), [start, duration, end, segment_speed])| {
+        let start = start.unwrap_or(0.);
+        let end = duration.map(|duration| start + duration).or(end);
+        if end.map_or(false, |end| end <= start) {
+            Err(format!("The end must be after the start for --input {}", Path::new(path).display()))?;
+        }
+        let speed = segment_speed.map_or(1., |s| s as f32);
+        if speed <= 0. {
+            Err("Speed must be above 0")?;
+        }
+        Ok(Segment { path: PathBuf::from(path), trim: source::Trim { start, end }, speed })
+    }).collect()
+}
+
+/// Comma-separated start-end:speed, e.g. 0-2s:1x,2.5-4s:4x
+fn parse_speed_map(s: &str) -> BinResult<Vec<source::SpeedRange>> {
+    let mut ranges = s.split(',').map(|part| {
+        let err = || format!("Invalid speed range '{}'. Use start-end:speed, e.g. 2-4s:4x", part);
+        let mut parts = part.trim().splitn(2, ':');
+        let times = parts.next().unwrap_or_default();
+        let speed = parts.next().ok_or_else(err)?;
+        let mut times = times.trim_end_matches('s').splitn(2, '-');
+        let start: f64 = times.next().unwrap_or_default().trim_end_matches('s').parse().map_err(|_| err())?;
+        let end: f64 = times.next().ok_or_else(err)?.parse().map_err(|_| err())?;
+        let speed: f64 = speed.trim_end_matches('x').parse().map_err(|_| err())?;
+        if start < 0. || end <= start || speed <= 0. {
+            Err(err())?;
+        }
+        Ok(source::SpeedRange { start, end, speed })
+    }).collect::<BinResult<Vec<_>>>()?;
+    ranges.sort_by(|a, b| a.start.partial_cmp(&b.start).unwrap());
+    if ranges.windows(2).any(|pair| pair[0].end > pair[1].start) {
+        Err("Speed ranges must not overlap")?;
+    }
+    Ok(ranges)
+}
+
+/// Aspect ratio like 16:9, optionally followed by a color
+fn parse_pad(s: &str) -> BinResult<Pad> {
+    let mut parts = s.splitn(3, ':');
+    let mut side = || parts.next().and_then(|n| n.trim().parse::<u32>().ok()).filter(|&n| n > 0 && n <= 10000);
+    match (side(), side()) {
+        (Some(aspect_width), Some(aspect_height)) => Ok(Pad {
+            aspect_width,
+            aspect_height,
+            color: parts.next().map(color::parse_color).transpose()?.unwrap_or(RGBA8::new(0, 0, 0, 0)),
+        }),
+        _ => Err(format!("Invalid --pad '{}'. Use an aspect ratio like 1:1 or 16:9:black", s).into()),
+    }
+}
+
+/// Percentage like 50% (the % sign is optional) as 0-1
+fn parse_scale(s: &str) -> BinResult<f32> {
+    match s.trim_end_matches('%').trim().parse::<f32>() {
+        Ok(percent) if percent > 0. && percent <= 100. => Ok(percent / 100.),
+        _ => Err(format!("Invalid scale '{}'. Use a percentage from 1% to 100%, e.g. 50%", s).into()),
+    }
+}
+
+/// -100 to 100 as -1 to 1
+fn parse_adjustment(s: Option<&str>) -> Result<f32, ()> {
+    match s.map(|s| s.parse::<f32>()) {
+        Some(Ok(value)) if (-100. ..=100.).contains(&value) => Ok(value / 100.),
+        Some(_) => Err(()),
+        None => Ok(0.),
+    }
+}
+
+/// none, ordered[:strength], diffusion[:strength]
+fn parse_dither(s: &str) -> BinResult<Dithering> {
+    let mut parts = s.splitn(2, ':');
+    let mode = parts.next().unwrap_or_default();
+    let strength = match parts.next().map(|s| s.parse::<f32>()) {
+        Some(Ok(strength)) if (0. ..=1.).contains(&strength) => strength,
+        Some(_) => Err("Dithering strength must be between 0 and 1")?,
+        None => 1.,
+    };
+    Ok(match mode {
+        "none" => Dithering::None,
+        "ordered" => Dithering::Ordered(strength),
+        "diffusion" => Dithering::Diffusion(strength),
+        _ => Err(format!("Unknown dithering mode '{}'. Use none, ordered or diffusion", mode))?,
+    })
+}
+
+/// X,Y,WxH
+fn parse_crop(s: &str) -> BinResult<Crop> {
+    let parse = || -> Option<Crop> {
+        let mut parts = s.splitn(3, ',');
+        let left = parts.next()?.trim().parse().ok()?;
+        let top = parts.next()?.trim().parse().ok()?;
+        let mut size = parts.next()?.splitn(2, 'x');
+        let width = size.next()?.trim().parse().ok()?;
+        let height = size.next()?.trim().parse().ok()?;
+        Some(Crop { left, top, width, height })
+    };
+    match parse() {
+        Some(crop) if crop.width > 0 && crop.height > 0 => Ok(crop),
+        _ => Err(format!("Invalid crop '{}'. Expected X,Y,WxH, e.g. 0,60,1280x600", s).into()),
+    }
+}
+
 #[derive(PartialEq)]
 enum DestPath<'a> {
     Path(&'a Path),
     Stdout,
+    /// Only `--export-frames`
+    None,
 }
 
 impl<'a> DestPath<'a> {
@@ -252,18 +1559,67 @@ impl fmt::Display for DestPath<'_> {
                 abs_path.as_ref().map(|p| p.as_path()).unwrap_or(orig_path).display().fmt(f)
             },
             Self::Stdout => f.write_str("stdout"),
+            Self::None => f.write_str("nothing"),
         }
     }
 }
 
 #[cfg(feature = "video")]
-fn get_video_decoder(path: &Path, fps: source::Fps, settings: Settings) -> BinResult<Box<dyn Source + Send>> {
-    Ok(Box::new(ffmpeg_source::FfmpegDecoder::new(path, fps, settings)?))
+fn get_video_decoder(path: &Path, video_stream: Option<usize>, video_filter: Option<&str>, fps: source::Fps, trim: source::Trim, settings: Settings) -> BinResult<Box<dyn Source + Send>> {
+    Ok(Box::new(ffmpeg_source::FfmpegDecoder::new(path, video_stream, video_filter, fps, trim, settings)?))
 }
 
+/// Video, Y4M, APNG or an archive of PNG frames, or a video from stdin if the path is `-`
+fn single_file_decoder(path: &Path, video_stream: Option<usize>, video_filter: Option<&str>, rate: source::Fps, trim: source::Trim, settings: Settings) -> BinResult<Box<dyn Source + Send>> {
+    if path == Path::new("-") {
+        return get_stdin_decoder(video_stream, video_filter, rate, trim, settings).exit_code(ExitCode::DecodeFailed);
+    }
+    if let Some(archive) = archive_source::open(path, &rate).exit_code(ExitCode::DecodeFailed)? {
+        if trim.start > 0. || trim.end.is_some() {
+            Err("Start and duration don't apply to archives of PNG files as input").exit_code(ExitCode::BadArguments)?;
+        }
+        return Ok(archive);
+    }
+    if let Some(y4m) = y4m_source::Y4mDecoder::open(path, rate.clone(), trim).exit_code(ExitCode::DecodeFailed)? {
+        return Ok(Box::new(y4m));
+    }
+    Ok(match apng_source::ApngDecoder::open(path, &rate).exit_code(ExitCode::DecodeFailed)? {
+        Some(apng) => {
+            if trim.start > 0. || trim.end.is_some() {
+                Err("Start and duration don't apply to APNG files as input").exit_code(ExitCode::BadArguments)?;
+            }
+            Box::new(apng)
+        },
+        None => get_video_decoder(path, video_stream, video_filter, rate, trim, settings).exit_code(ExitCode::DecodeFailed)?,
+    })
+}
+
+/// FFmpeg can read any video from a pipe
+#[cfg(feature = "video")]
+fn get_stdin_decoder(video_stream: Option<usize>, video_filter: Option<&str>, fps: source::Fps, trim: source::Trim, settings: Settings) -> BinResult<Box<dyn Source + Send>> {
+    get_video_decoder(Path::new("-"), video_stream, video_filter, fps, trim, settings)
+}
+
+/// MP4 needs seeking, so only Y4M can be piped in
 #[cfg(not(feature = "video"))]
+fn get_stdin_decoder(_: Option<usize>, video_filter: Option<&str>, fps: source::Fps, trim: source::Trim, _: Settings) -> BinResult<Box<dyn Source + Send>> {
+    if video_filter.is_some() {
+        Err("--vf needs FFmpeg. Recompile gifski with the video feature").exit_code(ExitCode::BadArguments)?;
+    }
+    Ok(Box::new(y4m_source::Y4mDecoder::from_stdin(fps, trim)?))
+}
+
+#[cfg(all(feature = "h264", not(feature = "video")))]
+fn get_video_decoder(path: &Path, video_stream: Option<usize>, video_filter: Option<&str>, fps: source::Fps, trim: source::Trim, _: Settings) -> BinResult<Box<dyn Source + Send>> {
+    if video_filter.is_some() {
+        Err("--vf needs FFmpeg. Recompile gifski with the video feature").exit_code(ExitCode::BadArguments)?;
+    }
+    Ok(Box::new(mp4_source::Mp4Decoder::new(path, video_stream, fps, trim)?))
+}
+
+#[cfg(not(any(feature = "video", feature = "h264")))]
 #[cold]
-fn get_video_decoder(_: &Path, _: source::Fps, _: Settings) -> BinResult<Box<dyn Source + Send>> {
+fn get_video_decoder(_: &Path, _: Option<usize>, _: Option<&str>, _: source::Fps, _: source::Trim, _: Settings) -> BinResult<Box<dyn Source + Send>> {
     Err(r"Video support is permanently disabled in this executable.
 
 To enable video decoding you need to recompile gifski from source with:
@@ -271,6 +1627,8 @@ cargo build --release --features=video
 or
 cargo install gifski --features=video
 
+For MP4 files with H.264 video, --features=h264 works without FFmpeg.
+
 Alternatively, use ffmpeg command to export PNG frames, and then specify
 the PNG files as input for this executable.
 ")?
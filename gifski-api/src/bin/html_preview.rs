@@ -0,0 +1,46 @@
+use crate::BinResult;
+use std::path::Path;
+
+/// Writes a page with the GIF embedded in it, so it can be shared as one file
+pub fn write(html_path: &Path, gif: &[u8], title: &str, summary: &[(&str, String)]) -> BinResult<()> {
+    // the logical screen size is in the GIF's header
+    let (width, height) = match gif.get(6..10) {
+        Some(size) => (u16::from_le_bytes([size[0], size[1]]), u16::from_le_bytes([size[2], size[3]])),
+        None => Err("The GIF is empty")?,
+    };
+    let mut html = format!(r#"<!DOCTYPE html>
+<html><head><meta charset="utf-8"><title>{title}</title>
+<style>
+body {{font: 14px/1.5 sans-serif; margin: 2em}}
+img {{max-width: 100%; height: auto; background: repeating-conic-gradient(#ccc 0 25%, #fff 0 50%) 0 0/16px 16px}}
+th {{text-align: left; padding-right: 1em; vertical-align: top}}
+td {{font-family: monospace}}
+</style></head><body>
+<h1>{title}</h1>
+<img src="data:image/gif;base64,{data}" width="{width}" height="{height}" alt="">
+<table>
+"#, title = escape(title), data = base64(gif), width = width, height = height);
+    for (label, value) in [("Dimensions", format!("{}×{}", width, height))].iter().chain(summary) {
+        html += &format!("<tr><th>{}</th><td>{}</td></tr>\n", escape(label), escape(value));
+    }
+    html += "</table>\n</body></html>\n";
+    std::fs::write(html_path, html)
+        .map_err(|e| format!("Can't write to {}: {}", html_path.display(), e))?;
+    Ok(())
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &byte)| bits | u32::from(byte) << (16 - 8 * i));
+        for i in 0..4 {
+            out.push(if i <= chunk.len() { ALPHABET[(bits >> (18 - 6 * i)) as usize & 63] as char } else { '=' });
+        }
+    }
+    out
+}
@@ -0,0 +1,118 @@
+use crate::apng_source::{be32, ApngDecoder};
+use crate::source::{Fps, Trim};
+use crate::y4m_source::Y4mDecoder;
+use crate::exit_code::ExitCode;
+use crate::BinResult;
+use clap::{App, Arg};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// `gifski info FILE…`
+pub fn info_main() -> BinResult<()> {
+    let matches = App::new("gifski info")
+        .about("Prints details about the input files: format, codec, frame rate, \n\
+                number of frames and dimensions. For GIF files, also palettes, \n\
+                delays and disposal of every frame")
+        .arg(Arg::with_name("FILE")
+            .help("Video, Y4M, PNG or GIF files")
+            .min_values(1)
+            .empty_values(false)
+            .required(true))
+        // skips "gifski", so that "info" is the name of the command
+        .get_matches_from_safe(wild::args_os().skip(1))
+        .unwrap_or_else(|e| {
+            if e.use_stderr() {
+                eprintln!("{}", e.message);
+                std::process::exit(ExitCode::BadArguments as i32);
+            }
+            e.exit()
+        });
+    for path in matches.values_of_os("FILE").into_iter().flatten() {
+        print_info(Path::new(path))?;
+    }
+    Ok(())
+}
+
+/// Prints what gifski's decoders see in the file
+pub fn print_info(path: &Path) -> BinResult<()> {
+    let mut header = Vec::with_capacity(26);
+    File::open(path)
+        .and_then(|file| file.take(26).read_to_end(&mut header))
+        .map_err(|e| format!("Can't open {}: {}", path.display(), e))?;
+
+    println!("{}:", path.display());
+    if header.starts_with(b"GIF8") {
+        return print_gif_info(path);
+    }
+    // the rate doesn't matter, nothing is decoded
+    let rate = Fps { fps: 20., speed: 1., speed_ranges: Vec::new() };
+    let trim = Trim { start: 0., end: None };
+    if let Some(y4m) = Y4mDecoder::open(path, rate.clone(), trim)? {
+        println!("{}", y4m.info());
+    } else if let Some(apng) = ApngDecoder::open(path, &rate)? {
+        println!("{}", apng.info());
+    } else if header.starts_with(PNG_SIGNATURE) && header.len() == 26 && &header[12..16] == b"IHDR" {
+        let color = match header[25] {
+            0 => "gray",
+            2 => "RGB",
+            3 => "palette",
+            4 => "gray+alpha",
+            _ => "RGBA",
+        };
+        println!("PNG image, {}x{}, {}-bit {}", be32(&header[16..]), be32(&header[20..]), header[24], color);
+    } else {
+        println!("{}", video_info(path, rate, trim)?);
+    }
+    Ok(())
+}
+
+/// Everything is read, so that disposal and palettes of every frame can be listed
+fn print_gif_info(path: &Path) -> BinResult<()> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(File::open(path)?)
+        .map_err(|e| format!("Can't read {}: {}", path.display(), e))?;
+    let global_colors = decoder.global_palette().map_or(0, |palette| palette.len() / 3);
+    println!("GIF, {}x{}, global palette {} colors", decoder.width(), decoder.height(), global_colors);
+
+    let mut frames = 0;
+    let mut duration = 0;
+    while let Some(frame) = decoder.read_next_frame().map_err(|e| format!("Can't read {}: {}", path.display(), e))? {
+        frames += 1;
+        duration += u32::from(frame.delay);
+        let palette = match &frame.palette {
+            Some(palette) => format!("local palette {} colors", palette.len() / 3),
+            None => "global palette".to_string(),
+        };
+        let transparent = frame.transparent.map(|index| format!(", transparent index {}", index)).unwrap_or_default();
+        let dispose = match frame.dispose {
+            gif::DisposalMethod::Any => "any",
+            gif::DisposalMethod::Keep => "keep",
+            gif::DisposalMethod::Background => "background",
+            gif::DisposalMethod::Previous => "previous",
+        };
+        println!("  #{} {:.2}s {}x{}+{}+{}, {}{}, dispose {}",
+            frames, f64::from(frame.delay) / 100., frame.width, frame.height, frame.left, frame.top,
+            palette, transparent, dispose);
+    }
+    println!("{} frames, {:.2}s", frames, f64::from(duration) / 100.);
+    Ok(())
+}
+
+#[cfg(feature = "video")]
+fn video_info(path: &Path, rate: Fps, trim: Trim) -> BinResult<String> {
+    crate::ffmpeg_source::FfmpegDecoder::new(path, None, None, rate, trim, gifski::Settings::default())?.info()
+}
+
+#[cfg(all(feature = "h264", not(feature = "video")))]
+fn video_info(path: &Path, rate: Fps, trim: Trim) -> BinResult<String> {
+    Ok(crate::mp4_source::Mp4Decoder::new(path, None, rate, trim)?.info())
+}
+
+#[cfg(not(any(feature = "video", feature = "h264")))]
+fn video_info(_: &Path, _: Fps, _: Trim) -> BinResult<String> {
+    Err("Unknown file type. Video files need gifski compiled with the video or h264 feature".into())
+}
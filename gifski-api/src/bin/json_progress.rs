@@ -0,0 +1,65 @@
+use gifski::progress::ProgressReporter;
+use std::io::Write;
+use std::time::Instant;
+
+/// Newline-delimited JSON events on stderr, for GUIs that wrap the command-line tool.
+///
+/// `{"event":"progress","frame":10,"total":100,"percent":10.0,"bytes":12345,"eta":4.5}` after each frame,
+/// and `{"event":"done","frames":100,"bytes":123456}` at the end. If the number of frames isn't known,
+/// `total`, `percent` and `eta` are `null`. With `--max-size`, `{"event":"retry","bytes":123456,"quality":80}`
+/// is sent when the GIF is too large, and the progress starts over.
+pub struct JsonProgress {
+    /// 0 if unknown
+    total_frames: u64,
+    start: Instant,
+    frames: usize,
+    bytes: u64,
+}
+
+impl JsonProgress {
+    pub fn new(total_frames: u64) -> Self {
+        Self { total_frames, start: Instant::now(), frames: 0, bytes: 0 }
+    }
+
+    fn emit(line: &str) {
+        let stderr = std::io::stderr();
+        let mut stderr = stderr.lock();
+        // a GUI that stopped listening shouldn't stop the encoding
+        let _ = writeln!(stderr, "{}", line);
+        let _ = stderr.flush();
+    }
+}
+
+impl ProgressReporter for JsonProgress {
+    /// Progress is reported only via `frame_written`
+    fn increase(&mut self) -> bool {
+        true
+    }
+
+    fn frame_written(&mut self, ordinal_frame_number: usize, _presentation_timestamp: f64, bytes_written: u64) -> bool {
+        self.frames = ordinal_frame_number;
+        self.bytes = bytes_written;
+        let (total, percent, eta) = if self.total_frames > 0 {
+            // the estimated number of frames may be too low
+            let total = self.total_frames.max(ordinal_frame_number as u64);
+            let done = ordinal_frame_number as f64 / total as f64;
+            let elapsed = self.start.elapsed().as_secs_f64();
+            let eta = elapsed / done - elapsed;
+            (total.to_string(), format!("{:.1}", done * 100.), format!("{:.1}", eta))
+        } else {
+            ("null".into(), "null".into(), "null".into())
+        };
+        Self::emit(&format!(r#"{{"event":"progress","frame":{},"total":{},"percent":{},"bytes":{},"eta":{}}}"#,
+            ordinal_frame_number, total, percent, bytes_written, eta));
+        true
+    }
+
+    fn retry(&mut self, size: u64, next_quality: u8) {
+        Self::emit(&format!(r#"{{"event":"retry","bytes":{},"quality":{}}}"#, size, next_quality));
+        *self = Self::new(self.total_frames);
+    }
+
+    fn done(&mut self, _msg: &str) {
+        Self::emit(&format!(r#"{{"event":"done","frames":{},"bytes":{}}}"#, self.frames, self.bytes));
+    }
+}
@@ -0,0 +1,216 @@
+use crate::source::*;
+use crate::BinResult;
+use gifski::Collector;
+use gifski::YuvMatrix;
+use imgref::*;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// H.264 video in an MP4 container, decoded with OpenH264 (no FFmpeg needed)
+pub struct Mp4Decoder {
+    mp4: mp4::Mp4Reader<BufReader<File>>,
+    track_id: u32,
+    rate: Fps,
+    trim: Trim,
+}
+
+impl Mp4Decoder {
+    /// `video_stream` counts only H.264 tracks, from 0
+    pub fn new(path: &Path, video_stream: Option<usize>, rate: Fps, trim: Trim) -> BinResult<Self> {
+        let file = File::open(path)
+            .map_err(|e| format!("Unable to open video file {}: {}", path.display(), e))?;
+        let size = file.metadata()?.len();
+        let mp4 = mp4::Mp4Reader::read_header(BufReader::new(file), size)
+            .map_err(|e| format!("Unable to read {} as an MP4 file: {}", path.display(), e))?;
+        let mut h264_tracks = mp4.tracks().values()
+            .filter(|track| matches!(track.media_type(), Ok(mp4::MediaType::H264)))
+            .map(|track| track.track_id());
+        let track_id = match video_stream {
+            None => h264_tracks.next()
+                .ok_or("The file has no H.264 video tracks. Other formats need gifski compiled with the video feature")?,
+            Some(n) => h264_tracks.nth(n)
+                .ok_or_else(|| format!("The file has no H.264 video track {} (they're counted from 0)", n))?,
+        };
+        Ok(Self { mp4, track_id, rate, trim })
+    }
+
+    /// Summary for `gifski info`
+    pub fn info(&self) -> String {
+        let track = self.track();
+        format!("MP4, H.264 track {}, {}x{}, {:.3} fps, {} frames, {:.2}s",
+            self.track_id, track.width(), track.height(), track.frame_rate(),
+            track.sample_count(), track.duration().as_secs_f64())
+    }
+
+    fn track(&self) -> &mp4::Mp4Track {
+        &self.mp4.tracks()[&self.track_id]
+    }
+}
+
+impl Source for Mp4Decoder {
+    fn total_frames(&self) -> u64 {
+        let track = self.track();
+        let duration = track.duration().as_secs_f64();
+        let end = self.trim.end.map_or(duration, |end| end.min(duration));
+        let duration = self.rate.output_time(end) - self.rate.output_time(self.trim.start);
+        ((duration.max(0.) * f64::from(self.rate.fps)).ceil() as u64).min(track.sample_count().into())
+    }
+
+    fn collect(&mut self, dest: &mut Collector) -> BinResult<()> {
+        self.decode(|n, y, u, v, matrix, pts| {
+            dest.add_frame_yuv420(n, y, u, v, matrix, false, pts)?;
+            Ok(())
+        })
+    }
+}
+
+impl Mp4Decoder {
+    /// Calls `add_frame` for every frame in the trimmed range, in presentation order
+    fn decode(&mut self, mut add_frame: impl FnMut(usize, ImgRef<'_, u8>, ImgRef<'_, u8>, ImgRef<'_, u8>, YuvMatrix, f64) -> BinResult<()>) -> BinResult<()> {
+        let track = self.track();
+        let timescale = f64::from(track.timescale());
+        let sample_count = track.sample_count();
+        // MP4 keeps parameter sets out of the samples, but the decoder needs them in the stream
+        let mut stream = Vec::new();
+        push_nal(&mut stream, track.sequence_parameter_set()?);
+        push_nal(&mut stream, track.picture_parameter_set()?);
+
+        let mut decoder = openh264::decoder::Decoder::new()?;
+        // frames come out of the decoder in presentation order, which may differ from order of samples
+        let mut pending_pts = BinaryHeap::new();
+        let mut retimer = Retimer::new(&self.rate, self.trim.start);
+        let mut n = 0;
+        let mut sample_ids = 1..=sample_count;
+        let mut past_trim_end = false;
+        loop {
+            let decoded = match sample_ids.next().filter(|_| !past_trim_end) {
+                Some(sample_id) => {
+                    let sample = match self.mp4.read_sample(self.track_id, sample_id)? {
+                        Some(sample) => sample,
+                        None => continue,
+                    };
+                    let ticks = sample.start_time as i64 + i64::from(sample.rendering_offset);
+                    // stop decoding after the end, with a margin for frames that are decoded out of order
+                    if self.trim.end.map_or(false, |end| ticks as f64 / timescale > end + 1.) {
+                        past_trim_end = true;
+                        continue;
+                    }
+                    pending_pts.push(Reverse(ticks));
+
+                    // samples have 4-byte lengths before each NAL unit, and the decoder wants start codes
+                    let mut data = &sample.bytes[..];
+                    while data.len() > 4 {
+                        let len = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
+                        push_nal(&mut stream, data.get(4..4 + len).ok_or("Corrupted H.264 sample")?);
+                        data = &data[4 + len..];
+                    }
+                    let decoded = decoder.decode(&stream)?;
+                    stream.clear();
+                    decoded
+                },
+                // an empty packet ends the stream, and each call then returns one of the frames held back for reordering
+                None => match decoder.decode(&[])? {
+                    Some(yuv) => Some(yuv),
+                    None => break,
+                },
+            };
+
+            let yuv = match decoded {
+                Some(yuv) => yuv,
+                None => continue,
+            };
+            let input_time = match pending_pts.pop() {
+                Some(Reverse(ticks)) => ticks as f64 / timescale,
+                None => continue,
+            };
+            if input_time < self.trim.start || self.trim.end.map_or(false, |end| input_time > end) {
+                continue;
+            }
+            let pts = match retimer.pts(input_time) {
+                Some(pts) => pts,
+                None => continue,
+            };
+
+            let (width, height) = yuv.dimension_y();
+            let (y_stride, u_stride, v_stride) = yuv.strides_yuv();
+            let (chroma_width, chroma_height) = ((width + 1) / 2, (height + 1) / 2);
+            // the stream's color info isn't available, so guess from the size like most players do
+            let matrix = if height >= 720 { YuvMatrix::BT709 } else { YuvMatrix::BT601 };
+            add_frame(n,
+                ImgRef::new_stride(yuv.y_with_stride(), width, height, y_stride),
+                ImgRef::new_stride(yuv.u_with_stride(), chroma_width, chroma_height, u_stride),
+                ImgRef::new_stride(yuv.v_with_stride(), chroma_width, chroma_height, v_stride),
+                matrix, pts)?;
+            n += 1;
+        }
+        Ok(())
+    }
+}
+
+/// Annex B format
+fn push_nal(stream: &mut Vec<u8>, nal: &[u8]) {
+    stream.extend_from_slice(&[0, 0, 0, 1]);
+    stream.extend_from_slice(nal);
+}
+
+/// Short H.264 video of a moving gradient, so that no two frames are the same
+#[cfg(test)]
+fn write_test_video(path: &Path, frames: usize) {
+    use openh264::encoder::{Encoder, EncoderConfig, RateControlMode};
+    use openh264::formats::YUVBuffer;
+
+    let (width, height) = (64, 48);
+    let mut encoder = Encoder::with_config(EncoderConfig::new(width, height).enable_skip_frame(false).rate_control_mode(RateControlMode::Off)).unwrap();
+    let (mut sps, mut pps, mut samples) = (Vec::new(), Vec::new(), Vec::new());
+    for i in 0..frames {
+        let rgb: Vec<u8> = (0..width * height).flat_map(|p| [(p % width * 4) as u8, (i * 20) as u8, (p / width * 5) as u8]).collect();
+        let bitstream = encoder.encode(&YUVBuffer::with_rgb(width as usize, height as usize, &rgb)).unwrap().to_vec();
+        let mut sample = Vec::new();
+        for nal in openh264::nal_units(&bitstream) {
+            let nal = nal.strip_prefix(&[0, 0, 0, 1]).or_else(|| nal.strip_prefix(&[0, 0, 1])).unwrap();
+            match nal[0] & 0x1F {
+                7 => sps = nal.to_vec(),
+                8 => pps = nal.to_vec(),
+                _ => {
+                    sample.extend_from_slice(&(nal.len() as u32).to_be_bytes());
+                    sample.extend_from_slice(nal);
+                },
+            }
+        }
+        samples.push(sample);
+    }
+
+    let config = mp4::Mp4Config { major_brand: "isom".parse().unwrap(), minor_version: 512, compatible_brands: vec!["isom".parse().unwrap()], timescale: 1000 };
+    let mut mp4 = mp4::Mp4Writer::write_start(File::create(path).unwrap(), &config).unwrap();
+    mp4.add_track(&mp4::TrackConfig {
+        track_type: mp4::TrackType::Video,
+        timescale: 1000,
+        language: "und".into(),
+        media_conf: mp4::MediaConfig::AvcConfig(mp4::AvcConfig { width: width as u16, height: height as u16, seq_param_set: sps, pic_param_set: pps }),
+    }).unwrap();
+    for (i, sample) in samples.into_iter().enumerate() {
+        mp4.write_sample(1, &mp4::Mp4Sample { start_time: i as u64 * 100, duration: 100, rendering_offset: 0, is_sync: i == 0, bytes: sample.into() }).unwrap();
+    }
+    mp4.write_end().unwrap();
+}
+
+#[test]
+fn decodes_every_frame() {
+    let path = std::env::temp_dir().join(format!("gifski-mp4-test-{}.mp4", std::process::id()));
+    write_test_video(&path, 12);
+    let rate = Fps { fps: 10., speed: 1., speed_ranges: Vec::new() };
+    let mut decoder = Mp4Decoder::new(&path, None, rate, Trim { start: 0., end: None }).unwrap();
+    assert_eq!(12, decoder.total_frames());
+    let mut frames = 0;
+    decoder.decode(|n, y, _, _, _, pts| {
+        assert_eq!((frames, 64, 48), (n, y.width(), y.height()));
+        assert!((pts - n as f64 / 10.).abs() < 0.001);
+        frames += 1;
+        Ok(())
+    }).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(12, frames);
+}
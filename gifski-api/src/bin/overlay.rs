@@ -0,0 +1,282 @@
+use crate::exit_code::{ExitCode, WithExitCode};
+use crate::BinResult;
+use imgref::*;
+use rgb::*;
+use std::str::FromStr;
+
+/// Where on the frame to draw
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Corner {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl FromStr for Corner {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "top-left" => Self::TopLeft,
+            "top-right" => Self::TopRight,
+            "bottom-left" => Self::BottomLeft,
+            "bottom-right" => Self::BottomRight,
+            _ => return Err(format!("Unknown position '{}'. Use top-left, top-right, bottom-left or bottom-right", s)),
+        })
+    }
+}
+
+impl Corner {
+    /// Top-left coordinates of an item of the given size, `margin` pixels away from the frame's edges.
+    /// Can be negative if the item doesn't fit.
+    fn place(self, frame: (usize, usize), item: (usize, usize), margin: usize) -> (isize, isize) {
+        let right = frame.0 as isize - item.0 as isize - margin as isize;
+        let bottom = frame.1 as isize - item.1 as isize - margin as isize;
+        let margin = margin as isize;
+        match self {
+            Self::TopLeft => (margin, margin),
+            Self::TopRight => (right, margin),
+            Self::BottomLeft => (margin, bottom),
+            Self::BottomRight => (right, bottom),
+        }
+    }
+}
+
+/// Everything drawn on top of the frames, after they're resized
+#[derive(Clone, Default)]
+pub struct Overlays {
+    pub image: Option<Image>,
+    pub text: Option<Text>,
+}
+
+impl Overlays {
+    pub fn is_empty(&self) -> bool {
+        self.image.is_none() && self.text.is_none()
+    }
+
+    pub fn draw(&mut self, frame: &mut ImgVec<RGBA8>, pts: f64) {
+        if let Some(image) = &self.image {
+            image.draw(frame);
+        }
+        if let Some(text) = &mut self.text {
+            text.draw(frame, pts);
+        }
+    }
+}
+
+/// Picture, such as a logo, blended onto every frame
+#[derive(Clone)]
+pub struct Image {
+    image: ImgVec<RGBA8>,
+    corner: Corner,
+    /// 0-1
+    opacity: f32,
+}
+
+impl Image {
+    /// Parses path[:position[:opacity]]
+    pub fn from_arg(arg: &str) -> BinResult<Self> {
+        let mut path = arg;
+        let mut corner = Corner::BottomRight;
+        let mut opacity = 1.;
+        // the path itself may contain colons, so only known suffixes are taken off
+        if let Some(pos) = path.rfind(':') {
+            if let Ok(value) = path[pos + 1..].parse::<f32>() {
+                if !(0. ..=1.).contains(&value) {
+                    Err("Overlay opacity must be between 0 and 1")?;
+                }
+                opacity = value;
+                path = &path[..pos];
+            }
+        }
+        if let Some(pos) = path.rfind(':') {
+            if let Ok(value) = path[pos + 1..].parse() {
+                corner = value;
+                path = &path[..pos];
+            }
+        }
+        let image = lodepng::decode32_file(path)
+            .map_err(|e| format!("Can't load overlay {}: {}", path, e)).exit_code(ExitCode::DecodeFailed)?;
+        Ok(Self {
+            image: ImgVec::new(image.buffer, image.width, image.height),
+            corner,
+            opacity,
+        })
+    }
+
+    pub fn draw(&self, frame: &mut ImgVec<RGBA8>) {
+        let margin = frame.width().min(frame.height()) / 40;
+        let (left, top) = self.corner.place((frame.width(), frame.height()), (self.image.width(), self.image.height()), margin);
+        for (y, src_row) in self.image.rows().enumerate() {
+            let y = top + y as isize;
+            if y < 0 || y >= frame.height() as isize {
+                continue;
+            }
+            let frame_row = &mut frame[y as usize];
+            for (x, src) in src_row.iter().enumerate() {
+                let x = left + x as isize;
+                if x < 0 || x >= frame_row.len() as isize {
+                    continue;
+                }
+                let dst = &mut frame_row[x as usize];
+                let src_a = f32::from(src.a) / 255. * self.opacity;
+                let dst_a = f32::from(dst.a) / 255. * (1. - src_a);
+                let out_a = src_a + dst_a;
+                if out_a <= 0. {
+                    continue;
+                }
+                let blend = |s: u8, d: u8| ((f32::from(s) * src_a + f32::from(d) * dst_a) / out_a).round() as u8;
+                *dst = RGBA8::new(blend(src.r, dst.r), blend(src.g, dst.g), blend(src.b, dst.b), (out_a * 255.).round() as u8);
+            }
+        }
+    }
+}
+
+/// Paints `color` over the frame, using the mask as alpha
+#[cfg_attr(not(feature = "text"), allow(dead_code))]
+fn fill_mask(frame: &mut ImgVec<RGBA8>, mask: ImgRef<'_, u8>, (left, top): (isize, isize), color: RGB8) {
+    for (y, mask_row) in mask.rows().enumerate() {
+        let y = top + y as isize;
+        if y < 0 || y >= frame.height() as isize {
+            continue;
+        }
+        let frame_row = &mut frame[y as usize];
+        for (x, &coverage) in mask_row.iter().enumerate() {
+            let x = left + x as isize;
+            if coverage == 0 || x < 0 || x >= frame_row.len() as isize {
+                continue;
+            }
+            let px = &mut frame_row[x as usize];
+            let a = u16::from(coverage);
+            let blend = |fg: u8, bg: u8| ((u16::from(fg) * a + u16::from(bg) * (255 - a) + 127) / 255) as u8;
+            *px = RGBA8::new(blend(color.r, px.r), blend(color.g, px.g), blend(color.b, px.b), px.a.max(coverage));
+        }
+    }
+}
+
+/// m:ss.cc
+#[cfg_attr(not(feature = "text"), allow(dead_code))]
+fn format_timestamp(pts: f64) -> String {
+    let pts = pts.max(0.);
+    format!("{}:{:05.2}", (pts / 60.) as u32, pts % 60.)
+}
+
+#[cfg(feature = "text")]
+pub use self::text::Text;
+
+#[cfg(feature = "text")]
+mod text {
+    use super::*;
+    use std::collections::HashMap;
+    use std::path::Path;
+
+    /// Used when no font has been specified
+    const DEFAULT_FONTS: &[&str] = &[
+        "/usr/share/fonts/truetype/dejavu/DejaVuSans.ttf",
+        "/usr/share/fonts/TTF/DejaVuSans.ttf",
+        "/usr/share/fonts/dejavu/DejaVuSans.ttf",
+        "/System/Library/Fonts/Supplemental/Arial.ttf",
+        "/Library/Fonts/Arial.ttf",
+        "C:\\Windows\\Fonts\\arial.ttf",
+    ];
+
+    /// Caption and/or timestamp drawn in white with a dark shadow
+    #[derive(Clone)]
+    pub struct Text {
+        font: fontdue::Font,
+        text: Option<String>,
+        timestamp: bool,
+        corner: Corner,
+        /// Font size in pixels. Picked for the frame height if not set.
+        size: Option<f32>,
+        glyphs: HashMap<char, (fontdue::Metrics, Vec<u8>)>,
+    }
+
+    impl Text {
+        pub fn new(text: Option<String>, timestamp: bool, corner: Corner, font_path: Option<&Path>, size: Option<f32>) -> BinResult<Self> {
+            let font_data = match font_path {
+                Some(path) => std::fs::read(path).map_err(|e| format!("Can't read font {}: {}", path.display(), e)).exit_code(ExitCode::InputNotFound)?,
+                None => DEFAULT_FONTS.iter().find_map(|path| std::fs::read(path).ok())
+                    .ok_or("No default font found. Use --font to choose a TTF or OTF file")?,
+            };
+            let font = fontdue::Font::from_bytes(font_data, fontdue::FontSettings::default())
+                .map_err(|e| format!("Can't use the font: {}", e)).exit_code(ExitCode::DecodeFailed)?;
+            Ok(Self { font, text, timestamp, corner, size, glyphs: HashMap::new() })
+        }
+
+        pub fn draw(&mut self, frame: &mut ImgVec<RGBA8>, pts: f64) {
+            let label = match (&self.text, self.timestamp) {
+                (Some(text), true) => format!("{}  {}", text, format_timestamp(pts)),
+                (Some(text), false) => text.clone(),
+                (None, _) => format_timestamp(pts),
+            };
+            let px = *self.size.get_or_insert_with(|| (frame.height() as f32 / 16.).max(10.));
+            let mask = self.render(&label, px);
+            let margin = (px / 2.) as usize;
+            let (left, top) = self.corner.place((frame.width(), frame.height()), (mask.width() + 1, mask.height() + 1), margin);
+            fill_mask(frame, mask.as_ref(), (left + 1, top + 1), RGB8::new(0, 0, 0));
+            fill_mask(frame, mask.as_ref(), (left, top), RGB8::new(255, 255, 255));
+        }
+
+        /// Coverage of a single line of text
+        fn render(&mut self, label: &str, px: f32) -> ImgVec<u8> {
+            for ch in label.chars() {
+                let font = &self.font;
+                self.glyphs.entry(ch).or_insert_with(|| font.rasterize(ch, px));
+            }
+            let line = self.font.horizontal_line_metrics(px);
+            let ascent = line.map_or(px, |line| line.ascent).ceil() as isize;
+            let descent = line.map_or(0., |line| line.descent).floor() as isize;
+            let height = (ascent - descent).max(1) as usize;
+            let width = label.chars().map(|ch| self.glyphs[&ch].0.advance_width).sum::<f32>().ceil().max(1.) as usize;
+
+            let mut mask = ImgVec::new(vec![0; width * height], width, height);
+            let mut pen = 0_f32;
+            for ch in label.chars() {
+                let (metrics, coverage) = &self.glyphs[&ch];
+                let left = pen.round() as isize + metrics.xmin as isize;
+                let top = ascent - metrics.ymin as isize - metrics.height as isize;
+                pen += metrics.advance_width;
+                if metrics.width == 0 {
+                    continue;
+                }
+                for (y, glyph_row) in coverage.chunks(metrics.width).enumerate() {
+                    let y = top + y as isize;
+                    if y < 0 || y >= height as isize {
+                        continue;
+                    }
+                    let mask_row = &mut mask[y as usize];
+                    for (x, &c) in glyph_row.iter().enumerate() {
+                        let x = left + x as isize;
+                        if x >= 0 && x < width as isize {
+                            let m = &mut mask_row[x as usize];
+                            *m = (*m).max(c);
+                        }
+                    }
+                }
+            }
+            mask
+        }
+    }
+}
+
+#[cfg(not(feature = "text"))]
+#[derive(Clone)]
+pub enum Text {}
+
+#[cfg(not(feature = "text"))]
+impl Text {
+    #[cold]
+    pub fn new(_: Option<String>, _: bool, _: Corner, _: Option<&std::path::Path>, _: Option<f32>) -> BinResult<Self> {
+        Err(r"Text overlays are disabled in this executable.
+
+To enable them you need to recompile gifski from source with:
+cargo build --release --features=text
+")?
+    }
+
+    pub fn draw(&mut self, _: &mut ImgVec<RGBA8>, _: f64) {
+        match *self {}
+    }
+}
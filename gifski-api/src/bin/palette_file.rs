@@ -0,0 +1,70 @@
+use crate::BinResult;
+use rgb::*;
+use std::path::Path;
+
+/// GIMP (.gpl), Adobe Color Table (.act), or a list of hex colors (.hex, as used by Lospec)
+pub fn read_palette(path: &Path) -> BinResult<Vec<RGB8>> {
+    let data = std::fs::read(path).map_err(|e| format!("Can't read palette {}: {}", path.display(), e))?;
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or_default().to_ascii_lowercase();
+    let colors = match ext.as_str() {
+        "gpl" => parse_gpl(&String::from_utf8_lossy(&data)),
+        "act" => parse_act(&data),
+        "hex" | "txt" => parse_hex(&String::from_utf8_lossy(&data)),
+        _ => Err("Unknown palette format. Use a .gpl, .act or .hex file".into()),
+    }.map_err(|e| format!("Can't read palette {}: {}", path.display(), e))?;
+    if colors.is_empty() {
+        Err(format!("The palette {} has no colors", path.display()))?;
+    }
+    Ok(colors)
+}
+
+/// "R G B name" lines after a header
+fn parse_gpl(text: &str) -> BinResult<Vec<RGB8>> {
+    let mut lines = text.lines();
+    if lines.next().map(str::trim) != Some("GIMP Palette") {
+        Err("not a GIMP palette")?;
+    }
+    lines.map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.contains(':'))
+        .map(|line| {
+            let mut channels = line.split_whitespace().take(3).map(|c| c.parse::<u8>());
+            match (channels.next(), channels.next(), channels.next()) {
+                (Some(Ok(r)), Some(Ok(g)), Some(Ok(b))) => Ok(RGB8::new(r, g, b)),
+                _ => Err(format!("invalid line '{}'", line).into()),
+            }
+        })
+        .collect()
+}
+
+/// 256 RGB triplets, optionally followed by the number of colors used and the transparent index
+fn parse_act(data: &[u8]) -> BinResult<Vec<RGB8>> {
+    if data.len() != 768 && data.len() != 772 {
+        Err("Adobe Color Table must be 768 or 772 bytes long")?;
+    }
+    let mut colors = data[..768].as_rgb().to_vec();
+    if data.len() == 772 {
+        let used = u16::from_be_bytes([data[768], data[769]]) as usize;
+        let transparent = u16::from_be_bytes([data[770], data[771]]) as usize;
+        if used > 0 && used <= 256 {
+            colors.truncate(used);
+        }
+        if transparent < colors.len() {
+            colors.remove(transparent);
+        }
+    }
+    Ok(colors)
+}
+
+/// One rrggbb color per line
+fn parse_hex(text: &str) -> BinResult<Vec<RGB8>> {
+    text.lines().map(str::trim).filter(|line| !line.is_empty())
+        .map(|line| {
+            let hex = line.strip_prefix('#').unwrap_or(line);
+            if hex.len() != 6 {
+                Err(format!("invalid color '{}'", line))?;
+            }
+            let rgb = u32::from_str_radix(hex, 16).map_err(|_| format!("invalid color '{}'", line))?;
+            Ok(RGB8::new((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8))
+        })
+        .collect()
+}
@@ -1,29 +1,130 @@
 use crate::source::Fps;
+use crate::source::Retimer;
 use crate::source::Source;
+use crate::tonemap::{self, ToneMap};
 use crate::BinResult;
 use gifski::Collector;
+use std::path::Path;
 use std::path::PathBuf;
 
 pub struct Lodecoder {
     frames: Vec<PathBuf>,
-    fps: f32,
+    rate: Fps,
+    /// in seconds, one for each frame, if they're not shown at a constant rate
+    durations: Option<Vec<f64>>,
+    tone_map: ToneMap,
 }
 
 impl Lodecoder {
-    pub fn new(frames: Vec<PathBuf>, params: &Fps) -> Self {
-        Self { frames, fps: params.fps }
+    pub fn new(frames: Vec<PathBuf>, params: &Fps, tone_map: ToneMap) -> Self {
+        Self { frames, rate: params.clone(), durations: None, tone_map }
+    }
+
+    pub fn with_durations(frames: Vec<PathBuf>, durations: Vec<f64>, params: &Fps, tone_map: ToneMap) -> Self {
+        debug_assert_eq!(frames.len(), durations.len());
+        Self { frames, rate: params.clone(), durations: Some(durations), tone_map }
+    }
+
+    /// Input time of a frame, as if the files were a video
+    fn input_time(&self, i: usize) -> f64 {
+        i as f64 / f64::from(self.rate.fps)
+    }
+
+    fn collect_with_durations(&mut self, durations: &[f64], dest: &mut Collector) -> BinResult<()> {
+        if let Some(&last) = durations.last() {
+            dest.set_last_frame_duration(last);
+        }
+        let mut pts = 0.;
+        for (i, (frame, duration)) in self.frames.drain(..).zip(durations).enumerate() {
+            add_frame_file(dest, i, frame, pts, self.tone_map)?;
+            pts += duration;
+        }
+        Ok(())
     }
 }
 
 impl Source for Lodecoder {
     fn total_frames(&self) -> u64 {
-        self.frames.len() as u64
+        if self.durations.is_some() {
+            return self.frames.len() as u64;
+        }
+        let duration = self.rate.output_time(self.input_time(self.frames.len()));
+        ((duration * f64::from(self.rate.fps)).ceil() as u64).min(self.frames.len() as u64)
     }
 
     fn collect(&mut self, dest: &mut Collector) -> BinResult<()> {
-        for (i, frame) in self.frames.drain(..).enumerate() {
-            dest.add_frame_png_file(i, frame, i as f64 / self.fps as f64)?;
+        if let Some(durations) = self.durations.take() {
+            return self.collect_with_durations(&durations, dest);
+        }
+        let frames = std::mem::take(&mut self.frames);
+        let mut retimer = Retimer::new(&self.rate, 0.);
+        let mut n = 0;
+        for (i, frame) in frames.into_iter().enumerate() {
+            if let Some(pts) = retimer.pts(self.input_time(i)) {
+                add_frame_file(dest, n, frame, pts, self.tone_map)?;
+                n += 1;
+            }
         }
         Ok(())
     }
 }
+
+fn is_png(path: &Path) -> bool {
+    path.extension().map_or(true, |ext| ext.eq_ignore_ascii_case("png"))
+}
+
+pub fn add_frame_file(dest: &mut Collector, i: usize, path: PathBuf, pts: f64, tone_map: ToneMap) -> BinResult<()> {
+    if let Some(image) = tonemap::decode_hdr(&path, tone_map)? {
+        dest.add_frame_rgba(i, image, pts)?;
+    } else if is_png(&path) {
+        dest.add_frame_png_file(i, path, pts)?;
+    } else {
+        dest.add_frame_rgba(i, decode_other(&path)?, pts)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "image")]
+fn decode_other(path: &Path) -> BinResult<imgref::ImgVec<rgb::RGBA8>> {
+    use rgb::FromSlice;
+    let image = image::open(path)
+        .map_err(|e| format!("Can't load {}: {}", path.display(), e))?
+        .into_rgba8();
+    let (width, height) = image.dimensions();
+    Ok(imgref::ImgVec::new(image.into_raw().as_rgba().to_vec(), width as usize, height as usize))
+}
+
+#[cfg(not(feature = "image"))]
+#[cold]
+fn decode_other(path: &Path) -> BinResult<imgref::ImgVec<rgb::RGBA8>> {
+    Err(format!(r"Only PNG files are supported as frames, but {} isn't one.
+
+To use other image formats you need to recompile gifski from source with:
+cargo build --release --features=image
+", path.display()))?
+}
+
+/// Lines of `path duration_ms`. Paths are relative to the manifest file.
+pub fn read_manifest(manifest_path: &Path) -> BinResult<Vec<(PathBuf, f64)>> {
+    let text = std::fs::read_to_string(manifest_path)
+        .map_err(|e| format!("Can't read {}: {}", manifest_path.display(), e))?;
+    let base_dir = manifest_path.parent().unwrap_or_else(|| Path::new(""));
+    let frames = text.lines().enumerate()
+        .map(|(n, line)| (n, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| {
+            let err = || format!("Line {} of {} should be a path followed by a duration in milliseconds", n + 1, manifest_path.display());
+            // paths can have spaces, so the duration is after the last one
+            let pos = line.rfind(char::is_whitespace).ok_or_else(err)?;
+            let duration_ms: f64 = line[pos..].trim().parse().map_err(|_| err())?;
+            if !duration_ms.is_finite() || duration_ms <= 0. {
+                Err(err())?;
+            }
+            Ok((base_dir.join(line[..pos].trim()), duration_ms / 1000.))
+        })
+        .collect::<BinResult<Vec<_>>>()?;
+    if frames.is_empty() {
+        Err(format!("{} doesn't list any frames", manifest_path.display()))?;
+    }
+    Ok(frames)
+}
@@ -0,0 +1,94 @@
+use gifski::progress::{ProgressReporter, StageProgress};
+use std::io::Stdout;
+use std::time::{Duration, Instant};
+
+/// Frame counter with the current stage, elapsed and remaining time, and the size written so far.
+///
+/// Frames come out of the encoder only after the first ones have been decoded and quantized,
+/// so until then the stage and the time come from `stage_progress`.
+pub struct ProgressBar {
+    pb: pbr::ProgressBar<Stdout>,
+    total_frames: u64,
+    start: Instant,
+    /// Frames and bytes written so far
+    written: (usize, u64),
+}
+
+impl ProgressBar {
+    pub fn new(total_frames: u64) -> Self {
+        let mut pb = pbr::ProgressBar::new(total_frames);
+        pb.show_speed = false;
+        pb.show_percent = false;
+        // it's in the message, estimated the same way as in JSON progress
+        pb.show_time_left = false;
+        pb.format(" #_. ");
+        pb.set_max_refresh_rate(Some(Duration::from_millis(250)));
+        let mut this = Self { pb, total_frames, start: Instant::now(), written: (0, 0) };
+        this.pb.message(&this.message("Decoding", 0, 0));
+        this.pb.tick();
+        this
+    }
+
+    fn message(&self, stage: &str, frames: usize, bytes: u64) -> String {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        let mut msg = format!("{} {}", stage, format_time(elapsed));
+        if frames > 0 {
+            // the estimated number of frames may be too low
+            let total = self.total_frames.max(frames as u64);
+            let done = frames as f64 / total as f64;
+            msg += &format!(", ~{} left, {}", format_time(elapsed / done - elapsed), format_size(bytes));
+        }
+        msg + " | Frame "
+    }
+}
+
+impl ProgressReporter for ProgressBar {
+    /// Progress is reported only via `stage_progress` and `frame_written`
+    fn increase(&mut self) -> bool {
+        true
+    }
+
+    fn stage_progress(&mut self, progress: &StageProgress) -> bool {
+        let (frames, bytes) = self.written;
+        let msg = if frames > 0 {
+            // only the time has changed
+            self.message("Encoding", frames, bytes)
+        } else {
+            let (stage, done) = if progress.quantized > 0 { ("Quantizing", progress.quantized) } else { ("Decoding", progress.decoded) };
+            format!("{} {}, {} frames | Frame ", stage, format_time(self.start.elapsed().as_secs_f64()), done)
+        };
+        self.pb.message(&msg);
+        self.pb.tick();
+        true
+    }
+
+    fn frame_written(&mut self, ordinal_frame_number: usize, _presentation_timestamp: f64, bytes_written: u64) -> bool {
+        self.written = (ordinal_frame_number, bytes_written);
+        let msg = self.message("Encoding", ordinal_frame_number, bytes_written);
+        self.pb.message(&msg);
+        self.pb.set(ordinal_frame_number as u64);
+        true
+    }
+
+    fn retry(&mut self, size: u64, next_quality: u8) {
+        self.pb.finish_print(&format!("{} is too large, trying quality {}", format_size(size), next_quality));
+        *self = Self::new(self.total_frames);
+    }
+
+    fn done(&mut self, msg: &str) {
+        self.pb.finish_print(msg);
+    }
+}
+
+fn format_time(secs: f64) -> String {
+    let secs = secs.max(0.).round() as u64;
+    format!("{}:{:02}", secs / 60, secs % 60)
+}
+
+fn format_size(bytes: u64) -> String {
+    if bytes < 1024 * 1024 {
+        format!("{}KB", (bytes + 1023) / 1024)
+    } else {
+        format!("{:.1}MB", bytes as f64 / (1024. * 1024.))
+    }
+}
@@ -0,0 +1,112 @@
+use crate::source::*;
+use crate::BinResult;
+use gifski::Collector;
+use imgref::*;
+use rgb::*;
+use std::io;
+use std::io::Read;
+
+#[derive(Debug, Copy, Clone)]
+enum PixelFormat {
+    Rgba,
+    Bgra,
+    Rgb,
+}
+
+impl PixelFormat {
+    fn bytes_per_pixel(self) -> usize {
+        match self {
+            Self::Rgba | Self::Bgra => 4,
+            Self::Rgb => 3,
+        }
+    }
+}
+
+/// Uncompressed frames of the same size, one after another, read from stdin
+pub struct RawDecoder {
+    width: usize,
+    height: usize,
+    format: PixelFormat,
+    rate: Fps,
+    trim: Trim,
+}
+
+impl RawDecoder {
+    /// `spec` is WxH:format[:fps]
+    pub fn new(spec: &str, mut rate: Fps, trim: Trim) -> BinResult<Self> {
+        let err = || format!("Invalid raw video format '{}'. Use WxH:rgba, WxH:bgra or WxH:rgb, optionally followed by :fps", spec);
+        let mut parts = spec.split(':');
+        let mut size = parts.next().unwrap_or_default().splitn(2, 'x');
+        let width: usize = size.next().unwrap_or_default().parse().map_err(|_| err())?;
+        let height: usize = size.next().ok_or_else(err)?.parse().map_err(|_| err())?;
+        let format = match parts.next() {
+            Some("rgba") => PixelFormat::Rgba,
+            Some("bgra") => PixelFormat::Bgra,
+            Some("rgb") => PixelFormat::Rgb,
+            _ => Err(err())?,
+        };
+        if let Some(fps) = parts.next() {
+            rate.fps = fps.parse().map_err(|_| err())?;
+        }
+        if width == 0 || height == 0 || !rate.fps.is_finite() || rate.fps <= 0. || parts.next().is_some() {
+            Err(err())?;
+        }
+        Ok(Self { width, height, format, rate, trim })
+    }
+}
+
+impl Source for RawDecoder {
+    /// Unknown until the input ends
+    fn total_frames(&self) -> u64 {
+        0
+    }
+
+    fn collect(&mut self, dest: &mut Collector) -> BinResult<()> {
+        let stdin = io::stdin();
+        let mut input = stdin.lock();
+        let mut buf = vec![0; self.width * self.height * self.format.bytes_per_pixel()];
+        let mut retimer = Retimer::new(&self.rate, self.trim.start);
+        let mut n = 0;
+        for i in 0.. {
+            if !read_frame(&mut input, &mut buf)? {
+                break;
+            }
+            let input_time = i as f64 / f64::from(self.rate.fps);
+            if self.trim.end.map_or(false, |end| input_time > end) {
+                break;
+            }
+            if input_time < self.trim.start {
+                continue;
+            }
+            let pts = match retimer.pts(input_time) {
+                Some(pts) => pts,
+                None => continue,
+            };
+            let pixels = match self.format {
+                PixelFormat::Rgba => buf.as_rgba().to_vec(),
+                PixelFormat::Bgra => buf.as_bgra().iter().map(|px| RGBA8::new(px.r, px.g, px.b, px.a)).collect(),
+                PixelFormat::Rgb => buf.as_rgb().iter().map(|px| px.alpha(255)).collect(),
+            };
+            dest.add_frame_rgba(n, ImgVec::new(pixels, self.width, self.height), pts)?;
+            n += 1;
+        }
+        Ok(())
+    }
+}
+
+/// `false` at the end of the input
+fn read_frame(input: &mut impl Read, buf: &mut [u8]) -> BinResult<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(len) => filled += len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if filled > 0 && filled < buf.len() {
+        Err(format!("The raw video input ended in the middle of a frame ({} of {} bytes)", filled, buf.len()))?;
+    }
+    Ok(filled > 0)
+}
@@ -0,0 +1,117 @@
+//! `--resume` writes to a `.part` file, and keeps a journal of how much of it is complete,
+//! so that an interrupted encoding can continue from the last saved frame.
+use crate::BinResult;
+use std::collections::hash_map::DefaultHasher;
+use std::ffi::OsString;
+use std::fs::File;
+use std::hash::{Hash, Hasher};
+use std::io::{Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Saving after every frame would slow down fast encodes
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone)]
+pub struct Journal {
+    part_path: PathBuf,
+    path: PathBuf,
+    /// Hash of the command-line arguments, because a different encoding can't be continued
+    args: u64,
+    /// Already in the file when this run started
+    pub frames: usize,
+    bytes: u64,
+    /// Written in this run
+    written_frames: usize,
+    written_bytes: u64,
+    last_saved: Option<Instant>,
+}
+
+impl Journal {
+    /// Opens the `.part` file to continue writing at the end of its last complete frame, or creates a new one
+    pub fn open(output: &Path, args: impl Iterator<Item = OsString>) -> BinResult<(Self, File)> {
+        let mut hasher = DefaultHasher::new();
+        args.for_each(|arg| arg.hash(&mut hasher));
+        let mut part_path = output.as_os_str().to_owned();
+        part_path.push(".part");
+        let part_path = PathBuf::from(part_path);
+        let mut path = part_path.clone().into_os_string();
+        path.push(".journal");
+
+        let mut journal = Self {
+            part_path,
+            path: path.into(),
+            args: hasher.finish(),
+            frames: 0,
+            bytes: 0,
+            written_frames: 0,
+            written_bytes: 0,
+            last_saved: None,
+        };
+        if let Some((frames, bytes)) = journal.load() {
+            if let Ok(mut file) = std::fs::OpenOptions::new().write(true).open(&journal.part_path) {
+                if file.metadata()?.len() >= bytes {
+                    file.set_len(bytes)?;
+                    file.seek(SeekFrom::End(0))?;
+                    journal.frames = frames;
+                    journal.bytes = bytes;
+                    return Ok((journal, file));
+                }
+            }
+        }
+        let file = File::create(&journal.part_path)
+            .map_err(|e| format!("Can't write to {}: {}", journal.part_path.display(), e))?;
+        Ok((journal, file))
+    }
+
+    /// Frames and bytes, if the journal is for the same arguments
+    fn load(&self) -> Option<(usize, u64)> {
+        let text = std::fs::read_to_string(&self.path).ok()?;
+        let mut args = None;
+        let mut frames = None;
+        let mut bytes = None;
+        for line in text.lines() {
+            let mut parts = line.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some("args"), Some(value)) => args = u64::from_str_radix(value, 16).ok(),
+                (Some("frames"), Some(value)) => frames = value.parse().ok(),
+                (Some("bytes"), Some(value)) => bytes = value.parse().ok(),
+                _ => {},
+            }
+        }
+        if args != Some(self.args) {
+            return None;
+        }
+        Some((frames?, bytes?)).filter(|&(frames, _)| frames > 0)
+    }
+
+    /// `ordinal_frame_number` counts from the start of this run
+    pub fn frame_written(&mut self, ordinal_frame_number: usize, compressed_size: u64) {
+        self.written_frames = ordinal_frame_number;
+        self.written_bytes += compressed_size;
+        if self.last_saved.map_or(true, |t| t.elapsed() >= SAVE_INTERVAL) {
+            self.last_saved = Some(Instant::now());
+            // if it can't be saved, the encoding can still finish
+            let _ = self.save();
+        }
+    }
+
+    fn save(&self) -> BinResult<()> {
+        let text = format!("gifski journal\nargs {:x}\nframes {}\nbytes {}\n",
+            self.args, self.frames + self.written_frames, self.bytes + self.written_bytes);
+        // replaced in one step, so that it's never half-written
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        std::fs::write(&tmp_path, text)?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        Ok(())
+    }
+
+    /// Moves the complete file to its destination
+    pub fn finish(&self, output: &Path) -> BinResult<()> {
+        std::fs::rename(&self.part_path, output)
+            .map_err(|e| format!("Can't write to {}: {}", output.display(), e))?;
+        let _ = std::fs::remove_file(&self.path);
+        Ok(())
+    }
+}
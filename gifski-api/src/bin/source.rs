@@ -6,10 +6,75 @@ pub trait Source: Send {
     fn collect(&mut self, dest: &mut Collector) -> BinResult<()>;
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct Fps {
     /// output rate
     pub fps: f32,
     /// skip frames
     pub speed: f32,
+    /// parts of the input played at a different speed, sorted and not overlapping
+    pub speed_ranges: Vec<SpeedRange>,
+}
+
+/// Part of the input, in seconds of the input's time
+#[derive(Debug, Copy, Clone)]
+pub struct SpeedRange {
+    pub start: f64,
+    pub end: f64,
+    pub speed: f64,
+}
+
+impl Fps {
+    /// Time in the animation when this moment of the input is shown
+    pub fn output_time(&self, input_time: f64) -> f64 {
+        let speed = f64::from(self.speed);
+        let mut output_time = 0.;
+        let mut pos = 0.;
+        for range in self.speed_ranges.iter().take_while(|range| range.start < input_time) {
+            let end = range.end.min(input_time);
+            output_time += (range.start - pos) / speed + (end - range.start) / range.speed;
+            pos = end;
+        }
+        output_time + (input_time - pos).max(0.) / speed
+    }
+
+    /// How often the input needs to be sampled to have enough frames for its slowest part
+    #[cfg_attr(not(feature = "video"), allow(dead_code))]
+    pub fn input_fps(&self) -> f64 {
+        let min_speed = self.speed_ranges.iter().map(|range| range.speed).fold(f64::from(self.speed), f64::min);
+        f64::from(self.fps) / min_speed
+    }
+}
+
+/// Gives input frames their time in the animation,
+/// and drops frames that would be shown for less than one frame at the output rate.
+pub struct Retimer<'a> {
+    rate: &'a Fps,
+    /// output time of the first frame
+    start: f64,
+    next_pts: f64,
+}
+
+impl<'a> Retimer<'a> {
+    pub fn new(rate: &'a Fps, input_start: f64) -> Self {
+        Self { rate, start: rate.output_time(input_start), next_pts: 0. }
+    }
+
+    /// `None` if the frame should be skipped
+    pub fn pts(&mut self, input_time: f64) -> Option<f64> {
+        let pts = self.rate.output_time(input_time) - self.start;
+        if pts < self.next_pts - 1e-6 {
+            return None;
+        }
+        self.next_pts = pts + 1. / f64::from(self.rate.fps);
+        Some(pts)
+    }
+}
+
+/// Part of a video to convert, in seconds of the video's time
+#[derive(Debug, Copy, Clone)]
+#[cfg_attr(not(any(feature = "video", feature = "h264")), allow(dead_code))]
+pub struct Trim {
+    pub start: f64,
+    pub end: Option<f64>,
 }
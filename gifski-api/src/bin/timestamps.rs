@@ -0,0 +1,76 @@
+//! Real timing of image sequences, for photos that weren't taken at a constant rate
+#[cfg(feature = "exif")]
+use crate::exit_code::{ExitCode, WithExitCode};
+use crate::BinResult;
+#[cfg(feature = "exif")]
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Milliseconds from the last number in the file name, like `frame_000123ms.png`
+pub fn from_filenames(frames: &[PathBuf]) -> BinResult<Vec<f64>> {
+    frames.iter().map(|path| {
+        let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+        let end = stem.rfind(|c: char| c.is_ascii_digit())
+            .ok_or_else(|| format!("The file name {} has no timestamp", path.display()))? + 1;
+        let start = stem[..end].rfind(|c: char| !c.is_ascii_digit()).map_or(0, |pos| pos + 1);
+        let ms: u64 = stem[start..end].parse().map_err(|_| format!("The timestamp in {} is too large", path.display()))?;
+        Ok(ms as f64 / 1000.)
+    }).collect()
+}
+
+/// Seconds from the date the photo was taken
+#[cfg(feature = "exif")]
+pub fn from_exif(frames: &[PathBuf]) -> BinResult<Vec<f64>> {
+    frames.iter().map(|path| exif_time(path).map_err(|e| format!("Can't get the EXIF date of {}: {}", path.display(), e)).exit_code(ExitCode::DecodeFailed)).collect()
+}
+
+#[cfg(feature = "exif")]
+fn exif_time(path: &Path) -> BinResult<f64> {
+    use exif::{In, Tag, Value};
+
+    let mut file = std::io::BufReader::new(std::fs::File::open(path)?);
+    let exif = exif::Reader::new().read_from_container(&mut file)?;
+    let ascii = |tag| match exif.get_field(tag, In::PRIMARY).map(|f| &f.value) {
+        Some(Value::Ascii(values)) => values.first().map(|v| v.as_slice()),
+        _ => None,
+    };
+    let date = ascii(Tag::DateTimeOriginal).or_else(|| ascii(Tag::DateTime)).ok_or("no date")?;
+    let mut date = exif::DateTime::from_ascii(date)?;
+    if let Some(subsec) = ascii(Tag::SubSecTimeOriginal).or_else(|| ascii(Tag::SubSecTime)) {
+        let _ = date.parse_subsec(subsec);
+    }
+    let days = days_from_civil(i64::from(date.year), date.month.into(), date.day.into());
+    let seconds = days * 86400 + i64::from(date.hour) * 3600 + i64::from(date.minute) * 60 + i64::from(date.second);
+    Ok(seconds as f64 + f64::from(date.nanosecond.unwrap_or(0)) / 1e9)
+}
+
+/// Days since 1970-01-01
+#[cfg(feature = "exif")]
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(not(feature = "exif"))]
+#[cold]
+pub fn from_exif(_: &[PathBuf]) -> BinResult<Vec<f64>> {
+    Err(r"EXIF support is disabled in this executable.
+
+To read dates of photos you need to recompile gifski from source with:
+cargo build --release --features=exif
+")?
+}
+
+/// Sorts frames by time, and turns timestamps into how long each frame is shown.
+/// The last frame is shown as long as the one before it.
+pub fn durations(frames: Vec<PathBuf>, timestamps: Vec<f64>) -> (Vec<PathBuf>, Vec<f64>) {
+    let mut frames: Vec<_> = frames.into_iter().zip(timestamps).collect();
+    frames.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+    let mut durations: Vec<_> = frames.windows(2).map(|pair| pair[1].1 - pair[0].1).collect();
+    durations.push(durations.last().copied().unwrap_or(1.));
+    (frames.into_iter().map(|(path, _)| path).collect(), durations)
+}
@@ -0,0 +1,125 @@
+use crate::BinResult;
+use imgref::*;
+use rgb::*;
+use std::path::Path;
+
+/// How colors brighter than white are brought into range of 8-bit frames
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ToneMap {
+    /// Clip for 16-bit PNG, which is usually already meant for display, and ACES for EXR
+    Auto,
+    /// Cut off everything brighter than white
+    Clip,
+    /// Gently compresses highlights, but makes everything a bit darker
+    Reinhard,
+    /// Filmic curve fitted to ACES, with more contrast
+    Aces,
+    /// Filmic curve from Uncharted 2 by John Hable
+    Hable,
+}
+
+impl std::str::FromStr for ToneMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "auto" => Self::Auto,
+            "clip" => Self::Clip,
+            "reinhard" => Self::Reinhard,
+            "aces" => Self::Aces,
+            "hable" => Self::Hable,
+            _ => return Err(format!("Unknown tone mapping '{}'. Use auto, clip, reinhard, aces or hable", s)),
+        })
+    }
+}
+
+impl ToneMap {
+    /// Linear light, where 1 is white
+    #[cfg_attr(not(feature = "hdr"), allow(dead_code))]
+    fn apply(self, c: f32) -> f32 {
+        let c = c.max(0.);
+        match self {
+            Self::Auto | Self::Clip => c.min(1.),
+            Self::Reinhard => c / (1. + c),
+            Self::Aces => (c * (2.51 * c + 0.03) / (c * (2.43 * c + 0.59) + 0.14)).min(1.),
+            Self::Hable => {
+                fn curve(x: f32) -> f32 {
+                    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+                    (x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f) - e / f
+                }
+                const WHITE: f32 = 11.2;
+                (curve(c * 2.) / curve(WHITE)).min(1.)
+            },
+        }
+    }
+
+    /// Linear RGB and alpha as 8-bit sRGB
+    #[cfg_attr(not(feature = "hdr"), allow(dead_code))]
+    fn to_srgb8(self, px: RGBA<f32>) -> RGBA8 {
+        let encode = |c: f32| {
+            let c = self.apply(c);
+            let c = if c <= 0.003_130_8 { c * 12.92 } else { 1.055 * c.powf(1. / 2.4) - 0.055 };
+            (c * 255.).round().clamp(0., 255.) as u8
+        };
+        RGBA8::new(encode(px.r), encode(px.g), encode(px.b), (px.a * 255.).round().clamp(0., 255.) as u8)
+    }
+}
+
+/// Decodes EXR and 16-bit PNG files, which need tone mapping. `None` for other files.
+#[cfg(feature = "hdr")]
+pub fn decode_hdr(path: &Path, tone_map: ToneMap) -> BinResult<Option<ImgVec<RGBA8>>> {
+    if path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("exr")) {
+        let tone_map = if tone_map == ToneMap::Auto { ToneMap::Aces } else { tone_map };
+        let image = decode_exr(path)?;
+        let (width, height) = (image.width(), image.height());
+        return Ok(Some(ImgVec::new(image.into_buf().into_iter().map(|px| tone_map.to_srgb8(px)).collect(), width, height)));
+    }
+    if !is_16_bit_png(path) {
+        return Ok(None);
+    }
+    let image = image::open(path)
+        .map_err(|e| format!("Can't load {}: {}", path.display(), e))?
+        .into_rgba16();
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let pixels = image.into_raw().chunks_exact(4).map(|px| {
+        let linear = |c: u16| {
+            let c = f32::from(c) / 65535.;
+            if c <= 0.040_45 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+        };
+        tone_map.to_srgb8(RGBA::new(linear(px[0]), linear(px[1]), linear(px[2]), f32::from(px[3]) / 65535.))
+    }).collect();
+    Ok(Some(ImgVec::new(pixels, width, height)))
+}
+
+#[cfg(not(feature = "hdr"))]
+pub fn decode_hdr(path: &Path, _: ToneMap) -> BinResult<Option<ImgVec<RGBA8>>> {
+    if path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("exr")) {
+        Err(format!(r"{} is an EXR file, and EXR support is disabled in this executable.
+
+To enable EXR and 16-bit PNG decoding you need to recompile gifski from source with:
+cargo build --release --features=hdr
+", path.display()))?;
+    }
+    Ok(None)
+}
+
+/// Bit depth is in the header, which must be the first chunk
+#[cfg(feature = "hdr")]
+fn is_16_bit_png(path: &Path) -> bool {
+    use std::io::Read;
+    let mut header = [0; 25];
+    std::fs::File::open(path).and_then(|mut file| file.read_exact(&mut header)).is_ok()
+        && header.starts_with(b"\x89PNG\r\n\x1a\n")
+        && &header[12..16] == b"IHDR"
+        && header[24] == 16
+}
+
+/// Linear RGBA of the first layer
+#[cfg(feature = "hdr")]
+fn decode_exr(path: &Path) -> BinResult<ImgVec<RGBA<f32>>> {
+    let image = exr::prelude::read_first_rgba_layer_from_file(path,
+        |resolution, _| ImgVec::new(vec![RGBA::new(0., 0., 0., 1.); resolution.width() * resolution.height()], resolution.width(), resolution.height()),
+        |image: &mut ImgVec<RGBA<f32>>, position, (r, g, b, a): (f32, f32, f32, f32)| image[(position.x(), position.y())] = RGBA::new(r, g, b, a),
+    ).map_err(|e| format!("Can't load {}: {}", path.display(), e))?;
+    Ok(image.layer_data.channel_data.pixels)
+}
@@ -0,0 +1,356 @@
+use crate::source::*;
+use crate::BinResult;
+use gifski::{Collector, YuvMatrix};
+use imgref::*;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const SIGNATURE: &[u8] = b"YUV4MPEG2 ";
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Sampling {
+    C420,
+    C422,
+    C444,
+    /// Luma only
+    Mono,
+}
+
+/// Uncompressed YUV video, as written by `ffmpeg -f yuv4mpegpipe`
+pub struct Y4mDecoder {
+    input: Box<dyn BufRead + Send>,
+    width: usize,
+    height: usize,
+    sampling: Sampling,
+    /// 8, 10 or 12
+    bit_depth: u8,
+    full_range: bool,
+    /// of the input
+    fps: f64,
+    total_frames: u64,
+    rate: Fps,
+    trim: Trim,
+}
+
+impl Y4mDecoder {
+    /// `None` if the file isn't Y4M
+    pub fn open(path: &Path, rate: Fps, trim: Trim) -> BinResult<Option<Self>> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(_) => return Ok(None),
+        };
+        let file_size = file.metadata()?.len();
+        let (mut decoder, header_len) = match Self::from_reader(Box::new(BufReader::new(file)), rate, trim)
+            .map_err(|e| format!("Can't read {}: {}", path.display(), e))? {
+            Some(decoder) => decoder,
+            None => return Ok(None),
+        };
+        let mut frames = BufReader::new(File::open(path)?);
+        frames.seek(SeekFrom::Start(header_len))?;
+        decoder.total_frames = count_frames(&mut frames, decoder.frame_len() as u64, file_size - header_len)?;
+        Ok(Some(decoder))
+    }
+
+    /// Video piped in. Its length isn't known until the end.
+    pub fn from_stdin(rate: Fps, trim: Trim) -> BinResult<Self> {
+        match Self::from_reader(Box::new(BufReader::new(io::stdin())), rate, trim)
+            .map_err(|e| format!("Can't read stdin: {}", e))? {
+            Some((decoder, _)) => Ok(decoder),
+            None => Err("Only Y4M video can be read from stdin, unless gifski is compiled with the video feature".into()),
+        }
+    }
+
+    /// The decoder and the length of the stream header
+    fn from_reader(mut input: Box<dyn BufRead + Send>, rate: Fps, trim: Trim) -> BinResult<Option<(Self, u64)>> {
+        if !input.fill_buf()?.starts_with(SIGNATURE) {
+            return Ok(None);
+        }
+        let mut header = Vec::new();
+        input.read_until(b'\n', &mut header)?;
+        let decoder = Self::parse_header(&String::from_utf8_lossy(&header), input, rate, trim)?;
+        Ok(Some((decoder, header.len() as u64)))
+    }
+
+    fn parse_header(header: &str, input: Box<dyn BufRead + Send>, rate: Fps, trim: Trim) -> BinResult<Self> {
+        let mut width = 0;
+        let mut height = 0;
+        let mut fps = None;
+        let mut colorspace = "420";
+        let mut full_range = false;
+        for param in header[SIGNATURE.len()..].split_ascii_whitespace() {
+            let mut chars = param.chars();
+            let tag = chars.next();
+            let value = chars.as_str();
+            match tag {
+                Some('W') => width = value.parse().map_err(|_| "invalid width")?,
+                Some('H') => height = value.parse().map_err(|_| "invalid height")?,
+                Some('F') => {
+                    let mut ratio = value.splitn(2, ':').map(|n| n.parse::<u32>());
+                    if let (Some(Ok(num)), Some(Ok(den))) = (ratio.next(), ratio.next()) {
+                        if num > 0 && den > 0 {
+                            fps = Some(f64::from(num) / f64::from(den));
+                        }
+                    }
+                },
+                Some('C') => colorspace = value,
+                Some('X') => full_range |= value.eq_ignore_ascii_case("COLORRANGE=FULL"),
+                _ => {},
+            }
+        }
+        if width == 0 || height == 0 {
+            Err("missing frame size")?;
+        }
+        let (sampling, bit_depth) = match colorspace {
+            "420" | "420jpeg" | "420paldv" | "420mpeg2" => (Sampling::C420, 8),
+            "422" => (Sampling::C422, 8),
+            "444" => (Sampling::C444, 8),
+            "420p10" => (Sampling::C420, 10),
+            "422p10" => (Sampling::C422, 10),
+            "444p10" => (Sampling::C444, 10),
+            "420p12" => (Sampling::C420, 12),
+            "422p12" => (Sampling::C422, 12),
+            "444p12" => (Sampling::C444, 12),
+            "mono" => (Sampling::Mono, 8),
+            "mono10" => (Sampling::Mono, 10),
+            "mono12" => (Sampling::Mono, 12),
+            other => Err(format!("colorspace C{} is not supported", other))?,
+        };
+        Ok(Self {
+            input,
+            width,
+            height,
+            sampling,
+            bit_depth,
+            full_range,
+            fps: fps.ok_or("missing or invalid frame rate")?,
+            total_frames: 0,
+            rate,
+            trim,
+        })
+    }
+
+    /// Summary for `gifski info`
+    pub fn info(&self) -> String {
+        let sampling = match self.sampling {
+            Sampling::C420 => "4:2:0",
+            Sampling::C422 => "4:2:2",
+            Sampling::C444 => "4:4:4",
+            Sampling::Mono => "mono",
+        };
+        format!("Y4M video, {}x{}, {} {}-bit {} range, {:.3} fps, {} frames",
+            self.width, self.height, sampling, self.bit_depth,
+            if self.full_range { "full" } else { "limited" },
+            self.fps, self.total_frames)
+    }
+
+    fn chroma_size(&self) -> (usize, usize) {
+        let (w, h) = (self.width, self.height);
+        match self.sampling {
+            Sampling::C420 => ((w + 1) / 2, (h + 1) / 2),
+            Sampling::C422 => ((w + 1) / 2, h),
+            Sampling::C444 => (w, h),
+            Sampling::Mono => (0, 0),
+        }
+    }
+
+    fn bytes_per_sample(&self) -> usize {
+        if self.bit_depth > 8 { 2 } else { 1 }
+    }
+
+    /// Bytes of pixel data in each frame
+    fn frame_len(&self) -> usize {
+        let (cw, ch) = self.chroma_size();
+        (self.width * self.height + 2 * cw * ch) * self.bytes_per_sample()
+    }
+
+    /// `false` at the end of the input
+    fn read_frame(&mut self, buf: &mut [u8]) -> BinResult<bool> {
+        let mut frame_header = Vec::new();
+        if self.input.read_until(b'\n', &mut frame_header)? == 0 {
+            return Ok(false);
+        }
+        if !frame_header.starts_with(b"FRAME") {
+            Err("Y4M frame header is missing")?;
+        }
+        self.input.read_exact(buf).map_err(|_| "The Y4M input ended in the middle of a frame")?;
+        Ok(true)
+    }
+
+    /// 8-bit plane from the frame data. Samples with more bits are dithered, so that gradients don't get banded.
+    fn plane(&self, data: &[u8], width: usize, height: usize) -> ImgVec<u8> {
+        if self.bit_depth == 8 {
+            return ImgVec::new(data.to_vec(), width, height);
+        }
+        let shift = self.bit_depth - 8;
+        let max = (1u16 << self.bit_depth) - 1;
+        let pixels = data.chunks_exact(2).enumerate().map(|(i, sample)| {
+            let (x, y) = (i % width, i / width);
+            let sample = u16::from_le_bytes([sample[0], sample[1]]).min(max);
+            let threshold = u16::from(BAYER[(y & 3) * 4 + (x & 3)]) >> (4 - shift.min(4));
+            ((sample + threshold) >> shift).min(255) as u8
+        }).collect();
+        ImgVec::new(pixels, width, height)
+    }
+}
+
+/// Frame headers can have parameters, so each one is read to find where the next frame starts.
+/// `len` is the length of the input after the stream header. A frame cut short isn't counted.
+fn count_frames<R: Read + Seek>(input: &mut BufReader<R>, frame_len: u64, mut len: u64) -> io::Result<u64> {
+    let mut frames = 0;
+    let mut frame_header = Vec::new();
+    loop {
+        frame_header.clear();
+        let header_len = input.read_until(b'\n', &mut frame_header)? as u64;
+        if header_len == 0 || !frame_header.starts_with(b"FRAME") || header_len + frame_len > len {
+            return Ok(frames);
+        }
+        input.seek_relative(frame_len as i64)?;
+        len -= header_len + frame_len;
+        frames += 1;
+    }
+}
+
+/// 4x4 ordered dither thresholds, 0-15
+const BAYER: [u8; 16] = [0, 8, 2, 10, 12, 4, 14, 6, 3, 11, 1, 9, 15, 7, 13, 5];
+
+/// Averages chroma down to 4:2:0, which is what the encoder takes. Palettes can't show more chroma detail anyway.
+fn to_420(plane: ImgVec<u8>, sampling: Sampling, width: usize, height: usize) -> ImgVec<u8> {
+    let (step_x, step_y) = match sampling {
+        Sampling::C422 => (1, 2),
+        Sampling::C444 => (2, 2),
+        Sampling::C420 | Sampling::Mono => return plane,
+    };
+    let (out_w, out_h) = ((width + 1) / 2, (height + 1) / 2);
+    let mut out = Vec::with_capacity(out_w * out_h);
+    for y in 0..out_h {
+        for x in 0..out_w {
+            let mut sum = 0;
+            let mut count = 0;
+            for sy in (y * step_y..(y + 1) * step_y).filter(|&sy| sy < plane.height()) {
+                for sx in (x * step_x..(x + 1) * step_x).filter(|&sx| sx < plane.width()) {
+                    sum += u32::from(plane[(sx, sy)]);
+                    count += 1;
+                }
+            }
+            out.push(((sum + count / 2) / count.max(1)) as u8);
+        }
+    }
+    ImgVec::new(out, out_w, out_h)
+}
+
+impl Source for Y4mDecoder {
+    fn total_frames(&self) -> u64 {
+        let duration = self.total_frames as f64 / self.fps;
+        let end = self.trim.end.map_or(duration, |end| end.min(duration));
+        let duration = self.rate.output_time(end) - self.rate.output_time(self.trim.start);
+        ((duration.max(0.) * f64::from(self.rate.fps)).ceil() as u64).min(self.total_frames)
+    }
+
+    fn collect(&mut self, dest: &mut Collector) -> BinResult<()> {
+        let rate = self.rate.clone();
+        let mut retimer = Retimer::new(&rate, self.trim.start);
+        let matrix = if self.height >= 720 { YuvMatrix::BT709 } else { YuvMatrix::BT601 };
+        let (width, height) = (self.width, self.height);
+        let (cw, ch) = self.chroma_size();
+        let luma_len = width * height * self.bytes_per_sample();
+        let chroma_len = cw * ch * self.bytes_per_sample();
+        let mut buf = vec![0; self.frame_len()];
+        let mut n = 0;
+        for i in 0.. {
+            if !self.read_frame(&mut buf)? {
+                break;
+            }
+            let input_time = i as f64 / self.fps;
+            if self.trim.end.map_or(false, |end| input_time > end) {
+                break;
+            }
+            if input_time < self.trim.start {
+                continue;
+            }
+            let pts = match retimer.pts(input_time) {
+                Some(pts) => pts,
+                None => continue,
+            };
+            let y = self.plane(&buf[..luma_len], width, height);
+            let (u, v) = if self.sampling == Sampling::Mono {
+                // neutral chroma gives the same value in R, G and B
+                let (cw, ch) = ((width + 1) / 2, (height + 1) / 2);
+                let gray = ImgVec::new(vec![128; cw * ch], cw, ch);
+                (gray.clone(), gray)
+            } else {
+                (to_420(self.plane(&buf[luma_len..luma_len + chroma_len], cw, ch), self.sampling, width, height),
+                 to_420(self.plane(&buf[luma_len + chroma_len..], cw, ch), self.sampling, width, height))
+            };
+            dest.add_frame_yuv420(n, y.as_ref(), u.as_ref(), v.as_ref(), matrix, self.full_range, pts)?;
+            n += 1;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+fn test_decoder(header: &str) -> BinResult<Y4mDecoder> {
+    Y4mDecoder::parse_header(header, Box::new(io::empty()), Fps { fps: 10., speed: 1., speed_ranges: Vec::new() }, Trim { start: 0., end: None })
+}
+
+#[test]
+fn parses_header() {
+    let decoder = test_decoder("YUV4MPEG2 W6 H4 F30000:1001 Ip A1:1 C422p10 XCOLORRANGE=FULL\n").unwrap();
+    assert_eq!((6, 4, Sampling::C422, 10, true), (decoder.width, decoder.height, decoder.sampling, decoder.bit_depth, decoder.full_range));
+    assert!((decoder.fps - 29.97).abs() < 0.001);
+    assert_eq!((3, 4), decoder.chroma_size());
+    assert_eq!((6 * 4 + 2 * 3 * 4) * 2, decoder.frame_len());
+
+    let decoder = test_decoder("YUV4MPEG2 W5 H3 F25:1\n").unwrap();
+    assert_eq!((Sampling::C420, 8, false), (decoder.sampling, decoder.bit_depth, decoder.full_range));
+    assert_eq!((3, 2), decoder.chroma_size());
+    assert_eq!(5 * 3 + 2 * 3 * 2, decoder.frame_len());
+
+    assert_eq!((Sampling::Mono, 12), test_decoder("YUV4MPEG2 W2 H2 F1:1 Cmono12\n").map(|d| (d.sampling, d.bit_depth)).unwrap());
+    assert!(test_decoder("YUV4MPEG2 W5 F25:1\n").is_err());
+    assert!(test_decoder("YUV4MPEG2 W5 H3 F25:0\n").is_err());
+    let err = test_decoder("YUV4MPEG2 W5 H3 F25:1 C411\n").err().unwrap();
+    assert!(err.to_string().contains("not supported"), "{}", err);
+}
+
+#[test]
+fn dithers_high_bit_depth() {
+    for &bit_depth in &[10, 12] {
+        let decoder = test_decoder(&format!("YUV4MPEG2 W8 H8 F25:1 Cmono{}\n", bit_depth)).unwrap();
+        // a quarter of the way between 8-bit levels 100 and 101
+        let shift = bit_depth - 8;
+        let sample = (100_u16 << shift) + (1 << shift) / 4;
+        let data: Vec<u8> = std::iter::repeat(sample.to_le_bytes()).take(64).flatten().collect();
+        let plane = decoder.plane(&data, 8, 8);
+        assert!(plane.pixels().all(|px| px == 100 || px == 101));
+        assert_eq!(100 * 64 + 16, plane.pixels().map(u32::from).sum::<u32>());
+
+        let max = ((1_u16 << bit_depth) - 1).to_le_bytes();
+        let data: Vec<u8> = std::iter::repeat(max).take(64).flatten().collect();
+        assert!(decoder.plane(&data, 8, 8).pixels().all(|px| px == 255));
+    }
+}
+
+#[test]
+fn averages_chroma_to_420() {
+    // 4:2:2 chroma of a 3x2 frame is 2x2, and only rows are averaged
+    let out = to_420(ImgVec::new(vec![10, 20, 30, 41], 2, 2), Sampling::C422, 3, 2);
+    assert_eq!((2, 1), (out.width(), out.height()));
+    assert_eq!(&[20, 31], out.buf().as_slice());
+
+    // blocks at the odd edges have fewer samples
+    let out = to_420(ImgVec::new((1..=9).collect(), 3, 3), Sampling::C444, 3, 3);
+    assert_eq!((2, 2), (out.width(), out.height()));
+    assert_eq!(&[3, 5, 8, 9], out.buf().as_slice());
+
+    let out = to_420(ImgVec::new(vec![1, 2, 3, 4], 2, 2), Sampling::C420, 3, 3);
+    assert_eq!(&[1, 2, 3, 4], out.buf().as_slice());
+}
+
+#[test]
+fn counts_frames_with_parameters() {
+    let data = b"FRAME\n123456FRAME Ip XYSCSS=420\n123456FRAME\n123";
+    let mut input = BufReader::new(io::Cursor::new(&data[..]));
+    assert_eq!(2, count_frames(&mut input, 6, data.len() as u64).unwrap());
+}
@@ -41,7 +41,8 @@
 //! it will build `target/aarch64-apple-ios/release/libgifski.a` (ignore the warning about cdylib).
 
 use super::*;
-use std::ffi::CStr;
+use rgb::alt::{BGR8, BGRA8};
+use std::ffi::{CStr, CString};
 use std::fs;
 use std::fs::File;
 use std::io;
@@ -72,6 +73,82 @@ pub struct GifskiSettings {
     pub repeat: i16,
 }
 
+/// Settings for creating a new encoder instance with `gifski_new2`.
+///
+/// New fields are only ever appended to this struct. The `size` field tells which version of it the caller has been compiled with,
+/// and the fields the caller doesn't know about get their default values. Zero in every field means default.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct GifskiSettings2 {
+    /// Must be set to `sizeof(GifskiSettings2)`
+    pub size: u32,
+    /// Resize to max this width if non-0.
+    pub width: u32,
+    /// Resize to max this height if width is non-0. Note that aspect ratio is not preserved.
+    pub height: u32,
+    /// 1-100, but useful range is 50-100. 0 for the default (100).
+    pub quality: u8,
+    /// Lower quality, but faster encode.
+    pub fast: bool,
+    /// If negative, looping is disabled. The number of times the sequence is repeated. 0 to loop forever.
+    pub repeat: i16,
+    /// 2-256, or 0 for 256
+    pub max_colors: u32,
+    /// One of `GifskiDithering` values
+    pub dithering: c_int,
+    /// 0-1, for ordered and diffusion dithering
+    pub dithering_strength: f32,
+    /// Lossy LZW compression level, like gifsicle's `--lossy`. 0 derives it from `quality`.
+    pub loss: u32,
+    /// If non-0, frames with PSNR below this (in dB) after lossy compression are compressed again with less loss.
+    pub lossy_min_psnr: f32,
+    /// Use less loss on mostly-static frames, and more on frames with lots of motion
+    pub adaptive_loss: bool,
+    /// Write interlaced frames
+    pub interlace: bool,
+    /// Slower encoding, but slightly smaller files
+    pub extra: bool,
+    /// Make the same GIF from the same input and settings on any machine
+    pub deterministic: bool,
+    /// Megabytes that frames waiting for encoding may use, or 0 for no limit.
+    /// Also how much bounce keeps in memory before frames go to a temporary file (0 for about 1GB).
+    pub memory_limit_mb: u32,
+}
+
+/// Size of the first version of `GifskiSettings2`. Keep this number when appending fields.
+const GIFSKI_SETTINGS2_MIN_SIZE: usize = 40;
+
+/// Dithering methods for `gifski_set_dithering`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum GifskiDithering {
+    AUTO = 0,
+    NONE,
+    ORDERED,
+    DIFFUSION,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum GifskiColorspace {
+    BT601 = 0,
+    BT709,
+    BT2020,
+}
+
+/// Optional parts of the library, see `gifski_has_feature`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum GifskiFeature {
+    GIFSICLE = 1,
+    PNG,
+    OPENMP,
+    DUMP,
+}
+
 #[repr(C)]
 #[derive(Copy, Clone)]
 pub struct ARGB8 {
@@ -90,10 +167,128 @@ pub struct GifskiHandle {
 pub struct GifskiHandleInternal {
     writer: Mutex<Option<Writer>>,
     collector: Mutex<Option<Collector>>,
-    progress: Mutex<Option<ProgressCallback>>,
+    progress: Mutex<Option<Box<dyn ProgressReporter>>>,
     /// Bool set to true when the thread has been set up,
     /// prevents re-setting of the thread after finish()
     write_thread: Mutex<(bool, Option<thread::JoinHandle<GifskiError>>)>,
+    /// Without threads, the writer moves here once the output is set, with the file to remove if writing fails
+    #[cfg(feature = "threadless")]
+    pumping: Mutex<Option<(Writer, Option<PathBuf>)>>,
+    error_callback: Mutex<Option<ErrorCallback>>,
+    stats_callback: Mutex<Option<StatsCallback>>,
+}
+
+struct StatsCallback {
+    cb: unsafe extern "C" fn(*const FrameStats, *mut c_void),
+    user_data: *mut c_void,
+}
+
+unsafe impl Send for StatsCallback {}
+
+/// User's progress callback combined with the stats callback
+struct CReporter {
+    progress: Option<Box<dyn ProgressReporter>>,
+    stats: Option<StatsCallback>,
+}
+
+impl ProgressReporter for CReporter {
+    fn increase(&mut self) -> bool {
+        self.progress.as_mut().map_or(true, |p| p.increase())
+    }
+
+    fn frame_written(&mut self, ordinal_frame_number: usize, presentation_timestamp: f64, bytes_written: u64) -> bool {
+        self.progress.as_mut().map_or(true, |p| p.frame_written(ordinal_frame_number, presentation_timestamp, bytes_written))
+    }
+
+    fn frame_stats(&mut self, stats: &FrameStats) {
+        if let Some(StatsCallback { cb, user_data }) = self.stats {
+            unsafe { cb(stats, user_data) }
+        }
+    }
+
+    fn done(&mut self, msg: &str) {
+        if let Some(p) = &mut self.progress {
+            p.done(msg);
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct ErrorCallback {
+    cb: unsafe extern "C" fn(GifskiError, i64, *const c_char, *mut c_void),
+    user_data: *mut c_void,
+}
+
+unsafe impl Send for ErrorCallback {}
+
+impl GifskiHandleInternal {
+    // the lock is released before calling back, because the callback may call gifski functions
+    fn report_error(&self, code: GifskiError, frame_number: Option<u32>, msg: &str) -> GifskiError {
+        let callback = *self.error_callback.lock().unwrap();
+        report_error(callback, code, frame_number, msg)
+    }
+
+    fn report_result(&self, res: CatResult<()>, frame_number: Option<u32>) -> GifskiError {
+        let callback = *self.error_callback.lock().unwrap();
+        report_result(callback, res, frame_number)
+    }
+
+    /// Without threads, frames are encoded (and written, once the output is set) as they're added
+    fn added_frame(&self, res: CatResult<()>, frame_number: u32) -> GifskiError {
+        #[cfg(feature = "threadless")]
+        let res = res.and_then(|()| match &mut *self.pumping.lock().unwrap() {
+            Some((writer, _)) => writer.pump(),
+            None => Ok(()),
+        });
+        self.report_result(res, Some(frame_number))
+    }
+}
+
+/// Passes the error to the error callback, or prints it to stderr if there's no callback
+#[cold]
+fn report_error(callback: Option<ErrorCallback>, code: GifskiError, frame_number: Option<u32>, msg: &str) -> GifskiError {
+    match callback {
+        Some(ErrorCallback { cb, user_data }) => {
+            let msg = CString::new(msg.replace('\0', "")).unwrap_or_default();
+            unsafe { cb(code, frame_number.map_or(-1, i64::from), msg.as_ptr(), user_data) };
+        },
+        None => eprintln!("{}", msg),
+    }
+    code
+}
+
+fn report_result(callback: Option<ErrorCallback>, res: CatResult<()>, frame_number: Option<u32>) -> GifskiError {
+    match res {
+        Ok(()) => GifskiError::OK,
+        Err(err) => match callback {
+            Some(_) => {
+                let msg = err.to_string();
+                report_error(callback, Err(err).into(), frame_number, &msg)
+            },
+            // errors used to be returned silently
+            None => Err(err).into(),
+        },
+    }
+}
+
+/// Version of the library, e.g. `"1.4.4"`. The string is static and must not be freed.
+#[no_mangle]
+pub extern "C" fn gifski_version_string() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+/// Returns `true` if this build of the library has been compiled with the given `GifskiFeature`.
+///
+/// Unknown features are reported as missing.
+#[no_mangle]
+pub extern "C" fn gifski_has_feature(feature: c_int) -> bool {
+    match feature {
+        x if x == GifskiFeature::GIFSICLE as c_int => cfg!(feature = "gifsicle"),
+        x if x == GifskiFeature::PNG as c_int => true,
+        x if x == GifskiFeature::OPENMP as c_int => cfg!(feature = "openmp"),
+        x if x == GifskiFeature::DUMP as c_int => cfg!(feature = "dump"),
+        _ => false,
+    }
 }
 
 /// Call to start the process
@@ -109,17 +304,130 @@ pub unsafe extern "C" fn gifski_new(settings: *const GifskiSettings) -> *const G
     let s = Settings {
         width: if settings.width > 0 { Some(settings.width) } else { None },
         height: if settings.height > 0 { Some(settings.height) } else { None },
+        scale: None,
         quality: settings.quality,
         fast: settings.fast,
         repeat: if settings.repeat == -1 { Repeat::Finite(0) } else if settings.repeat == 0 { Repeat::Infinite } else { Repeat::Finite(settings.repeat as u16) },
+        adaptive_loss: false,
+        interlace: false,
+        lossy_min_psnr: None,
+        optimize_frames: false,
+        lossy: LossyMode::Quality,
+        max_colors: 256,
+        dithering: Dithering::Auto,
+        bounce: false,
+        crop: None,
+        reverse: false,
+        loop_end_delay: 0.,
+        loop_crossfade: 0.,
+        threads: 0,
+        duplicate_threshold: 0,
+        stabilize: false,
+        deband: false,
+        brightness: 0.,
+        contrast: 0.,
+        gamma: 1.,
+        look: Look::Original,
+        sharpen: 0,
+        blur: 0.,
+        pad: None,
+        deterministic: false,
+        memory_limit_mb: 0,
+        queue_depth: 0,
+        denoise_half_size: false,
+        reuse_palettes: false,
+    };
+
+    new_handle(s)
+}
+
+/// Same as `gifski_new`, but with the extensible `GifskiSettings2` struct, which has more options.
+///
+/// Returns `NULL` if the settings are invalid, or if `settings->size` is larger than the struct this library knows
+/// (i.e. the header is newer than the library).
+#[no_mangle]
+pub unsafe extern "C" fn gifski_new2(settings: *const GifskiSettings2) -> *const GifskiHandle {
+    if settings.is_null() {
+        return ptr::null_mut();
+    }
+    // read only as many bytes as the caller has, and zero (default) the rest
+    let size = ptr::read_unaligned(settings as *const u32) as usize;
+    if size < GIFSKI_SETTINGS2_MIN_SIZE || size > mem::size_of::<GifskiSettings2>() {
+        return ptr::null_mut();
+    }
+    let mut settings2: GifskiSettings2 = mem::zeroed();
+    ptr::copy_nonoverlapping(settings as *const u8, &mut settings2 as *mut GifskiSettings2 as *mut u8, size);
+    match settings_from_c2(&settings2) {
+        Some(s) => new_handle(s),
+        None => ptr::null_mut(),
+    }
+}
+
+fn settings_from_c2(settings: &GifskiSettings2) -> Option<Settings> {
+    let max_colors = match settings.max_colors {
+        0 => 256,
+        n @ 2..=256 => n as u16,
+        _ => return None,
     };
+    if !(0. ..=1.).contains(&settings.dithering_strength) {
+        return None;
+    }
+    let dithering = match settings.dithering {
+        x if x == GifskiDithering::AUTO as c_int => Dithering::Auto,
+        x if x == GifskiDithering::NONE as c_int => Dithering::None,
+        x if x == GifskiDithering::ORDERED as c_int => Dithering::Ordered(settings.dithering_strength),
+        x if x == GifskiDithering::DIFFUSION as c_int => Dithering::Diffusion(settings.dithering_strength),
+        _ => return None,
+    };
+    Some(Settings {
+        width: if settings.width > 0 { Some(settings.width) } else { None },
+        height: if settings.height > 0 { Some(settings.height) } else { None },
+        scale: None,
+        quality: if settings.quality > 0 { settings.quality } else { Settings::default().quality },
+        fast: settings.fast,
+        repeat: if settings.repeat == -1 { Repeat::Finite(0) } else if settings.repeat == 0 { Repeat::Infinite } else { Repeat::Finite(settings.repeat as u16) },
+        adaptive_loss: settings.adaptive_loss,
+        interlace: settings.interlace,
+        lossy_min_psnr: if settings.lossy_min_psnr > 0. { Some(settings.lossy_min_psnr) } else { None },
+        optimize_frames: settings.extra,
+        lossy: if settings.loss > 0 { LossyMode::Loss(settings.loss) } else { LossyMode::Quality },
+        max_colors,
+        dithering,
+        bounce: false,
+        crop: None,
+        reverse: false,
+        loop_end_delay: 0.,
+        loop_crossfade: 0.,
+        threads: 0,
+        duplicate_threshold: 0,
+        stabilize: false,
+        deband: false,
+        brightness: 0.,
+        contrast: 0.,
+        gamma: 1.,
+        look: Look::Original,
+        sharpen: 0,
+        blur: 0.,
+        pad: None,
+        deterministic: settings.deterministic,
+        memory_limit_mb: settings.memory_limit_mb,
+        queue_depth: 0,
+        denoise_half_size: false,
+        reuse_palettes: false,
+    })
+}
 
+fn new_handle(s: Settings) -> *const GifskiHandle {
     if let Ok((collector, writer)) = new(s) {
         Arc::into_raw(Arc::new(GifskiHandleInternal {
             writer: Mutex::new(Some(writer)),
             write_thread: Mutex::new((false, None)),
+            #[cfg(feature = "threadless")]
+            pumping: Mutex::new(None),
             collector: Mutex::new(Some(collector)),
             progress: Mutex::new(None),
+            error_callback: Mutex::new(None),
+            stats_callback: Mutex::new(None),
         })) as *const GifskiHandle
     } else {
         ptr::null_mut()
@@ -142,23 +450,42 @@ pub unsafe extern "C" fn gifski_new(settings: *const GifskiSettings) -> *const G
 /// Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
 #[no_mangle]
 pub unsafe extern "C" fn gifski_add_frame_png_file(handle: *const GifskiHandle, frame_number: u32, file_path: *const c_char, presentation_timestamp: f64) -> GifskiError {
-    if file_path.is_null() {
-        return GifskiError::NULL_ARG;
+    match utf8_path(file_path) {
+        Ok(path) => add_frame_png_path(handle, frame_number, path, presentation_timestamp),
+        Err(err) => err,
     }
-    let g = match borrow(handle) {
+}
+
+/// Same as `gifski_add_frame_png_file`, but the path is a NUL-terminated UTF-16 string,
+/// so it works with any Windows file name.
+#[cfg(windows)]
+#[no_mangle]
+pub unsafe extern "C" fn gifski_add_frame_png_file_w(handle: *const GifskiHandle, frame_number: u32, file_path: *const u16, presentation_timestamp: f64) -> GifskiError {
+    match wide_path(file_path) {
+        Ok(path) => add_frame_png_path(handle, frame_number, path, presentation_timestamp),
+        Err(err) => err,
+    }
+}
+
+/// Same as `gifski_add_frame_png_file`, but the path is used as-is, without requiring it to be valid UTF-8.
+#[cfg(unix)]
+#[no_mangle]
+pub unsafe extern "C" fn gifski_add_frame_png_file_bytes(handle: *const GifskiHandle, frame_number: u32, file_path: *const c_char, presentation_timestamp: f64) -> GifskiError {
+    match bytes_path(file_path) {
+        Ok(path) => add_frame_png_path(handle, frame_number, path, presentation_timestamp),
+        Err(err) => err,
+    }
+}
+
+fn add_frame_png_path(handle: *const GifskiHandle, frame_number: u32, path: PathBuf, presentation_timestamp: f64) -> GifskiError {
+    let g = match unsafe { borrow(handle) } {
         Some(g) => g,
         None => return GifskiError::NULL_ARG,
     };
-    let path = if let Ok(s) = CStr::from_ptr(file_path).to_str() {
-        PathBuf::from(s)
-    } else {
-        return GifskiError::INVALID_INPUT;
-    };
     if let Some(ref mut c) = *g.collector.lock().unwrap() {
-        c.add_frame_png_file(frame_number as usize, path, presentation_timestamp).into()
+        g.added_frame(c.add_frame_png_file(frame_number as usize, path, presentation_timestamp), frame_number)
     } else {
-        eprintln!("frames can't be added any more, because gifski_end_adding_frames has been called already");
-        GifskiError::INVALID_STATE
+        g.report_error(GifskiError::INVALID_STATE, Some(frame_number), "frames can't be added any more, because gifski_end_adding_frames has been called already")
     }
 }
 
@@ -185,6 +512,94 @@ pub unsafe extern "C" fn gifski_add_frame_rgba(handle: *const GifskiHandle, fram
     add_frame_rgba(handle, frame_number, Img::new(pixels.into(), width as usize, height as usize), presentation_timestamp)
 }
 
+/// Same as `gifski_add_frame_rgba`, but with a map of areas that need better quality, such as the mouse pointer or click highlights.
+///
+/// `importance` is an array width×height bytes large. 0 means no preference, 255 is the most important.
+/// Both arrays are copied, so you can free/reuse them immediately.
+#[no_mangle]
+pub unsafe extern "C" fn gifski_add_frame_rgba_with_importance(handle: *const GifskiHandle, frame_number: u32, width: u32, height: u32, pixels: *const RGBA8, importance: *const u8, presentation_timestamp: f64) -> GifskiError {
+    if pixels.is_null() || importance.is_null() {
+        return GifskiError::NULL_ARG;
+    }
+    if width < 1 || height < 1 || width > 0xFFFF || height > 0xFFFF {
+        return GifskiError::INVALID_INPUT;
+    }
+    let width = width as usize;
+    let height = height as usize;
+    let pixels = slice::from_raw_parts(pixels, width * height);
+    let importance = slice::from_raw_parts(importance, width * height);
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    if let Some(ref mut c) = *g.collector.lock().unwrap() {
+        let res = c.add_frame_rgba_with_importance(frame_number as usize, ImgVec::new(pixels.to_vec(), width, height), ImgVec::new(importance.to_vec(), width, height), presentation_timestamp);
+        g.added_frame(res, frame_number)
+    } else {
+        g.report_error(GifskiError::INVALID_STATE, Some(frame_number), "frames can't be added any more, because gifski_end_adding_frames has been called already")
+    }
+}
+
+/// Caller's pixels, released with the caller's function when gifski is done with them
+struct ForeignPixels {
+    pixels: *const RGBA8,
+    len: usize,
+    free_fn: Option<unsafe extern "C" fn(*mut c_void)>,
+    user_data: *mut c_void,
+}
+
+unsafe impl Send for ForeignPixels {}
+
+impl AsRef<[RGBA8]> for ForeignPixels {
+    fn as_ref(&self) -> &[RGBA8] {
+        unsafe { slice::from_raw_parts(self.pixels, self.len) }
+    }
+}
+
+impl Drop for ForeignPixels {
+    fn drop(&mut self) {
+        if let Some(free_fn) = self.free_fn {
+            unsafe { free_fn(self.user_data) }
+        }
+    }
+}
+
+/// Same as `gifski_add_frame_rgba_stride`, but the pixels aren't copied by this function.
+/// Instead, gifski keeps the buffer until the frame is processed, and then calls `free_fn(user_data)`,
+/// which can `free()` the buffer or return it to a pool.
+///
+/// The buffer must not be modified until `free_fn` is called. `free_fn` is called exactly once, including when this function fails,
+/// but it may be called from another thread. `free_fn` can be `NULL`, but then the buffer must stay valid until `gifski_finish` returns.
+#[no_mangle]
+pub unsafe extern "C" fn gifski_add_frame_rgba_nocopy(handle: *const GifskiHandle, frame_number: u32, width: u32, height: u32, bytes_per_row: u32, pixels: *const RGBA8, presentation_timestamp: f64,
+    free_fn: Option<unsafe extern "C" fn(*mut c_void)>, user_data: *mut c_void) -> GifskiError {
+    let stride = bytes_per_row as usize / mem::size_of::<RGBA8>();
+    let width = width as usize;
+    let height = height as usize;
+    let foreign = ForeignPixels {
+        pixels,
+        len: if height > 0 { stride * (height - 1) + width } else { 0 },
+        free_fn,
+        user_data,
+    };
+    if pixels.is_null() {
+        return GifskiError::NULL_ARG;
+    }
+    if stride < width || width < 1 || height < 1 {
+        return GifskiError::INVALID_INPUT;
+    }
+    let img = Img::new_stride(Box::new(foreign) as ExternalPixels, width, height, stride);
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    if let Some(ref mut c) = *g.collector.lock().unwrap() {
+        g.added_frame(c.add_frame_rgba_external(frame_number as usize, img, presentation_timestamp), frame_number)
+    } else {
+        g.report_error(GifskiError::INVALID_STATE, Some(frame_number), "frames can't be added any more, because gifski_end_adding_frames has been called already")
+    }
+}
+
 /// Same as `gifski_add_frame_rgba`, but with bytes per row arg.
 #[no_mangle]
 pub unsafe extern "C" fn gifski_add_frame_rgba_stride(handle: *const GifskiHandle, frame_number: u32, width: u32, height: u32, bytes_per_row: u32, pixels: *const RGBA8, presentation_timestamp: f64) -> GifskiError {
@@ -208,11 +623,20 @@ fn add_frame_rgba(handle: *const GifskiHandle, frame_number: u32, frame: Img<Cow
         None => return GifskiError::NULL_ARG,
     };
     if let Some(ref mut c) = *g.collector.lock().unwrap() {
-        c.add_frame_rgba_cow(frame_number as usize, frame, presentation_timestamp).into()
+        g.added_frame(c.add_frame_rgba_cow(frame_number as usize, frame, presentation_timestamp), frame_number)
     } else {
-        eprintln!("frames can't be added any more, because gifski_end_adding_frames has been called already");
-        GifskiError::INVALID_STATE
+        g.report_error(GifskiError::INVALID_STATE, Some(frame_number), "frames can't be added any more, because gifski_end_adding_frames has been called already")
+    }
+}
+
+/// Copies the caller's rows straight into the frame's buffer, converting them with `extend`
+unsafe fn converted_rows<T>(pixels: *const T, width: usize, height: usize, stride: usize, extend: impl Fn(&[T], &mut Vec<RGBA8>)) -> ImgVec<RGBA8> {
+    let pixels = slice::from_raw_parts(pixels, stride * (height - 1) + width);
+    let mut buf = Vec::with_capacity(width * height);
+    for row in pixels.chunks(stride) {
+        extend(&row[..width], &mut buf);
     }
+    ImgVec::new(buf, width, height)
 }
 
 /// Same as `gifski_add_frame_rgba`, except it expects components in ARGB order.
@@ -228,17 +652,12 @@ pub unsafe extern "C" fn gifski_add_frame_argb(handle: *const GifskiHandle, fram
         return GifskiError::NULL_ARG;
     }
     let width = width as usize;
+    let height = height as usize;
     let stride = bytes_per_row as usize / mem::size_of_val(&*pixels);
-    if stride < width {
+    if stride < width || width < 1 || height < 1 {
         return GifskiError::INVALID_INPUT;
     }
-    let pixels = slice::from_raw_parts(pixels, stride * height as usize);
-    let img = ImgVec::new(pixels.chunks(stride).flat_map(|r| r[0..width].iter().map(|p| RGBA8 {
-        r: p.r,
-        g: p.g,
-        b: p.b,
-        a: p.a,
-    })).collect(), width as usize, height as usize);
+    let img = converted_rows(pixels, width, height, stride, simd::extend_from_argb);
     add_frame_rgba(handle, frame_number, img.into(), presentation_timestamp)
 }
 
@@ -255,41 +674,345 @@ pub unsafe extern "C" fn gifski_add_frame_rgb(handle: *const GifskiHandle, frame
         return GifskiError::NULL_ARG;
     }
     let width = width as usize;
+    let height = height as usize;
+    let stride = bytes_per_row as usize / mem::size_of_val(&*pixels);
+    if stride < width || width < 1 || height < 1 {
+        return GifskiError::INVALID_INPUT;
+    }
+    let img = converted_rows(pixels, width, height, stride, |row, out| out.extend(row.iter().map(|&px| px.alpha(255))));
+    add_frame_rgba(handle, frame_number, img.into(), presentation_timestamp)
+}
+
+/// Same as `gifski_add_frame_rgba_stride`, except it expects components in BGRA order,
+/// which is what `CGBitmapContext` and Windows screen capture usually produce.
+///
+/// Bytes per row must be multiple of 4 and greater or equal width×4.
+///
+/// Colors are in sRGB, uncorrelated BGRA, with alpha byte last.
+#[no_mangle]
+pub unsafe extern "C" fn gifski_add_frame_bgra(handle: *const GifskiHandle, frame_number: u32, width: u32, height: u32, bytes_per_row: u32, pixels: *const BGRA8, presentation_timestamp: f64) -> GifskiError {
+    if pixels.is_null() {
+        return GifskiError::NULL_ARG;
+    }
+    let width = width as usize;
+    let height = height as usize;
+    let stride = bytes_per_row as usize / mem::size_of_val(&*pixels);
+    if stride < width || width < 1 || height < 1 {
+        return GifskiError::INVALID_INPUT;
+    }
+    let img = converted_rows(pixels, width, height, stride, simd::extend_from_bgra);
+    add_frame_rgba(handle, frame_number, img.into(), presentation_timestamp)
+}
+
+/// Same as `gifski_add_frame_rgba_stride`, except it expects BGR components (3 bytes per pixel).
+///
+/// Bytes per row must be multiple of 3 and greater or equal width×3.
+///
+/// Colors are in sRGB, blue byte first.
+#[no_mangle]
+pub unsafe extern "C" fn gifski_add_frame_bgr(handle: *const GifskiHandle, frame_number: u32, width: u32, height: u32, bytes_per_row: u32, pixels: *const BGR8, presentation_timestamp: f64) -> GifskiError {
+    if pixels.is_null() {
+        return GifskiError::NULL_ARG;
+    }
+    let width = width as usize;
+    let height = height as usize;
     let stride = bytes_per_row as usize / mem::size_of_val(&*pixels);
-    if stride < width {
+    if stride < width || width < 1 || height < 1 {
+        return GifskiError::INVALID_INPUT;
+    }
+    let img = converted_rows(pixels, width, height, stride, |row, out| out.extend(row.iter().map(|px| RGBA8::new(px.r, px.g, px.b, 255))));
+    add_frame_rgba(handle, frame_number, img.into(), presentation_timestamp)
+}
+
+/// Same as `gifski_add_frame_rgba_stride`, except it expects a single gray channel (1 byte per pixel).
+///
+/// Bytes per row must be greater or equal width.
+///
+/// Colors are in sRGB (not linear light).
+#[no_mangle]
+pub unsafe extern "C" fn gifski_add_frame_gray(handle: *const GifskiHandle, frame_number: u32, width: u32, height: u32, bytes_per_row: u32, pixels: *const u8, presentation_timestamp: f64) -> GifskiError {
+    if pixels.is_null() {
+        return GifskiError::NULL_ARG;
+    }
+    let width = width as usize;
+    let height = height as usize;
+    let stride = bytes_per_row as usize;
+    if stride < width || width < 1 || height < 1 {
+        return GifskiError::INVALID_INPUT;
+    }
+    let img = converted_rows(pixels, width, height, stride, |row, out| out.extend(row.iter().map(|&g| RGBA8::new(g, g, g, 255))));
+    add_frame_rgba(handle, frame_number, img.into(), presentation_timestamp)
+}
+
+/// Planar YUV 4:2:0 frame, as produced by video decoders.
+///
+/// `u` and `v` planes have half the width and height of the `y` plane (rounded up).
+/// Each plane has its own bytes per row, which must be greater or equal its width.
+///
+/// `colorspace` is one of `GifskiColorspace` values. If `full_range` is false, luma is in the 16-235 range.
+///
+/// Planes are converted to RGB before this function returns, without copying them first.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn gifski_add_frame_yuv420(handle: *const GifskiHandle, frame_number: u32, width: u32, height: u32,
+    y: *const u8, y_bytes_per_row: u32,
+    u: *const u8, u_bytes_per_row: u32,
+    v: *const u8, v_bytes_per_row: u32,
+    colorspace: c_int, full_range: bool, presentation_timestamp: f64) -> GifskiError {
+    if y.is_null() || u.is_null() || v.is_null() {
+        return GifskiError::NULL_ARG;
+    }
+    let matrix = match colorspace {
+        x if x == GifskiColorspace::BT601 as c_int => YuvMatrix::BT601,
+        x if x == GifskiColorspace::BT709 as c_int => YuvMatrix::BT709,
+        x if x == GifskiColorspace::BT2020 as c_int => YuvMatrix::BT2020,
+        _ => return GifskiError::INVALID_INPUT,
+    };
+    let width = width as usize;
+    let height = height as usize;
+    let chroma_width = (width + 1) / 2;
+    let chroma_height = (height + 1) / 2;
+    let (y_stride, u_stride, v_stride) = (y_bytes_per_row as usize, u_bytes_per_row as usize, v_bytes_per_row as usize);
+    if width < 1 || height < 1 || y_stride < width || u_stride < chroma_width || v_stride < chroma_width {
+        return GifskiError::INVALID_INPUT;
+    }
+    let y = Img::new_stride(slice::from_raw_parts(y, y_stride * height + width - y_stride), width, height, y_stride);
+    let u = Img::new_stride(slice::from_raw_parts(u, u_stride * chroma_height + chroma_width - u_stride), chroma_width, chroma_height, u_stride);
+    let v = Img::new_stride(slice::from_raw_parts(v, v_stride * chroma_height + chroma_width - v_stride), chroma_width, chroma_height, v_stride);
+
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    if let Some(ref mut c) = *g.collector.lock().unwrap() {
+        g.added_frame(c.add_frame_yuv420(frame_number as usize, y, u, v, matrix, full_range, presentation_timestamp), frame_number)
+    } else {
+        g.report_error(GifskiError::INVALID_STATE, Some(frame_number), "frames can't be added any more, because gifski_end_adding_frames has been called already")
+    }
+}
+
+/// Get a callback for frame processed, and abort processing if desired.
+///
+/// The callback is called once per input frame,
+/// even if the encoder decides to skip some frames.
+///
+/// It gets arbitrary pointer (`user_data`) as an argument. `user_data` can be `NULL`.
+///
+/// The callback must return `1` to continue processing, or `0` to abort.
+///
+/// The callback must be thread-safe (it will be called from another thread).
+/// It must remain valid at all times, until `gifski_finish` completes.
+///
+/// This function must be called before `gifski_set_file_output()` to take effect.
+#[no_mangle]
+pub unsafe extern "C" fn gifski_set_progress_callback(handle: *const GifskiHandle, cb: unsafe extern fn(*mut c_void) -> c_int, user_data: *mut c_void) -> GifskiError {
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    let t = g.write_thread.lock().unwrap();
+    if t.0 {
+        return g.report_error(GifskiError::INVALID_STATE, None, "tried to set progress callback after writing has already started");
+    }
+    *g.progress.lock().unwrap() = Some(Box::new(ProgressCallback::new(cb, user_data)));
+    GifskiError::OK
+}
+
+/// Same as `gifski_set_progress_callback`, but the callback also gets the frame number (1..),
+/// its presentation timestamp, and number of bytes of the GIF written so far.
+///
+/// The callback must return `1` to continue processing, or `0` to abort.
+///
+/// Only one progress callback can be set. This function must be called before `gifski_set_file_output()` to take effect.
+#[no_mangle]
+pub unsafe extern "C" fn gifski_set_progress_callback2(handle: *const GifskiHandle, cb: unsafe extern fn(u32, f64, u64, *mut c_void) -> c_int, user_data: *mut c_void) -> GifskiError {
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    let t = g.write_thread.lock().unwrap();
+    if t.0 {
+        return g.report_error(GifskiError::INVALID_STATE, None, "tried to set progress callback after writing has already started");
+    }
+    *g.progress.lock().unwrap() = Some(Box::new(ProgressCallback2::new(cb, user_data)));
+    GifskiError::OK
+}
+
+/// Get a callback for errors, instead of having them printed to stderr.
+///
+/// The callback gets the `GifskiError` code, the number of the frame that caused the error
+/// (or `-1` if the error isn't about a particular frame), a human-readable message (valid only during the call), and `user_data`.
+///
+/// The callback must be thread-safe (it may be called from another thread).
+/// It must remain valid at all times, until `gifski_finish` completes.
+#[no_mangle]
+pub unsafe extern "C" fn gifski_set_error_callback(handle: *const GifskiHandle, cb: unsafe extern "C" fn(GifskiError, i64, *const c_char, *mut c_void), user_data: *mut c_void) -> GifskiError {
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    *g.error_callback.lock().unwrap() = Some(ErrorCallback { cb, user_data });
+    GifskiError::OK
+}
+
+/// Instead of dithering semi-transparent pixels to fully transparent or opaque,
+/// blend them with this color, making the animation fully opaque.
+///
+/// Applies to frames added after this call.
+#[no_mangle]
+pub unsafe extern "C" fn gifski_set_matte_color(handle: *const GifskiHandle, r: u8, g: u8, b: u8) -> GifskiError {
+    let handle = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    if let Some(c) = &mut *handle.collector.lock().unwrap() {
+        c.set_matte_color(RGB8::new(r, g, b));
+        GifskiError::OK
+    } else {
+        handle.report_error(GifskiError::INVALID_STATE, None, "tried to set matte color after gifski_end_adding_frames")
+    }
+}
+
+/// Play the frames forwards, and then backwards. Frames are kept until the end, and once they take more than
+/// `memory_limit_mb` of `GifskiSettings2` (or about 1GB without it) the rest go to a temporary file.
+///
+/// This function must be called before `gifski_set_file_output()` to take effect.
+#[no_mangle]
+pub unsafe extern "C" fn gifski_set_bounce(handle: *const GifskiHandle, bounce: bool) -> GifskiError {
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    if let Some(w) = &mut *g.writer.lock().unwrap() {
+        w.settings.bounce = bounce;
+        GifskiError::OK
+    } else {
+        g.report_error(GifskiError::INVALID_STATE, None, "tried to set bounce after writing has already started")
+    }
+}
+
+/// Write interlaced frames. Some viewers display them progressively while loading, but they compress slightly worse.
+///
+/// Same as `interlace` in `GifskiSettings2`, for handles from `gifski_new()`.
+///
+/// This function must be called before `gifski_set_file_output()` to take effect.
+#[no_mangle]
+pub unsafe extern "C" fn gifski_set_interlace(handle: *const GifskiHandle, interlace: bool) -> GifskiError {
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    if let Some(w) = &mut *g.writer.lock().unwrap() {
+        w.settings.interlace = interlace;
+        GifskiError::OK
+    } else {
+        g.report_error(GifskiError::INVALID_STATE, None, "tried to set interlace after writing has already started")
+    }
+}
+
+/// Get a callback after each frame is written, with its number (1..), delay (in 1/100ths of a second),
+/// number of colors in its palette, and its compressed size in bytes.
+///
+/// `stats` pointer is valid only during the call. `user_data` can be `NULL`.
+///
+/// The callback must be thread-safe (it will be called from another thread).
+///
+/// This function must be called before `gifski_set_file_output()` to take effect.
+#[no_mangle]
+pub unsafe extern "C" fn gifski_set_frame_stats_callback(handle: *const GifskiHandle, cb: unsafe extern "C" fn(*const FrameStats, *mut c_void), user_data: *mut c_void) -> GifskiError {
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    let t = g.write_thread.lock().unwrap();
+    if t.0 {
+        return g.report_error(GifskiError::INVALID_STATE, None, "tried to set stats callback after writing has already started");
+    }
+    *g.stats_callback.lock().unwrap() = Some(StatsCallback { cb, user_data });
+    GifskiError::OK
+}
+
+/// Limit number of colors in each frame's palette to `max_colors` (2-256). Fewer colors give smaller, but uglier files.
+///
+/// One of the colors is reserved for transparency in all frames except the first one.
+///
+/// This function must be called before `gifski_set_file_output()` to take effect.
+///
+/// Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+#[no_mangle]
+pub unsafe extern "C" fn gifski_set_max_colors(handle: *const GifskiHandle, max_colors: u32) -> GifskiError {
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    if !(2..=256).contains(&max_colors) {
+        return GifskiError::INVALID_INPUT;
+    }
+    if let Some(w) = &mut *g.writer.lock().unwrap() {
+        w.settings.max_colors = max_colors as u16;
+        GifskiError::OK
+    } else {
+        g.report_error(GifskiError::INVALID_STATE, None, "tried to set max colors after writing has already started")
+    }
+}
+
+/// Choose how colors that aren't in the palette are approximated.
+///
+/// `mode` is one of `GifskiDithering` constants, and `strength` (0-1) applies to `ORDERED` and `DIFFUSION` only.
+///
+/// This function must be called before `gifski_set_file_output()` to take effect.
+///
+/// Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+#[no_mangle]
+pub unsafe extern "C" fn gifski_set_dithering(handle: *const GifskiHandle, mode: c_int, strength: f32) -> GifskiError {
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    if !(0. ..=1.).contains(&strength) {
         return GifskiError::INVALID_INPUT;
     }
-    let pixels = slice::from_raw_parts(pixels, stride * height as usize);
-    let img = ImgVec::new(pixels.chunks(stride).flat_map(|r| r[0..width].iter().map(|&p| p.alpha(255))).collect(), width as usize, height as usize);
-    add_frame_rgba(handle, frame_number, img.into(), presentation_timestamp)
+    let dithering = match mode {
+        x if x == GifskiDithering::AUTO as c_int => Dithering::Auto,
+        x if x == GifskiDithering::NONE as c_int => Dithering::None,
+        x if x == GifskiDithering::ORDERED as c_int => Dithering::Ordered(strength),
+        x if x == GifskiDithering::DIFFUSION as c_int => Dithering::Diffusion(strength),
+        _ => return GifskiError::INVALID_INPUT,
+    };
+    if let Some(w) = &mut *g.writer.lock().unwrap() {
+        w.settings.dithering = dithering;
+        GifskiError::OK
+    } else {
+        g.report_error(GifskiError::INVALID_STATE, None, "tried to set dithering after writing has already started")
+    }
 }
 
-/// Get a callback for frame processed, and abort processing if desired.
-///
-/// The callback is called once per input frame,
-/// even if the encoder decides to skip some frames.
-///
-/// It gets arbitrary pointer (`user_data`) as an argument. `user_data` can be `NULL`.
-///
-/// The callback must return `1` to continue processing, or `0` to abort.
+/// Use exactly these colors for the whole animation, instead of finding the best palette for each frame.
 ///
-/// The callback must be thread-safe (it will be called from another thread).
-/// It must remain valid at all times, until `gifski_finish` completes.
+/// `colors` is an array of `count` RGB colors (3 bytes each). At most 255 colors are allowed,
+/// because one palette entry is needed for transparency.
 ///
 /// This function must be called before `gifski_set_file_output()` to take effect.
+///
+/// Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
 #[no_mangle]
-pub unsafe extern "C" fn gifski_set_progress_callback(handle: *const GifskiHandle, cb: unsafe extern fn(*mut c_void) -> c_int, user_data: *mut c_void) -> GifskiError {
+pub unsafe extern "C" fn gifski_set_fixed_palette(handle: *const GifskiHandle, colors: *const RGB8, count: u32) -> GifskiError {
     let g = match borrow(handle) {
         Some(g) => g,
         None => return GifskiError::NULL_ARG,
     };
-    let t = g.write_thread.lock().unwrap();
-    if t.0 {
-        eprintln!("tried to set progress callback after writing has already started");
-        return GifskiError::INVALID_STATE;
+    if colors.is_null() {
+        return GifskiError::NULL_ARG;
+    }
+    if !(1..=255).contains(&count) {
+        return GifskiError::INVALID_INPUT;
+    }
+    let colors = slice::from_raw_parts(colors, count as usize);
+    if let Some(w) = &mut *g.writer.lock().unwrap() {
+        w.set_fixed_palette(colors.to_vec()).into()
+    } else {
+        g.report_error(GifskiError::INVALID_STATE, None, "tried to set palette after writing has already started")
     }
-    *g.progress.lock().unwrap() = Some(ProgressCallback::new(cb, user_data));
-    GifskiError::OK
 }
 
 /// Start writing to the `destination`. This has to be called before any frames are added.
@@ -303,32 +1026,89 @@ pub unsafe extern "C" fn gifski_set_file_output(handle: *const GifskiHandle, des
         Some(g) => g,
         None => return GifskiError::NULL_ARG,
     };
-    let (file, path) = match prepare_for_file_writing(g, destination) {
+    match utf8_path(destination) {
+        Ok(path) => set_file_output_path(g, path),
+        Err(err) => err,
+    }
+}
+
+/// Same as `gifski_set_file_output`, but the path is a NUL-terminated UTF-16 string,
+/// so it works with any Windows file name.
+#[cfg(windows)]
+#[no_mangle]
+pub unsafe extern "C" fn gifski_set_file_output_w(handle: *const GifskiHandle, destination: *const u16) -> GifskiError {
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    match wide_path(destination) {
+        Ok(path) => set_file_output_path(g, path),
+        Err(err) => err,
+    }
+}
+
+/// Same as `gifski_set_file_output`, but the path is used as-is, without requiring it to be valid UTF-8.
+#[cfg(unix)]
+#[no_mangle]
+pub unsafe extern "C" fn gifski_set_file_output_bytes(handle: *const GifskiHandle, destination: *const c_char) -> GifskiError {
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    match bytes_path(destination) {
+        Ok(path) => set_file_output_path(g, path),
+        Err(err) => err,
+    }
+}
+
+fn set_file_output_path(g: &GifskiHandleInternal, path: PathBuf) -> GifskiError {
+    let file = match prepare_for_file_writing(g, &path) {
         Ok(res) => res,
         Err(err) => return err,
     };
     gifski_write_thread_start(g, file, Some(path))
 }
 
+fn prepare_for_file_writing(g: &GifskiHandleInternal, path: &Path) -> Result<File, GifskiError> {
+    let t = g.write_thread.lock().unwrap();
+    if t.0 {
+        return Err(g.report_error(GifskiError::INVALID_STATE, None, "tried to start writing for the second time, after it has already started"));
+    }
+    File::create(path).map_err(|err| err.kind().into())
+}
 
-fn prepare_for_file_writing(g: &GifskiHandleInternal, destination: *const c_char) -> Result<(File, PathBuf), GifskiError> {
-    if destination.is_null() {
+unsafe fn utf8_path(path: *const c_char) -> Result<PathBuf, GifskiError> {
+    if path.is_null() {
         return Err(GifskiError::NULL_ARG);
     }
-    let path = if let Ok(s) = unsafe { CStr::from_ptr(destination).to_str() } {
-        Path::new(s)
-    } else {
-        return Err(GifskiError::INVALID_INPUT);
-    };
-    let t = g.write_thread.lock().unwrap();
-    if t.0 {
-        eprintln!("tried to start writing for the second time, after it has already started");
-        return Err(GifskiError::INVALID_STATE);
+    match CStr::from_ptr(path).to_str() {
+        Ok(s) => Ok(PathBuf::from(s)),
+        Err(_) => Err(GifskiError::INVALID_INPUT),
+    }
+}
+
+#[cfg(unix)]
+unsafe fn bytes_path(path: *const c_char) -> Result<PathBuf, GifskiError> {
+    use std::os::unix::ffi::OsStrExt;
+
+    if path.is_null() {
+        return Err(GifskiError::NULL_ARG);
     }
-    match File::create(path) {
-        Ok(file) => Ok((file, path.into())),
-        Err(err) => Err(err.kind().into()),
+    Ok(PathBuf::from(std::ffi::OsStr::from_bytes(CStr::from_ptr(path).to_bytes())))
+}
+
+#[cfg(windows)]
+unsafe fn wide_path(path: *const u16) -> Result<PathBuf, GifskiError> {
+    use std::os::windows::ffi::OsStringExt;
+
+    if path.is_null() {
+        return Err(GifskiError::NULL_ARG);
     }
+    let mut len = 0;
+    while *path.add(len) != 0 {
+        len += 1;
+    }
+    Ok(PathBuf::from(std::ffi::OsString::from_wide(slice::from_raw_parts(path, len))))
 }
 
 struct CallbackWriter {
@@ -381,21 +1161,104 @@ pub unsafe extern "C" fn gifski_set_write_callback(handle: *const GifskiHandle,
     gifski_write_thread_start(g, writer, None)
 }
 
+/// Start writing to an already-open file descriptor, e.g. one given to a sandboxed app.
+///
+/// The descriptor is not closed by gifski. It must remain open until `gifski_finish` completes.
+///
+/// This call will not block.
+///
+/// Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+#[cfg(unix)]
+#[no_mangle]
+pub unsafe extern "C" fn gifski_set_fd_output(handle: *const GifskiHandle, fd: c_int) -> GifskiError {
+    use std::os::unix::io::FromRawFd;
+
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    if fd < 0 {
+        return GifskiError::INVALID_INPUT;
+    }
+    // ManuallyDrop, because the caller owns the descriptor
+    let file = mem::ManuallyDrop::new(File::from_raw_fd(fd));
+    gifski_write_thread_start(g, BorrowedFile(file), None)
+}
+
+#[cfg(unix)]
+struct BorrowedFile(mem::ManuallyDrop<File>);
+
+#[cfg(unix)]
+impl io::Write for BorrowedFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+extern "C" {
+    fn fwrite(ptr: *const c_void, size: usize, nitems: usize, stream: *mut c_void) -> usize;
+    fn fflush(stream: *mut c_void) -> c_int;
+}
+
+/// Writes to C's `FILE*`
+struct StreamWriter(*mut c_void);
+
+unsafe impl Send for StreamWriter {}
+
+impl io::Write for StreamWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match unsafe { fwrite(buf.as_ptr() as *const c_void, 1, buf.len(), self.0) } {
+            0 if !buf.is_empty() => Err(io::ErrorKind::WriteZero.into()),
+            n => Ok(n),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match unsafe { fflush(self.0) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+}
+
+/// Start writing to a C stdio stream (`FILE*`) opened for writing in binary mode.
+///
+/// The stream is flushed, but not closed by gifski. It must remain open until `gifski_finish` completes.
+///
+/// This call will not block.
+///
+/// Returns 0 (`GIFSKI_OK`) on success, and non-0 `GIFSKI_*` constant on error.
+#[no_mangle]
+pub unsafe extern "C" fn gifski_set_stream_output(handle: *const GifskiHandle, stream: *mut c_void) -> GifskiError {
+    let g = match borrow(handle) {
+        Some(g) => g,
+        None => return GifskiError::NULL_ARG,
+    };
+    if stream.is_null() {
+        return GifskiError::NULL_ARG;
+    }
+    gifski_write_thread_start(g, StreamWriter(stream), None)
+}
+
+#[cfg(not(feature = "threadless"))]
 fn gifski_write_thread_start<W: 'static +  Write + Send>(g: &GifskiHandleInternal, file: W, path: Option<PathBuf>) -> GifskiError {
     let mut t = g.write_thread.lock().unwrap();
     if t.0 {
-        eprintln!("gifski_set_file_output/gifski_set_write_callback has been called already");
-        return GifskiError::INVALID_STATE;
+        return g.report_error(GifskiError::INVALID_STATE, None, "gifski_set_file_output/gifski_set_write_callback has been called already");
     }
     let writer = g.writer.lock().unwrap().take();
-    let mut user_progress = g.progress.lock().unwrap().take();
+    let mut reporter = CReporter {
+        progress: g.progress.lock().unwrap().take(),
+        stats: g.stats_callback.lock().unwrap().take(),
+    };
+    let error_callback = *g.error_callback.lock().unwrap();
     let handle = thread::Builder::new().name("c-write".into()).spawn(move || {
         if let Some(writer) = writer {
-            let mut progress: &mut dyn ProgressReporter = &mut NoProgress {};
-            if let Some(cb) = &mut user_progress {
-                progress = &mut *cb;
-            }
-            match writer.write(file, progress).into() {
+            match report_result(error_callback, writer.write(file, &mut reporter), None) {
                 res @ GifskiError::OK |
                 res @ GifskiError::ALREADY_EXISTS => res,
                 err => {
@@ -406,8 +1269,7 @@ fn gifski_write_thread_start<W: 'static +  Write + Send>(g: &GifskiHandleInterna
                 },
             }
         } else {
-            eprintln!("gifski_set_file_output or gifski_write_* has been called once already");
-            GifskiError::INVALID_STATE
+            report_error(error_callback, GifskiError::INVALID_STATE, None, "gifski_set_file_output or gifski_write_* has been called once already")
         }
     });
     match handle {
@@ -419,6 +1281,28 @@ fn gifski_write_thread_start<W: 'static +  Write + Send>(g: &GifskiHandleInterna
     }
 }
 
+/// Without threads frames are written by `gifski_add_frame_*` calls, and the rest in `gifski_finish`
+#[cfg(feature = "threadless")]
+fn gifski_write_thread_start<W: 'static +  Write + Send>(g: &GifskiHandleInternal, file: W, path: Option<PathBuf>) -> GifskiError {
+    let mut t = g.write_thread.lock().unwrap();
+    if t.0 {
+        return g.report_error(GifskiError::INVALID_STATE, None, "gifski_set_file_output/gifski_set_write_callback has been called already");
+    }
+    let reporter = CReporter {
+        progress: g.progress.lock().unwrap().take(),
+        stats: g.stats_callback.lock().unwrap().take(),
+    };
+    let mut writer = match g.writer.lock().unwrap().take() {
+        Some(writer) => writer,
+        None => return g.report_error(GifskiError::INVALID_STATE, None, "gifski_set_file_output or gifski_write_* has been called once already"),
+    };
+    t.0 = true;
+    // frames added so far
+    let res = writer.set_output(file, reporter).and_then(|()| writer.pump());
+    *g.pumping.lock().unwrap() = Some((writer, path));
+    g.report_result(res, None)
+}
+
 unsafe fn borrow<'a>(handle: *const GifskiHandle) -> Option<&'a GifskiHandleInternal> {
     let g = handle as *const GifskiHandleInternal;
     g.as_ref()
@@ -444,6 +1328,23 @@ pub unsafe extern "C" fn gifski_finish(g: *const GifskiHandle) -> GifskiError {
     // dropping of the collector (if any) completes writing
     *g.collector.lock().unwrap() = None;
 
+    #[cfg(feature = "threadless")]
+    {
+        let pumping = g.pumping.lock().unwrap().take();
+        if let Some((writer, path)) = pumping {
+            return match g.report_result(writer.finish(), None) {
+                res @ GifskiError::OK |
+                res @ GifskiError::ALREADY_EXISTS => res,
+                err => {
+                    if let Some(path) = path {
+                        let _ = fs::remove_file(path); // clean up unfinished file
+                    }
+                    err
+                },
+            };
+        }
+    }
+
     let thread = g.write_thread.lock().unwrap().1.take();
     if let Some(thread) = thread {
         thread.join().expect("writer thread failed")
@@ -489,6 +1390,41 @@ fn c_cb() {
     assert_eq!(2, progress_called);
 }
 
+#[test]
+#[cfg(feature = "threadless")]
+fn c_writes_while_adding_frames() {
+    let g = unsafe {
+        gifski_new(&GifskiSettings {
+            width: 32,
+            height: 32,
+            quality: 100,
+            fast: false,
+            repeat: -1,
+        })
+    };
+    assert!(!g.is_null());
+    let mut bytes_written = 0usize;
+    unsafe extern "C" fn cb(s: usize, _buf: *const u8, user_data: *mut c_void) -> c_int {
+        let bytes_written = user_data as *mut usize;
+        *bytes_written += s;
+        0
+    }
+    unsafe {
+        assert_eq!(GifskiError::OK, gifski_set_write_callback(g, Some(cb), (&mut bytes_written) as *mut _ as _));
+        // more frames than the denoiser's lookahead
+        for n in 0..12u8 {
+            let pixels = vec![RGB::new(n * 20, 0, 255 - n * 20); 32 * 32];
+            assert_eq!(GifskiError::OK, gifski_add_frame_rgb(g, n.into(), 32, 32 * 3, 32, pixels.as_ptr(), f64::from(n) / 10.));
+        }
+    }
+    let before_finish = bytes_written;
+    assert!(before_finish > 0);
+    unsafe {
+        assert_eq!(GifskiError::OK, gifski_finish(g));
+    }
+    assert!(bytes_written > before_finish);
+}
+
 #[test]
 fn cant_write_after_finish() {
     let g = unsafe { gifski_new(&GifskiSettings {
@@ -570,3 +1506,222 @@ fn c_incomplete() {
         assert_eq!(GifskiError::OK, gifski_finish(g));
     }
 }
+
+#[test]
+fn c_palette_settings() {
+    let g = unsafe { gifski_new(&GifskiSettings {
+        width: 0, height: 0,
+        quality: 100,
+        fast: true,
+        repeat: 0,
+    })};
+    assert!(!g.is_null());
+    let palette = [RGB::new(0, 0, 0), RGB::new(255, 255, 255)];
+    unsafe extern "C" fn cb(_s: usize, _buf: *const u8, _user: *mut c_void) -> c_int {
+        0
+    }
+    unsafe {
+        assert_eq!(GifskiError::INVALID_INPUT, gifski_set_max_colors(g, 1));
+        assert_eq!(GifskiError::OK, gifski_set_max_colors(g, 16));
+        assert_eq!(GifskiError::OK, gifski_set_matte_color(g, 255, 255, 255));
+        assert_eq!(GifskiError::OK, gifski_set_bounce(g, true));
+        assert_eq!(GifskiError::OK, gifski_set_interlace(g, true));
+        assert_eq!(GifskiError::INVALID_INPUT, gifski_set_dithering(g, 99, 0.5));
+        assert_eq!(GifskiError::OK, gifski_set_dithering(g, GifskiDithering::ORDERED as c_int, 0.5));
+        assert_eq!(GifskiError::INVALID_INPUT, gifski_set_fixed_palette(g, palette.as_ptr(), 0));
+        assert_eq!(GifskiError::OK, gifski_set_fixed_palette(g, palette.as_ptr(), 2));
+        assert_eq!(GifskiError::OK, gifski_set_write_callback(g, Some(cb), ptr::null_mut()));
+        assert_eq!(GifskiError::INVALID_STATE, gifski_set_fixed_palette(g, palette.as_ptr(), 2));
+        assert_eq!(GifskiError::INVALID_STATE, gifski_set_max_colors(g, 16));
+        assert_eq!(GifskiError::INVALID_STATE, gifski_set_dithering(g, GifskiDithering::NONE as c_int, 0.));
+        assert_eq!(GifskiError::INVALID_STATE, gifski_set_interlace(g, false));
+        assert_eq!(GifskiError::OK, gifski_add_frame_rgb(g, 0, 1, 3, 1, &RGB::new(10, 20, 30), 5.0));
+        assert_eq!(GifskiError::OK, gifski_finish(g));
+    }
+}
+
+#[test]
+fn c_other_pixel_formats() {
+    let g = unsafe { gifski_new(&GifskiSettings {
+        width: 0, height: 0,
+        quality: 100,
+        fast: true,
+        repeat: 0,
+    })};
+    assert!(!g.is_null());
+    unsafe extern "C" fn cb(_s: usize, _buf: *const u8, _user: *mut c_void) -> c_int {
+        0
+    }
+    // all frames must have the same size
+    let bgra = [BGRA8 { b: 1, g: 2, r: 3, a: 255 }, BGRA8 { b: 0, g: 0, r: 0, a: 0 }, BGRA8 { b: 1, g: 2, r: 3, a: 255 }, BGRA8 { b: 1, g: 2, r: 3, a: 255 }];
+    let bgr = [BGR8 { b: 1, g: 2, r: 3 }; 4];
+    unsafe {
+        assert_eq!(GifskiError::OK, gifski_set_write_callback(g, Some(cb), ptr::null_mut()));
+        assert_eq!(GifskiError::NULL_ARG, gifski_add_frame_bgra(g, 0, 1, 1, 4, ptr::null(), 0.));
+        assert_eq!(GifskiError::INVALID_INPUT, gifski_add_frame_bgra(g, 0, 2, 1, 4, bgra.as_ptr(), 0.));
+        assert_eq!(GifskiError::OK, gifski_add_frame_bgra(g, 0, 2, 2, 8, bgra.as_ptr(), 0.));
+        assert_eq!(GifskiError::OK, gifski_add_frame_bgr(g, 1, 2, 2, 6, bgr.as_ptr(), 1.));
+        let planes = [128u8; 8];
+        assert_eq!(GifskiError::INVALID_INPUT, gifski_add_frame_gray(g, 2, 3, 2, 2, planes.as_ptr(), 2.));
+        assert_eq!(GifskiError::OK, gifski_add_frame_gray(g, 3, 2, 2, 3, planes.as_ptr(), 3.));
+        assert_eq!(GifskiError::INVALID_INPUT, gifski_add_frame_yuv420(g, 2, 2, 2, planes.as_ptr(), 2, planes.as_ptr(), 0, planes.as_ptr(), 1, 0, false, 2.));
+        assert_eq!(GifskiError::OK, gifski_add_frame_yuv420(g, 2, 2, 2, planes.as_ptr(), 2, planes.as_ptr(), 1, planes.as_ptr(), 1, GifskiColorspace::BT709 as c_int, false, 2.));
+        assert_eq!(GifskiError::OK, gifski_finish(g));
+    }
+}
+
+#[test]
+fn c_version() {
+    let version = unsafe { CStr::from_ptr(gifski_version_string()) };
+    assert_eq!(env!("CARGO_PKG_VERSION"), version.to_str().unwrap());
+    assert!(gifski_has_feature(GifskiFeature::PNG as c_int));
+    assert_eq!(cfg!(feature = "gifsicle"), gifski_has_feature(GifskiFeature::GIFSICLE as c_int));
+    assert!(!gifski_has_feature(0));
+}
+
+#[test]
+fn c_settings2() {
+    // memory_limit_mb has been appended to the first version
+    assert_eq!(GIFSKI_SETTINGS2_MIN_SIZE + 4, mem::size_of::<GifskiSettings2>());
+    let mut settings: GifskiSettings2 = unsafe { mem::zeroed() };
+    settings.quality = 90;
+    unsafe {
+        assert!(gifski_new2(&settings).is_null(), "size is required");
+        settings.size = mem::size_of::<GifskiSettings2>() as u32 + 4;
+        assert!(gifski_new2(&settings).is_null(), "too new");
+        settings.size = mem::size_of::<GifskiSettings2>() as u32;
+        settings.max_colors = 1;
+        assert!(gifski_new2(&settings).is_null());
+        settings.max_colors = 16;
+        settings.dithering = GifskiDithering::ORDERED as c_int;
+        settings.dithering_strength = 0.5;
+        let g = gifski_new2(&settings);
+        assert!(!g.is_null());
+        assert_eq!(GifskiError::OK, gifski_finish(g));
+    }
+}
+
+#[test]
+fn c_settings2_zero_is_default() {
+    let mut settings: GifskiSettings2 = unsafe { mem::zeroed() };
+    settings.size = mem::size_of::<GifskiSettings2>() as u32;
+    let s = settings_from_c2(&settings).unwrap();
+    let defaults = Settings::default();
+    assert_eq!(defaults.quality, s.quality);
+    assert_eq!(defaults.max_colors, s.max_colors);
+    assert_eq!(None, s.width);
+    assert!(matches!(s.lossy, LossyMode::Quality));
+    assert!(matches!(s.repeat, Repeat::Infinite));
+    assert!(!s.deterministic);
+
+    settings.quality = 50;
+    assert_eq!(50, settings_from_c2(&settings).unwrap().quality);
+    settings.deterministic = true;
+    assert!(settings_from_c2(&settings).unwrap().deterministic);
+    settings.memory_limit_mb = 64;
+    assert_eq!(64, settings_from_c2(&settings).unwrap().memory_limit_mb);
+
+    // callers with the first version of the struct get the default for fields appended later
+    settings.size = GIFSKI_SETTINGS2_MIN_SIZE as u32;
+    let g = unsafe { gifski_new2(&settings) };
+    assert!(!g.is_null());
+    assert_eq!(GifskiError::OK, unsafe { gifski_finish(g) });
+}
+
+#[test]
+fn c_progress2() {
+    let g = unsafe { gifski_new(&GifskiSettings {
+        width: 1, height: 1,
+        quality: 100,
+        fast: true,
+        repeat: -1,
+    })};
+    assert!(!g.is_null());
+    unsafe extern "C" fn cb(_s: usize, _buf: *const u8, _user: *mut c_void) -> c_int {
+        0
+    }
+    let mut last = (0u32, 0f64, 0u64);
+    let mut total_size = 0u64;
+    unsafe extern "C" fn scb(stats: *const FrameStats, user_data: *mut c_void) {
+        assert!((*stats).palette_size > 0);
+        *(user_data as *mut u64) += (*stats).compressed_size;
+    }
+    unsafe extern "C" fn pcb(frame_number: u32, pts: f64, bytes_written: u64, user_data: *mut c_void) -> c_int {
+        let last = user_data as *mut (u32, f64, u64);
+        assert!(frame_number > (*last).0);
+        *last = (frame_number, pts, bytes_written);
+        1
+    }
+    unsafe {
+        assert_eq!(GifskiError::OK, gifski_set_progress_callback2(g, pcb, (&mut last) as *mut _ as _));
+        assert_eq!(GifskiError::OK, gifski_set_frame_stats_callback(g, scb, (&mut total_size) as *mut _ as _));
+        assert_eq!(GifskiError::OK, gifski_set_write_callback(g, Some(cb), ptr::null_mut()));
+        let pixels = Box::into_raw(Box::new(RGBA8::new(0, 0, 0, 255)));
+        unsafe extern "C" fn free_pixels(pixels: *mut c_void) {
+            drop(Box::from_raw(pixels as *mut RGBA8));
+        }
+        assert_eq!(GifskiError::OK, gifski_add_frame_rgba_nocopy(g, 0, 1, 1, 4, pixels, 0., Some(free_pixels), pixels as *mut c_void));
+        assert_eq!(GifskiError::OK, gifski_add_frame_rgba_with_importance(g, 1, 1, 1, &RGBA8::new(255, 0, 0, 255), &255, 0.5));
+        assert_eq!(GifskiError::OK, gifski_finish(g));
+    }
+    assert_eq!(2, last.0);
+    assert_eq!(0.5, last.1);
+    assert!(last.2 > 0);
+    assert_eq!(last.2, total_size);
+}
+
+#[test]
+fn c_error_callback() {
+    let g = unsafe { gifski_new(&GifskiSettings {
+        width: 1, height: 1,
+        quality: 100,
+        fast: true,
+        repeat: -1,
+    })};
+    assert!(!g.is_null());
+    let mut last = (GifskiError::OK, 0i64);
+    unsafe extern "C" fn ecb(code: GifskiError, frame_number: i64, msg: *const c_char, user_data: *mut c_void) {
+        assert!(!CStr::from_ptr(msg).to_bytes().is_empty());
+        *(user_data as *mut (GifskiError, i64)) = (code, frame_number);
+    }
+    unsafe extern "C" fn cb(_s: usize, _buf: *const u8, _user: *mut c_void) -> c_int {
+        0
+    }
+    unsafe {
+        assert_eq!(GifskiError::OK, gifski_set_error_callback(g, ecb, (&mut last) as *mut _ as _));
+        assert_eq!(GifskiError::OK, gifski_set_write_callback(g, Some(cb), ptr::null_mut()));
+        assert_eq!(GifskiError::INVALID_STATE, gifski_set_max_colors(g, 16));
+        assert_eq!((GifskiError::INVALID_STATE, -1), last);
+        let name = CString::new("/non-existent/frame.png").unwrap();
+        assert_eq!(GifskiError::OTHER, gifski_add_frame_png_file(g, 7, name.as_ptr(), 0.));
+        assert_eq!((GifskiError::OTHER, 7), last);
+        assert_eq!(GifskiError::OK, gifski_add_frame_rgb(g, 0, 1, 3, 1, &RGB::new(0, 0, 0), 0.));
+        gifski_finish(g);
+    }
+}
+
+#[test]
+fn c_error_callback_can_call_gifski() {
+    let g = unsafe { gifski_new(&GifskiSettings {
+        width: 1, height: 1,
+        quality: 100,
+        fast: true,
+        repeat: -1,
+    })};
+    assert!(!g.is_null());
+    unsafe extern "C" fn quiet(_code: GifskiError, _frame_number: i64, _msg: *const c_char, _user_data: *mut c_void) {}
+    unsafe extern "C" fn replace_self(_code: GifskiError, _frame_number: i64, _msg: *const c_char, handle: *mut c_void) {
+        assert_eq!(GifskiError::OK, gifski_set_error_callback(handle as *const GifskiHandle, quiet, ptr::null_mut()));
+    }
+    unsafe extern "C" fn cb(_s: usize, _buf: *const u8, _user: *mut c_void) -> c_int {
+        0
+    }
+    unsafe {
+        assert_eq!(GifskiError::OK, gifski_set_error_callback(g, replace_self, g as *mut c_void));
+        assert_eq!(GifskiError::OK, gifski_set_write_callback(g, Some(cb), ptr::null_mut()));
+        assert_eq!(GifskiError::INVALID_STATE, gifski_set_max_colors(g, 16));
+        assert_eq!(GifskiError::INVALID_STATE, gifski_set_max_colors(g, 16));
+        assert_eq!(GifskiError::OK, gifski_add_frame_rgb(g, 0, 1, 3, 1, &RGB::new(0, 0, 0), 0.));
+        gifski_finish(g);
+    }
+}
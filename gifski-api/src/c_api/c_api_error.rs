@@ -80,7 +80,8 @@ impl From<CatResult<()>> for GifskiError {
                 Quant(_) => GifskiError::QUANT,
                 Pal(_) => GifskiError::GIF,
                 ThreadSend => GifskiError::THREAD_LOST,
-                Io(ref err) => err.kind().into(),
+                InvalidInput(_) => GifskiError::INVALID_INPUT,
+                Io(ref err) | TempFile(ref err) => err.kind().into(),
                 _ => GifskiError::OTHER,
             },
         }
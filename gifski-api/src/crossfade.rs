@@ -0,0 +1,107 @@
+use crate::error::*;
+use crate::DecodedFrame;
+use imgref::*;
+use rgb::*;
+use std::collections::VecDeque;
+
+/// Hides the jump when the animation loops, by fading its end into its beginning.
+///
+/// Frames from the first `duration` seconds are held back, and blended into as many frames at the end.
+/// The animation then starts where the held back frames ended, so it gets shorter by `duration`.
+pub(crate) struct LoopCrossfade<I> {
+    inputs: I,
+    duration: f64,
+    /// The first frames, played only blended into the end
+    head: VecDeque<DecodedFrame>,
+    /// The last frames seen so far, at most as many as in `head`
+    tail: VecDeque<DecodedFrame>,
+    /// Subtracted from timestamps of frames after `head`, so they start where `head` did
+    pts_shift: Option<f64>,
+    /// Number of frames being blended, set when the end is reached
+    fade_frames: Option<usize>,
+}
+
+impl<I> LoopCrossfade<I> {
+    pub fn new(inputs: I, duration: f64) -> Self {
+        Self { inputs, duration, head: VecDeque::new(), tail: VecDeque::new(), pts_shift: None, fade_frames: None }
+    }
+
+    /// Called once, after the last frame
+    fn start_fading(&mut self) -> usize {
+        if self.tail.is_empty() {
+            // the input is too short to loop into itself
+            self.tail = std::mem::take(&mut self.head);
+        } else {
+            // the frames right before the loop point are the ones worth blending
+            let unused = self.head.len() - self.tail.len();
+            self.head.drain(..unused);
+        }
+        self.head.len()
+    }
+}
+
+impl<I: Iterator<Item = CatResult<DecodedFrame>>> Iterator for LoopCrossfade<I> {
+    type Item = CatResult<DecodedFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.fade_frames.is_none() {
+            match self.inputs.next() {
+                Some(Ok(frame)) => {
+                    let in_head = self.tail.is_empty() && self.head.front().map_or(true, |first| frame.1 - first.1 < self.duration);
+                    if in_head {
+                        self.head.push_back(frame);
+                        continue;
+                    }
+                    let first_pts = self.head[0].1;
+                    let shift = *self.pts_shift.get_or_insert(frame.1 - first_pts);
+                    let (image, pts, importance_map) = frame;
+                    self.tail.push_back((image, pts - shift, importance_map));
+                    if self.tail.len() > self.head.len() {
+                        return self.tail.pop_front().map(Ok);
+                    }
+                },
+                Some(Err(err)) => return Some(Err(err)),
+                None => self.fade_frames = Some(self.start_fading()),
+            }
+        }
+
+        let (image, pts, importance_map) = self.tail.pop_front()?;
+        let (head_image, _, head_importance_map) = match self.head.pop_front() {
+            Some(head) => head,
+            None => return Some(Ok((image, pts, importance_map))),
+        };
+        if head_image.width() != image.width() || head_image.height() != image.height() {
+            return Some(Err(Error::WrongSize(format!("Can't crossfade frames of different sizes ({}×{} and {}×{})",
+                image.width(), image.height(), head_image.width(), head_image.height()))));
+        }
+        // the first blended frame is mostly the end, the last one mostly the beginning
+        let fade_frames = self.fade_frames.unwrap_or_default();
+        let weight = (fade_frames - self.head.len()) as f32 / (fade_frames + 1) as f32;
+        let mix = |end: u8, start: u8| (f32::from(end) * (1. - weight) + f32::from(start) * weight).round() as u8;
+        let pixels = image.pixels().zip(head_image.pixels())
+            .map(|(end, start)| RGBA8::new(mix(end.r, start.r), mix(end.g, start.g), mix(end.b, start.b), mix(end.a, start.a)))
+            .collect();
+        let importance_map = match (importance_map, head_importance_map) {
+            (Some(end), Some(start)) => Some(ImgVec::new(end.pixels().zip(start.pixels()).map(|(a, b)| a.max(b)).collect(), end.width(), end.height())),
+            (end, start) => end.or(start),
+        };
+        Some(Ok((ImgVec::new(pixels, image.width(), image.height()), pts, importance_map)))
+    }
+}
+
+#[test]
+fn fades_end_into_start() {
+    let frames = (0..8u8).map(|n| Ok((ImgVec::new(vec![RGBA8::new(n * 10, 0, 0, 255)], 1, 1), f64::from(n) / 10., None)));
+    let out: Vec<_> = LoopCrossfade::new(frames, 0.2).map(|frame| frame.unwrap()).collect();
+    let reds: Vec<_> = out.iter().map(|(image, ..)| image.buf()[0].r).collect();
+    assert_eq!(reds, [20, 30, 40, 50, 40, 30]);
+    assert!(out[0].1.abs() < 1e-6);
+    assert!((out[5].1 - 0.5).abs() < 1e-6);
+}
+
+#[test]
+fn too_short_to_fade() {
+    let frames = (0..3u8).map(|n| Ok((ImgVec::new(vec![RGBA8::new(n, 0, 0, 255)], 1, 1), f64::from(n), None)));
+    let out: Vec<_> = LoopCrossfade::new(frames, 10.).map(|frame| frame.unwrap().0.buf()[0].r).collect();
+    assert_eq!(out, [0, 1, 2]);
+}
@@ -0,0 +1,82 @@
+use imgref::*;
+use rgb::*;
+
+/// How far from each pixel to look for the other side of a band
+const RADIUS: u32 = 12;
+/// Channels differing by less than this are assumed to be a band in a gradient, rather than an edge
+const THRESHOLD: u8 = 4;
+
+/// Smooths out steps in gradients, such as dark backgrounds in screen recordings,
+/// which palettes would otherwise turn into even more distinct bands.
+///
+/// Edges and details are kept: pixels change only when all of their surroundings are within the threshold.
+pub(crate) fn deband(image: &mut ImgVec<RGBA8>) {
+    let (width, height) = (image.width(), image.height());
+    if width < 2 || height < 2 {
+        return;
+    }
+    let src = image.clone();
+    let sample = |x: usize, y: usize, dx: isize, dy: isize| -> RGBA8 {
+        let x = (x as isize + dx).max(0).min(width as isize - 1) as usize;
+        let y = (y as isize + dy).max(0).min(height as isize - 1) as usize;
+        src[(x, y)]
+    };
+
+    for (y, row) in image.rows_mut().enumerate() {
+        for (x, px) in row.iter_mut().enumerate() {
+            if px.a < 255 {
+                continue;
+            }
+            let h = hash(x as u32, y as u32);
+            let dx = (h % (RADIUS + 1)) as isize;
+            let dy = ((h >> 8) % (RADIUS + 1)) as isize;
+            let samples = [sample(x, y, dx, dy), sample(x, y, -dx, -dy), sample(x, y, -dy, dx), sample(x, y, dy, -dx)];
+            let close = |a: u8, b: u8| a.max(b) - a.min(b) < THRESHOLD;
+            if !samples.iter().all(|s| s.a == 255 && close(s.r, px.r) && close(s.g, px.g) && close(s.b, px.b)) {
+                continue;
+            }
+            // noise instead of rounding, so that the average doesn't form new bands
+            let noise = ((h >> 16) & 0xFF) as f32 / 256.;
+            let average = |channel: fn(&RGBA8) -> u8| {
+                let sum: u32 = samples.iter().map(channel).map(u32::from).sum();
+                (sum as f32 / 4. + noise).min(255.) as u8
+            };
+            *px = RGBA8::new(average(|s| s.r), average(|s| s.g), average(|s| s.b), 255);
+        }
+    }
+}
+
+/// Cheap pseudo-random number, so that the output doesn't vary between runs
+fn hash(x: u32, y: u32) -> u32 {
+    let mut h = x.wrapping_mul(0x9E37_79B1) ^ y.wrapping_mul(0x85EB_CA77);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2C1B_3C6D);
+    h ^= h >> 12;
+    h
+}
+
+#[test]
+fn smooths_bands_keeps_edges() {
+    // a dark gradient in steps of 2, next to a sharp edge
+    let pixels = (0..32).flat_map(|_| (0..128).map(|x| {
+        let v = if x < 96 { (x / 16 * 2) as u8 } else { 200 };
+        RGBA8::new(v, v, v, 255)
+    })).collect();
+    let mut image = ImgVec::new(pixels, 128, 32);
+    let original = image.clone();
+    deband(&mut image);
+
+    let levels = |img: &ImgVec<RGBA8>| {
+        let mut levels: Vec<_> = img.pixels().filter(|px| px.r < 100).map(|px| px.r).collect();
+        levels.sort_unstable();
+        levels.dedup();
+        levels.len()
+    };
+    assert!(levels(&image) > levels(&original), "{} vs {}", levels(&image), levels(&original));
+    for (px, orig) in image.pixels().zip(original.pixels()) {
+        assert!(px.r.max(orig.r) - px.r.min(orig.r) < THRESHOLD);
+        if orig.r == 200 {
+            assert_eq!(200, px.r);
+        }
+    }
+}
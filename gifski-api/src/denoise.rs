@@ -1,5 +1,6 @@
 pub use imgref::ImgRef;
 use imgref::ImgVec;
+use crate::pool::Pools;
 use rgb::ComponentMap;
 use rgb::RGB8;
 pub use rgb::RGBA8;
@@ -65,11 +66,31 @@ pub struct Denoiser<T> {
     splat: ImgVec<Acc>,
     processed: Vec<(ImgVec<RGBA8>, ImgVec<u8>)>,
     metadatas: Vec<T>,
+    /// Size of the frames, when the denoiser works on frames of half their width and height
+    full_size: Option<(usize, usize)>,
+    /// Full-size frames, which are output instead of the denoised half-size ones
+    originals: Vec<ImgVec<RGBA8>>,
+    /// Output buffers are taken from here
+    pools: Pools,
+}
+
+/// Bytes the denoiser uses for frames of this size
+pub fn denoiser_memory_size(width: usize, height: usize, half_size: bool) -> usize {
+    let per_pixel = std::mem::size_of::<Acc>() + LOOKAHEAD * (std::mem::size_of::<RGBA8>() + 1);
+    if half_size {
+        half(width) * half(height) * per_pixel + width * height * LOOKAHEAD * std::mem::size_of::<RGBA8>()
+    } else {
+        width * height * per_pixel
+    }
+}
+
+fn half(size: usize) -> usize {
+    (size + 1) / 2
 }
 
 impl<T> Denoiser<T> {
     #[inline]
-    pub fn new(width: usize, height: usize, quality: u8) -> Self {
+    pub fn new(width: usize, height: usize, quality: u8, pools: Pools) -> Self {
         let area = width.checked_mul(height).unwrap();
         let clear = Acc {
             r: Default::default(),
@@ -86,7 +107,48 @@ impl<T> Denoiser<T> {
             metadatas: Vec::with_capacity(4),
             threshold: (55 - u32::from(quality) / 2).pow(2),
             splat: ImgVec::new(vec![clear; area], width, height),
+            full_size: None,
+            originals: Vec::new(),
+            pools,
+        }
+    }
+
+    /// Finds changed pixels on frames scaled down to half the width and height, which takes a quarter of the memory and time.
+    /// Frames come out at full size, but not denoised. Each pixel of the smaller importance map covers 2×2 pixels.
+    pub fn new_half_size(width: usize, height: usize, quality: u8, pools: Pools) -> Self {
+        Self {
+            full_size: Some((width, height)),
+            originals: Vec::with_capacity(4),
+            ..Self::new(half(width), half(height), quality, pools)
+        }
+    }
+
+    /// Averages 2×2 blocks. The last row or column is used twice for odd sizes.
+    fn half_size(&self, frame: ImgRef<RGBA8>) -> ImgVec<RGBA8> {
+        let (width, height) = (self.splat.width(), self.splat.height());
+        let (last_x, last_y) = (frame.width() - 1, frame.height() - 1);
+        let mut out = self.pools.rgba.take(width * height);
+        for y in 0..height {
+            let (y0, y1) = (y * 2, (y * 2 + 1).min(last_y));
+            out.extend((0..width).map(|x| {
+                let (x0, x1) = (x * 2, (x * 2 + 1).min(last_x));
+                let block = [frame[(x0, y0)], frame[(x1, y0)], frame[(x0, y1)], frame[(x1, y1)]];
+                let sum = block.iter().fold([2u16; 4], |sum, px| {
+                    [sum[0] + u16::from(px.r), sum[1] + u16::from(px.g), sum[2] + u16::from(px.b), sum[3] + u16::from(px.a)]
+                });
+                RGBA8::new((sum[0] / 4) as u8, (sum[1] / 4) as u8, (sum[2] / 4) as u8, (sum[3] / 4) as u8)
+            }));
+        }
+        ImgVec::new(out, width, height)
+    }
+
+    /// Each pixel of the half-size importance map becomes 2×2 pixels
+    fn full_size_importance_map(&self, importance_map: ImgRef<u8>, (width, height): (usize, usize)) -> ImgVec<u8> {
+        let mut out = self.pools.bytes.take(width * height);
+        for y in 0..height {
+            out.extend((0..width).map(|x| importance_map[(x / 2, y / 2)]));
         }
+        ImgVec::new(out, width, height)
     }
 
     fn quick_append(&mut self, frame: ImgRef<RGBA8>) {
@@ -98,8 +160,8 @@ impl<T> Denoiser<T> {
     /// Generate last few frames
     pub fn flush(&mut self) {
         while self.processed.len() < self.metadatas.len() {
-            let mut median1 = Vec::with_capacity(self.splat.width() * self.splat.height());
-            let mut imp_map1 = Vec::with_capacity(self.splat.width() * self.splat.height());
+            let mut median1 = self.pools.rgba.take(self.splat.width() * self.splat.height());
+            let mut imp_map1 = self.pools.bytes.take(self.splat.width() * self.splat.height());
 
             for acc in self.splat.pixels_mut() {
                 acc.append(RGBA8::new(0, 0, 0, 0));
@@ -120,6 +182,21 @@ impl<T> Denoiser<T> {
     }
 
     pub fn push_frame(&mut self, frame: ImgRef<RGBA8>, frame_metadata: T) {
+        if let Some((width, height)) = self.full_size {
+            assert_eq!((width, height), (frame.width(), frame.height()));
+            let mut original = self.pools.rgba.take(width * height);
+            original.extend(frame.pixels());
+            self.originals.insert(0, ImgVec::new(original, width, height));
+
+            let half = self.half_size(frame);
+            self.denoise_frame(half.as_ref(), frame_metadata);
+            self.pools.rgba.give(half.into_buf());
+        } else {
+            self.denoise_frame(frame, frame_metadata);
+        }
+    }
+
+    fn denoise_frame(&mut self, frame: ImgRef<RGBA8>, frame_metadata: T) {
         assert_eq!(frame.width(), self.splat.width());
         assert_eq!(frame.height(), self.splat.height());
 
@@ -132,8 +209,8 @@ impl<T> Denoiser<T> {
             return;
         }
 
-        let mut median = Vec::with_capacity(frame.width() * frame.height());
-        let mut imp_map = Vec::with_capacity(frame.width() * frame.height());
+        let mut median = self.pools.rgba.take(frame.width() * frame.height());
+        let mut imp_map = self.pools.bytes.take(frame.width() * frame.height());
         for (acc, src) in self.splat.pixels_mut().zip(frame.pixels()) {
             acc.append(src);
 
@@ -150,6 +227,13 @@ impl<T> Denoiser<T> {
     pub fn pop(&mut self) -> Denoised<T> {
         if let Some((frame, importance_map)) = self.processed.pop() {
             let meta = self.metadatas.pop().expect("meta");
+            if let Some(full_size) = self.full_size {
+                let full_importance_map = self.full_size_importance_map(importance_map.as_ref(), full_size);
+                self.pools.rgba.give(frame.into_buf());
+                self.pools.bytes.give(importance_map.into_buf());
+                let frame = self.originals.pop().expect("original");
+                return Denoised::Frame { frame, importance_map: full_importance_map, meta };
+            }
             Denoised::Frame { frame, importance_map, meta }
         } else if !self.metadatas.is_empty() {
             Denoised::NotYet
@@ -294,7 +378,7 @@ fn px<T>(f: Denoised<T>) -> (RGBA8, T) {
 
 #[test]
 fn one() {
-    let mut d = Denoiser::new(1,1, 100);
+    let mut d = Denoiser::new(1,1, 100, Pools::new());
     let w = RGBA8::new(255,255,255,255);
     d.push_frame(ImgVec::new(vec![w], 1, 1).as_ref(), 0);
     assert!(matches!(d.pop(), Denoised::NotYet));
@@ -305,7 +389,7 @@ fn one() {
 
 #[test]
 fn two() {
-    let mut d = Denoiser::new(1,1, 100);
+    let mut d = Denoiser::new(1,1, 100, Pools::new());
     let w = RGBA8::new(254,253,252,255);
     let b = RGBA8::new(8,7,0,255);
     d.push_frame(ImgVec::new(vec![w], 1, 1).as_ref(), 0);
@@ -319,7 +403,7 @@ fn two() {
 
 #[test]
 fn three() {
-    let mut d = Denoiser::new(1,1, 100);
+    let mut d = Denoiser::new(1,1, 100, Pools::new());
     let w = RGBA8::new(254,253,252,255);
     let b = RGBA8::new(8,7,0,255);
     d.push_frame(ImgVec::new(vec![w], 1, 1).as_ref(), 0);
@@ -336,7 +420,7 @@ fn three() {
 
 #[test]
 fn four() {
-    let mut d = Denoiser::new(1,1, 100);
+    let mut d = Denoiser::new(1,1, 100, Pools::new());
     let w = RGBA8::new(254,253,252,255);
     let b = RGBA8::new(8,7,0,255);
     let t = RGBA8::new(0,0,0,0);
@@ -355,7 +439,7 @@ fn four() {
 
 #[test]
 fn five() {
-    let mut d = Denoiser::new(1,1, 100);
+    let mut d = Denoiser::new(1,1, 100, Pools::new());
     let w = RGBA8::new(254,253,252,255);
     let b = RGBA8::new(8,7,0,255);
     let t = RGBA8::new(0,0,0,0);
@@ -376,7 +460,7 @@ fn five() {
 
 #[test]
 fn six() {
-    let mut d = Denoiser::new(1,1, 100);
+    let mut d = Denoiser::new(1,1, 100, Pools::new());
     let w = RGBA8::new(254,253,252,255);
     let b = RGBA8::new(8,7,0,255);
     let t = RGBA8::new(0,0,0,0);
@@ -404,7 +488,7 @@ fn six() {
 
 #[test]
 fn many() {
-    let mut d = Denoiser::new(1,1, 100);
+    let mut d = Denoiser::new(1,1, 100, Pools::new());
     let w = RGBA8::new(255,254,253,255);
     let b = RGBA8::new(1,2,3,255);
     let t = RGBA8::new(0,0,0,0);
@@ -429,3 +513,26 @@ fn many() {
     assert_eq!(px(d.pop()), (b, "b6"));
     assert!(matches!(d.pop(), Denoised::Done));
 }
+
+#[test]
+fn half_size() {
+    let mut d = Denoiser::new_half_size(3, 3, 100, Pools::new());
+    let w = RGBA8::new(254,253,252,255);
+    let b = RGBA8::new(8,7,0,255);
+    let frame0 = ImgVec::new(vec![w; 9], 3, 3);
+    let mut frame1 = frame0.clone();
+    frame1[(2_usize, 1_usize)] = b;
+    d.push_frame(frame0.as_ref(), 0);
+    d.push_frame(frame1.as_ref(), 1);
+    d.flush();
+    assert!(matches!(d.pop(), Denoised::Frame { .. }));
+    if let Denoised::Frame { frame, importance_map, meta } = d.pop() {
+        assert_eq!(1, meta);
+        assert_eq!(frame1, frame);
+        assert_eq!((3, 3), (importance_map.width(), importance_map.height()));
+        // the changed pixel is in the top right block of the half-size frame
+        let changed: Vec<_> = importance_map.pixels().map(|imp| imp > 0).collect();
+        assert_eq!(vec![false, false, true, false, false, true, false, false, false], changed);
+    } else { panic!("no frame") }
+    assert!(matches!(d.pop(), Denoised::Done));
+}
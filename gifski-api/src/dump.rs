@@ -0,0 +1,39 @@
+//! Diagnostic output of the denoiser's decisions.
+//!
+//! Build with `--features=dump` and set `GIFSKI_DUMP_DIR` to an existing directory.
+//! For every frame it will write `NNNNN-importance.png` (grayscale, brighter pixels get more palette weight,
+//! black pixels are left to the background) and `NNNNN-denoised.png` (the frame as the denoiser chose to show it).
+use crate::error::*;
+use imgref::*;
+use rgb::*;
+use std::path::{Path, PathBuf};
+
+pub(crate) struct Dumper {
+    dir: PathBuf,
+}
+
+impl Dumper {
+    /// Dumping is enabled only if `GIFSKI_DUMP_DIR` is set
+    pub fn from_env() -> Option<Self> {
+        let dir = std::env::var_os("GIFSKI_DUMP_DIR")?;
+        Some(Self { dir: dir.into() })
+    }
+
+    pub fn dump(&self, ordinal_frame_number: usize, importance_map: ImgRef<'_, u8>, frame: ImgRef<'_, RGBA8>) -> CatResult<()> {
+        let path = self.dir.join(format!("{:05}-importance.png", ordinal_frame_number));
+        let (buf, width, height) = importance_map.to_contiguous_buf();
+        lodepng::encode_file(&path, &buf, width, height, lodepng::ColorType::GREY, 8)
+            .map_err(|err| write_error(&path, err))?;
+
+        let path = self.dir.join(format!("{:05}-denoised.png", ordinal_frame_number));
+        let (buf, width, height) = frame.to_contiguous_buf();
+        lodepng::encode32_file(&path, &buf, width, height)
+            .map_err(|err| write_error(&path, err))?;
+        Ok(())
+    }
+}
+
+#[cold]
+fn write_error(path: &Path, err: lodepng::Error) -> Error {
+    Error::PNG(format!("Can't write {}: {}", path.display(), err))
+}
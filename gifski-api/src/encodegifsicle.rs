@@ -3,24 +3,121 @@ use crate::GIFFrame;
 use crate::Settings;
 use crate::{Encoder, Repeat};
 use gifsicle::*;
+use imgref::*;
+use rgb::*;
 use std::io::Write;
 use std::ptr;
+use std::thread;
 
-pub(crate) struct Gifsicle<'w> {
+/// Large frames are compressed in horizontal strips of at least this many pixels each.
+/// Each strip starts with a clear code, which costs a little compression.
+const MIN_STRIP_PIXELS: usize = 1 << 18;
+/// The number of strips depends only on the frame size, so that the GIF doesn't depend on the number of threads
+const MAX_STRIPS: usize = 8;
+/// `GIF_WRITE_CAREFUL_MIN_CODE_SIZE` from gif.h
+const GIF_WRITE_CAREFUL_MIN_CODE_SIZE: std::os::raw::c_int = 1;
+
+/// An already-quantized frame for `GifsicleWriter`
+pub struct IndexedFrame {
+    /// Position of the frame on the screen
+    pub left: u16,
+    pub top: u16,
+    /// Indices into the `palette`
+    pub image: ImgVec<u8>,
+    /// At most 256 colors
+    pub palette: Vec<RGBA8>,
+    pub transparent_index: Option<u8>,
+    pub dispose: gif::DisposalMethod,
+    /// In 1/100th of a second
+    pub delay: u16,
+}
+
+/// Lossy LZW writer for frames that already have their palettes
+///
+/// It doesn't remap or requantize anything. It only makes LZW compression
+/// treat runs of similar colors as identical, which shortens the compressed stream.
+pub struct GifsicleWriter<'w> {
+    inner: Gifsicle<&'w mut dyn Write>,
+    settings: Settings,
+    screen_width: u16,
+    screen_height: u16,
+    loss: u32,
+}
+
+impl<'w> GifsicleWriter<'w> {
+    /// `loss` is the same as gifsicle's `--lossy` value. 0 is lossless, 20-80 is typical, 200 is very lossy.
+    pub fn new(out: &'w mut dyn Write, screen_width: u16, screen_height: u16, repeat: Repeat, loss: u32) -> Self {
+        Self {
+            inner: Gifsicle::new(loss, out),
+            settings: Settings { repeat, ..Settings::default() },
+            screen_width,
+            screen_height,
+            loss,
+        }
+    }
+
+    /// Change loss for the following frames
+    pub fn set_loss(&mut self, loss: u32) {
+        self.loss = loss;
+    }
+
+    pub fn write_frame(&mut self, frame: IndexedFrame) -> CatResult<()> {
+        let IndexedFrame { left, top, image, palette, transparent_index, dispose, delay } = frame;
+
+        if image.width() == 0 || image.height() == 0 ||
+            left as usize + image.width() > self.screen_width as usize ||
+            top as usize + image.height() > self.screen_height as usize {
+            return Err(Error::WrongSize(format!("Frame {}×{} at {},{} doesn't fit in {}×{} screen",
+                image.width(), image.height(), left, top, self.screen_width, self.screen_height)));
+        }
+        if palette.is_empty() || palette.len() > 256 ||
+            transparent_index.map_or(false, |idx| idx as usize >= palette.len()) ||
+            image.pixels().any(|px| px as usize >= palette.len()) {
+            return Err(Error::WrongSize("Palette doesn't cover all of the frame's indices".into()));
+        }
+
+        // gifsicle needs a contiguous buffer
+        let (buf, width, height) = image.into_contiguous_buf();
+        self.inner.write_frame(&GIFFrame {
+            left,
+            top,
+            screen_width: self.screen_width,
+            screen_height: self.screen_height,
+            image: ImgVec::new(buf, width, height),
+            pal: palette,
+            dispose,
+            transparent_index,
+            loss: self.loss,
+        }, delay, &self.settings)
+    }
+
+    /// Writes the end of the file. Remember to call it, because dropping the writer doesn't do that.
+    pub fn finish(mut self) -> CatResult<()> {
+        self.inner.finish()
+    }
+}
+
+pub(crate) struct Gifsicle<W: Write> {
     gfs: *mut Gif_Stream,
     gif_writer: *mut Gif_Writer,
-    out: &'w mut dyn Write,
+    out: W,
     info: Gif_CompressInfo,
+    /// Compress strips of large frames on threads of their own
+    parallel_strips: bool,
 }
 
-impl<'w> Gifsicle<'w> {
-    pub fn new(loss: u32, out: &'w mut (dyn std::io::Write + 'w)) -> Self {
+// gifsicle's stream and writer are used only through this struct
+unsafe impl<W: Write + Send> Send for Gifsicle<W> {}
+
+impl<W: Write> Gifsicle<W> {
+    pub fn new(loss: u32, out: W) -> Self {
         unsafe {
             let mut g = Self {
                 gfs: ptr::null_mut(),
                 gif_writer: ptr::null_mut(),
                 info: std::mem::zeroed(),
                 out,
+                parallel_strips: !cfg!(feature = "threadless"),
             };
             Gif_InitCompressInfo(&mut g.info);
             g.info.loss = loss as _;
@@ -28,6 +125,12 @@ impl<'w> Gifsicle<'w> {
         }
     }
 
+    /// For callers that can't spawn threads. The output is the same.
+    pub(crate) fn without_threads(mut self) -> Self {
+        self.parallel_strips = false;
+        self
+    }
+
     fn flush_writer(&mut self) -> CatResult<()> {
         unsafe {
             if (*self.gif_writer).pos > 0 {
@@ -41,7 +144,275 @@ impl<'w> Gifsicle<'w> {
     }
 }
 
-impl Drop for Gifsicle<'_> {
+/// Loss of the frame, lowered until it meets `Settings::lossy_min_psnr`
+fn frame_loss(frame: &GIFFrame, settings: &Settings) -> CatResult<u32> {
+    let mut loss = frame.loss;
+    if let Some(min_psnr) = settings.lossy_min_psnr {
+        while loss > 0 && lossy_psnr(frame.image.as_ref(), &frame.pal, frame.transparent_index, loss)? < min_psnr.into() {
+            loss /= 2;
+        }
+    }
+    Ok(loss)
+}
+
+/// Compresses the frame on its own, and decodes it back
+fn lossy_decoded(image: ImgRef<'_, u8>, pal: &[RGBA8], transparent_index: Option<u8>, loss: u32) -> CatResult<Vec<u8>> {
+    let mut gif = Vec::new();
+    let mut g = Gifsicle::new(loss, &mut gif).without_threads();
+    let (buf, width, height) = image.to_contiguous_buf();
+    g.write_frame(&GIFFrame {
+        left: 0,
+        top: 0,
+        screen_width: width as u16,
+        screen_height: height as u16,
+        image: ImgVec::new(buf.into_owned(), width, height),
+        pal: pal.to_vec(),
+        dispose: gif::DisposalMethod::Keep,
+        transparent_index,
+        loss,
+    }, 1, &Settings::default())?;
+    g.finish()?;
+    drop(g);
+
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(&gif[..])?;
+    let decoded = decoder.read_next_frame()?.ok_or(Error::Gifsicle)?;
+    Ok(decoded.buffer.to_vec())
+}
+
+/// Measures how much the lossy compression changes the frame
+fn lossy_psnr(image: ImgRef<'_, u8>, pal: &[RGBA8], transparent_index: Option<u8>, loss: u32) -> CatResult<f64> {
+    let decoded = lossy_decoded(image, pal, transparent_index, loss)?;
+    let (width, height) = (image.width(), image.height());
+    let transparent = RGBA8::new(0, 0, 0, 0);
+    let color = |idx: u8| if Some(idx) == transparent_index { transparent } else { pal.get(idx as usize).copied().unwrap_or(transparent) };
+    let sum_sq: u64 = image.pixels().zip(decoded.iter().copied())
+        .map(|(orig, lossy)| {
+            let (a, b) = (color(orig), color(lossy));
+            [a.r, a.g, a.b, a.a].iter().zip([b.r, b.g, b.b, b.a].iter())
+                .map(|(&a, &b)| (i32::from(a) - i32::from(b)).pow(2) as u64)
+                .sum::<u64>()
+        })
+        .sum();
+    if sum_sq == 0 {
+        return Ok(f64::INFINITY);
+    }
+    let mse = sum_sq as f64 / (4 * width * height) as f64;
+    Ok(10. * (255. * 255. / mse).log10())
+}
+
+fn new_colormap(pal: &[RGBA8]) -> *mut Gif_Colormap {
+    unsafe {
+        let colormap = Gif_NewFullColormap(0, pal.len() as _);
+        for c in pal {
+            Gif_AddColor(colormap, &mut Gif_Color {
+                gfc_red: c.r,
+                gfc_green: c.g,
+                gfc_blue: c.b,
+
+                haspixel: 0, // dunno?
+                pixel: 0,
+            }, -1);
+        }
+        colormap
+    }
+}
+
+fn strip_count(width: usize, height: usize) -> usize {
+    (width * height / MIN_STRIP_PIXELS).min(MAX_STRIPS).min(height).max(1)
+}
+
+/// Compressed image data (minimum code size followed by sub-blocks) of a frame split into strips,
+/// which are compressed in parallel and joined.
+/// Rows must be already in the order they're stored in, so interlaced images are reordered first.
+fn compress_in_strips(image: ImgRef<'_, u8>, pal: &[RGBA8], transparent_index: Option<u8>, loss: u32, strips: usize, parallel: bool) -> CatResult<Vec<u8>> {
+    let (width, height) = (image.width(), image.height());
+    let rows = (height + strips - 1) / strips;
+    let mut strips = (0..height).step_by(rows).map(|top| image.sub_image(0, top, width, rows.min(height - top)));
+    let parts = if parallel {
+        let first = strips.next().ok_or(Error::Gifsicle)?;
+        let threads = strips.map(|strip| {
+            let (buf, width, height) = strip.to_contiguous_buf();
+            let strip = ImgVec::new(buf.into_owned(), width, height);
+            let pal = pal.to_vec();
+            thread::Builder::new().name("lzw".into()).spawn(move || compress_strip(strip.as_ref(), &pal, transparent_index, loss))
+        }).collect::<Result<Vec<_>, _>>()?;
+        let mut parts = vec![compress_strip(first, pal, transparent_index, loss)?];
+        for thread in threads {
+            parts.push(thread.join().map_err(|_| Error::ThreadSend)??);
+        }
+        parts
+    } else {
+        strips.map(|strip| compress_strip(strip, pal, transparent_index, loss)).collect::<CatResult<Vec<_>>>()?
+    };
+    join_lzw(&parts)
+}
+
+/// Compresses a strip as a standalone image. Minimum code size is based on the palette, so that it's the same for all strips.
+fn compress_strip(image: ImgRef<'_, u8>, pal: &[RGBA8], transparent_index: Option<u8>, loss: u32) -> CatResult<Vec<u8>> {
+    let (buf, width, height) = image.to_contiguous_buf();
+    unsafe {
+        let gfs = Gif_NewStream();
+        if gfs.is_null() {
+            return Err(Error::Gifsicle);
+        }
+        let g = match Gif_NewImage().as_mut() {
+            Some(g) => g,
+            None => {
+                Gif_DeleteStream(gfs);
+                return Err(Error::Gifsicle);
+            },
+        };
+        g.width = width as u16;
+        g.height = height as u16;
+        g.transparent = transparent_index.map(|i| i as _).unwrap_or(-1);
+        g.local = new_colormap(pal); // it's owned by the image
+        let mut info = std::mem::zeroed();
+        Gif_InitCompressInfo(&mut info);
+        info.loss = loss as _;
+        info.flags = GIF_WRITE_CAREFUL_MIN_CODE_SIZE;
+        let res = if 0 != Gif_SetUncompressedImage(g, buf.as_ptr() as *mut u8, None, 0) &&
+            0 != Gif_FullCompressImage(gfs, g, &info) && !g.compressed.is_null() {
+            Ok(std::slice::from_raw_parts(g.compressed, g.compressed_len as usize).to_vec())
+        } else {
+            Err(Error::Gifsicle)
+        };
+        Gif_DeleteImage(g);
+        Gif_DeleteStream(gfs);
+        res
+    }
+}
+
+/// Joins compressed strips into one image. Each strip starts with a clear code, so the codes of the next strip
+/// can follow the previous strip once its end code is dropped. They're repacked, because they don't start on a byte boundary anymore.
+fn join_lzw(parts: &[Vec<u8>]) -> CatResult<Vec<u8>> {
+    let min_code_bits = *parts.first().and_then(|part| part.first()).ok_or(Error::Gifsicle)?;
+    if !(2..=8).contains(&min_code_bits) {
+        return Err(Error::Gifsicle);
+    }
+    let clear = 1u32 << min_code_bits;
+    let end = clear + 1;
+    let mut out = BitWriter::default();
+    // the clear code starting the next strip is read at the initial size, but written at the size the previous strip ended with
+    let mut write_bits = min_code_bits + 1;
+    for (n, part) in parts.iter().enumerate() {
+        if part.first() != Some(&min_code_bits) {
+            return Err(Error::Gifsicle);
+        }
+        let mut codes = BitReader { data: unblock(&part[1..])?, pos: 0, acc: 0, bits: 0 };
+        let mut code_bits = min_code_bits + 1;
+        // same as giflib's decoder: one more for every code, including the first one after a clear code
+        let mut running = end + 1;
+        if codes.read(code_bits) != Some(clear) {
+            return Err(Error::Gifsicle);
+        }
+        out.write(clear, write_bits);
+        loop {
+            let code = codes.read(code_bits).ok_or(Error::Gifsicle)?;
+            if code == end {
+                if n + 1 == parts.len() {
+                    out.write(code, code_bits);
+                }
+                write_bits = code_bits;
+                break;
+            }
+            out.write(code, code_bits);
+            if code == clear {
+                code_bits = min_code_bits + 1;
+                running = end + 1;
+            } else {
+                running = (running + 1).min(1 << 12 | 1);
+                if running > 1 << code_bits && code_bits < 12 {
+                    code_bits += 1;
+                }
+            }
+        }
+    }
+    let bytes = out.finish();
+    let mut blocks = Vec::with_capacity(bytes.len() + bytes.len() / 255 + 3);
+    blocks.push(min_code_bits);
+    for block in bytes.chunks(255) {
+        blocks.push(block.len() as u8);
+        blocks.extend_from_slice(block);
+    }
+    blocks.push(0);
+    Ok(blocks)
+}
+
+/// Concatenates the sub-blocks
+fn unblock(mut blocks: &[u8]) -> CatResult<Vec<u8>> {
+    let mut data = Vec::with_capacity(blocks.len());
+    loop {
+        let (&len, rest) = blocks.split_first().ok_or(Error::Gifsicle)?;
+        if len == 0 {
+            return Ok(data);
+        }
+        let block = rest.get(..len as usize).ok_or(Error::Gifsicle)?;
+        data.extend_from_slice(block);
+        blocks = &rest[len as usize..];
+    }
+}
+
+/// LZW codes are packed starting from the least significant bit
+struct BitReader {
+    data: Vec<u8>,
+    pos: usize,
+    acc: u32,
+    bits: u8,
+}
+
+impl BitReader {
+    fn read(&mut self, bits: u8) -> Option<u32> {
+        while self.bits < bits {
+            self.acc |= u32::from(*self.data.get(self.pos)?) << self.bits;
+            self.pos += 1;
+            self.bits += 8;
+        }
+        let code = self.acc & ((1 << bits) - 1);
+        self.acc >>= bits;
+        self.bits -= bits;
+        Some(code)
+    }
+}
+
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    acc: u32,
+    bits: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, code: u32, bits: u8) {
+        self.acc |= code << self.bits;
+        self.bits += bits;
+        while self.bits >= 8 {
+            self.bytes.push(self.acc as u8);
+            self.acc >>= 8;
+            self.bits -= 8;
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.bits > 0 {
+            self.bytes.push(self.acc as u8);
+        }
+        self.bytes
+    }
+}
+
+/// The frame's pixels as the GIF shows them after lossy compression, or `None` if the compression is lossless
+pub(crate) fn lossy_image(frame: &GIFFrame, settings: &Settings) -> CatResult<Option<ImgVec<u8>>> {
+    let loss = frame_loss(frame, settings)?;
+    if loss == 0 {
+        return Ok(None);
+    }
+    let decoded = lossy_decoded(frame.image.as_ref(), &frame.pal, frame.transparent_index, loss)?;
+    Ok(Some(ImgVec::new(decoded, frame.image.width(), frame.image.height())))
+}
+
+impl<W: Write> Drop for Gifsicle<W> {
     fn drop(&mut self) {
         unsafe {
             if !self.gif_writer.is_null() {
@@ -52,7 +423,7 @@ impl Drop for Gifsicle<'_> {
     }
 }
 
-impl Encoder for Gifsicle<'_> {
+impl<W: Write> Encoder for Gifsicle<W> {
     fn finish(&mut self) -> CatResult<()> {
         if !self.gif_writer.is_null() {
             self.flush_writer()?;
@@ -66,8 +437,9 @@ impl Encoder for Gifsicle<'_> {
         }
         Ok(())
     }
-    fn write_frame(&mut self, frame: GIFFrame, delay: u16, settings: &Settings) -> CatResult<()> {
-        let GIFFrame {left, top, pal, screen_width, screen_height, image, dispose, transparent_index} = frame;
+    fn write_frame(&mut self, frame: &GIFFrame, delay: u16, settings: &Settings) -> CatResult<()> {
+        let GIFFrame {left, top, ref pal, screen_width, screen_height, ref image, dispose, transparent_index, ..} = *frame;
+        let loss = frame_loss(frame, settings)?;
 
         if self.gfs.is_null() {
             let gfs = unsafe {
@@ -106,25 +478,39 @@ impl Encoder for Gifsicle<'_> {
             gif::DisposalMethod::Previous => Disposal::Previous,
         } as _;
         g.transparent = transparent_index.map(|i| i as _).unwrap_or(-1);
+        // gifsicle reorders rows itself when writing
+        g.interlace = settings.interlace as _;
 
-        g.local = unsafe { Gif_NewFullColormap(0, pal.len() as _) }; // it's owned by the image
-        for c in pal.iter() {
-            unsafe {
-                Gif_AddColor((*g).local, &mut Gif_Color {
-                    gfc_red: c.r,
-                    gfc_green: c.g,
-                    gfc_blue: c.b,
-
-                    haspixel: 0, // dunno?
-                    pixel: 0,
-                }, -1);
+        g.local = new_colormap(pal); // it's owned by the image
+
+        let strips = strip_count(image.width(), image.height());
+        let mut compressed = if strips > 1 {
+            let compressed = if settings.interlace {
+                let rows = crate::encoderust::interlaced_rows(image.as_ref());
+                compress_in_strips(ImgVec::new(rows, image.width(), image.height()).as_ref(), pal, transparent_index, loss, strips, self.parallel_strips)
+            } else {
+                compress_in_strips(image.as_ref(), pal, transparent_index, loss, strips, self.parallel_strips)
+            };
+            match compressed {
+                Ok(compressed) => Some(compressed),
+                Err(err) => {
+                    unsafe { Gif_DeleteImage(g) };
+                    return Err(err);
+                },
             }
-        }
+        } else {
+            None
+        };
         unsafe {
-            if 0 == Gif_SetUncompressedImage(g, image.buf().as_ptr() as *mut u8, None, 0) {
+            if let Some(compressed) = &mut compressed {
+                // it's borrowed, and not freed by gifsicle, because free_compressed is not set
+                g.compressed = compressed.as_mut_ptr();
+                g.compressed_len = compressed.len() as _;
+            } else if 0 == Gif_SetUncompressedImage(g, image.buf().as_ptr() as *mut u8, None, 0) {
                 Gif_DeleteImage(g);
                 return Err(Error::Gifsicle);
             }
+            (*self.gif_writer).gcinfo.loss = loss as _;
             let res = Gif_IncrementalWriteImage(self.gif_writer, self.gfs, g);
             Gif_DeleteImage(g);
             if 0 == res {
@@ -135,3 +521,61 @@ impl Encoder for Gifsicle<'_> {
         Ok(())
     }
 }
+
+#[test]
+fn lossy_writer() {
+    let mut out = Vec::new();
+    let mut w = GifsicleWriter::new(&mut out, 2, 1, Repeat::Infinite, 30);
+    w.write_frame(IndexedFrame {
+        left: 0, top: 0,
+        image: ImgVec::new(vec![0, 1], 2, 1),
+        palette: vec![RGBA8::new(0, 0, 0, 255), RGBA8::new(255, 255, 255, 255)],
+        transparent_index: None,
+        dispose: gif::DisposalMethod::Keep,
+        delay: 10,
+    }).unwrap();
+    assert!(w.write_frame(IndexedFrame {
+        left: 1, top: 0,
+        image: ImgVec::new(vec![0, 1], 2, 1),
+        palette: vec![RGBA8::new(0, 0, 0, 255), RGBA8::new(255, 255, 255, 255)],
+        transparent_index: None,
+        dispose: gif::DisposalMethod::Keep,
+        delay: 10,
+    }).is_err());
+    w.finish().unwrap();
+    assert_eq!(b"GIF89a", &out[..6]);
+    assert_eq!(Some(&b';'), out.last());
+}
+
+#[test]
+fn large_frame_in_strips() {
+    let (width, height) = (1024, 700);
+    assert_eq!(2, strip_count(width, height));
+    let palette: Vec<_> = (0..=255).map(|i| RGBA8::new(i, 255 - i, i / 2, 255)).collect();
+    let image = ImgVec::new((0..width * height).map(|i| ((i % width) / 7 + (i / width) / 5) as u8).collect::<Vec<u8>>(), width, height);
+    let encode = |loss, interlace| {
+        let mut out = Vec::new();
+        let mut w = Gifsicle::new(loss, &mut out);
+        w.write_frame(&GIFFrame {
+            left: 0, top: 0,
+            screen_width: width as u16, screen_height: height as u16,
+            image: image.clone(),
+            pal: palette.clone(),
+            dispose: gif::DisposalMethod::Keep,
+            transparent_index: None,
+            loss,
+        }, 10, &Settings { interlace, ..Settings::default() }).unwrap();
+        w.finish().unwrap();
+        drop(w);
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(&out[..]).unwrap();
+        decoder.read_next_frame().unwrap().unwrap().buffer.to_vec()
+    };
+    assert!(encode(0, false) == image.buf()[..]);
+    assert!(encode(0, true) == image.buf()[..]);
+    // the same strips are compressed on one thread
+    let lossy = encode(60, false);
+    assert!(lossy != image.buf()[..]);
+    assert!(lossy == lossy_decoded(image.as_ref(), &palette, None, 60).unwrap());
+}
@@ -2,7 +2,9 @@ use crate::error::CatResult;
 use crate::GIFFrame;
 use crate::Settings;
 use crate::{Encoder, Repeat};
+use imgref::*;
 use rgb::*;
+use std::borrow::Cow;
 use std::io::Write;
 
 pub(crate) struct RustEncoder<W: Write> {
@@ -20,8 +22,8 @@ impl<W: Write> RustEncoder<W> {
 }
 
 impl<W: Write> Encoder for RustEncoder<W> {
-    fn write_frame(&mut self, f: GIFFrame, delay: u16, settings: &Settings) -> CatResult<()> {
-        let GIFFrame {left, top, pal, image, screen_width, screen_height, dispose, transparent_index} = f;
+    fn write_frame(&mut self, f: &GIFFrame, delay: u16, settings: &Settings) -> CatResult<()> {
+        let GIFFrame {left, top, ref pal, ref image, screen_width, screen_height, dispose, transparent_index, ..} = *f;
 
         let writer = &mut self.writer;
 
@@ -41,7 +43,13 @@ impl<W: Write> Encoder for RustEncoder<W> {
             Some(ref mut enc) => enc,
         };
 
-        let (buffer, width, height) = image.into_contiguous_buf();
+        let (width, height) = (image.width(), image.height());
+        let image = image.as_ref();
+        let buffer: Cow<'_, [u8]> = if settings.interlace {
+            interlaced_rows(image).into()
+        } else {
+            image.to_contiguous_buf().0
+        };
 
         let mut pal_rgb = Vec::with_capacity(3 * pal.len());
         for p in pal.iter() {
@@ -57,10 +65,27 @@ impl<W: Write> Encoder for RustEncoder<W> {
             left,
             width: width as u16,
             height: height as u16,
-            interlaced: false,
+            interlaced: settings.interlace,
             palette: Some(pal_rgb),
-            buffer: buffer.into(),
+            buffer,
         })?;
         Ok(())
     }
 }
+
+/// The gif crate expects rows to be already in the interlaced order
+pub(crate) fn interlaced_rows(image: ImgRef<'_, u8>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(image.width() * image.height());
+    for &(start, step) in &[(0, 8), (4, 8), (2, 4), (1, 2)] {
+        for row in image.rows().skip(start).step_by(step) {
+            out.extend_from_slice(row);
+        }
+    }
+    out
+}
+
+#[test]
+fn interlace_order() {
+    let img = ImgVec::new((0..10).collect(), 1, 10);
+    assert_eq!(vec![0, 8, 4, 2, 6, 1, 3, 5, 7, 9], interlaced_rows(img.as_ref()));
+}
@@ -15,13 +15,23 @@ quick_error! {
         Gif(err: gif::EncodingError) {
             display("GIF encoding error: {}", err)
         }
+        GifDecode(msg: String) {
+            display("GIF decoding error: {}", msg)
+            from(e: gif::DecodingError) -> (e.to_string())
+        }
         NoFrames {
             display("Found no usable frames to encode")
         }
+        InvalidInput(msg: String) {
+            display("{}", msg)
+        }
         Io(err: io::Error) {
             from()
             display("I/O: {}", err)
         }
+        TempFile(err: io::Error) {
+            display("Temporary file: {}", err)
+        }
         PNG(msg: String) {
             display("{}", msg)
         }
@@ -33,6 +43,9 @@ quick_error! {
             from()
             display("pngquant error: {}", liq)
         }
+        TooLarge(size: u64) {
+            display("The GIF doesn't fit in the size limit even at the lowest quality ({}KB)", (size + 1023) / 1024)
+        }
         Pal(gif: gif_dispose::Error) {
             from()
             display("gif dispose error: {}", gif)
@@ -0,0 +1,253 @@
+use crate::deband::deband;
+use crate::pool::BufferPool;
+use crate::{Look, Pad, Settings};
+use imgref::*;
+use rgb::*;
+
+/// Filters applied to frames after resizing
+#[derive(Copy, Clone)]
+pub(crate) struct Filters {
+    brightness: f32,
+    contrast: f32,
+    gamma: f32,
+    look: Look,
+    blur: f32,
+    sharpen: u8,
+    deband: bool,
+    pad: Option<Pad>,
+}
+
+impl Filters {
+    pub fn new(settings: &Settings) -> Self {
+        Self {
+            brightness: settings.brightness,
+            contrast: settings.contrast,
+            gamma: settings.gamma,
+            look: settings.look,
+            blur: settings.blur,
+            sharpen: settings.sharpen,
+            deband: settings.deband,
+            pad: settings.pad,
+        }
+    }
+
+    /// None of the filters change anything
+    pub fn is_empty(&self) -> bool {
+        self.brightness == 0. && self.contrast == 0. && self.gamma == 1. && self.look == Look::Original &&
+            self.blur <= 0. && self.sharpen == 0 && !self.deband && self.pad.is_none()
+    }
+
+    /// Replaced images are given back to the `pool`
+    pub fn apply(&self, image: &mut ImgVec<RGBA8>, pool: &BufferPool<RGBA8>) {
+        if self.brightness != 0. || self.contrast != 0. || self.gamma != 1. {
+            let lut = tone_curve(self.brightness, self.contrast, self.gamma);
+            for px in image.pixels_mut() {
+                *px = RGBA8::new(lut[px.r as usize], lut[px.g as usize], lut[px.b as usize], px.a);
+            }
+        }
+        if self.look != Look::Original {
+            for px in image.pixels_mut() {
+                *px = apply_look(*px, self.look);
+            }
+        }
+        if self.blur > 0. {
+            let blurred = gaussian_blur(image.as_ref(), self.blur, pool.take(image.width() * image.height()));
+            pool.give(std::mem::replace(image, blurred).into_buf());
+        }
+        // sharpening makes bands more visible, so they're smoothed afterwards
+        if self.sharpen > 0 {
+            sharpen(image, self.sharpen);
+        }
+        if self.deband {
+            deband(image);
+        }
+        if let Some(pad) = self.pad {
+            add_padding(image, pad);
+        }
+    }
+
+    /// Pads an importance map the same way as `apply` pads its frame. The borders aren't important.
+    pub fn pad_importance_map(&self, map: &mut ImgVec<u8>) {
+        if let Some(pad) = self.pad {
+            pad_with(map, pad, 0);
+        }
+    }
+}
+
+/// Output for every channel value. Gamma is applied first, so that contrast stays centered on mid-gray.
+fn tone_curve(brightness: f32, contrast: f32, gamma: f32) -> [u8; 256] {
+    let mut lut = [0; 256];
+    for (i, out) in lut.iter_mut().enumerate() {
+        let c = (i as f32 / 255.).powf(1. / gamma);
+        let c = (c - 0.5) * (1. + contrast) + 0.5 + brightness;
+        *out = (c * 255.).round().clamp(0., 255.) as u8;
+    }
+    lut
+}
+
+/// Grayscale and sepia depend only on luma, so frames have at most 256 colors
+fn apply_look(px: RGBA8, look: Look) -> RGBA8 {
+    let luma = (u32::from(px.r) * 77 + u32::from(px.g) * 150 + u32::from(px.b) * 29 + 128) >> 8;
+    let tint = |factor: u32| (luma * factor / 1000).min(255) as u8;
+    match look {
+        Look::Original => px,
+        Look::Grayscale => RGBA8::new(luma as u8, luma as u8, luma as u8, px.a),
+        // the classic sepia matrix applied to gray
+        Look::Sepia => RGBA8::new(tint(1351), tint(1203), tint(937), px.a),
+    }
+}
+
+/// Centers the image on a canvas of the pad's aspect ratio, and at least the size of the image
+fn add_padding(image: &mut ImgVec<RGBA8>, pad: Pad) {
+    pad_with(image, pad, pad.color);
+}
+
+fn pad_with<T: Copy>(image: &mut ImgVec<T>, pad: Pad, fill: T) {
+    let (width, height) = (image.width(), image.height());
+    let (aspect_width, aspect_height) = (pad.aspect_width.max(1) as usize, pad.aspect_height.max(1) as usize);
+    let (canvas_width, canvas_height) = if width * aspect_height > height * aspect_width {
+        (width, (width * aspect_height + aspect_width - 1) / aspect_width)
+    } else {
+        ((height * aspect_width + aspect_height - 1) / aspect_height, height)
+    };
+    if (canvas_width, canvas_height) == (width, height) {
+        return;
+    }
+    let (left, top) = ((canvas_width - width) / 2, (canvas_height - height) / 2);
+    let mut canvas = ImgVec::new(vec![fill; canvas_width * canvas_height], canvas_width, canvas_height);
+    for (dst, src) in canvas.rows_mut().skip(top).zip(image.rows()) {
+        dst[left..left + width].copy_from_slice(src);
+    }
+    *image = canvas;
+}
+
+/// Unsharp mask. `amount` is 1-100.
+fn sharpen(image: &mut ImgVec<RGBA8>, amount: u8) {
+    let blurred = gaussian_blur(image.as_ref(), 1., Vec::new());
+    let strength = f32::from(amount) / 50.;
+    let sharp = |px: u8, blurred: u8| {
+        let px = f32::from(px);
+        (px + (px - f32::from(blurred)) * strength).round().clamp(0., 255.) as u8
+    };
+    for (px, b) in image.pixels_mut().zip(blurred.pixels()) {
+        if px.a > 0 {
+            *px = RGBA8::new(sharp(px.r, b.r), sharp(px.g, b.g), sharp(px.b, b.b), px.a);
+        }
+    }
+}
+
+/// `sigma` is the standard deviation in pixels, like the radius of CSS `blur()`.
+/// Colors are weighted by alpha, so that transparent pixels don't darken the edges.
+/// The result is written to the empty `out` buffer.
+fn gaussian_blur(image: ImgRef<'_, RGBA8>, sigma: f32, mut out: Vec<RGBA8>) -> ImgVec<RGBA8> {
+    let (width, height) = (image.width(), image.height());
+    let half = (sigma * 3.).ceil().max(1.) as isize;
+    let mut kernel: Vec<f32> = (-half..=half).map(|i| (-(i * i) as f32 / (2. * sigma * sigma)).exp()).collect();
+    let total: f32 = kernel.iter().sum();
+    kernel.iter_mut().for_each(|w| *w /= total);
+
+    let premultiplied: Vec<RGBA<f32>> = image.pixels().map(|px| {
+        let a = f32::from(px.a) / 255.;
+        RGBA::new(f32::from(px.r) * a, f32::from(px.g) * a, f32::from(px.b) * a, f32::from(px.a))
+    }).collect();
+
+    // separable, so two 1D passes give the same result as a 2D kernel
+    let convolve = |src: &[RGBA<f32>], index: &dyn Fn(isize) -> usize| -> RGBA<f32> {
+        let mut sum = RGBA::new(0., 0., 0., 0.);
+        for (offset, &w) in (-half..=half).zip(&kernel) {
+            let px = src[index(offset)];
+            sum.r += px.r * w;
+            sum.g += px.g * w;
+            sum.b += px.b * w;
+            sum.a += px.a * w;
+        }
+        sum
+    };
+    let clamp = |pos: isize, len: usize| pos.max(0).min(len as isize - 1) as usize;
+
+    let mut horizontal = Vec::with_capacity(width * height);
+    for y in 0..height {
+        for x in 0..width {
+            horizontal.push(convolve(&premultiplied, &|offset| y * width + clamp(x as isize + offset, width)));
+        }
+    }
+    out.clear();
+    for y in 0..height {
+        for x in 0..width {
+            let px = convolve(&horizontal, &|offset| clamp(y as isize + offset, height) * width + x);
+            let unpremultiply = |c: f32| if px.a > 0. { (c * 255. / px.a).round().clamp(0., 255.) as u8 } else { 0 };
+            out.push(RGBA8::new(unpremultiply(px.r), unpremultiply(px.g), unpremultiply(px.b), px.a.round().min(255.) as u8));
+        }
+    }
+    ImgVec::new(out, width, height)
+}
+
+#[test]
+fn tone() {
+    let lut = tone_curve(0., 0., 1.);
+    assert!(lut.iter().enumerate().all(|(i, &c)| i == c as usize));
+
+    let brighter = tone_curve(0.2, 0., 1.);
+    assert_eq!(51, brighter[0]);
+    assert_eq!(255, brighter[220]);
+
+    let contrast = tone_curve(0., 1., 1.);
+    assert_eq!(0, contrast[60]);
+    assert_eq!(255, contrast[200]);
+    assert!(contrast[150] > 150);
+    let flat = tone_curve(0., -1., 1.);
+    assert!(flat.iter().all(|&c| c == flat[0]));
+
+    let gamma = tone_curve(0., 0., 2.);
+    assert_eq!(0, gamma[0]);
+    assert_eq!(181, gamma[128]);
+    assert_eq!(255, gamma[255]);
+}
+
+#[test]
+fn looks() {
+    let px = RGBA8::new(200, 100, 50, 77);
+    assert_eq!(RGBA8::new(124, 124, 124, 77), apply_look(px, Look::Grayscale));
+    assert_eq!(RGBA8::new(167, 149, 116, 77), apply_look(px, Look::Sepia));
+    assert_eq!(RGBA8::new(255, 255, 238, 255), apply_look(RGBA8::new(255, 255, 255, 255), Look::Sepia));
+    assert_eq!(px, apply_look(px, Look::Original));
+}
+
+#[test]
+fn pads_to_aspect_ratio() {
+    let red = RGBA8::new(255, 0, 0, 255);
+    let image = ImgVec::new(vec![red; 4 * 2], 4, 2);
+    let mut square = image.clone();
+    add_padding(&mut square, Pad { aspect_width: 1, aspect_height: 1, color: RGBA8::new(0, 0, 0, 0) });
+    assert_eq!((4, 4), (square.width(), square.height()));
+    assert!(square[0].iter().chain(&square[3]).all(|px| px.a == 0));
+    assert!(square[1].iter().chain(&square[2]).all(|&px| px == red));
+
+    let white = RGBA8::new(255, 255, 255, 255);
+    let mut wide = image;
+    add_padding(&mut wide, Pad { aspect_width: 3, aspect_height: 1, color: white });
+    assert_eq!((6, 2), (wide.width(), wide.height()));
+    assert_eq!(&[white, red, red, red, red, white], &wide[0]);
+}
+
+#[test]
+fn blur_and_sharpen() {
+    // a vertical edge
+    let pixels = (0..16).flat_map(|_| (0..16).map(|x| if x < 8 { RGBA8::new(50, 50, 50, 255) } else { RGBA8::new(150, 150, 150, 255) })).collect();
+    let image = ImgVec::new(pixels, 16, 16);
+
+    let blurred = gaussian_blur(image.as_ref(), 1.5, Vec::new());
+    let row = &blurred[8];
+    assert_eq!(50, row[0].r);
+    assert_eq!(150, row[15].r);
+    assert!(row[7].r > 60 && row[8].r < 140, "{:?}", row);
+    assert!(row.windows(2).all(|w| w[0].r <= w[1].r));
+    assert!(blurred.pixels().all(|px| px.a == 255));
+
+    let mut sharpened = image.clone();
+    sharpen(&mut sharpened, 50);
+    let row = &sharpened[8];
+    assert_eq!(50, row[0].r);
+    assert_eq!(150, row[15].r);
+    assert!(row[7].r < 50 && row[8].r > 150, "{:?}", row);
+}
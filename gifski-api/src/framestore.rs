@@ -0,0 +1,153 @@
+use crate::error::*;
+use crate::DecodedFrame;
+use imgref::*;
+use rgb::*;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Long videos don't fit in memory uncompressed. Used when `Settings::memory_limit_mb` isn't set.
+const DEFAULT_MEMORY_LIMIT: usize = 1 << 30;
+
+static NEXT_FILE_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Stack of frames for playing them back in reverse.
+/// Frames that don't fit in the memory limit are kept in a temporary file.
+pub(crate) struct FrameStore {
+    in_memory: Vec<DecodedFrame>,
+    memory_used: usize,
+    memory_limit: usize,
+    /// Frames pushed after the memory has run out
+    spilled: Vec<SpilledFrame>,
+    file: Option<TempFile>,
+}
+
+/// Where to find a frame in the temporary file
+struct SpilledFrame {
+    offset: u64,
+    width: usize,
+    height: usize,
+    pts: f64,
+    has_importance_map: bool,
+}
+
+struct TempFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+impl FrameStore {
+    /// `memory_limit_mb` is `Settings::memory_limit_mb`, 0 for the default
+    pub fn new(memory_limit_mb: u32) -> Self {
+        Self::with_memory_limit(if memory_limit_mb > 0 { (memory_limit_mb as usize) << 20 } else { DEFAULT_MEMORY_LIMIT })
+    }
+
+    pub fn with_memory_limit(memory_limit: usize) -> Self {
+        Self { in_memory: Vec::new(), memory_used: 0, memory_limit, spilled: Vec::new(), file: None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.in_memory.len() + self.spilled.len()
+    }
+
+    pub fn push(&mut self, frame: DecodedFrame) -> CatResult<()> {
+        let size = frame.0.width() * frame.0.height() * 5;
+        if self.spilled.is_empty() && self.memory_used + size <= self.memory_limit {
+            self.memory_used += size;
+            self.in_memory.push(frame);
+            return Ok(());
+        }
+
+        self.spill(frame).map_err(Error::TempFile)
+    }
+
+    fn spill(&mut self, frame: DecodedFrame) -> io::Result<()> {
+        if self.file.is_none() {
+            let path = std::env::temp_dir().join(format!("gifski-{}-{}.tmp", std::process::id(), NEXT_FILE_ID.fetch_add(1, Ordering::Relaxed)));
+            let file = std::fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+            self.file = Some(TempFile { file, path });
+        }
+        let file = &mut self.file.as_mut().unwrap().file;
+        let (image, pts, importance_map) = frame;
+        let offset = file.seek(SeekFrom::End(0))?;
+        let (width, height) = (image.width(), image.height());
+        let (pixels, ..) = image.into_contiguous_buf();
+        file.write_all(pixels.as_bytes())?;
+        if let Some(importance_map) = &importance_map {
+            let importance_map = importance_map.as_ref();
+            let (map, ..) = importance_map.to_contiguous_buf();
+            file.write_all(&map)?;
+        }
+        self.spilled.push(SpilledFrame { offset, width, height, pts, has_importance_map: importance_map.is_some() });
+        Ok(())
+    }
+
+    /// The most recently pushed frame
+    pub fn pop(&mut self) -> Option<CatResult<DecodedFrame>> {
+        if let (Some(spilled), Some(temp)) = (self.spilled.pop(), &mut self.file) {
+            return Some(read_spilled(&mut temp.file, &spilled).map_err(Error::TempFile));
+        }
+        let frame = self.in_memory.pop()?;
+        self.memory_used -= frame.0.width() * frame.0.height() * 5;
+        Some(Ok(frame))
+    }
+
+    /// Copy of a frame, counting from the first pushed one
+    pub fn get(&mut self, index: usize) -> Option<CatResult<DecodedFrame>> {
+        if let Some(frame) = self.in_memory.get(index) {
+            return Some(Ok(frame.clone()));
+        }
+        let spilled = self.spilled.get(index - self.in_memory.len())?;
+        let temp = self.file.as_mut()?;
+        Some(read_spilled(&mut temp.file, spilled).map_err(Error::TempFile))
+    }
+}
+
+fn read_spilled(file: &mut File, frame: &SpilledFrame) -> io::Result<DecodedFrame> {
+    file.seek(SeekFrom::Start(frame.offset))?;
+    let mut pixels = vec![RGBA8::default(); frame.width * frame.height];
+    file.read_exact(pixels.as_bytes_mut())?;
+    let importance_map = if frame.has_importance_map {
+        let mut map = vec![0; frame.width * frame.height];
+        file.read_exact(&mut map)?;
+        Some(ImgVec::new(map, frame.width, frame.height))
+    } else {
+        None
+    };
+    Ok((ImgVec::new(pixels, frame.width, frame.height), frame.pts, importance_map))
+}
+
+#[test]
+fn spills_to_disk() {
+    let frame = |n: u8| (ImgVec::new(vec![RGBA8::new(n, n, n, 255); 6], 3, 2), f64::from(n), if n % 2 == 0 { Some(ImgVec::new(vec![n; 6], 3, 2)) } else { None });
+    // room for two frames
+    let mut store = FrameStore::with_memory_limit(60);
+    for n in 0..5 {
+        store.push(frame(n)).unwrap();
+    }
+    assert_eq!(5, store.len());
+    assert_eq!(3, store.spilled.len());
+    // can be read in order, any number of times
+    for _ in 0..2 {
+        for n in 0..5 {
+            let (image, pts, importance_map) = store.get(n.into()).unwrap().unwrap();
+            assert_eq!(frame(n), (image, pts, importance_map));
+        }
+    }
+    assert!(store.get(5).is_none());
+    for n in (0..5).rev() {
+        let (image, pts, importance_map) = store.pop().unwrap().unwrap();
+        let expected = frame(n);
+        assert_eq!(expected.0, image);
+        assert_eq!(expected.1, pts);
+        assert_eq!(expected.2, importance_map);
+    }
+    assert!(store.pop().is_none());
+}
@@ -32,19 +32,98 @@ pub mod progress;
 use crate::progress::*;
 pub mod c_api;
 mod denoise;
-use crate::denoise::*;
 mod encoderust;
+mod deband;
+mod filter;
+use crate::filter::Filters;
+mod crossfade;
+mod framestore;
+mod maxsize;
+mod pipeline;
+mod pngdecode;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod pool;
+mod simd;
+mod stabilize;
+pub mod sync;
+#[cfg(not(feature = "threadless"))]
+mod workers;
+#[cfg(feature = "threadless")]
+mod threadless;
+use crate::framestore::FrameStore;
+#[cfg(not(feature = "threadless"))]
+use crate::pipeline::*;
+#[cfg(not(feature = "threadless"))]
+use crate::workers::QuantizeWorkers;
+use crate::pool::Pools;
+#[cfg(feature = "dump")]
+mod dump;
+mod yuv;
+pub use crate::yuv::YuvMatrix;
+use crate::yuv::Yuv420;
 
 #[cfg(feature = "gifsicle")]
 mod encodegifsicle;
+#[cfg(feature = "gifsicle")]
+pub use crate::encodegifsicle::{GifsicleWriter, IndexedFrame};
+#[cfg(feature = "gifsicle")]
+mod recompress;
+#[cfg(feature = "gifsicle")]
+pub use crate::recompress::recompress;
 
-use crossbeam_channel::{Receiver, Sender};
 use std::io::prelude::*;
 use std::path::PathBuf;
+#[cfg(not(feature = "threadless"))]
 use std::thread;
 use std::borrow::Cow;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(not(all(target_arch = "wasm32", target_os = "unknown")))]
+use std::time::Instant;
+
+type DecodedImage = CatResult<(InputFrame, f64)>;
+
+/// Pixels owned by someone else, e.g. a C caller's buffer that is released when this is dropped
+pub(crate) type ExternalPixels = Box<dyn AsRef<[RGBA8]> + Send>;
+
+/// Frame as added to the `Collector`
+enum InputFrame {
+    /// Already resized
+    Rgba(ImgVec<RGBA8>),
+    /// Already resized, with caller's importance map of the same size
+    RgbaWithImportance(ImgVec<RGBA8>, ImgVec<u8>),
+    /// Caller's buffer, copied and resized on the writer's thread
+    External(Img<ExternalPixels>, Option<Matte>),
+}
+
+impl InputFrame {
+    /// Bytes held until the frame is encoded
+    fn memory_size(&self) -> usize {
+        match self {
+            InputFrame::Rgba(image) => image.buf().len() * 4,
+            InputFrame::RgbaWithImportance(image, importance_map) => image.buf().len() * 4 + importance_map.buf().len(),
+            InputFrame::External(image, _) => (**image.buf()).as_ref().len() * 4,
+        }
+    }
 
-type DecodedImage = CatResult<(ImgVec<RGBA8>, f64)>;
+    /// Pixels, and optionally importance map
+    fn decode(self, settings: &Settings, timings: &Timings, pools: &Pools) -> CatResult<(ImgVec<RGBA8>, Option<ImgVec<u8>>)> {
+        match self {
+            InputFrame::Rgba(image) => Ok((image, None)),
+            InputFrame::RgbaWithImportance(image, importance_map) => Ok((image, Some(importance_map))),
+            InputFrame::External(image, matte) => {
+                let pixels: &[RGBA8] = (**image.buf()).as_ref();
+                let image = Img::new_stride(Cow::Borrowed(pixels), image.width(), image.height(), image.stride());
+                let start = Instant::now();
+                let image = Collector::resized_binary_alpha(image, settings.width, settings.height, settings.scale, settings.crop, Filters::new(settings), matte, pools);
+                timings.add(&timings.resize, start);
+                Ok((image?, None))
+            },
+        }
+    }
+}
 
 /// Number of repetitions
 #[derive(Debug, Copy, Clone)]
@@ -53,6 +132,70 @@ pub enum Repeat {
     Infinite,
 }
 
+/// How much lossy LZW compression to use (requires the `gifsicle` feature)
+#[derive(Copy, Clone)]
+pub enum LossyMode {
+    /// Derived from `Settings.quality`. Quality 100 is lossless.
+    Quality,
+    /// Fixed amount of loss, same as gifsicle's `--lossy` option. 0 is lossless, 200 is very lossy.
+    Loss(u32),
+    /// Custom function mapping `Settings.quality` to loss
+    Curve(fn(quality: u8) -> u32),
+}
+
+/// Area of input frames to use, in pixels of the input frames
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Crop {
+    pub left: u32,
+    pub top: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Which pixels are blended with the matte color, see `Collector::set_matte`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MatteMode {
+    /// Only partially transparent pixels. Fully transparent pixels stay transparent.
+    SemiTransparent,
+    /// All pixels that aren't opaque, removing transparency from the animation
+    Flatten,
+}
+
+type Matte = (RGBA8, MatteMode);
+
+/// Canvas with a fixed aspect ratio that frames are centered on, e.g. 1:1 for square GIFs
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Pad {
+    pub aspect_width: u32,
+    pub aspect_height: u32,
+    /// Color of the added borders. Can be transparent.
+    pub color: RGBA8,
+}
+
+/// Color style applied to all frames
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Look {
+    /// Colors as they are
+    Original,
+    /// Shades of gray. Needs fewer colors, so files are smaller.
+    Grayscale,
+    /// Shades of brown, like an old photo
+    Sepia,
+}
+
+/// How colors that aren't in the palette are approximated
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Dithering {
+    /// Depends on `Settings.quality`
+    Auto,
+    /// Nearest palette color. Flat, but may have banding.
+    None,
+    /// Regular pattern with the given strength (0-1). Compresses better than diffusion.
+    Ordered(f32),
+    /// Floyd-Steinberg error diffusion with the given strength (0-1)
+    Diffusion(f32),
+}
+
 /// Encoding settings for the `new()` function
 #[derive(Copy, Clone)]
 pub struct Settings {
@@ -60,12 +203,94 @@ pub struct Settings {
     pub width: Option<u32>,
     /// Resize to max this height if width is non-0. Note that aspect ratio is not preserved.
     pub height: Option<u32>,
+    /// Resize to this fraction (0-1) of each input's size, instead of `width` and `height`
+    pub scale: Option<f32>,
     /// 1-100, but useful range is 50-100. Recommended to set to 100.
     pub quality: u8,
     /// Lower quality, but faster encode.
     pub fast: bool,
     /// Sets the looping method for the image sequence.
     pub repeat: Repeat,
+    /// Vary lossy compression per frame: use less loss on mostly-static frames, and more on frames with lots of motion.
+    /// Only has effect when `quality` is below 100.
+    pub adaptive_loss: bool,
+    /// Write interlaced frames. Some viewers display them progressively while loading, but they compress slightly worse.
+    pub interlace: bool,
+    /// Minimum PSNR (in dB) of frames after lossy compression, compared to the same frames compressed losslessly.
+    /// Frames below it are compressed again with less loss. 30-40 is a sensible range.
+    pub lossy_min_psnr: Option<f32>,
+    /// Spend more time on a final pass that finds cheaper encodings of differences between frames (like `gifsicle -O3`).
+    pub optimize_frames: bool,
+    /// How `quality` translates to lossy LZW compression
+    pub lossy: LossyMode,
+    /// 2-256. Maximum number of colors per frame (including transparency).
+    pub max_colors: u16,
+    /// Color dithering method
+    pub dithering: Dithering,
+    /// Play the frames forwards, and then backwards. Frames are kept until the end,
+    /// and once they take more than `memory_limit_mb` (or about 1GB) the rest go to a temporary file.
+    pub bounce: bool,
+    /// Cut out this area of each frame, before resizing
+    pub crop: Option<Crop>,
+    /// Play the frames backwards. Frames are kept until the end,
+    /// and once they take more than `memory_limit_mb` (or about 1GB) the rest go to a temporary file.
+    pub reverse: bool,
+    /// Extra time (in seconds) to show the last frame for, before the animation loops
+    pub loop_end_delay: f32,
+    /// Seconds of the beginning that are blended into the end, so that footage without a natural loop point
+    /// doesn't jump when it loops. The animation gets shorter by this much. 0 disables it.
+    pub loop_crossfade: f32,
+    /// Maximum number of threads for quantization, or 0 for automatic. Frames are quantized on more threads
+    /// while quantization is behind the other stages. When built with OpenMP, frames are quantized one at a time
+    /// on this many OpenMP threads, and it changes the limit for the whole process.
+    ///
+    /// It doesn't limit the other threads: diffing, remapping and frame optimization each use one thread of their own
+    /// (unless frames are tiny), resizing happens on the threads that add frames,
+    /// and lossy LZW compression of frames over half a megapixel is split into strips compressed on threads of their own.
+    pub threads: u8,
+    /// Merge a frame into the next one if none of their pixels' channels differ by more than this (0-255).
+    /// 0 merges only identical frames. Small values help with noisy screen recordings.
+    pub duplicate_threshold: u8,
+    /// Reduce camera shake by following the motion of the whole frame. Crops 5% off each edge to make room for it.
+    pub stabilize: bool,
+    /// Smooth out banding in gradients of the input, which quantization would make worse. Applied after resizing.
+    pub deband: bool,
+    /// -1 to 1. Added to all color channels (as a fraction of full brightness). 0 is unchanged.
+    pub brightness: f32,
+    /// -1 to 1. Stretches colors away from mid-gray, or at -1 makes everything gray. 0 is unchanged.
+    pub contrast: f32,
+    /// Above 1 brightens dark colors, below 1 darkens them. 1 is unchanged.
+    pub gamma: f32,
+    /// Grayscale or sepia, applied after other color adjustments
+    pub look: Look,
+    /// 0-100. Sharpen frames after resizing, e.g. text in downscaled screen recordings. 0 is off.
+    pub sharpen: u8,
+    /// Blur frames after resizing, with this radius in pixels (standard deviation, like CSS `blur()`). 0 is off.
+    /// Slight blur can make noisy camera footage compress much better.
+    pub blur: f32,
+    /// Add borders to make frames this aspect ratio. Applied after resizing and filters.
+    pub pad: Option<Pad>,
+    /// Make the output byte-identical for the same input and settings, regardless of the number of CPUs.
+    /// With OpenMP, quantization is then single-threaded, because the order in which threads add up colors changes the palettes.
+    /// Quantization threads are then all started at once, instead of depending on how fast the other stages are.
+    /// Nothing else depends on timing or threads: palette reuse is decided on one thread in order of frames,
+    /// and frames kept for `bounce` and `reverse` are stored losslessly, whether in memory or in a temporary file.
+    pub deterministic: bool,
+    /// Megabytes that frames waiting between stages (and the denoiser) may use, or 0 for no limit.
+    /// When it's reached, adding frames and the stages wait until the writer catches up. Frames need to be added in order,
+    /// or from several threads, because a frame that's far ahead can wait for the frames before it.
+    /// With the `threadless` feature adding frames doesn't wait, because `Writer::pump()` decides when frames are encoded.
+    /// Also limits memory used by `bounce` and `reverse` before frames go to a temporary file.
+    pub memory_limit_mb: u32,
+    /// Frames that can wait between each pair of stages, or 0 for the defaults (4 to 8, depending on the stage).
+    /// Deeper queues keep more threads busy on machines with many cores, and shallower ones use less memory.
+    pub queue_depth: u8,
+    /// Find changed pixels on frames scaled down to half the width and height, for very large frames.
+    /// The denoiser then takes a quarter of the memory and time, but frames aren't denoised, only their unchanged pixels are skipped.
+    pub denoise_half_size: bool,
+    /// Let frames use the previous frame's palette again when their colors are nearly the same, instead of quantizing them.
+    /// Faster for videos with steady colors, but colors that are new in a frame must be in the palette already.
+    pub reuse_palettes: bool,
 }
 
 impl Settings {
@@ -75,13 +300,27 @@ impl Settings {
         (self.quality as u16 * 4 / 3).min(100) as u8
     }
 
+    /// Capacity of a queue between stages, with `default` used when `queue_depth` isn't set
+    pub(crate) fn queue_depth(&self, default: usize) -> usize {
+        if self.queue_depth > 0 { self.queue_depth.into() } else { default }
+    }
+
     /// add_frame is going to resize the images to this size.
     pub fn dimensions_for_image(&self, width: usize, height: usize) -> (usize, usize) {
-        dimensions_for_image((width, height), (self.width, self.height))
+        let (width, height) = match self.crop {
+            Some(crop) => (crop.width as usize, crop.height as usize),
+            None => (width, height),
+        };
+        dimensions_for_image((width, height), (self.width, self.height), self.scale)
     }
 
-    pub(crate) fn gifsicle_loss(&self) -> u32 {
-        (100. / 6. - self.quality as f32 / 6.).powf(1.75).ceil() as u32
+    /// Amount of lossy LZW compression these settings use, same as gifsicle's `--lossy` (0 is lossless)
+    pub fn gifsicle_loss(&self) -> u32 {
+        match self.lossy {
+            LossyMode::Quality => (100. / 6. - self.quality as f32 / 6.).powf(1.75).ceil() as u32,
+            LossyMode::Loss(loss) => loss,
+            LossyMode::Curve(curve) => curve(self.quality),
+        }
     }
 }
 
@@ -89,9 +328,38 @@ impl Default for Settings {
     fn default() -> Self {
         Self {
             width: None, height: None,
+            scale: None,
             quality: 100,
             fast: false,
             repeat: Repeat::Infinite,
+            adaptive_loss: false,
+            interlace: false,
+            lossy_min_psnr: None,
+            optimize_frames: false,
+            lossy: LossyMode::Quality,
+            max_colors: 256,
+            dithering: Dithering::Auto,
+            bounce: false,
+            crop: None,
+            reverse: false,
+            loop_end_delay: 0.,
+            loop_crossfade: 0.,
+            threads: 0,
+            duplicate_threshold: 0,
+            stabilize: false,
+            deband: false,
+            brightness: 0.,
+            contrast: 0.,
+            gamma: 1.,
+            look: Look::Original,
+            sharpen: 0,
+            blur: 0.,
+            pad: None,
+            deterministic: false,
+            memory_limit_mb: 0,
+            queue_depth: 0,
+            denoise_half_size: false,
+            reuse_palettes: false,
         }
     }
 }
@@ -103,7 +371,23 @@ impl Default for Settings {
 pub struct Collector {
     width: Option<u32>,
     height: Option<u32>,
+    scale: Option<f32>,
+    crop: Option<Crop>,
+    filters: Filters,
+    /// Background to blend semi-transparent pixels with
+    matte: Option<Matte>,
     queue: OrdQueue<DecodedImage>,
+    timings: Arc<Timings>,
+    /// Shared with the `Writer`, which gives the frames' buffers back
+    pools: Pools,
+    /// Added to frame indexes and timestamps of the current segment, see `start_next_segment`
+    segment_offset: (usize, f64),
+    /// Index after the last frame added so far, and the latest timestamp
+    added_end: (usize, Option<f64>),
+    /// Shared with the `Writer`, see `set_last_frame_duration()`
+    last_frame_duration: LastFrameDuration,
+    /// See `set_frame_filter()`
+    frame_filter: Option<FrameFilter>,
 }
 
 /// Perform GIF writing
@@ -111,8 +395,40 @@ pub struct Writer {
     /// Input frame decoder results
     queue_iter: Option<OrdQueueIter<DecodedImage>>,
     settings: Settings,
+    /// If non-empty, the only colors used
+    fixed_palette: Vec<RGB8>,
+    /// Used in addition to the best colors for each frame
+    fixed_colors: Vec<RGB8>,
+    /// Timestamp of the frame to hand out, and where to
+    poster: Option<(f64, PosterCallback)>,
+    output_frame_callback: Option<OutputFrameCallback>,
+    /// Number of frames already in the file being continued
+    resume_after: usize,
+    /// Shared with the `Collector`
+    timings: Arc<Timings>,
+    /// Shared with the `Collector`
+    pools: Pools,
+    /// Shared with the `Collector`'s queue
+    memory_limit: Option<Arc<MemoryLimit>>,
+    /// Shared with the `Collector`
+    last_frame_duration: LastFrameDuration,
+    /// See `set_max_size()`
+    max_size: Option<u64>,
+    /// Copies of frames, for encoding them again if the GIF is over `max_size`
+    recording: Option<maxsize::Recording>,
+    /// Frames to encode instead of the `Collector`'s
+    replay: Option<maxsize::Replay>,
+    /// Stages of frames encoded by `pump()`
+    #[cfg(feature = "threadless")]
+    pumped: Option<threadless::Pumped>,
 }
 
+type PosterCallback = Box<dyn FnOnce(ImgVec<RGBA8>) + Send>;
+type FrameFilter = Box<dyn FnMut(&mut ImgVec<RGBA8>, f64) + Send>;
+type OutputFrameCallback = Box<dyn FnMut(ImgRef<'_, RGBA8>, u16) + Send>;
+/// Set by the `Collector`, and read after the last frame has been added
+type LastFrameDuration = Arc<std::sync::Mutex<Option<f64>>>;
+
 struct GIFFrame {
     left: u16,
     top: u16,
@@ -122,15 +438,27 @@ struct GIFFrame {
     pal: Vec<RGBA8>,
     dispose: gif::DisposalMethod,
     transparent_index: Option<u8>,
+    /// Lossy LZW setting for this frame
+    #[cfg_attr(not(feature = "gifsicle"), allow(dead_code))]
+    loss: u32,
 }
 
 trait Encoder {
-    fn write_frame(&mut self, frame: GIFFrame, delay: u16, settings: &Settings) -> CatResult<()>;
+    fn write_frame(&mut self, frame: &GIFFrame, delay: u16, settings: &Settings) -> CatResult<()>;
     fn finish(&mut self) -> CatResult<()> {
         Ok(())
     }
 }
 
+/// Discards frames, for `Writer::write_frames_only()`
+struct NoEncoder;
+
+impl Encoder for NoEncoder {
+    fn write_frame(&mut self, _: &GIFFrame, _: u16, _: &Settings) -> CatResult<()> {
+        Ok(())
+    }
+}
+
 /// Frame before quantization
 struct DiffMessage {
     /// 1..
@@ -140,6 +468,8 @@ struct DiffMessage {
     dispose: gif::DisposalMethod,
     image: ImgVec<RGBA8>,
     importance_map: Vec<u8>,
+    /// The colors are close enough to the previous frame's that its palette can be used again
+    reuse_palette: bool,
 }
 
 /// Frame post quantization, before remap
@@ -148,9 +478,11 @@ struct RemapMessage {
     ordinal_frame_number: usize,
     end_pts: f64,
     dispose: gif::DisposalMethod,
-    liq: Attributes,
-    remap: QuantizationResult,
-    liq_image: Image<'static>,
+    /// `None` if the previous frame's palette is used again
+    quantized: Option<(Attributes, QuantizationResult)>,
+    /// Pixels that have been quantized, handed over to be remapped
+    image: ImgVec<RGBA8>,
+    loss: u32,
 }
 
 /// Frame post quantization and remap
@@ -161,25 +493,79 @@ struct FrameMessage {
     frame: GIFFrame,
 }
 
+#[cfg(not(feature = "threadless"))]
+impl RemapMessage {
+    /// Bytes held until the frame is remapped
+    fn memory_size(&self) -> usize {
+        self.image.buf().len() * 4
+    }
+}
+
+#[cfg(not(feature = "threadless"))]
+impl FrameMessage {
+    /// Bytes held until the frame is written
+    fn memory_size(&self) -> usize {
+        self.frame.image.buf().len() + self.frame.pal.len() * 4
+    }
+}
+
 /// Start new encoding
 ///
 /// Encoding is multi-threaded, and the `Collector` and `Writer`
 /// can be used on sepate threads.
 ///
+/// With the `threadless` feature (e.g. for WebAssembly) encoding doesn't start any threads.
+/// Call `writer.pump()` after adding frames, then drop the `Collector` and call `writer.write()`.
+/// To write frames as they're pumped, call `writer.set_output()` first, and `writer.finish()` instead of `write()`.
+/// `sync::Encoder` doesn't use threads either, and encodes each frame as it's added.
+///
 /// You feed input frames to the `Collector`, and ask the `Writer` to
 /// start writing the GIF.
 pub fn new(settings: Settings) -> CatResult<(Collector, Writer)> {
-    let (queue, queue_iter) = ordqueue::new(4);
+    // adding frames can't wait for encoding when both happen on the same thread
+    let memory_limit = if settings.memory_limit_mb > 0 && !cfg!(feature = "threadless") {
+        Some(Arc::new(MemoryLimit::new((settings.memory_limit_mb as usize) << 20)))
+    } else {
+        None
+    };
+    let (queue, queue_iter) = ordqueue::new(settings.queue_depth(4), memory_limit.as_ref());
+    let timings = Arc::new(Timings::default());
+    let pools = Pools::new();
+    let last_frame_duration = LastFrameDuration::default();
 
     Ok((
         Collector {
             queue,
             width: settings.width,
             height: settings.height,
+            scale: settings.scale,
+            crop: settings.crop,
+            filters: Filters::new(&settings),
+            matte: None,
+            timings: timings.clone(),
+            pools: pools.clone(),
+            segment_offset: (0, 0.),
+            added_end: (0, None),
+            last_frame_duration: last_frame_duration.clone(),
+            frame_filter: None,
         },
         Writer {
             queue_iter: Some(queue_iter),
             settings,
+            fixed_palette: Vec::new(),
+            fixed_colors: Vec::new(),
+            poster: None,
+            output_frame_callback: None,
+            resume_after: 0,
+            timings,
+            pools,
+            memory_limit,
+            last_frame_duration,
+            max_size: None,
+            recording: None,
+            replay: None,
+            #[cfg(feature = "threadless")]
+            pumped: None,
         },
     ))
 }
@@ -193,11 +579,57 @@ impl Collector {
     ///
     /// If the first frame doesn't start at pts=0, the delay will be used for the last frame.
     pub fn add_frame_rgba(&mut self, frame_index: usize, image: ImgVec<RGBA8>, presentation_timestamp: f64) -> CatResult<()> {
-        self.queue.push(frame_index, Ok((Self::resized_binary_alpha(image.into(), self.width, self.height)?, presentation_timestamp)))
+        self.push(frame_index, InputFrame::Rgba(self.resize(image.into())?), presentation_timestamp)
     }
 
     pub(crate) fn add_frame_rgba_cow(&mut self, frame_index: usize, image: Img<Cow<[RGBA8]>>, presentation_timestamp: f64) -> CatResult<()> {
-        self.queue.push(frame_index, Ok((Self::resized_binary_alpha(image, self.width, self.height)?, presentation_timestamp)))
+        self.push(frame_index, InputFrame::Rgba(self.resize(image)?), presentation_timestamp)
+    }
+
+    /// The buffer is kept until the frame is processed, and dropped on the writer's thread.
+    /// It must have at least `stride * (height - 1) + width` pixels.
+    pub(crate) fn add_frame_rgba_external(&mut self, frame_index: usize, image: Img<ExternalPixels>, presentation_timestamp: f64) -> CatResult<()> {
+        let len = (**image.buf()).as_ref().len();
+        if image.width() == 0 || image.height() == 0 || image.stride() < image.width() || len < image.stride() * (image.height() - 1) + image.width() {
+            return Err(Error::InvalidInput("frame buffer is too small".into()));
+        }
+        // the filter runs right after resizing, so it can't wait for the writer's thread
+        if self.frame_filter.is_some() {
+            let pixels: &[RGBA8] = (**image.buf()).as_ref();
+            let resized = self.resize(Img::new_stride(Cow::Borrowed(pixels), image.width(), image.height(), image.stride()))?;
+            return self.push(frame_index, InputFrame::Rgba(resized), presentation_timestamp);
+        }
+        self.push(frame_index, InputFrame::External(image, self.matte), presentation_timestamp)
+    }
+
+    /// Same as `add_frame_rgba`, but with a map of areas that need better quality, such as the mouse pointer in screen recordings.
+    ///
+    /// The importance map must have the same size as the image. 0 means no preference, 255 is the most important.
+    /// It can only add quality to the frame, and doesn't make areas that haven't changed since the previous frame get redrawn.
+    pub fn add_frame_rgba_with_importance(&mut self, frame_index: usize, image: ImgVec<RGBA8>, importance_map: ImgVec<u8>, presentation_timestamp: f64) -> CatResult<()> {
+        if image.width() != importance_map.width() || image.height() != importance_map.height() {
+            return Err(Error::InvalidInput(format!("importance map is {}x{}, but the frame is {}x{}",
+                importance_map.width(), importance_map.height(), image.width(), image.height())));
+        }
+        let image = self.resize(image.into())?;
+        let importance_map = importance_map_like_frame(importance_map, self.width, self.height, self.scale, self.crop, &self.filters);
+        self.push(frame_index, InputFrame::RgbaWithImportance(image, importance_map), presentation_timestamp)
+    }
+
+    /// Planar YUV 4:2:0 frame, such as a video decoder's output.
+    ///
+    /// `u` and `v` planes are half the width and height of the `y` plane (rounded up).
+    /// The planes are converted to RGB and resized on this thread, like PNG files in `add_frame_png_file`,
+    /// so that the conversion doesn't hold up the writer's threads.
+    ///
+    /// Frame index starts at 0. Presentation timestamp is time in seconds, same as in `add_frame_rgba`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_frame_yuv420(&mut self, frame_index: usize, y: ImgRef<'_, u8>, u: ImgRef<'_, u8>, v: ImgRef<'_, u8>, matrix: YuvMatrix, full_range: bool, presentation_timestamp: f64) -> CatResult<()> {
+        let frame = Yuv420::new(y, u, v, matrix, full_range)?;
+        let start = Instant::now();
+        let image = frame.to_rgba();
+        self.timings.add(&self.timings.decode, start);
+        self.push(frame_index, InputFrame::Rgba(self.resize(image.into())?), presentation_timestamp)
     }
 
     /// Read and decode a PNG file from disk.
@@ -208,19 +640,117 @@ impl Collector {
     ///
     /// If the first frame doesn't start at pts=0, the delay will be used for the last frame.
     pub fn add_frame_png_file(&mut self, frame_index: usize, path: PathBuf, presentation_timestamp: f64) -> CatResult<()> {
-        let width = self.width;
-        let height = self.height;
-        let image = lodepng::decode32_file(&path)
-            .map_err(|err| Error::PNG(format!("Can't load {}: {}", path.display(), err)))?;
+        let start = Instant::now();
+        let image = pngdecode::decode_png_file(&path)?;
+        self.timings.add(&self.timings.decode, start);
+
+        let (buf, width, height) = image.into_contiguous_buf();
+        let image = Img::new(buf.into(), width, height);
+        self.push(frame_index, InputFrame::Rgba(self.resize(image)?), presentation_timestamp)
+    }
 
-        let image = Img::new(image.buffer.into(), image.width, image.height);
-        self.queue.push(frame_index, Ok((Self::resized_binary_alpha(image, width, height)?, presentation_timestamp)))
+    /// How long the last frame is shown, in seconds.
+    ///
+    /// Timestamps only say when frames start, so without this the last frame is shown as long as the frame before it,
+    /// or for as long as the first frame's timestamp, if it isn't 0. Can be called at any time before the `Collector` is dropped.
+    pub fn set_last_frame_duration(&mut self, seconds: f64) {
+        if let Ok(mut duration) = self.last_frame_duration.lock() {
+            *duration = Some(seconds);
+        }
     }
 
-    #[allow(clippy::identity_op)]
-    #[allow(clippy::erasing_op)]
-    fn resized_binary_alpha(image: Img<Cow<[RGBA8]>>, width: Option<u32>, height: Option<u32>) -> CatResult<ImgVec<RGBA8>> {
-        let (width, height) = dimensions_for_image((image.width(), image.height()), (width, height));
+    /// Frames added after this are numbered and timed after all the frames added so far,
+    /// so that several inputs can be played one after another.
+    ///
+    /// Frame indexes and timestamps of the next input start from 0 again.
+    /// `gap` is how long the last frame added so far is shown, in seconds.
+    pub fn start_next_segment(&mut self, gap: f64) {
+        self.segment_offset = (self.added_end.0, self.added_end.1.map_or(0., |pts| pts + gap));
+    }
+
+    fn push(&mut self, frame_index: usize, mut frame: InputFrame, presentation_timestamp: f64) -> CatResult<()> {
+        let frame_index = frame_index + self.segment_offset.0;
+        let presentation_timestamp = presentation_timestamp + self.segment_offset.1;
+        if let Some(filter) = &mut self.frame_filter {
+            match &mut frame {
+                InputFrame::Rgba(image) | InputFrame::RgbaWithImportance(image, _) => filter(image, presentation_timestamp),
+                // resized already when there's a filter
+                InputFrame::External(..) => {},
+            }
+        }
+        self.added_end.0 = self.added_end.0.max(frame_index + 1);
+        self.added_end.1 = Some(self.added_end.1.map_or(presentation_timestamp, |pts| pts.max(presentation_timestamp)));
+        let bytes = frame.memory_size();
+        let queue = &mut self.queue;
+        self.timings.wait(Waiting::InputFull, || queue.push_sized(frame_index, Ok((frame, presentation_timestamp)), bytes))
+    }
+
+    /// Modify every frame right after it has been cropped and resized, e.g. to draw an overlay on it,
+    /// so that the overlay keeps its size.
+    ///
+    /// The callback gets the frame and its presentation timestamp (after `start_next_segment()` offsets),
+    /// and is called on the thread that adds frames, in the order they're added. Applies to frames added after this call.
+    pub fn set_frame_filter(&mut self, filter: impl FnMut(&mut ImgVec<RGBA8>, f64) + Send + 'static) {
+        self.frame_filter = Some(Box::new(filter));
+    }
+
+    /// Instead of dithering semi-transparent pixels to fully transparent or opaque,
+    /// blend them with this color, making frames added afterwards fully opaque.
+    pub fn set_matte_color(&mut self, color: RGB8) {
+        self.set_matte(color.alpha(255), MatteMode::Flatten);
+    }
+
+    /// Same as `set_matte_color`, but can keep fully transparent pixels transparent.
+    ///
+    /// If the matte color is itself semi-transparent, pixels are blended over it,
+    /// and transparency that is left is dithered as usual.
+    pub fn set_matte(&mut self, color: RGBA8, mode: MatteMode) {
+        self.matte = Some((color, mode));
+    }
+
+    fn resize(&self, image: Img<Cow<[RGBA8]>>) -> CatResult<ImgVec<RGBA8>> {
+        let start = Instant::now();
+        let image = Self::resized_binary_alpha(image, self.width, self.height, self.scale, self.crop, self.filters, self.matte, &self.pools);
+        self.timings.add(&self.timings.resize, start);
+        image
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn resized_binary_alpha(image: Img<Cow<[RGBA8]>>, width: Option<u32>, height: Option<u32>, scale: Option<f32>, crop: Option<Crop>, filters: Filters, matte: Option<Matte>, pools: &Pools) -> CatResult<ImgVec<RGBA8>> {
+        let image = match crop {
+            Some(Crop { left, top, width, height }) => {
+                let (left, top, width, height) = (left as usize, top as usize, width as usize, height as usize);
+                if width == 0 || height == 0 || left + width > image.width() || top + height > image.height() {
+                    return Err(Error::InvalidInput(format!("Crop area {}x{} at {},{} doesn't fit in {}x{} frame",
+                        width, height, left, top, image.width(), image.height())));
+                }
+                let stride = image.stride();
+                let (image_width, image_height) = (image.width(), image.height());
+                match image.into_buf() {
+                    Cow::Owned(buf) => {
+                        let cropped = cropped_in_place(ImgVec::new_stride(buf, image_width, image_height, stride), left, top, width, height);
+                        Img::new(Cow::Owned(cropped.into_buf()), width, height)
+                    },
+                    // stays borrowed if whole rows are cropped
+                    Cow::Borrowed(buf) => {
+                        let cropped = Img::new_stride(buf, image_width, image_height, stride).sub_image(left, top, width, height);
+                        let buf = if cropped.stride() == width {
+                            Cow::Borrowed(&cropped.into_buf()[..width * height])
+                        } else {
+                            Cow::Owned(cropped.pixels().collect())
+                        };
+                        Img::new(buf, width, height)
+                    },
+                }
+            },
+            None => image,
+        };
+        let (width, height) = dimensions_for_image((image.width(), image.height()), (width, height), scale);
+
+        // Opaque frames that keep their size have nothing to blend or dither
+        if width == image.width() && height == image.height() && filters.is_empty() && image.as_ref().rows().all(simd::all_opaque) {
+            return Ok(image.into_owned());
+        }
 
         let mut image = if width != image.width() || height != image.height() {
             let tmp = image.as_ref();
@@ -228,38 +758,114 @@ impl Collector {
             assert_eq!(buf.len(), img_width * img_height);
 
             let mut r = resize::new(img_width, img_height, width, height, resize::Pixel::RGBA8P, resize::Type::Lanczos3)?;
-            let mut dst = vec![RGBA8::new(0, 0, 0, 0); width * height];
+            let mut dst = pools.rgba.take_filled(width * height, RGBA8::new(0, 0, 0, 0));
             r.resize(&buf, &mut dst)?;
             ImgVec::new(dst, width, height)
         } else {
             image.into_owned()
         };
 
-        const DITHER: [u8; 64] = [
-         0*2+8,48*2+8,12*2+8,60*2+8, 3*2+8,51*2+8,15*2+8,63*2+8,
-        32*2+8,16*2+8,44*2+8,28*2+8,35*2+8,19*2+8,47*2+8,31*2+8,
-         8*2+8,56*2+8, 4*2+8,52*2+8,11*2+8,59*2+8, 7*2+8,55*2+8,
-        40*2+8,24*2+8,36*2+8,20*2+8,43*2+8,27*2+8,39*2+8,23*2+8,
-         2*2+8,50*2+8,14*2+8,62*2+8, 1*2+8,49*2+8,13*2+8,61*2+8,
-        34*2+8,18*2+8,46*2+8,30*2+8,33*2+8,17*2+8,45*2+8,29*2+8,
-        10*2+8,58*2+8, 6*2+8,54*2+8, 9*2+8,57*2+8, 5*2+8,53*2+8,
-        42*2+8,26*2+8,38*2+8,22*2+8,41*2+8,25*2+8,37*2+8,21*2+8];
+        filters.apply(&mut image, &pools.rgba);
+
+        if let Some((matte, mode)) = matte {
+            for row in image.rows_mut() {
+                simd::for_each_translucent(row, |_, px| {
+                    if px.a > 0 || mode == MatteMode::Flatten {
+                        *px = blend_over_matte(*px, matte);
+                    }
+                });
+            }
+        }
 
         // Make transparency binary
         for (y, row) in image.rows_mut().enumerate() {
-            for (x, px) in row.iter_mut().enumerate() {
-                if px.a < 255 {
-                    px.a = if px.a < DITHER[(y & 7) * 8 + (x & 7)] { 0 } else { 255 };
-                }
-            }
+            let thresholds = &DITHER[(y & 7) * 8..][..8];
+            simd::for_each_translucent(row, |x, px| {
+                px.a = if px.a < thresholds[x & 7] { 0 } else { 255 };
+            });
         }
         Ok(image)
     }
 }
 
+/// Pixel composited over the matte color
+fn blend_over_matte(px: RGBA8, matte: RGBA8) -> RGBA8 {
+    let a = u32::from(px.a);
+    // matte's alpha, scaled by how much of the matte shows through
+    let m = u32::from(matte.a) * (255 - a) / 255;
+    let out_a = a + m;
+    if out_a == 0 {
+        return px;
+    }
+    let blend = |c: u8, mc: u8| ((u32::from(c) * a + u32::from(mc) * m + out_a / 2) / out_a) as u8;
+    RGBA8::new(blend(px.r, matte.r), blend(px.g, matte.g), blend(px.b, matte.b), out_a as u8)
+}
+
+/// Crops, scales and pads the map the same way as `resized_binary_alpha` does its frame, so that it covers the same pixels.
+/// The crop area has been checked against the frame already.
+fn importance_map_like_frame(map: ImgVec<u8>, width: Option<u32>, height: Option<u32>, scale: Option<f32>, crop: Option<Crop>, filters: &Filters) -> ImgVec<u8> {
+    let map = match crop {
+        Some(Crop { left, top, width, height }) => cropped_in_place(map, left as usize, top as usize, width as usize, height as usize),
+        None => map,
+    };
+    let (width, height) = dimensions_for_image((map.width(), map.height()), (width, height), scale);
+    let mut map = resized_nearest(map.as_ref(), width, height);
+    filters.pad_importance_map(&mut map);
+    map
+}
+
+/// Resizing without interpolation, so that maps keep their values
+fn resized_nearest(map: ImgRef<'_, u8>, width: usize, height: usize) -> ImgVec<u8> {
+    if map.width() == width && map.height() == height {
+        let (buf, width, height) = map.to_contiguous_buf();
+        return ImgVec::new(buf.into_owned(), width, height);
+    }
+    let mut out = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row = &map[y * map.height() / height];
+        out.extend((0..width).map(|x| row[x * map.width() / width]));
+    }
+    ImgVec::new(out, width, height)
+}
+
+/// 8×8 ordered dithering thresholds, scaled to 8..=134
+#[allow(clippy::identity_op)]
+#[allow(clippy::erasing_op)]
+const DITHER: [u8; 64] = [
+ 0*2+8,48*2+8,12*2+8,60*2+8, 3*2+8,51*2+8,15*2+8,63*2+8,
+32*2+8,16*2+8,44*2+8,28*2+8,35*2+8,19*2+8,47*2+8,31*2+8,
+ 8*2+8,56*2+8, 4*2+8,52*2+8,11*2+8,59*2+8, 7*2+8,55*2+8,
+40*2+8,24*2+8,36*2+8,20*2+8,43*2+8,27*2+8,39*2+8,23*2+8,
+ 2*2+8,50*2+8,14*2+8,62*2+8, 1*2+8,49*2+8,13*2+8,61*2+8,
+34*2+8,18*2+8,46*2+8,30*2+8,33*2+8,17*2+8,45*2+8,29*2+8,
+10*2+8,58*2+8, 6*2+8,54*2+8, 9*2+8,57*2+8, 5*2+8,53*2+8,
+42*2+8,26*2+8,38*2+8,22*2+8,41*2+8,25*2+8,37*2+8,21*2+8];
+
+/// Shifts colors by a repeating pattern, so that remapping to the nearest palette color gives ordered dithering.
+/// The result is written to the empty `out` buffer.
+fn ordered_dither(image: ImgRef<'_, RGBA8>, strength: f32, mut out: Vec<RGBA8>) -> ImgVec<RGBA8> {
+    let spread = 32. * strength.clamp(0., 1.);
+    out.clear();
+    for (y, row) in image.rows().enumerate() {
+        let mut offsets = [0; 8];
+        for (offset, &threshold) in offsets.iter_mut().zip(&DITHER[(y & 7) * 8..]) {
+            let threshold = (threshold - 8) / 2;
+            // colors are integers, so rounding the offset is the same as rounding the shifted color
+            *offset = (((threshold as f32 + 0.5) / 64. - 0.5) * spread + 0.5).floor() as i16;
+        }
+        simd::add_offsets(row, &offsets, &mut out);
+    }
+    ImgVec::new(out, image.width(), image.height())
+}
+
 /// add_frame is going to resize the image to this size.
 /// The `Option` args are user-specified max width and max height
-fn dimensions_for_image((img_w, img_h): (usize, usize), resize_to: (Option<u32>, Option<u32>)) -> (usize, usize) {
+fn dimensions_for_image((img_w, img_h): (usize, usize), resize_to: (Option<u32>, Option<u32>), scale: Option<f32>) -> (usize, usize) {
+    if let Some(scale) = scale {
+        let scale = scale.min(1.);
+        let scaled = |size: usize| ((size as f32 * scale).round() as usize).max(1).min(size);
+        return (scaled(img_w), scaled(img_h));
+    }
     match resize_to {
         (None, None) => {
             let factor = (img_w * img_h + 800 * 600) / (800 * 600);
@@ -285,12 +891,86 @@ fn dimensions_for_image((img_w, img_h): (usize, usize), resize_to: (Option<u32>,
 
 /// Encode collected frames
 impl Writer {
+    /// Use exactly these colors (at most 255) for the whole animation, instead of the best colors for each frame.
+    ///
+    /// Useful for brand colors or retro palettes. The palette is used as-is, so it should have all the colors the animation needs.
+    /// Can't be combined with `add_fixed_colors`.
+    pub fn set_fixed_palette(&mut self, colors: Vec<RGB8>) -> CatResult<()> {
+        if colors.is_empty() || colors.len() > 255 {
+            return Err(Error::InvalidInput(format!("Fixed palette must have 1-255 colors, not {}", colors.len())));
+        }
+        if !self.fixed_colors.is_empty() {
+            return Err(Error::InvalidInput("Fixed colors can't be added to a fixed palette, put them in the palette instead".into()));
+        }
+        self.fixed_palette = colors;
+        Ok(())
+    }
+
+    /// Make sure these colors are in every frame's palette, along with the best colors for the frame.
+    ///
+    /// Can be called multiple times. Useful for colors that must be exact, such as a logo. Can't be combined with `set_fixed_palette`.
+    pub fn add_fixed_colors(&mut self, colors: &[RGB8]) -> CatResult<()> {
+        if !self.fixed_palette.is_empty() {
+            return Err(Error::InvalidInput("Fixed colors can't be added to a fixed palette, put them in the palette instead".into()));
+        }
+        if self.fixed_colors.len() + colors.len() > 255 {
+            return Err(Error::InvalidInput(format!("At most 255 fixed colors can be used, not {}", self.fixed_colors.len() + colors.len())));
+        }
+        self.fixed_colors.extend_from_slice(colors);
+        Ok(())
+    }
+
+    /// Get a copy of the frame displayed at the given presentation timestamp (in seconds, 0 for the first frame),
+    /// as it is in the GIF, after quantization, dithering and lossy compression. Useful for making a still preview.
+    /// If the GIF ends earlier, it's the last frame.
+    ///
+    /// The callback is called on the thread that calls `write()`, at most once.
+    pub fn set_poster_callback(&mut self, presentation_timestamp: f64, callback: impl FnOnce(ImgVec<RGBA8>) + Send + 'static) {
+        self.poster = Some((presentation_timestamp, Box::new(callback)));
+    }
+
+    /// Get every frame as it is displayed in the GIF, after quantization, with its delay in 1/100ths of a second.
+    /// Useful for checking quality, or for giving the frames to another encoder.
+    ///
+    /// Frames that aren't written, e.g. duplicates, aren't included. The previous frame is shown for longer instead.
+    /// The callback is called in order of frames, on the thread that calls `write()`.
+    pub fn set_output_frame_callback(&mut self, callback: impl FnMut(ImgRef<'_, RGBA8>, u16) + Send + 'static) {
+        self.output_frame_callback = Some(Box::new(callback));
+    }
+
+    /// Continue an interrupted encoding. All frames need to be added again, but the first `frames` frames
+    /// are used only for timing. The GIF header isn't written, so the output can be appended to the interrupted file
+    /// (cut after its last complete frame).
+    pub fn resume_after(&mut self, frames: usize) {
+        self.resume_after = frames;
+    }
+
+    /// If the GIF is larger than `max_size` bytes, encode it again with lower quality until it fits.
+    ///
+    /// Frames are decoded and resized only once, and kept for the next attempts in memory,
+    /// or in a temporary file if they're over `Settings::memory_limit_mb`.
+    /// The GIF is kept in memory, and written to the output only once it fits.
+    /// `ProgressReporter::retry()` is called before each new attempt.
+    ///
+    /// The poster frame is given out only for the attempt that fits,
+    /// but the output frame callback gets the frames of every attempt.
+    ///
+    /// If it doesn't fit even at the lowest quality, `write()` fails with `Error::TooLarge`.
+    pub fn set_max_size(&mut self, max_size: u64) {
+        self.max_size = Some(max_size);
+        self.recording = Some(Arc::new(std::sync::Mutex::new(FrameStore::new(self.settings.memory_limit_mb))));
+    }
+
     /// `importance_map` is computed from previous and next frame.
     /// Improves quality of pixels visible for longer.
     /// Avoids wasting palette on pixels identical to the background.
     ///
-    /// `background` is the previous frame.
-    fn quantize(image: ImgRef<'_, RGBA8>, importance_map: &[u8], has_prev_frame: bool, settings: &Settings) -> CatResult<(Attributes, QuantizationResult, Image<'static>)> {
+    /// If `fixed_palette` is not empty, it's used instead of finding the best colors.
+    /// Otherwise `fixed_colors` are added to the best colors.
+    ///
+    /// Returns the image that has been quantized, which may be a dithered replacement of the input.
+    #[allow(clippy::too_many_arguments)]
+    fn quantize(image: ImgVec<RGBA8>, importance_map: &[u8], has_prev_frame: bool, settings: &Settings, fixed_palette: &[RGB8], fixed_colors: &[RGB8], pools: &Pools) -> CatResult<(Attributes, QuantizationResult, ImgVec<RGBA8>)> {
         let mut liq = Attributes::new();
         if settings.fast {
             liq.set_speed(10);
@@ -301,310 +981,598 @@ impl Writer {
             100 // the first frame is too important to ruin it
         };
         liq.set_quality(0, quality);
-        let mut img = liq.new_image_stride_copy(image.buf(), image.width(), image.height(), image.stride(), 0.)?;
-        img.set_importance_map(importance_map)?;
-        if has_prev_frame {
-            img.add_fixed_color(RGBA8::new(0, 0, 0, 0));
-        }
-        let res = liq.quantize(&img)?;
-        Ok((liq, res, img))
+        let image = Self::dithered(image, settings, pools);
+        // borrows the pixels instead of copying them, because the image is kept for remapping anyway
+        let res = {
+            let mut img = liq.new_image_stride(image.buf(), image.width(), image.height(), image.stride(), 0.)?;
+            img.set_importance_map(importance_map)?;
+            if has_prev_frame {
+                img.add_fixed_color(RGBA8::new(0, 0, 0, 0));
+            }
+            if settings.max_colors < 256 {
+                liq.set_max_colors(settings.max_colors.max(2).into());
+            }
+            if fixed_palette.is_empty() {
+                for color in fixed_colors {
+                    img.add_fixed_color(color.alpha(255));
+                }
+            } else {
+                debug_assert!(fixed_colors.is_empty());
+                for color in fixed_palette {
+                    img.add_fixed_color(color.alpha(255));
+                }
+                // fixed colors count towards the limit, so there's no room left for any other colors
+                let max_colors = fixed_palette.len() + if has_prev_frame { 1 } else { 0 };
+                liq.set_max_colors(max_colors.max(2) as i32);
+            }
+            liq.quantize(&img)?
+        };
+        Ok((liq, res, image))
     }
 
-    fn remap(liq: Attributes, mut res: QuantizationResult, mut img: Image<'static>, background: Option<ImgRef<'_, RGBA8>>, settings: &Settings) -> CatResult<(ImgVec<u8>, Vec<RGBA8>)> {
-        if let Some(bg) = background {
-            img.set_background(liq.new_image_stride(bg.buf(), bg.width(), bg.height(), bg.stride(), 0.)?)?;
+    /// Ordered dithering is applied before quantization, and error diffusion during remapping
+    fn dithered(image: ImgVec<RGBA8>, settings: &Settings, pools: &Pools) -> ImgVec<RGBA8> {
+        if let Dithering::Ordered(strength) = settings.dithering {
+            let dithered = ordered_dither(image.as_ref(), strength, pools.rgba.take(image.width() * image.height()));
+            pools.rgba.give(image.into_buf());
+            dithered
+        } else {
+            image
         }
-
-        res.set_dithering_level((settings.quality as f32 / 50.0 - 1.).max(0.));
-
-        let (pal, pal_img) = res.remapped(&mut img)?;
-        debug_assert_eq!(img.width() * img.height(), pal_img.len());
-
-        Ok((Img::new(pal_img, img.width(), img.height()), pal))
     }
 
-    fn write_frames(write_queue: Receiver<FrameMessage>, enc: &mut dyn Encoder, settings: &Settings, reporter: &mut dyn ProgressReporter) -> CatResult<()> {
-        let mut pts_in_delay_units = 0_u64;
-
-        let mut n_done = 0;
-        for FrameMessage {frame, ordinal_frame_number, end_pts, ..} in write_queue {
-            let delay = ((end_pts * 100.0).round() as u64)
-                .saturating_sub(pts_in_delay_units)
-                .min(30000) as u16;
-            pts_in_delay_units += u64::from(delay);
-
-            debug_assert_ne!(0, delay);
+    /// `background` is the previous frame.
+    ///
+    /// Very large frames are remapped in bands of rows, so that libimagequant's temporary buffers stay small.
+    /// Only the remapping is banded. Quantization, and the diff and importance maps, still use whole frames.
+    fn remap(liq: &Attributes, res: &mut QuantizationResult, image: ImgRef<'_, RGBA8>, background: Option<ImgRef<'_, RGBA8>>, settings: &Settings, pools: &Pools) -> CatResult<(ImgVec<u8>, Vec<RGBA8>)> {
+        Self::remap_in_bands(liq, res, image, background, settings, pools, REMAP_BAND_PIXELS)
+    }
 
-            // skip frames with bad pts
-            if delay != 0 {
-                enc.write_frame(frame, delay, settings)?;
+    fn remap_in_bands(liq: &Attributes, res: &mut QuantizationResult, image: ImgRef<'_, RGBA8>, background: Option<ImgRef<'_, RGBA8>>, settings: &Settings, pools: &Pools, band_pixels: usize) -> CatResult<(ImgVec<u8>, Vec<RGBA8>)> {
+        let dithering_level = match settings.dithering {
+            Dithering::Auto => (settings.quality as f32 / 50.0 - 1.).max(0.),
+            Dithering::Diffusion(strength) => strength.clamp(0., 1.),
+            // ordered dithering has already been applied to the image
+            Dithering::None | Dithering::Ordered(_) => 0.,
+        };
+        res.set_dithering_level(dithering_level);
+
+        let (width, height) = (image.width(), image.height());
+        let band_height = (band_pixels / width).max(1);
+        if band_height >= height {
+            let mut img = liq.new_image_stride(image.buf(), width, height, image.stride(), 0.)?;
+            if let Some(bg) = background {
+                img.set_background(liq.new_image_stride(bg.buf(), bg.width(), bg.height(), bg.stride(), 0.)?)?;
             }
+            let (pal, pal_img) = res.remapped(&mut img)?;
+            return Ok((Img::new(pal_img, width, height), pal));
+        }
 
-            // loop to report skipped frames too
-            while n_done < ordinal_frame_number {
-                n_done += 1;
-                if !reporter.increase() {
-                    return Err(Error::Aborted);
-                }
+        // Remapping adjusts the palette, which would give each band different colors. Fixed colors aren't adjusted.
+        let (fixed_liq, mut fixed) = Self::fixed_palette_result(&res.palette())?;
+        fixed.set_dithering_level(dithering_level);
+        let mut pal_img = pools.bytes.take_filled(width * height, 0);
+        for (band, out) in pal_img.chunks_mut(band_height * width).enumerate() {
+            let top = band * band_height;
+            // error diffusion starts in the rows above the band, so that it carries over the edge between bands
+            let context = top.min(REMAP_BAND_CONTEXT_ROWS);
+            let rows = context + out.len() / width;
+            let band_image = image.sub_image(0, top - context, width, rows);
+            let mut img = fixed_liq.new_image_stride(band_image.buf(), width, rows, band_image.stride(), 0.)?;
+            if let Some(bg) = background {
+                let bg = bg.sub_image(0, top - context, width, rows);
+                img.set_background(fixed_liq.new_image_stride(bg.buf(), width, rows, bg.stride(), 0.)?)?;
             }
+            let (_, band_pal_img) = fixed.remapped(&mut img)?;
+            out.copy_from_slice(&band_pal_img[context * width..]);
         }
-        if n_done == 0 {
-            return Err(Error::NoFrames);
-        }
-        enc.finish()?;
-        Ok(())
+
+        Ok((Img::new(pal_img, width, height), fixed.palette()))
+    }
+
+    /// Result with exactly these colors, all fixed, so that remapping uses them as they are
+    fn fixed_palette_result(palette: &[RGBA8]) -> CatResult<(Attributes, QuantizationResult)> {
+        let mut liq = Attributes::new();
+        liq.set_max_colors(palette.len().max(2) as i32);
+        let res = {
+            let mut img = liq.new_image(palette, palette.len(), 1, 0.)?;
+            for &color in palette {
+                img.add_fixed_color(color);
+            }
+            liq.quantize(&img)?
+        };
+        Ok((liq, res))
     }
 
     /// Start writing frames. This function will not return until `Collector` is dropped.
     ///
     /// `outfile` can be any writer, such as `File` or `&mut Vec`.
     ///
-    /// `ProgressReporter.increase()` is called each time a new frame is being written.
+    /// `ProgressReporter.frame_written()` is called each time a new frame is being written.
+    ///
+    /// With the `threadless` feature, frames encoded by `pump()` are written out first,
+    /// then the remaining frames are encoded on this thread. To write frames as they're pumped, use `set_output()` and `finish()` instead.
     #[allow(unused_mut)]
-    pub fn write<W: Write>(self, mut writer: W, reporter: &mut dyn ProgressReporter) -> CatResult<()> {
+    pub fn write<W: Write>(mut self, writer: W, reporter: &mut dyn ProgressReporter) -> CatResult<()> {
+        if let Some(max_size) = self.max_size.take() {
+            return maxsize::write_max_size(self, max_size, writer, reporter);
+        }
+
+        let bytes_written = Arc::new(AtomicU64::new(0));
+        let writer = CountingWriter { writer, bytes_written: bytes_written.clone(), timings: self.timings.clone() };
+        let mut writer = HeaderSkipper { writer, header: if self.resume_after > 0 { Some(Vec::new()) } else { None } };
 
         #[cfg(feature = "gifsicle")]
         {
-            if self.settings.quality < 100 {
+            if self.settings.gifsicle_loss() > 0 {
                 let mut gifsicle = encodegifsicle::Gifsicle::new(self.settings.gifsicle_loss(), &mut writer);
-                return self.write_with_encoder(&mut gifsicle, reporter);
+                return self.write_with_encoder(&mut gifsicle, &bytes_written, reporter);
             }
         }
-        self.write_with_encoder(&mut encoderust::RustEncoder::new(writer), reporter)
+        let mut encoder = encoderust::RustEncoder::new(writer);
+        self.write_with_encoder(&mut encoder, &bytes_written, reporter)
+    }
+
+    /// Processes frames like `write()`, but doesn't compress a GIF.
+    ///
+    /// This is for the callbacks, e.g. `set_output_frame_callback()`. `set_max_size()` is ignored.
+    pub fn write_frames_only(mut self, reporter: &mut dyn ProgressReporter) -> CatResult<()> {
+        self.max_size = None;
+        self.write_with_encoder(&mut NoEncoder, &AtomicU64::new(0), reporter)
     }
 
-    fn write_with_encoder(mut self, encoder: &mut dyn Encoder, reporter: &mut dyn ProgressReporter) -> CatResult<()> {
-        let decode_queue_recv = self.queue_iter.take().ok_or(Error::Aborted)?;
+    #[cfg(not(feature = "threadless"))]
+    fn write_with_encoder(mut self, encoder: &mut dyn Encoder, bytes_written: &AtomicU64, reporter: &mut dyn ProgressReporter) -> CatResult<()> {
+        #[cfg(feature = "openmp")]
+        {
+            extern "C" {
+                fn omp_set_num_threads(num_threads: std::os::raw::c_int);
+            }
+            if self.settings.deterministic {
+                unsafe { omp_set_num_threads(1) };
+            } else if self.settings.threads > 0 {
+                unsafe { omp_set_num_threads(self.settings.threads.into()) };
+            }
+        }
 
         let settings = self.settings;
-        let (quant_queue, quant_queue_recv) = crossbeam_channel::bounded(4);
-        let diff_thread = thread::Builder::new().name("diff".into()).spawn(move || {
-            Self::make_diffs(decode_queue_recv, quant_queue, &settings)
-        })?;
-        let (remap_queue, remap_queue_recv) = crossbeam_channel::bounded(8);
-        let quant_thread = thread::Builder::new().name("quant".into()).spawn(move || {
-            Self::quantize_frames(quant_queue_recv, remap_queue, &settings)
+        // the first frame shows whether frames are big enough to be worth spreading across threads
+        let (frames, inline): (Box<dyn FnOnce() -> CatResult<DecodedFrames<'static>> + Send>, bool) = match self.replay.take() {
+            Some(replay) => {
+                let mut replay = replay.peekable();
+                let inline = matches!(replay.peek(), Some(Ok((image, ..))) if image.width() * image.height() <= INLINE_MAX_PIXELS);
+                (Box::new(move || Ok(Box::new(replay) as DecodedFrames<'static>)), inline)
+            },
+            None => {
+                let mut decode_queue_recv = self.queue_iter.take().ok_or(Error::Aborted)?;
+                let timings = self.timings.clone();
+                let mut received = std::iter::from_fn(move || timings.wait(Waiting::InputEmpty, || decode_queue_recv.next()));
+                let mut input = InputStage::new(settings, self.timings.clone(), self.pools.clone());
+                let first = received.next().map(|res| res.and_then(|frame| input.decode(frame)));
+                let inline = matches!(&first, Some(Ok((image, ..))) if image.width() * image.height() <= INLINE_MAX_PIXELS);
+                let inputs = first.into_iter().chain(received.map(move |res| res.and_then(|frame| input.decode(frame))));
+                (Box::new(move || sequence_effects(Box::new(inputs), &settings)), inline)
+            },
+        };
+        let recording = self.recording.take();
+        let frames = move || frames().map(|frames| maxsize::recorded(frames, recording));
+
+        let memory_limit = self.memory_limit.take();
+        let mut diffs = DiffStage::new(settings, self.resume_after, memory_limit.clone(), self.last_frame_duration.clone(), self.timings.clone(), self.pools.clone());
+        let mut quantize = QuantizeStage::new(settings, std::mem::take(&mut self.fixed_palette), std::mem::take(&mut self.fixed_colors), self.timings.clone(), self.pools.clone());
+        let mut remap = RemapStage::new(settings, self.timings.clone(), self.pools.clone());
+        let mut write = WriteStage::new(settings, self.output_frame_callback.take(), self.poster.take(), self.timings.clone(), self.pools.clone());
+
+        if inline {
+            let mut optimize = if settings.optimize_frames { Some(OptimizeStage::new(self.timings.clone())) } else { None };
+            let mut write_frame = |msg| optimized(&mut optimize, msg, &mut |msg| write.push(msg, encoder, bytes_written, reporter));
+            for frame in frames()? {
+                diffs.push(frame?, &mut |msg| quantize.push(msg, &mut |msg| remap.push(msg, &mut write_frame)))?;
+            }
+            diffs.finish(&mut |msg| quantize.push(msg, &mut |msg| remap.push(msg, &mut write_frame)))?;
+            remap.finish(&mut write_frame)?;
+            write.finish(encoder)?;
+            reporter.stage_times(&self.timings.stage_times());
+            return Ok(());
+        }
+
+        // the memory limit counts frames between every pair of stages, not only the frames being added
+        let (remap_queue, remap_queue_recv) = ordqueue::new(settings.queue_depth(8), memory_limit.as_ref());
+        let mut quantize = QuantizeWorkers::new(quantize, remap_queue, &settings, self.timings.clone());
+        let diff_thread = thread::Builder::new().name("diff".into()).spawn(move || -> CatResult<()> {
+            let mut emit = |msg| quantize.push(msg);
+            for frame in frames()? {
+                diffs.push(frame?, &mut emit)?;
+            }
+            let res = diffs.finish(&mut emit);
+            quantize.finish().and(res)
         })?;
-        let (write_queue, write_queue_recv) = crossbeam_channel::bounded(6);
-        let remap_thread = thread::Builder::new().name("remap".into()).spawn(move || {
-            Self::remap_frames(remap_queue_recv, write_queue, &settings)
+        let (write_queue, mut write_queue_recv) = ordqueue::new(settings.queue_depth(6), memory_limit.as_ref());
+        let (write_queue, optimize_thread) = if settings.optimize_frames {
+            let (optimize_queue, mut optimize_queue_recv) = ordqueue::new(settings.queue_depth(6), memory_limit.as_ref());
+            let mut optimize = OptimizeStage::new(self.timings.clone());
+            let timings = self.timings.clone();
+            let optimize_thread = thread::Builder::new().name("optimize".into()).spawn(move || -> CatResult<()> {
+                let mut emit = send_to(write_queue, &timings);
+                while let Some(msg) = timings.wait(Waiting::WriteEmpty, || optimize_queue_recv.next()) {
+                    optimize.push(msg, &mut emit)?;
+                }
+                Ok(())
+            })?;
+            (optimize_queue, Some(optimize_thread))
+        } else {
+            (write_queue, None)
+        };
+        let timings = self.timings.clone();
+        let remap_thread = thread::Builder::new().name("remap".into()).spawn(move || -> CatResult<()> {
+            let mut remap_queue_recv = remap_queue_recv;
+            let mut emit = send_to(write_queue, &timings);
+            while let Some(msg) = timings.wait(Waiting::RemapEmpty, || remap_queue_recv.next()) {
+                remap.push(msg, &mut emit)?;
+            }
+            remap.finish(&mut emit)
         })?;
-        Self::write_frames(write_queue_recv, encoder, &self.settings, reporter)?;
+        loop {
+            match self.timings.wait(Waiting::WriteEmpty, || write_queue_recv.next_timeout(STAGE_PROGRESS_INTERVAL)) {
+                Ok(msg) => write.push(msg, encoder, bytes_written, reporter)?,
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => {
+                    if !reporter.stage_progress(&self.timings.stage_progress()) {
+                        return Err(Error::Aborted);
+                    }
+                },
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        write.finish(encoder)?;
         diff_thread.join().map_err(|_| Error::ThreadSend)??;
-        quant_thread.join().map_err(|_| Error::ThreadSend)??;
         remap_thread.join().map_err(|_| Error::ThreadSend)??;
+        if let Some(optimize_thread) = optimize_thread {
+            optimize_thread.join().map_err(|_| Error::ThreadSend)??;
+        }
+        reporter.stage_times(&self.timings.stage_times());
         Ok(())
     }
+}
 
-    fn make_diffs(mut inputs: OrdQueueIter<DecodedImage>, quant_queue: Sender<DiffMessage>, settings: &Settings) -> CatResult<()> {
-        let (first_frame, first_frame_pts) = inputs.next().transpose()?.ok_or(Error::NoFrames)?;
-        let mut prev_frame_pts = -1.0;
+/// Frames up to this size are encoded on the writer's thread,
+/// because for them passing frames between threads takes longer than encoding
+#[cfg(not(feature = "threadless"))]
+const INLINE_MAX_PIXELS: usize = 128 * 128;
+
+/// How long the writer waits for a frame before it reports progress of the earlier stages
+#[cfg(not(feature = "threadless"))]
+const STAGE_PROGRESS_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Frames are remapped in bands of rows with at most this many pixels (unless a single row is longer)
+const REMAP_BAND_PIXELS: usize = 1 << 24;
+
+/// Rows above each band that are remapped again, only to carry dithering errors into the band
+const REMAP_BAND_CONTEXT_ROWS: usize = 16;
+
+/// Stage output that goes to the next stage's thread
+#[cfg(not(feature = "threadless"))]
+fn send_to(mut queue: OrdQueue<FrameMessage>, timings: &Timings) -> impl FnMut(FrameMessage) -> CatResult<()> + '_ {
+    let mut index = 0;
+    move |msg| {
+        let bytes = msg.memory_size();
+        timings.wait(Waiting::WriteFull, || queue.push_sized(index, msg, bytes))?;
+        index += 1;
+        Ok(())
+    }
+}
 
-        let mut denoiser = Denoiser::new(first_frame.width(), first_frame.height(), settings.quality);
+type DecodedFrame = (ImgVec<RGBA8>, f64, Option<ImgVec<u8>>);
+type DecodedFrames<'a> = Box<dyn Iterator<Item = CatResult<DecodedFrame>> + 'a>;
 
-        let first_frame_has_transparency = first_frame.pixels().any(|px| px.a < 128);
+/// Passes frames through, and then repeats them in reverse order (without repeating the first and last frame).
+/// Long inputs are buffered on disk.
+struct Bounce<I> {
+    inputs: I,
+    played: FrameStore,
+    last_pts: f64,
+    reversing: bool,
+}
 
-        let mut next_frame = Some((first_frame, first_frame_pts));
-        let mut ordinal_frame_number = 0;
-        loop {
-            // NB! There are two interleaved loops here:
-            //  - one to feed the denoiser
-            //  - the other to process denoised frames
-            //
-            // The denoiser buffers five frames, so these two loops process different frames!
-            // But need to be interleaved in one `loop{}` to get frames falling out of denoiser's buffer.
-
-            ////////////////////// Feed denoiser: /////////////////////
-
-            let curr_frame = next_frame.take();
-            next_frame = inputs.next().transpose()?;
-
-            if let Some((image, mut pts)) = curr_frame {
-                pts -= first_frame_pts;
-                ordinal_frame_number += 1;
-
-                let dispose = if let Some((next, _)) = &next_frame {
-                    if next.width() != image.width() || next.height() != image.height() {
-                        return Err(Error::WrongSize(format!("Frame {} has wrong size ({}×{}, expected {}×{})", ordinal_frame_number,
-                            next.width(), next.height(), image.width(), image.height())));
-                    }
+impl<I> Bounce<I> {
+    pub fn new(inputs: I, memory_limit_mb: u32) -> Self {
+        Self { inputs, played: FrameStore::new(memory_limit_mb), last_pts: 0., reversing: false }
+    }
+}
 
-                    // Skip identical frames
-                    if next.as_ref() == image.as_ref() {
-                        prev_frame_pts = pts;
-                        continue;
-                    }
+impl<I: Iterator<Item = CatResult<DecodedFrame>>> Iterator for Bounce<I> {
+    type Item = CatResult<DecodedFrame>;
 
-                    // If the next frame becomes transparent, this frame has to clear to bg for it
-                    if next.pixels().zip(image.pixels()).any(|(next, curr)| next.a < curr.a) {
-                        gif::DisposalMethod::Background
-                    } else {
-                        gif::DisposalMethod::Keep
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.reversing {
+            match self.inputs.next() {
+                Some(Ok(frame)) => {
+                    self.last_pts = frame.1;
+                    if let Err(err) = self.played.push(frame.clone()) {
+                        return Some(Err(err));
                     }
-                } else {
-                    if first_frame_has_transparency {
-                        // Last frame should reset to background to avoid breaking transparent looped anims
-                        gif::DisposalMethod::Background
-                    } else {
-                        // macOS preview gets Background wrong
-                        gif::DisposalMethod::Keep
+                    return Some(Ok(frame));
+                },
+                Some(Err(err)) => return Some(Err(err)),
+                None => {
+                    self.reversing = true;
+                    // the last frame has been played already
+                    if let Some(Err(err)) = self.played.pop() {
+                        return Some(Err(err));
                     }
-                };
-
-                // conversion from pts to delay
-                let end_pts = if let Some((_, next_pts)) = next_frame {
-                    next_pts - first_frame_pts
-                } else if first_frame_pts > 1. / 100. {
-                    // this is gifski's weird rule that non-zero first-frame pts
-                    // shifts the whole anim and is the delay of the last frame
-                    pts + first_frame_pts
-                } else {
-                    // otherwise assume steady framerate
-                    pts + (pts - prev_frame_pts)
-                };
-                debug_assert!(end_pts > 0.);
-                prev_frame_pts = pts;
-
-                denoiser.push_frame(image.as_ref(), (ordinal_frame_number, end_pts, dispose));
-                if next_frame.is_none() {
-                    denoiser.flush();
-                }
+                },
             }
+        }
+        // the first frame will be played by looping
+        if self.played.len() > 1 {
+            let last_pts = self.last_pts;
+            Some(self.played.pop()?.map(|(image, pts, importance_map)| (image, 2. * last_pts - pts, importance_map)))
+        } else {
+            None
+        }
+    }
+}
 
-            ////////////////////// Consume denoised frames /////////////////////
+/// Keeps track of the file size for progress reporting
+struct CountingWriter<W> {
+    writer: W,
+    bytes_written: Arc<AtomicU64>,
+    timings: Arc<Timings>,
+}
 
-            let (importance_map, image, (ordinal_frame_number, end_pts, dispose)) = match denoiser.pop() {
-                Denoised::Done => {
-                    debug_assert!(next_frame.is_none());
-                    break
-                },
-                Denoised::NotYet => continue,
-                Denoised::Frame { importance_map, frame, meta } => ( importance_map, frame, meta ),
-            };
-
-            let (importance_map, ..) = importance_map.into_contiguous_buf();
-
-            quant_queue.send(DiffMessage {
-                dispose,
-                importance_map,
-                ordinal_frame_number,
-                image,
-                end_pts,
-            })?;
-        }
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let start = Instant::now();
+        let n = self.writer.write(buf);
+        self.timings.add(&self.timings.write, start);
+        let n = n?;
+        self.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
 
-        Ok(())
+    fn flush(&mut self) -> std::io::Result<()> {
+        let start = Instant::now();
+        let res = self.writer.flush();
+        self.timings.add(&self.timings.write, start);
+        res
     }
+}
 
-    fn quantize_frames(inputs: Receiver<DiffMessage>, remap_queue: Sender<RemapMessage>, settings: &Settings) -> CatResult<()> {
-        let mut prev_frame_keeps = false;
-        while let Some(DiffMessage {image, end_pts, dispose, ordinal_frame_number, mut importance_map}) = inputs.recv().ok() {
-            if !prev_frame_keeps || importance_map.iter().any(|&px| px > 0) {
-                let (liq, remap, liq_image) = Self::quantize(image.as_ref(), &importance_map, ordinal_frame_number > 1, settings)?;
-                let max_loss = settings.gifsicle_loss();
-                for imp in &mut importance_map {
-                    // encoding assumes rgba background looks like encoded background, which is not true for lossy
-                    *imp = ((256 - (*imp) as u32) * max_loss / 256).min(255) as u8;
-                }
-                remap_queue.send(RemapMessage {
-                    ordinal_frame_number,
-                    end_pts,
-                    dispose,
-                    liq, remap,
-                    liq_image,
-                })?;
-            }
-            prev_frame_keeps = dispose == gif::DisposalMethod::Keep;
+/// Drops everything before the first frame of a GIF file
+struct HeaderSkipper<W> {
+    writer: W,
+    /// Until the header is complete. `None` if it has been skipped, or isn't to be skipped.
+    header: Option<Vec<u8>>,
+}
+
+impl<W: Write> Write for HeaderSkipper<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let header = match &mut self.header {
+            Some(header) => header,
+            None => return self.writer.write(buf),
+        };
+        header.extend_from_slice(buf);
+        if let Some(len) = gif_header_len(header) {
+            let rest = header.split_off(len);
+            self.header = None;
+            self.writer.write_all(&rest)?;
         }
-        Ok(())
+        Ok(buf.len())
     }
 
-    fn remap_frames(inputs: Receiver<RemapMessage>, write_queue: Sender<FrameMessage>, settings: &Settings) -> CatResult<()> {
-        let next_frame = inputs.recv().map_err(|_| Error::NoFrames)?;
-        let mut screen = gif_dispose::Screen::new(next_frame.liq_image.width(), next_frame.liq_image.height(), RGBA8::new(0, 0, 0, 0), None);
-
-        let mut next_frame = Some(next_frame);
-
-        let mut first_frame = true;
-        while let Some(RemapMessage {ordinal_frame_number, end_pts, dispose, liq, remap, liq_image}) = {
-            // that's not the while loop, that block gets the next element
-            let curr_frame = next_frame.take();
-            next_frame = inputs.recv().ok();
-            curr_frame
-        } {
-            let screen_width = screen.pixels.width() as u16;
-            let screen_height = screen.pixels.height() as u16;
-            let mut screen_after_dispose = screen.dispose();
-
-            let (mut image8, mut image8_pal) = {
-                let bg = if !first_frame { Some(screen_after_dispose.pixels()) } else { None };
-                Self::remap(liq, remap, liq_image, bg, settings)?
-            };
-
-            // Palette may have multiple transparent indices :(
-            let mut transparent_index = None;
-            for (i, p) in image8_pal.iter_mut().enumerate() {
-                if p.a <= 128 {
-                    p.a = 0;
-                    let new_index = i as u8;
-                    if let Some(old_index) = transparent_index {
-                        image8.pixels_mut().filter(|px| **px == new_index).for_each(|px| *px = old_index);
-                    } else {
-                        transparent_index = Some(new_index);
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Length of the signature, screen descriptor, global palette and extensions before the first frame,
+/// or `None` if more data is needed
+fn gif_header_len(data: &[u8]) -> Option<usize> {
+    let flags = *data.get(10)?;
+    let mut pos = 13 + if flags & 0x80 != 0 { 3 << ((flags & 7) + 1) } else { 0 };
+    loop {
+        match (*data.get(pos)?, *data.get(pos + 1)?) {
+            // application or comment extension
+            (0x21, label) if label != 0xF9 => {
+                pos += 2;
+                loop {
+                    let len = usize::from(*data.get(pos)?);
+                    pos += 1 + len;
+                    if len == 0 {
+                        break;
                     }
                 }
-            }
+            },
+            // graphic control, image, or end
+            _ => return Some(pos),
+        }
+    }
+}
 
-            // Check that palette is fine and has no duplicate transparent indices
-            debug_assert!(image8_pal.iter().enumerate().all(|(idx, color)| {
-                Some(idx as u8) == transparent_index || color.a > 128 || !image8.pixels().any(|px| px == idx as u8)
-            }));
+/// There's no clock on wasm32-unknown-unknown without JS bindings, so stage times are zero there
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+#[derive(Copy, Clone)]
+struct Instant;
 
-            let (left, top, image8) = if !first_frame && next_frame.is_some() {
-                match trim_image(image8, &image8_pal, transparent_index, screen_after_dispose.pixels()) {
-                    Some(trimmed) => trimmed,
-                    None => continue, // no pixels left
-                }
-            } else {
-                // must keep first and last frame
-                (0, 0, image8)
-            };
-
-            screen_after_dispose.then_blit(Some(&image8_pal), dispose, left, top as _, image8.as_ref(), transparent_index)?;
-
-            let frame = GIFFrame {
-                left,
-                top,
-                screen_width,
-                screen_height,
-                image: image8,
-                pal: image8_pal,
-                transparent_index,
-                dispose,
-            };
-
-            write_queue.send(FrameMessage {
-                ordinal_frame_number,
-                end_pts,
-                frame,
-            })?;
+#[cfg(all(target_arch = "wasm32", target_os = "unknown"))]
+impl Instant {
+    fn now() -> Self {
+        Instant
+    }
+
+    fn elapsed(&self) -> Duration {
+        Duration::from_secs(0)
+    }
+}
+
+/// Time spent in each stage, in nanoseconds, added up from all threads
+#[derive(Default)]
+struct Timings {
+    frames: AtomicUsize,
+    /// Frames that got a palette, for `ProgressReporter::stage_progress`
+    quantized: AtomicUsize,
+    decode: AtomicU64,
+    resize: AtomicU64,
+    denoise: AtomicU64,
+    quantize: AtomicU64,
+    remap: AtomicU64,
+    optimize: AtomicU64,
+    lzw: AtomicU64,
+    write: AtomicU64,
+    #[cfg(feature = "metrics")]
+    metrics: metrics::Metrics,
+}
+
+/// Queues between stages, and the side of them that waits. Measured only with the `metrics` feature.
+#[derive(Copy, Clone)]
+#[cfg_attr(feature = "threadless", allow(dead_code))]
+pub(crate) enum Waiting {
+    InputEmpty,
+    /// Adding a frame waits for room in the queue, or for the memory limit
+    InputFull,
+    QuantizeEmpty,
+    QuantizeFull,
+    RemapEmpty,
+    RemapFull,
+    WriteEmpty,
+    WriteFull,
+}
 
-            first_frame = false;
+impl Timings {
+    fn add(&self, stage: &AtomicU64, start: Instant) {
+        stage.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// The encoder writes as it compresses, so time spent writing is taken out of the LZW time
+    fn start_lzw(&self) -> (Instant, u64) {
+        (Instant::now(), self.write.load(Ordering::Relaxed))
+    }
+
+    fn add_lzw(&self, (start, write_before): (Instant, u64)) {
+        let write_time = self.write.load(Ordering::Relaxed) - write_before;
+        self.lzw.fetch_add((start.elapsed().as_nanos() as u64).saturating_sub(write_time), Ordering::Relaxed);
+    }
+
+    /// Runs `f`, which waits on a queue
+    #[inline]
+    fn wait<R>(&self, waiting: Waiting, f: impl FnOnce() -> R) -> R {
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+        let res = f();
+        #[cfg(feature = "metrics")]
+        self.metrics.add_wait(waiting, start);
+        #[cfg(not(feature = "metrics"))]
+        let _ = waiting;
+        res
+    }
+
+    #[cfg(not(feature = "threadless"))]
+    fn stage_progress(&self) -> StageProgress {
+        StageProgress {
+            decoded: self.frames.load(Ordering::Relaxed),
+            quantized: self.quantized.load(Ordering::Relaxed),
         }
-        Ok(())
+    }
+
+    fn stage_times(&self) -> StageTimes {
+        let get = |stage: &AtomicU64| Duration::from_nanos(stage.load(Ordering::Relaxed));
+        StageTimes {
+            frames: self.frames.load(Ordering::Relaxed),
+            decode: get(&self.decode),
+            resize: get(&self.resize),
+            denoise: get(&self.denoise),
+            quantize: get(&self.quantize),
+            remap: get(&self.remap),
+            optimize: get(&self.optimize),
+            lzw: get(&self.lzw),
+            write: get(&self.write),
+            #[cfg(feature = "metrics")]
+            waits: self.metrics.queue_waits(),
+            #[cfg(not(feature = "metrics"))]
+            waits: Default::default(),
+        }
+    }
+}
+
+/// No channel of any pixel differs by more than `threshold`. Fully transparent pixels are the same regardless of their color.
+fn frames_match(a: ImgRef<'_, RGBA8>, b: ImgRef<'_, RGBA8>, threshold: u8) -> bool {
+    if threshold == 0 {
+        return a == b;
+    }
+    a.rows().zip(b.rows()).all(|(a, b)| simd::rows_close(a, b, threshold))
+}
+
+/// Importance map is 0 where the frame doesn't change, so its coverage is a measure of motion.
+/// Static frames get a fraction of the loss, and frames that change entirely get up to 1.75× more.
+fn adaptive_loss(max_loss: u32, importance_map: &[u8]) -> u32 {
+    if importance_map.is_empty() {
+        return max_loss;
+    }
+    let changed = importance_map.iter().filter(|&&px| px > 0).count();
+    let motion = changed as f32 / importance_map.len() as f32;
+    (max_loss as f32 * (0.25 + 1.5 * motion)).round() as u32
+}
+
+/// Encoding assumes rgba background looks like encoded background, which is not true for lossy.
+/// Scaled against the loss used for the frame, which with `adaptive_loss` can be lower than the maximum.
+fn scale_importance_to_loss(importance_map: &mut [u8], loss: u32) {
+    for imp in importance_map {
+        *imp = ((256 - u32::from(*imp)) * loss / 256).min(255) as u8;
+    }
+}
+
+/// Pixels that are already on screen are made transparent, so that the LZW compression gets longer runs,
+/// and then the frame is cropped to the pixels that are left.
+fn optimize_frame(frame: &mut GIFFrame, screen: ImgRef<'_, RGBA8>) {
+    let transparent_index = match frame.transparent_index {
+        Some(idx) => idx,
+        None => return,
+    };
+    let pal = &frame.pal;
+    for (row, screen_row) in frame.image.rows_mut().zip(screen.rows()) {
+        for (px, bg) in row.iter_mut().zip(screen_row) {
+            if pal.get(*px as usize) == Some(bg) {
+                *px = transparent_index;
+            }
+        }
+    }
+
+    // Background disposal clears the whole area of the frame, so it can't shrink
+    if !matches!(frame.dispose, gif::DisposalMethod::Keep | gif::DisposalMethod::Any) {
+        return;
+    }
+
+    let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+    for (y, row) in frame.image.rows().enumerate() {
+        for (x, &px) in row.iter().enumerate() {
+            if px != transparent_index {
+                min_x = min_x.min(x);
+                max_x = max_x.max(x);
+                min_y = min_y.min(y);
+                max_y = max_y.max(y);
+            }
+        }
+    }
+    if min_x > max_x {
+        // nothing changes, but the frame has to stay for its delay
+        min_x = 0; max_x = 0; min_y = 0; max_y = 0;
+    }
+
+    let (width, height) = (max_x + 1 - min_x, max_y + 1 - min_y);
+    if width != frame.image.width() || height != frame.image.height() {
+        let image = std::mem::replace(&mut frame.image, ImgVec::new(Vec::new(), 0, 0));
+        frame.image = cropped_in_place(image, min_x, min_y, width, height);
+        frame.left += min_x as u16;
+        frame.top += min_y as u16;
     }
 }
 
 fn trim_image(mut image8: ImgVec<u8>, image8_pal: &[RGBA8], transparent_index: Option<u8>, screen: ImgRef<RGBA8>) -> Option<(u16, u16, ImgVec<u8>)> {
     let mut image_trimmed = image8.as_ref();
+    let palette = simd::PaletteLookup::new(image8_pal, transparent_index);
 
     let bottom = image_trimmed.rows().zip(screen.rows()).rev()
-        .take_while(|(img_row, screen_row)| {
-            img_row.iter().copied().zip(screen_row.iter().copied())
-                .all(|(px, bg)| {
-                    Some(px) == transparent_index || image8_pal.get(px as usize) == Some(&bg)
-                })
-        })
+        .take_while(|(img_row, screen_row)| palette.row_matches(img_row, screen_row))
         .count();
 
     if bottom > 0 {
@@ -615,12 +1583,7 @@ fn trim_image(mut image8: ImgVec<u8>, image8_pal: &[RGBA8], transparent_index: O
     }
 
     let top = image_trimmed.rows().zip(screen.rows())
-        .take_while(|(img_row, screen_row)| {
-            img_row.iter().copied().zip(screen_row.iter().copied())
-                .all(|(px, bg)| {
-                    Some(px) == transparent_index || image8_pal.get(px as usize) == Some(&bg)
-                })
-        })
+        .take_while(|(img_row, screen_row)| palette.row_matches(img_row, screen_row))
         .count();
 
     if top > 0 {
@@ -628,9 +1591,279 @@ fn trim_image(mut image8: ImgVec<u8>, image8_pal: &[RGBA8], transparent_index: O
     }
 
     if image_trimmed.height() != image8.height() {
-        let (buf, width, height) = image_trimmed.to_contiguous_buf();
-        image8 = Img::new(buf.into_owned(), width, height);
+        let (width, height) = (image_trimmed.width(), image_trimmed.height());
+        image8 = cropped_in_place(image8, 0, top, width, height);
     }
 
     Some((0, top as _, image8))
 }
+
+/// Same as `sub_image(…).to_contiguous_buf()`, but reuses the image's buffer.
+/// Rows only ever move towards the start of the buffer, so they can be copied over in order.
+fn cropped_in_place<T: Copy>(image: ImgVec<T>, left: usize, top: usize, width: usize, height: usize) -> ImgVec<T> {
+    assert!(left + width <= image.width() && top + height <= image.height());
+    let stride = image.stride();
+    let mut buf = image.into_buf();
+    for y in 0..height {
+        let start = (top + y) * stride + left;
+        buf.copy_within(start..start + width, y * width);
+    }
+    buf.truncate(width * height);
+    ImgVec::new(buf, width, height)
+}
+
+#[test]
+fn importance_map_follows_crop() {
+    // each value is its position in the uncropped frame
+    let map = ImgVec::new((0..8 * 4).map(|i| i as u8).collect(), 8, 4);
+    let crop = Crop { left: 4, top: 1, width: 4, height: 2 };
+    let no_filters = Filters::new(&Settings::default());
+    let cropped = importance_map_like_frame(map.clone(), None, None, None, Some(crop), &no_filters);
+    assert_eq!((4, 2), (cropped.width(), cropped.height()));
+    assert_eq!(&[12, 13, 14, 15], &cropped[0]);
+    assert_eq!(&[20, 21, 22, 23], &cropped[1]);
+
+    let scaled = importance_map_like_frame(map.clone(), None, None, Some(0.5), Some(crop), &no_filters);
+    assert_eq!((2, 1), (scaled.width(), scaled.height()));
+    assert_eq!(&[12, 14], &scaled[0]);
+}
+
+#[test]
+fn importance_map_follows_crop_and_pad() {
+    let settings = Settings { crop: Some(Crop { left: 4, top: 0, width: 4, height: 4 }), pad: Some(Pad { aspect_width: 2, aspect_height: 1, color: RGBA8::new(1, 2, 3, 255) }), ..Settings::default() };
+    let filters = Filters::new(&settings);
+    let frame = Img::new(Cow::Owned(vec![RGBA8::new(9, 9, 9, 255); 8 * 4]), 8, 4);
+    let frame = Collector::resized_binary_alpha(frame, None, None, None, settings.crop, filters, None, &Pools::new()).unwrap();
+
+    let map = ImgVec::new((0..8 * 4).map(|i| i as u8 + 1).collect(), 8, 4);
+    let map = importance_map_like_frame(map, None, None, None, settings.crop, &filters);
+    assert_eq!((frame.width(), frame.height()), (map.width(), map.height()));
+    // the cropped 4×4 area is centered on an 8×4 canvas, and the borders aren't important
+    for (y, (frame_row, map_row)) in frame.rows().zip(map.rows()).enumerate() {
+        let first = 8 * y as u8 + 5;
+        assert_eq!(&[0, 0, first, first + 1, first + 2, first + 3, 0, 0], map_row);
+        assert!(frame_row[..2].iter().chain(&frame_row[6..]).all(|&px| px == RGBA8::new(1, 2, 3, 255)));
+    }
+}
+
+#[test]
+fn memory_limit_smaller_than_frames() {
+    // each frame alone is over the limit, so every stage can only hold its next frame
+    let settings = Settings { memory_limit_mb: 1, optimize_frames: true, ..Settings::default() };
+    let (mut collector, writer) = new(settings).unwrap();
+    let adding = std::thread::spawn(move || {
+        for n in 0..12u8 {
+            let frame = ImgVec::new(vec![RGBA8::new(n * 20, 0, 255 - n * 20, 255); 640 * 480], 640, 480);
+            collector.add_frame_rgba(n.into(), frame, f64::from(n) / 10.).unwrap();
+        }
+    });
+    let mut gif = Vec::new();
+    writer.write(&mut gif, &mut NoProgress {}).unwrap();
+    adding.join().unwrap();
+    let mut decoder = gif::DecodeOptions::new().read_info(&gif[..]).unwrap();
+    let mut frames = 0;
+    while decoder.read_next_frame().unwrap().is_some() {
+        frames += 1;
+    }
+    assert_eq!(12, frames);
+}
+
+#[test]
+fn deterministic_output_is_identical() {
+    let encode = |threads| {
+        // frames over the memory limit are kept for `bounce` in a temporary file, only 2 fit in memory
+        let settings = Settings { deterministic: true, threads, reuse_palettes: true, bounce: true, memory_limit_mb: 1, ..Settings::default() };
+        let (mut collector, writer) = new(settings).unwrap();
+        let adding = std::thread::spawn(move || {
+            for n in 0..8u32 {
+                let frame = ImgVec::new((0..320 * 240).map(|i| {
+                    let (x, y) = (i % 320, i / 320);
+                    // every other frame is nearly the same as the one before, so it can use its palette again
+                    let shift = n / 2 * 30 + n % 2;
+                    RGBA8::new((x + shift) as u8, (y + shift) as u8, ((x ^ y) / 4) as u8, 255)
+                }).collect(), 320, 240);
+                collector.add_frame_rgba(n as usize, frame, f64::from(n) / 10.).unwrap();
+            }
+        });
+        let mut gif = Vec::new();
+        writer.write(&mut gif, &mut NoProgress {}).unwrap();
+        adding.join().unwrap();
+        gif
+    };
+    let gif = encode(0);
+    assert!(gif == encode(0));
+    assert!(gif == encode(1));
+}
+
+#[test]
+fn last_frame_duration() {
+    let (mut collector, writer) = new(Settings::default()).unwrap();
+    for n in 0..3u8 {
+        collector.add_frame_rgba(n.into(), ImgVec::new(vec![RGBA8::new(n * 100, 0, 0, 255); 4 * 4], 4, 4), f64::from(n) / 10.).unwrap();
+    }
+    collector.set_last_frame_duration(0.5);
+    drop(collector);
+    let mut gif = Vec::new();
+    writer.write(&mut gif, &mut NoProgress {}).unwrap();
+    let mut decoder = gif::DecodeOptions::new().read_info(&gif[..]).unwrap();
+    let mut delays = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().unwrap() {
+        delays.push(frame.delay);
+    }
+    assert_eq!(vec![10, 10, 50], delays);
+}
+
+#[test]
+#[cfg(feature = "gifsicle")]
+fn output_frames_match_lossy_gif() {
+    let encode = |out: Option<&mut Vec<u8>>| {
+        let settings = Settings { lossy: LossyMode::Loss(200), ..Settings::default() };
+        let (mut collector, mut writer) = new(settings).unwrap();
+        let frames = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let callback_frames = frames.clone();
+        writer.set_output_frame_callback(move |frame, _| callback_frames.lock().unwrap().push(frame.to_contiguous_buf().0.into_owned()));
+        for n in 0..3u32 {
+            let frame = ImgVec::new((0..64 * 48).map(|i| RGBA8::new((i * 7 + n * 40) as u8, (i / 64 * 5) as u8, (i % 13 * 19) as u8, 255)).collect(), 64, 48);
+            collector.add_frame_rgba(n as usize, frame, f64::from(n) / 10.).unwrap();
+        }
+        drop(collector);
+        match out {
+            Some(gif) => writer.write(gif, &mut NoProgress {}).unwrap(),
+            None => writer.write_frames_only(&mut NoProgress {}).unwrap(),
+        }
+        let frames = frames.lock().unwrap().clone();
+        frames
+    };
+    let mut gif = Vec::new();
+    let frames = encode(Some(&mut gif));
+    assert_eq!(frames, encode(None));
+
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(&gif[..]).unwrap();
+    let mut screen = gif_dispose::Screen::new_decoder(&decoder);
+    let mut n = 0;
+    while let Some(frame) = decoder.read_next_frame().unwrap() {
+        screen.blit_frame(frame).unwrap();
+        assert!(screen.pixels.buf() == &frames[n], "frame {}", n);
+        n += 1;
+    }
+    assert_eq!(3, n);
+}
+
+#[test]
+#[cfg(not(feature = "threadless"))]
+fn stage_progress_can_abort() {
+    struct AbortWaiting(Vec<StageProgress>);
+    impl ProgressReporter for AbortWaiting {
+        fn increase(&mut self) -> bool {
+            true
+        }
+        fn stage_progress(&mut self, progress: &StageProgress) -> bool {
+            self.0.push(*progress);
+            false
+        }
+        fn done(&mut self, _: &str) {}
+    }
+
+    let (mut collector, writer) = new(Settings::default()).unwrap();
+    collector.add_frame_rgba(0, ImgVec::new(vec![RGBA8::new(255, 0, 0, 255); 640 * 480], 640, 480), 0.).unwrap();
+    // the next frame doesn't come in time
+    let adding = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_secs(1));
+        drop(collector);
+    });
+    let mut reporter = AbortWaiting(Vec::new());
+    assert!(matches!(writer.write(&mut Vec::new(), &mut reporter), Err(Error::Aborted)));
+    assert_eq!(1, reporter.0.len());
+    assert_eq!(1, reporter.0[0].decoded);
+    adding.join().unwrap();
+}
+
+#[test]
+#[cfg(feature = "gifsicle")]
+fn interlaced_lossy_rows_in_order() {
+    let settings = Settings { interlace: true, lossy: LossyMode::Loss(20), ..Settings::default() };
+    let (mut collector, writer) = new(settings).unwrap();
+    // rows of one color each, so that a row in the wrong place is off by at least 24
+    let color = |y: usize| RGBA8::new((y * 12) as u8, 255 - (y * 12) as u8, 0, 255);
+    collector.add_frame_rgba(0, ImgVec::new((0..16 * 20).map(|i| color(i / 16)).collect(), 16, 20), 0.).unwrap();
+    drop(collector);
+    let mut gif = Vec::new();
+    writer.write(&mut gif, &mut NoProgress {}).unwrap();
+
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(&gif[..]).unwrap();
+    // reading the pixels deinterlaces them, and clears the flag
+    assert!(decoder.next_frame_info().unwrap().unwrap().interlaced);
+    let mut buffer = vec![0; decoder.buffer_size()];
+    decoder.read_into_buffer(&mut buffer).unwrap();
+    for (y, row) in buffer.chunks(16 * 4).enumerate() {
+        let expected = color(y);
+        for px in row.chunks(4) {
+            assert!(px.iter().zip(expected.as_slice()).all(|(&a, &b)| (i16::from(a) - i16::from(b)).abs() < 16), "row {}: {:?}", y, px);
+        }
+    }
+}
+
+#[test]
+fn poster_is_output_frame() {
+    let poster_at = |pts| {
+        let (mut collector, mut writer) = new(Settings { quality: 50, ..Settings::default() }).unwrap();
+        let frames = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let poster = Arc::new(std::sync::Mutex::new(None));
+        let (callback_frames, callback_poster) = (frames.clone(), poster.clone());
+        writer.set_output_frame_callback(move |frame, _| callback_frames.lock().unwrap().push(frame.to_contiguous_buf().0.into_owned()));
+        writer.set_poster_callback(pts, move |frame| *callback_poster.lock().unwrap() = Some(frame.into_buf()));
+        let input = |n: u32| ImgVec::new((0..64 * 48u32).map(|i| RGBA8::new((i * 7 + n * 40) as u8, (i / 64 * 5) as u8, (i % 13 * 19) as u8, 255)).collect(), 64, 48);
+        for n in 0..3 {
+            collector.add_frame_rgba(n as usize, input(n), f64::from(n) / 10.).unwrap();
+        }
+        drop(collector);
+        writer.write(&mut Vec::new(), &mut NoProgress {}).unwrap();
+        let poster = poster.lock().unwrap().take().unwrap();
+        let frames = frames.lock().unwrap().clone();
+        (poster, frames, input(1).into_buf())
+    };
+    let (poster, frames, input) = poster_at(0.15);
+    assert!(poster == frames[1]);
+    // it's quantized
+    assert!(poster != input);
+    let (poster, frames, _) = poster_at(5.);
+    assert!(poster == frames[2]);
+}
+
+#[test]
+fn frame_filter_runs_after_resize() {
+    let (mut collector, mut writer) = new(Settings { width: Some(32), ..Settings::default() }).unwrap();
+    let adding_thread = std::thread::current().id();
+    collector.set_frame_filter(move |frame, _| {
+        assert_eq!(adding_thread, std::thread::current().id());
+        assert_eq!((32, 24), (frame.width(), frame.height()));
+        frame[(0usize, 0usize)] = RGBA8::new(255, 0, 0, 255);
+    });
+    let first_frame = Arc::new(std::sync::Mutex::new(None));
+    let callback_frame = first_frame.clone();
+    writer.set_output_frame_callback(move |frame, _| {
+        callback_frame.lock().unwrap().get_or_insert_with(|| frame.to_contiguous_buf().0.into_owned());
+    });
+    collector.add_frame_rgba(0, ImgVec::new(vec![RGBA8::new(0, 0, 255, 255); 64 * 48], 64, 48), 0.).unwrap();
+    drop(collector);
+    writer.write(&mut Vec::new(), &mut NoProgress {}).unwrap();
+    let frame = first_frame.lock().unwrap().take().unwrap();
+    // one pixel, not scaled down with the frame
+    assert_eq!(RGBA8::new(255, 0, 0, 255), frame[0]);
+    assert_eq!(RGBA8::new(0, 0, 255, 255), frame[1]);
+}
+
+#[test]
+fn importance_scaled_to_frame_loss() {
+    let max_loss = 100;
+    let importance_map = [0u8, 0, 0, 255];
+    let loss = adaptive_loss(max_loss, &importance_map);
+    assert!(loss < max_loss);
+    let mut scaled = importance_map;
+    scale_importance_to_loss(&mut scaled, loss);
+    assert_eq!([loss as u8, loss as u8, loss as u8, 0], scaled);
+}
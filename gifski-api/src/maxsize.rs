@@ -0,0 +1,162 @@
+//! Encoding again with lower quality until the GIF fits in `Writer::set_max_size()`.
+//!
+//! Frames are decoded, resized and put through the sequence effects only once. They're recorded on the first attempt,
+//! and the later attempts start from the recording.
+
+use crate::error::*;
+use crate::framestore::FrameStore;
+use crate::progress::ProgressReporter;
+use crate::{DecodedFrame, DecodedFrames, Writer};
+use imgref::*;
+use rgb::*;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+pub(crate) type Recording = Arc<Mutex<FrameStore>>;
+
+/// Frames of the first attempt, in order
+pub(crate) struct Replay {
+    recording: Recording,
+    next: usize,
+}
+
+impl Iterator for Replay {
+    type Item = CatResult<DecodedFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let frame = self.recording.lock().unwrap().get(self.next)?;
+        self.next += 1;
+        Some(frame)
+    }
+}
+
+/// Copies frames to the recording, if there is one
+pub(crate) fn recorded(frames: DecodedFrames<'_>, recording: Option<Recording>) -> DecodedFrames<'_> {
+    match recording {
+        None => frames,
+        Some(recording) => Box::new(frames.map(move |frame| {
+            let frame = frame?;
+            recording.lock().unwrap().push(frame.clone())?;
+            Ok(frame)
+        })),
+    }
+}
+
+pub(crate) fn write_max_size<W: Write>(mut first: Writer, max_size: u64, mut writer: W, reporter: &mut dyn ProgressReporter) -> CatResult<()> {
+    let recording = first.recording.clone().ok_or(Error::Aborted)?;
+    let mut settings = first.settings;
+    let fixed_palette = first.fixed_palette.clone();
+    let fixed_colors = first.fixed_colors.clone();
+    let (resume_after, timings, pools) = (first.resume_after, first.timings.clone(), first.pools.clone());
+    let last_frame_duration = first.last_frame_duration.clone();
+
+    // only the attempt that fits gives out its poster frame
+    let poster = first.poster.take().map(|(pts, callback)| (pts, callback, Arc::new(Mutex::new(None))));
+    let output_frame_callback = first.output_frame_callback.take().map(|callback| Arc::new(Mutex::new(callback)));
+
+    let mut attempt = first;
+    let mut gif = Vec::new();
+    loop {
+        if let Some((pts, _, frame)) = &poster {
+            let frame = frame.clone();
+            attempt.poster = Some((*pts, Box::new(move |image| *frame.lock().unwrap() = Some(image))));
+        }
+        if let Some(callback) = &output_frame_callback {
+            let callback = callback.clone();
+            attempt.output_frame_callback = Some(Box::new(move |image: ImgRef<'_, RGBA8>, delay| (*callback.lock().unwrap())(image, delay)));
+        }
+        gif.clear();
+        attempt.write(&mut gif, reporter)?;
+        if gif.len() as u64 <= max_size {
+            break;
+        }
+
+        let quality = i32::from(settings.quality);
+        let ratio = (max_size as f64 / gif.len() as f64).sqrt();
+        let next_quality = ((f64::from(quality) * ratio) as i32).min(quality - 5);
+        if next_quality < 1 {
+            return Err(Error::TooLarge(gif.len() as u64));
+        }
+        settings.quality = next_quality as u8;
+        reporter.retry(gif.len() as u64, settings.quality);
+
+        attempt = Writer {
+            queue_iter: None,
+            settings,
+            fixed_palette: fixed_palette.clone(),
+            fixed_colors: fixed_colors.clone(),
+            poster: None,
+            output_frame_callback: None,
+            resume_after,
+            timings: timings.clone(),
+            pools: pools.clone(),
+            memory_limit: None,
+            last_frame_duration: last_frame_duration.clone(),
+            max_size: None,
+            recording: None,
+            replay: Some(Replay { recording: recording.clone(), next: 0 }),
+            #[cfg(feature = "threadless")]
+            pumped: None,
+        };
+    }
+
+    if let Some((_, callback, frame)) = poster {
+        if let Some(frame) = frame.lock().unwrap().take() {
+            callback(frame);
+        }
+    }
+    writer.write_all(&gif)?;
+    Ok(())
+}
+
+#[test]
+fn encodes_again_until_it_fits() {
+    struct Retries(Vec<u8>);
+    impl ProgressReporter for Retries {
+        fn increase(&mut self) -> bool {
+            true
+        }
+        fn retry(&mut self, _: u64, next_quality: u8) {
+            self.0.push(next_quality);
+        }
+        fn done(&mut self, _: &str) {}
+    }
+
+    // gradients need many colors, and compress better with fewer
+    let frame = |n: u32| ImgVec::new((0..64 * 64).map(|i: u32| {
+        let (x, y) = ((i % 64) as u8, (i / 64) as u8);
+        RGBA8::new(x * 4, y * 4, (x + y).wrapping_add(n as u8 * 20).wrapping_mul(2), 255)
+    }).collect(), 64, 64);
+    let encode = |max_size| {
+        let (mut collector, mut writer) = crate::new(crate::Settings::default()).unwrap();
+        if let Some(max_size) = max_size {
+            writer.set_max_size(max_size);
+        }
+        let adding = std::thread::spawn(move || {
+            for n in 0..3 {
+                collector.add_frame_rgba(n as usize, frame(n), f64::from(n) / 10.).unwrap();
+            }
+        });
+        let mut gif = Vec::new();
+        let mut retries = Retries(Vec::new());
+        let res = writer.write(&mut gif, &mut retries);
+        adding.join().unwrap();
+        res.map(|_| (gif, retries.0))
+    };
+
+    let (full, retries) = encode(None).unwrap();
+    assert!(retries.is_empty());
+
+    let max_size = full.len() as u64 * 3 / 4;
+    let (gif, retries) = encode(Some(max_size)).unwrap();
+    assert!(gif.len() as u64 <= max_size);
+    assert!(!retries.is_empty());
+    let mut decoder = gif::DecodeOptions::new().read_info(&gif[..]).unwrap();
+    let mut frames = 0;
+    while decoder.read_next_frame().unwrap().is_some() {
+        frames += 1;
+    }
+    assert_eq!(3, frames);
+
+    assert!(matches!(encode(Some(100)), Err(Error::TooLarge(_))));
+}
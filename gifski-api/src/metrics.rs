@@ -0,0 +1,65 @@
+//! Time each frame spends in each stage, and time stages spend waiting on the queues between them.
+//! Only with the `metrics` feature, so that performance regressions can be found without a profiler.
+
+use crate::progress::{FrameTimes, QueueWait, QueueWaits};
+use crate::{Instant, Waiting};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    /// Nanoseconds, indexed by `Waiting`
+    waits: [AtomicU64; 8],
+    /// Frames that haven't been written yet
+    frames: Mutex<BTreeMap<usize, FrameTimes>>,
+}
+
+impl Metrics {
+    pub fn add_wait(&self, waiting: Waiting, start: Instant) {
+        self.waits[waiting as usize].fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_frame_time(&self, ordinal_frame_number: usize, start: Instant, stage: fn(&mut FrameTimes) -> &mut Duration) {
+        let elapsed = start.elapsed();
+        if let Ok(mut frames) = self.frames.lock() {
+            let times = frames.entry(ordinal_frame_number).or_insert_with(|| FrameTimes { ordinal_frame_number, ..FrameTimes::default() });
+            *stage(times) += elapsed;
+        }
+    }
+
+    /// Times of a frame that has been written. Frames before it have been skipped or merged, and are forgotten.
+    pub fn take_frame_times(&self, ordinal_frame_number: usize) -> Option<FrameTimes> {
+        let mut frames = self.frames.lock().ok()?;
+        let later = frames.split_off(&(ordinal_frame_number + 1));
+        let times = frames.remove(&ordinal_frame_number);
+        *frames = later;
+        times
+    }
+
+    pub fn queue_waits(&self) -> QueueWaits {
+        let get = |waiting: Waiting| Duration::from_nanos(self.waits[waiting as usize].load(Ordering::Relaxed));
+        let queue = |empty, full| QueueWait { empty: get(empty), full: get(full) };
+        QueueWaits {
+            input: queue(Waiting::InputEmpty, Waiting::InputFull),
+            quantize: queue(Waiting::QuantizeEmpty, Waiting::QuantizeFull),
+            remap: queue(Waiting::RemapEmpty, Waiting::RemapFull),
+            write: queue(Waiting::WriteEmpty, Waiting::WriteFull),
+        }
+    }
+}
+
+#[test]
+fn skipped_frames_are_forgotten() {
+    let metrics = Metrics::default();
+    for frame in 1..=3 {
+        metrics.add_frame_time(frame, Instant::now(), |times| &mut times.quantize);
+    }
+    metrics.add_frame_time(3, Instant::now(), |times| &mut times.write);
+    let times = metrics.take_frame_times(2).unwrap();
+    assert_eq!(2, times.ordinal_frame_number);
+    assert!(metrics.take_frame_times(1).is_none());
+    assert_eq!(3, metrics.take_frame_times(3).unwrap().ordinal_frame_number);
+    assert!(metrics.frames.lock().unwrap().is_empty());
+}
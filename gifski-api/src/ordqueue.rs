@@ -1,35 +1,211 @@
 use crate::error::*;
 use crossbeam_channel::{Receiver, Sender};
+#[cfg(not(feature = "threadless"))]
+use crossbeam_channel::RecvTimeoutError;
 use std::cmp::Ordering;
 use std::collections::BinaryHeap;
 use std::iter::FusedIterator;
+use std::sync::{Arc, Condvar, Mutex};
+#[cfg(not(feature = "threadless"))]
+use std::time::Duration;
 
 pub struct OrdQueue<T> {
     sender: Sender<ReverseTuple<T>>,
+    memory: Option<Arc<QueueMemory>>,
 }
 
 pub struct OrdQueueIter<T> {
     receiver: Receiver<ReverseTuple<T>>,
     next_index: usize,
     receive_buffer: BinaryHeap<ReverseTuple<T>>,
+    memory: Option<Arc<QueueMemory>>,
 }
 
-pub fn new<T>(depth: usize) -> (OrdQueue<T>, OrdQueueIter<T>) {
+/// Bytes used by items in all the queues that share the limit, including items that arrived before their turn
+pub struct MemoryLimit {
+    usage: Mutex<MemoryUsage>,
+    freed: Condvar,
+}
+
+struct MemoryUsage {
+    limit: usize,
+    /// Used outside of the queues until the end, e.g. by the denoiser
+    reserved: usize,
+    /// In all the queues
+    queued: usize,
+    queues: Vec<QueueUsage>,
+}
+
+#[derive(Default)]
+struct QueueUsage {
+    queued: usize,
+    /// Index of the item that the receiving side waits for
+    next_index: usize,
+    receiver_dropped: bool,
+}
+
+/// Items of one queue, counted towards a shared `MemoryLimit`
+struct QueueMemory {
+    limit: Arc<MemoryLimit>,
+    queue: usize,
+}
+
+impl MemoryLimit {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            usage: Mutex::new(MemoryUsage { limit, reserved: 0, queued: 0, queues: Vec::new() }),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Counts memory that stays in use, leaving less for the queues
+    pub fn reserve(&self, bytes: usize) {
+        if let Ok(mut usage) = self.usage.lock() {
+            usage.reserved += bytes;
+        }
+    }
+}
+
+impl QueueMemory {
+    fn new(limit: Arc<MemoryLimit>) -> Self {
+        let queue = {
+            let mut usage = limit.usage.lock().unwrap_or_else(|e| e.into_inner());
+            usage.queues.push(QueueUsage::default());
+            usage.queues.len() - 1
+        };
+        Self { limit, queue }
+    }
+
+    /// Waits until the item fits. The item that the receiving side waits for always fits,
+    /// otherwise items that arrived before it could block it forever. This is per queue,
+    /// so that a later stage can always take the frame it needs, and free the memory of earlier stages.
+    fn acquire(&self, index: usize, bytes: usize) -> CatResult<()> {
+        let mut usage = self.limit.usage.lock().map_err(|_| Error::ThreadSend)?;
+        loop {
+            let queue = &usage.queues[self.queue];
+            if queue.queued == 0 || index == queue.next_index || usage.reserved + usage.queued + bytes <= usage.limit {
+                break;
+            }
+            if queue.receiver_dropped {
+                return Err(Error::ThreadSend);
+            }
+            usage = self.limit.freed.wait(usage).map_err(|_| Error::ThreadSend)?;
+        }
+        usage.queued += bytes;
+        usage.queues[self.queue].queued += bytes;
+        Ok(())
+    }
+
+    fn release(&self, bytes: usize, next_index: usize) {
+        if let Ok(mut usage) = self.limit.usage.lock() {
+            usage.queued -= bytes;
+            let queue = &mut usage.queues[self.queue];
+            queue.queued -= bytes;
+            queue.next_index = next_index;
+        }
+        self.limit.freed.notify_all();
+    }
+
+    fn receiver_dropped(&self) {
+        if let Ok(mut usage) = self.limit.usage.lock() {
+            usage.queues[self.queue].receiver_dropped = true;
+        }
+        self.limit.freed.notify_all();
+    }
+}
+
+/// Pushing sized items waits while they'd take more than the limit, together with the other queues sharing it
+pub fn new<T>(depth: usize, memory: Option<&Arc<MemoryLimit>>) -> (OrdQueue<T>, OrdQueueIter<T>) {
+    let memory = memory.map(|memory| Arc::new(QueueMemory::new(memory.clone())));
+    #[cfg(not(feature = "threadless"))]
     let (sender, receiver) = crossbeam_channel::bounded(depth);
+    // frames may be added and encoded on the same thread, so adding can't wait for encoding
+    #[cfg(feature = "threadless")]
+    let (sender, receiver) = {
+        let _ = depth;
+        crossbeam_channel::unbounded()
+    };
     (OrdQueue {
         sender,
+        memory: memory.clone(),
     }, OrdQueueIter {
         receiver,
         next_index: 0,
-        receive_buffer: BinaryHeap::new()
+        receive_buffer: BinaryHeap::new(),
+        memory,
     })
 }
 
+impl<T> Clone for OrdQueue<T> {
+    fn clone(&self) -> Self {
+        Self { sender: self.sender.clone(), memory: self.memory.clone() }
+    }
+}
+
 impl<T: Send + 'static> OrdQueue<T> {
-    pub fn push(&mut self, index: usize, item: T) -> CatResult<()> {
-        self.sender.send(ReverseTuple(index, item))?;
+    /// Counts `bytes` towards the memory limit, if there is one, until the item is taken out
+    pub fn push_sized(&mut self, index: usize, item: T, bytes: usize) -> CatResult<()> {
+        let bytes = match &self.memory {
+            Some(memory) => {
+                memory.acquire(index, bytes)?;
+                bytes
+            },
+            None => 0,
+        };
+        self.sender.send(ReverseTuple(index, item, bytes))?;
         Ok(())
     }
+
+    /// The receiving side is behind
+    #[cfg(not(feature = "threadless"))]
+    pub fn is_full(&self) -> bool {
+        self.sender.is_full()
+    }
+}
+
+impl<T> OrdQueueIter<T> {
+    /// Next item if it has been pushed already. Never waits.
+    #[cfg(feature = "threadless")]
+    pub fn try_next(&mut self) -> Option<T> {
+        while let Ok(item) = self.receiver.try_recv() {
+            self.receive_buffer.push(item);
+        }
+        if self.receive_buffer.peek().map(|i| i.0) != Some(self.next_index) {
+            return None;
+        }
+        let item = self.receive_buffer.pop()?;
+        Some(self.taken(item))
+    }
+
+    /// Same as `next()`, but stops waiting after `timeout`. The end of the queue is `Disconnected`.
+    #[cfg(not(feature = "threadless"))]
+    pub fn next_timeout(&mut self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        while self.receive_buffer.peek().map(|i| i.0) != Some(self.next_index) {
+            match self.receiver.recv_timeout(timeout) {
+                Ok(item) => self.receive_buffer.push(item),
+                Err(RecvTimeoutError::Timeout) => return Err(RecvTimeoutError::Timeout),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        let item = self.receive_buffer.pop().ok_or(RecvTimeoutError::Disconnected)?;
+        Ok(self.taken(item))
+    }
+
+    fn taken(&mut self, item: ReverseTuple<T>) -> T {
+        self.next_index += 1;
+        if let Some(memory) = &self.memory {
+            memory.release(item.2, self.next_index);
+        }
+        item.1
+    }
+}
+
+impl<T> Drop for OrdQueueIter<T> {
+    fn drop(&mut self) {
+        if let Some(memory) = &self.memory {
+            memory.receiver_dropped();
+        }
+    }
 }
 
 impl<T> FusedIterator for OrdQueueIter<T> {}
@@ -49,16 +225,13 @@ impl<T> Iterator for OrdQueueIter<T> {
             }
         }
 
-        if let Some(item) = self.receive_buffer.pop() {
-            self.next_index += 1;
-            Some(item.1)
-        } else {
-            None
-        }
+        let item = self.receive_buffer.pop()?;
+        Some(self.taken(item))
     }
 }
 
-struct ReverseTuple<T>(usize, T);
+/// Index, item, and its bytes counted towards the memory limit
+struct ReverseTuple<T>(usize, T, usize);
 impl<T> PartialEq for ReverseTuple<T> {
     fn eq(&self, o: &Self) -> bool { o.0.eq(&self.0) }
 }
@@ -69,3 +242,54 @@ impl<T> PartialOrd for ReverseTuple<T> {
 impl<T> Ord for ReverseTuple<T> {
     fn cmp(&self, o: &Self) -> Ordering { o.0.cmp(&self.0) }
 }
+
+#[test]
+fn memory_limit_waits_for_receiver() {
+    let memory = Arc::new(MemoryLimit::new(100));
+    let (mut queue, mut iter) = new(4, Some(&memory));
+    queue.push_sized(1, 1, 80).unwrap();
+    // over the limit, but it's the item the receiver waits for
+    queue.push_sized(0, 0, 80).unwrap();
+    assert_eq!(Some(0), iter.next());
+    assert_eq!(Some(1), iter.next());
+
+    queue.push_sized(2, 2, 80).unwrap();
+    let pusher = std::thread::spawn(move || queue.push_sized(3, 3, 80));
+    assert_eq!(Some(2), iter.next());
+    pusher.join().unwrap().unwrap();
+    assert_eq!(Some(3), iter.next());
+    assert_eq!(None, iter.next());
+}
+
+#[test]
+fn memory_limit_lets_later_queue_drain_earlier() {
+    let memory = Arc::new(MemoryLimit::new(100));
+    let (mut first, mut first_iter) = new(4, Some(&memory));
+    let (mut second, mut second_iter) = new(4, Some(&memory));
+    first.push_sized(0, 0, 80).unwrap();
+    first.push_sized(1, 1, 10).unwrap();
+    // the first queue takes all the memory, but the second one can still move its next item along
+    assert_eq!(Some(0), first_iter.next());
+    second.push_sized(0, 0, 80).unwrap();
+    let pusher = std::thread::spawn(move || second.push_sized(1, 1, 80));
+    assert_eq!(Some(0), second_iter.next());
+    pusher.join().unwrap().unwrap();
+    assert_eq!(Some(1), second_iter.next());
+    assert_eq!(Some(1), first_iter.next());
+    drop(first);
+    assert_eq!(None, first_iter.next());
+}
+
+#[test]
+#[cfg(not(feature = "threadless"))]
+fn next_timeout_waits_for_next_index() {
+    let (mut queue, mut iter) = new(4, None);
+    assert_eq!(Err(RecvTimeoutError::Timeout), iter.next_timeout(Duration::from_millis(1)));
+    queue.push_sized(1, 1, 0).unwrap();
+    assert_eq!(Err(RecvTimeoutError::Timeout), iter.next_timeout(Duration::from_millis(1)));
+    queue.push_sized(0, 0, 0).unwrap();
+    assert_eq!(Ok(0), iter.next_timeout(Duration::from_millis(1)));
+    assert_eq!(Ok(1), iter.next_timeout(Duration::from_millis(1)));
+    drop(queue);
+    assert_eq!(Err(RecvTimeoutError::Disconnected), iter.next_timeout(Duration::from_millis(1)));
+}
@@ -0,0 +1,719 @@
+//! Stages of encoding. Each stage takes frames one at a time and emits what it has finished,
+//! so the stages can run on their own threads, or be called one after another on the same thread.
+
+use crate::crossfade::LoopCrossfade;
+use crate::denoise::*;
+use crate::error::*;
+use crate::framestore::FrameStore;
+use crate::ordqueue::MemoryLimit;
+use crate::pool::Pools;
+use crate::progress::*;
+use crate::stabilize::Stabilize;
+use crate::{frames_match, optimize_frame, simd, trim_image, Bounce, Instant, LastFrameDuration, Timings};
+use crate::{DecodedFrame, DiffMessage, Encoder, FrameMessage, GIFFrame, InputFrame, RemapMessage, Settings, Writer};
+use crate::{OutputFrameCallback, PosterCallback};
+use imagequant::{Attributes, QuantizationResult};
+use imgref::*;
+use rgb::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Where a stage sends its results, e.g. to the next stage's thread
+pub(crate) type Emit<'a, T> = &'a mut dyn FnMut(T) -> CatResult<()>;
+
+/// Frame number, end timestamp, disposal, and the user's importance map, kept while the frame is in the denoiser
+type DenoiseMeta = (usize, f64, gif::DisposalMethod, Option<ImgVec<u8>>);
+
+/// Decodes and resizes frames from the `Collector`
+pub(crate) struct InputStage {
+    settings: Settings,
+    timings: Arc<Timings>,
+    pools: Pools,
+}
+
+impl InputStage {
+    pub fn new(settings: Settings, timings: Arc<Timings>, pools: Pools) -> Self {
+        Self { settings, timings, pools }
+    }
+
+    pub fn decode(&mut self, (frame, pts): (InputFrame, f64)) -> CatResult<DecodedFrame> {
+        let (image, importance_map) = frame.decode(&self.settings, &self.timings, &self.pools)?;
+        self.timings.frames.fetch_add(1, Ordering::Relaxed);
+        Ok((image, pts, importance_map))
+    }
+}
+
+/// Stabilization, reversing, loop crossfade and bounce look ahead, possibly up to the last frame
+pub(crate) fn uses_whole_sequence(settings: &Settings) -> bool {
+    settings.stabilize || settings.reverse || settings.loop_crossfade > 0. || settings.bounce
+}
+
+/// Applies the settings that change the sequence of frames
+pub(crate) fn sequence_effects<'a>(mut inputs: Box<dyn Iterator<Item = CatResult<DecodedFrame>> + 'a>, settings: &Settings) -> CatResult<Box<dyn Iterator<Item = CatResult<DecodedFrame>> + 'a>> {
+    // before reversing, so that it follows the camera's real motion
+    if settings.stabilize {
+        inputs = Box::new(Stabilize::new(inputs));
+    }
+    if settings.reverse {
+        let mut frames = FrameStore::new(settings.memory_limit_mb);
+        let mut last_pts = 0.;
+        for frame in inputs {
+            let frame = frame?;
+            last_pts = frame.1;
+            frames.push(frame)?;
+        }
+        inputs = Box::new(std::iter::from_fn(move || frames.pop())
+            .map(move |frame| frame.map(|(image, pts, importance_map)| (image, last_pts - pts, importance_map))));
+    }
+    if settings.loop_crossfade > 0. {
+        inputs = Box::new(LoopCrossfade::new(inputs, settings.loop_crossfade.into()));
+    }
+    if settings.bounce {
+        inputs = Box::new(Bounce::new(inputs, settings.memory_limit_mb));
+    }
+    Ok(inputs)
+}
+
+/// Skips duplicate frames, decides disposal and delay of frames,
+/// and finds which pixels are worth changing (using the denoiser)
+pub(crate) struct DiffStage {
+    settings: Settings,
+    resume_after: usize,
+    /// The denoiser's memory counts towards the limit
+    memory_limit: Option<Arc<MemoryLimit>>,
+    last_frame_duration: LastFrameDuration,
+    timings: Arc<Timings>,
+    pools: Pools,
+    #[cfg(feature = "dump")]
+    dumper: Option<crate::dump::Dumper>,
+    /// Created for the first frame that is encoded
+    denoiser: Option<Denoiser<DenoiseMeta>>,
+    frames_received: usize,
+    first_frame_pts: f64,
+    /// Timestamps are relative to the first frame that is encoded
+    start_pts: f64,
+    first_frame_has_transparency: bool,
+    prev_frame_pts: f64,
+    ordinal_frame_number: usize,
+    /// The latest frame, which needs the next one to know its disposal and delay
+    pending: Option<DecodedFrame>,
+}
+
+impl DiffStage {
+    pub fn new(settings: Settings, resume_after: usize, memory_limit: Option<Arc<MemoryLimit>>, last_frame_duration: LastFrameDuration, timings: Arc<Timings>, pools: Pools) -> Self {
+        Self {
+            settings,
+            resume_after,
+            memory_limit,
+            last_frame_duration,
+            timings,
+            pools,
+            #[cfg(feature = "dump")]
+            dumper: crate::dump::Dumper::from_env(),
+            denoiser: None,
+            frames_received: 0,
+            first_frame_pts: 0.,
+            start_pts: 0.,
+            first_frame_has_transparency: false,
+            prev_frame_pts: -1.0,
+            ordinal_frame_number: 0,
+            pending: None,
+        }
+    }
+
+    pub fn push(&mut self, frame: DecodedFrame, emit: Emit<'_, DiffMessage>) -> CatResult<()> {
+        self.frames_received += 1;
+        if self.frames_received == 1 {
+            self.first_frame_pts = frame.1;
+        }
+        // frames already in the file being continued only set the start
+        if self.frames_received <= self.resume_after + 1 {
+            self.start_pts = frame.1;
+            if let Some((skipped, ..)) = self.pending.replace(frame) {
+                self.pools.rgba.give(skipped.into_buf());
+            }
+            return Ok(());
+        }
+        if let Some(curr) = self.pending.take() {
+            self.feed(curr, Some(&frame), emit)?;
+        }
+        self.pending = Some(frame);
+        Ok(())
+    }
+
+    /// Processes the last frame, and the frames still in the denoiser
+    pub fn finish(&mut self, emit: Emit<'_, DiffMessage>) -> CatResult<()> {
+        if self.frames_received <= self.resume_after {
+            return Err(Error::NoFrames);
+        }
+        let last = self.pending.take().ok_or(Error::NoFrames)?;
+        self.feed(last, None, emit)
+    }
+
+    /// The denoiser buffers a few frames, so frames come out of it later than they go in
+    fn feed(&mut self, (image, mut pts, user_importance_map): DecodedFrame, next: Option<&DecodedFrame>, emit: Emit<'_, DiffMessage>) -> CatResult<()> {
+        let denoiser = match &mut self.denoiser {
+            Some(denoiser) => denoiser,
+            None => {
+                self.first_frame_has_transparency = image.pixels().any(|px| px.a < 128);
+                if let Some(memory_limit) = &self.memory_limit {
+                    memory_limit.reserve(denoiser_memory_size(image.width(), image.height(), self.settings.denoise_half_size));
+                }
+                self.denoiser.get_or_insert(if self.settings.denoise_half_size {
+                    Denoiser::new_half_size(image.width(), image.height(), self.settings.quality, self.pools.clone())
+                } else {
+                    Denoiser::new(image.width(), image.height(), self.settings.quality, self.pools.clone())
+                })
+            },
+        };
+
+        pts -= self.start_pts;
+        self.ordinal_frame_number += 1;
+
+        let dispose = if let Some((next, ..)) = next {
+            if next.width() != image.width() || next.height() != image.height() {
+                return Err(Error::WrongSize(format!("Frame {} has wrong size ({}×{}, expected {}×{})", self.ordinal_frame_number,
+                    next.width(), next.height(), image.width(), image.height())));
+            }
+
+            // Skip identical frames
+            if frames_match(next.as_ref(), image.as_ref(), self.settings.duplicate_threshold) {
+                self.prev_frame_pts = pts;
+                self.pools.rgba.give(image.into_buf());
+                return Ok(());
+            }
+
+            // If the next frame becomes transparent, this frame has to clear to bg for it
+            if next.pixels().zip(image.pixels()).any(|(next, curr)| next.a < curr.a) {
+                gif::DisposalMethod::Background
+            } else {
+                gif::DisposalMethod::Keep
+            }
+        } else {
+            if self.first_frame_has_transparency {
+                // Last frame should reset to background to avoid breaking transparent looped anims
+                gif::DisposalMethod::Background
+            } else {
+                // macOS preview gets Background wrong
+                gif::DisposalMethod::Keep
+            }
+        };
+
+        // conversion from pts to delay
+        let last_frame_duration = if next.is_none() { self.last_frame_duration.lock().ok().and_then(|d| *d) } else { None };
+        let end_pts = if let Some((_, next_pts, _)) = next {
+            next_pts - self.start_pts
+        } else if let Some(duration) = last_frame_duration {
+            pts + duration
+        } else if self.first_frame_pts > 1. / 100. {
+            // this is gifski's weird rule that non-zero first-frame pts
+            // shifts the whole anim and is the delay of the last frame
+            pts + self.first_frame_pts
+        } else {
+            // otherwise assume steady framerate
+            pts + (pts - self.prev_frame_pts)
+        };
+        debug_assert!(end_pts > 0.);
+        self.prev_frame_pts = pts;
+        let end_pts = if next.is_none() {
+            end_pts + f64::from(self.settings.loop_end_delay)
+        } else {
+            end_pts
+        };
+
+        let start = Instant::now();
+        denoiser.push_frame(image.as_ref(), (self.ordinal_frame_number, end_pts, dispose, user_importance_map));
+        self.pools.rgba.give(image.into_buf());
+        if next.is_none() {
+            denoiser.flush();
+        }
+        self.timings.add(&self.timings.denoise, start);
+        #[cfg(feature = "metrics")]
+        self.timings.metrics.add_frame_time(self.ordinal_frame_number, start, |times| &mut times.denoise);
+
+        self.emit_denoised(emit)
+    }
+
+    fn emit_denoised(&mut self, emit: Emit<'_, DiffMessage>) -> CatResult<()> {
+        let denoiser = match &mut self.denoiser {
+            Some(denoiser) => denoiser,
+            None => return Ok(()),
+        };
+        loop {
+            let start = Instant::now();
+            let denoised = denoiser.pop();
+            self.timings.add(&self.timings.denoise, start);
+            let (importance_map, image, (ordinal_frame_number, end_pts, dispose, user_importance_map)) = match denoised {
+                Denoised::Frame { importance_map, frame, meta } => (importance_map, frame, meta),
+                Denoised::NotYet | Denoised::Done => return Ok(()),
+            };
+
+            #[cfg(feature = "dump")]
+            {
+                if let Some(dumper) = &self.dumper {
+                    dumper.dump(ordinal_frame_number, importance_map.as_ref(), image.as_ref())?;
+                }
+            }
+
+            let (mut importance_map, ..) = importance_map.into_contiguous_buf();
+            if let Some(user_importance_map) = user_importance_map {
+                for (imp, user) in importance_map.iter_mut().zip(user_importance_map.pixels()) {
+                    // 0 means the pixel hasn't changed and will be transparent
+                    if *imp > 0 {
+                        *imp = (*imp).max(user);
+                    }
+                }
+            }
+
+            emit(DiffMessage {
+                dispose,
+                importance_map,
+                ordinal_frame_number,
+                image,
+                end_pts,
+                reuse_palette: false,
+            })?;
+        }
+    }
+}
+
+/// Picks colors for each frame
+#[derive(Clone)]
+pub(crate) struct QuantizeStage {
+    settings: Settings,
+    /// If non-empty, the only colors used
+    fixed_palette: Vec<RGB8>,
+    /// Used in addition to the best colors for each frame
+    fixed_colors: Vec<RGB8>,
+    timings: Arc<Timings>,
+    pools: Pools,
+    prev_frame_keeps: bool,
+    /// Colors of the frame whose palette the next frame could use again
+    palette_histogram: Option<Vec<u32>>,
+    /// Colors of the current frame
+    histogram: Vec<u32>,
+}
+
+/// Frames can use the previous palette if at most this fraction of their important colors is different
+const PALETTE_REUSE_MAX_DIFFERENCE: f64 = 0.03;
+
+/// …and no single color's share changed by more than this
+const PALETTE_REUSE_MAX_BIN_DIFFERENCE: f64 = 0.005;
+
+impl QuantizeStage {
+    pub fn new(settings: Settings, fixed_palette: Vec<RGB8>, fixed_colors: Vec<RGB8>, timings: Arc<Timings>, pools: Pools) -> Self {
+        Self { settings, fixed_palette, fixed_colors, timings, pools, prev_frame_keeps: false, palette_histogram: None, histogram: Vec::new() }
+    }
+
+    pub fn push(&mut self, msg: DiffMessage, emit: Emit<'_, RemapMessage>) -> CatResult<()> {
+        if let Some(msg) = self.select(msg) {
+            emit(self.quantize(msg)?)?;
+        }
+        Ok(())
+    }
+
+    /// Drops frames that wouldn't change anything on screen, and decides which frames can skip quantization.
+    /// Needs to see every frame, in order.
+    pub fn select(&mut self, mut msg: DiffMessage) -> Option<DiffMessage> {
+        let prev_frame_keeps = self.prev_frame_keeps;
+        self.prev_frame_keeps = msg.dispose == gif::DisposalMethod::Keep;
+        if !prev_frame_keeps || simd::any_nonzero(&msg.importance_map) {
+            // the first frame has no transparent color in its palette, so it's not worth reusing
+            if self.settings.reuse_palettes && msg.ordinal_frame_number > 1 {
+                color_histogram(msg.image.as_ref(), &msg.importance_map, &mut self.histogram);
+                msg.reuse_palette = self.palette_histogram.as_ref()
+                    .map_or(false, |palette_histogram| palette_covers(palette_histogram, &self.histogram));
+                if !msg.reuse_palette {
+                    let histogram = std::mem::take(&mut self.histogram);
+                    self.histogram = self.palette_histogram.replace(histogram).unwrap_or_default();
+                }
+            }
+            Some(msg)
+        } else {
+            self.pools.rgba.give(msg.image.into_buf());
+            self.pools.bytes.give(msg.importance_map);
+            None
+        }
+    }
+
+    /// Frames don't depend on each other here, so clones of the stage can quantize frames on several threads
+    pub fn quantize(&self, msg: DiffMessage) -> CatResult<RemapMessage> {
+        let DiffMessage {image, end_pts, dispose, ordinal_frame_number, mut importance_map, reuse_palette} = msg;
+        let settings = &self.settings;
+        let start = Instant::now();
+        let (quantized, image) = if reuse_palette {
+            (None, Writer::dithered(image, settings, &self.pools))
+        } else {
+            let (liq, remap, image) = Writer::quantize(image, &importance_map, ordinal_frame_number > 1, settings, &self.fixed_palette, &self.fixed_colors, &self.pools)?;
+            (Some((liq, remap)), image)
+        };
+        self.timings.add(&self.timings.quantize, start);
+        self.timings.quantized.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "metrics")]
+        self.timings.metrics.add_frame_time(ordinal_frame_number, start, |times| &mut times.quantize);
+        let max_loss = settings.gifsicle_loss();
+        let loss = if settings.adaptive_loss && ordinal_frame_number > 1 {
+            crate::adaptive_loss(max_loss, &importance_map)
+        } else {
+            max_loss
+        };
+        crate::scale_importance_to_loss(&mut importance_map, loss);
+        self.pools.bytes.give(importance_map);
+        Ok(RemapMessage {
+            ordinal_frame_number,
+            end_pts,
+            dispose,
+            quantized,
+            image,
+            loss,
+        })
+    }
+}
+
+/// Colors of important opaque pixels, weighted by importance, with 4 bits per channel
+fn color_histogram(image: ImgRef<'_, RGBA8>, importance_map: &[u8], histogram: &mut Vec<u32>) {
+    histogram.clear();
+    histogram.resize(1 << 12, 0);
+    for (px, &imp) in image.pixels().zip(importance_map) {
+        if imp > 0 && px.a >= 128 {
+            let bin = usize::from(px.r >> 4) << 8 | usize::from(px.g >> 4) << 4 | usize::from(px.b >> 4);
+            histogram[bin] += u32::from(imp);
+        }
+    }
+}
+
+/// Histograms are weighted by importance, so pixels that didn't change don't count, and a new color counts
+/// even if it's only a few pixels, like a cursor or a subtitle, because the palette may not have anything close to it
+fn palette_covers(palette_histogram: &[u32], histogram: &[u32]) -> bool {
+    let has_new_colors = palette_histogram.iter().zip(histogram).any(|(&p, &h)| p == 0 && h > 0);
+    !has_new_colors &&
+        histogram_difference(palette_histogram, histogram) <= PALETTE_REUSE_MAX_DIFFERENCE &&
+        largest_bin_difference(palette_histogram, histogram) <= PALETTE_REUSE_MAX_BIN_DIFFERENCE
+}
+
+/// Largest change of one bin's share of all colors, 0 to 1
+fn largest_bin_difference(a: &[u32], b: &[u32]) -> f64 {
+    let total_a = a.iter().map(|&n| u64::from(n)).sum::<u64>() as f64;
+    let total_b = b.iter().map(|&n| u64::from(n)).sum::<u64>() as f64;
+    if total_a == 0. || total_b == 0. {
+        return if total_a == total_b { 0. } else { 1. };
+    }
+    a.iter().zip(b).map(|(&a, &b)| (f64::from(a) / total_a - f64::from(b) / total_b).abs()).fold(0., f64::max)
+}
+
+/// Fraction of colors that would have to change to turn one histogram into the other, 0 to 1
+fn histogram_difference(a: &[u32], b: &[u32]) -> f64 {
+    let total_a = a.iter().map(|&n| u64::from(n)).sum::<u64>() as f64;
+    let total_b = b.iter().map(|&n| u64::from(n)).sum::<u64>() as f64;
+    if total_a == 0. || total_b == 0. {
+        return if total_a == total_b { 0. } else { 1. };
+    }
+    a.iter().zip(b).map(|(&a, &b)| (f64::from(a) / total_a - f64::from(b) / total_b).abs()).sum::<f64>() / 2.
+}
+
+/// Maps frames to their palettes, and trims parts that don't change
+pub(crate) struct RemapStage {
+    settings: Settings,
+    timings: Arc<Timings>,
+    pools: Pools,
+    /// What the GIF shows so far, created for the first frame
+    screen: Option<gif_dispose::Screen>,
+    /// Palette of the latest quantized frame, for frames that use it again
+    quantized: Option<(Attributes, QuantizationResult)>,
+    /// The last frame can't be trimmed, so each frame waits to see if there's a next one
+    pending: Option<RemapMessage>,
+}
+
+impl RemapStage {
+    pub fn new(settings: Settings, timings: Arc<Timings>, pools: Pools) -> Self {
+        Self { settings, timings, pools, screen: None, quantized: None, pending: None }
+    }
+
+    pub fn push(&mut self, msg: RemapMessage, emit: Emit<'_, FrameMessage>) -> CatResult<()> {
+        if let Some(curr) = self.pending.replace(msg) {
+            self.remap(curr, true, emit)?;
+        }
+        Ok(())
+    }
+
+    pub fn finish(&mut self, emit: Emit<'_, FrameMessage>) -> CatResult<()> {
+        let last = self.pending.take().ok_or(Error::NoFrames)?;
+        self.remap(last, false, emit)
+    }
+
+    fn remap(&mut self, msg: RemapMessage, has_next: bool, emit: Emit<'_, FrameMessage>) -> CatResult<()> {
+        let RemapMessage {ordinal_frame_number, end_pts, dispose, quantized, image, loss} = msg;
+        if quantized.is_some() {
+            self.quantized = quantized;
+        }
+        let (liq, remap) = self.quantized.as_mut().ok_or(Error::NoFrames)?;
+        let start = Instant::now();
+        let first_frame = self.screen.is_none();
+        let screen = self.screen.get_or_insert_with(|| {
+            gif_dispose::Screen::new(image.width(), image.height(), RGBA8::new(0, 0, 0, 0), None)
+        });
+        let screen_width = screen.pixels.width() as u16;
+        let screen_height = screen.pixels.height() as u16;
+        let mut screen_after_dispose = screen.dispose();
+
+        let (mut image8, mut image8_pal) = {
+            let bg = if !first_frame { Some(screen_after_dispose.pixels()) } else { None };
+            Writer::remap(liq, remap, image.as_ref(), bg, &self.settings, &self.pools)?
+        };
+        self.pools.rgba.give(image.into_buf());
+
+        // Palette may have multiple transparent indices :(
+        let mut transparent_index = None;
+        for (i, p) in image8_pal.iter_mut().enumerate() {
+            if p.a <= 128 {
+                p.a = 0;
+                let new_index = i as u8;
+                if let Some(old_index) = transparent_index {
+                    image8.pixels_mut().filter(|px| **px == new_index).for_each(|px| *px = old_index);
+                } else {
+                    transparent_index = Some(new_index);
+                }
+            }
+        }
+
+        // Check that palette is fine and has no duplicate transparent indices
+        debug_assert!(image8_pal.iter().enumerate().all(|(idx, color)| {
+            Some(idx as u8) == transparent_index || color.a > 128 || !image8.pixels().any(|px| px == idx as u8)
+        }));
+
+        let (left, top, image8) = if !first_frame && has_next {
+            match trim_image(image8, &image8_pal, transparent_index, screen_after_dispose.pixels()) {
+                Some(trimmed) => trimmed,
+                None => { // no pixels left
+                    self.timings.add(&self.timings.remap, start);
+                    return Ok(());
+                },
+            }
+        } else {
+            // must keep first and last frame
+            (0, 0, image8)
+        };
+
+        screen_after_dispose.then_blit(Some(&image8_pal), dispose, left, top as _, image8.as_ref(), transparent_index)?;
+        self.timings.add(&self.timings.remap, start);
+        #[cfg(feature = "metrics")]
+        self.timings.metrics.add_frame_time(ordinal_frame_number, start, |times| &mut times.remap);
+
+        let frame = GIFFrame {
+            left,
+            top,
+            screen_width,
+            screen_height,
+            image: image8,
+            pal: image8_pal,
+            transparent_index,
+            dispose,
+            loss,
+        };
+
+        emit(FrameMessage {
+            ordinal_frame_number,
+            end_pts,
+            frame,
+        })
+    }
+}
+
+/// Revisits each frame with the previous one to find a cheaper difference to encode
+pub(crate) struct OptimizeStage {
+    timings: Arc<Timings>,
+    screen: Option<gif_dispose::Screen>,
+}
+
+impl OptimizeStage {
+    pub fn new(timings: Arc<Timings>) -> Self {
+        Self { timings, screen: None }
+    }
+
+    pub fn push(&mut self, mut msg: FrameMessage, emit: Emit<'_, FrameMessage>) -> CatResult<()> {
+        let start = Instant::now();
+        let frame = &mut msg.frame;
+        let first_frame = self.screen.is_none();
+        let screen = self.screen.get_or_insert_with(|| {
+            gif_dispose::Screen::new(frame.screen_width.into(), frame.screen_height.into(), RGBA8::new(0, 0, 0, 0), None)
+        });
+        let mut screen_after_dispose = screen.dispose();
+        if !first_frame {
+            let bg = screen_after_dispose.pixels().sub_image(frame.left.into(), frame.top.into(), frame.image.width(), frame.image.height());
+            optimize_frame(frame, bg);
+        }
+        screen_after_dispose.then_blit(Some(&frame.pal), frame.dispose, frame.left, frame.top, frame.image.as_ref(), frame.transparent_index)?;
+        self.timings.add(&self.timings.optimize, start);
+        #[cfg(feature = "metrics")]
+        self.timings.metrics.add_frame_time(msg.ordinal_frame_number, start, |times| &mut times.optimize);
+        emit(msg)
+    }
+}
+
+/// Passes the frame through the optimize stage, if there is one
+pub(crate) fn optimized(optimize: &mut Option<OptimizeStage>, msg: FrameMessage, emit: Emit<'_, FrameMessage>) -> CatResult<()> {
+    match optimize {
+        Some(optimize) => optimize.push(msg, emit),
+        None => emit(msg),
+    }
+}
+
+/// Compresses frames and reports progress
+pub(crate) struct WriteStage {
+    settings: Settings,
+    output_frame_callback: Option<OutputFrameCallback>,
+    poster: Option<(f64, PosterCallback)>,
+    timings: Arc<Timings>,
+    pools: Pools,
+    pts_in_delay_units: u64,
+    start_pts: f64,
+    /// What the GIF shows, only for the output frame callback and the poster
+    screen: Option<gif_dispose::Screen>,
+    n_done: usize,
+}
+
+impl WriteStage {
+    pub fn new(settings: Settings, output_frame_callback: Option<OutputFrameCallback>, poster: Option<(f64, PosterCallback)>, timings: Arc<Timings>, pools: Pools) -> Self {
+        Self { settings, output_frame_callback, poster, timings, pools, pts_in_delay_units: 0, start_pts: 0., screen: None, n_done: 0 }
+    }
+
+    pub fn push(&mut self, msg: FrameMessage, enc: &mut dyn Encoder, bytes_written: &AtomicU64, reporter: &mut dyn ProgressReporter) -> CatResult<()> {
+        let FrameMessage {frame, ordinal_frame_number, end_pts, ..} = msg;
+        let timings = &self.timings;
+        let delay = ((end_pts * 100.0).round() as u64)
+            .saturating_sub(self.pts_in_delay_units)
+            .min(30000) as u16;
+        self.pts_in_delay_units += u64::from(delay);
+
+        debug_assert_ne!(0, delay);
+
+        // skip frames with bad pts
+        if delay != 0 {
+            if self.output_frame_callback.is_some() || self.poster.is_some() {
+                let shown_until = self.pts_in_delay_units as f64 / 100.;
+                let screen = self.screen.get_or_insert_with(|| {
+                    gif_dispose::Screen::new(frame.screen_width.into(), frame.screen_height.into(), RGBA8::new(0, 0, 0, 0), None)
+                });
+                // show the pixels lossy compression will leave, not the ones before it
+                #[cfg(feature = "gifsicle")]
+                let lossy = crate::encodegifsicle::lossy_image(&frame, &self.settings)?;
+                #[cfg(not(feature = "gifsicle"))]
+                let lossy: Option<ImgVec<u8>> = None;
+                let image = lossy.as_ref().map_or(frame.image.as_ref(), |lossy| lossy.as_ref());
+                screen.dispose().then_blit(Some(&frame.pal), frame.dispose, frame.left, frame.top, image, frame.transparent_index)?;
+                if let Some(callback) = &mut self.output_frame_callback {
+                    callback(screen.pixels.as_ref(), delay);
+                }
+                // the poster is the frame shown at the requested time, or the last frame
+                let poster_shown = self.poster.as_ref().map_or(false, |&(poster_pts, _)| shown_until > poster_pts);
+                if poster_shown {
+                    if let Some((_, callback)) = self.poster.take() {
+                        callback(screen.pixels.clone());
+                    }
+                }
+            }
+            let palette_size = frame.pal.len() as u16;
+            let bytes_before = bytes_written.load(Ordering::Relaxed);
+            let start = timings.start_lzw();
+            enc.write_frame(&frame, delay, &self.settings)?;
+            timings.add_lzw(start);
+            reporter.frame_stats(&FrameStats {
+                ordinal_frame_number: ordinal_frame_number as u32,
+                delay,
+                palette_size,
+                compressed_size: bytes_written.load(Ordering::Relaxed) - bytes_before,
+            });
+            #[cfg(feature = "metrics")]
+            {
+                timings.metrics.add_frame_time(ordinal_frame_number, start.0, |times| &mut times.write);
+                if let Some(times) = timings.metrics.take_frame_times(ordinal_frame_number) {
+                    reporter.frame_times(&times);
+                }
+            }
+        }
+        self.pools.bytes.give(frame.image.into_buf());
+
+        // loop to report skipped frames too
+        while self.n_done < ordinal_frame_number {
+            self.n_done += 1;
+            if !reporter.frame_written(self.n_done, self.start_pts, bytes_written.load(Ordering::Relaxed)) {
+                return Err(Error::Aborted);
+            }
+        }
+        self.start_pts = end_pts;
+        Ok(())
+    }
+
+    pub fn finish(&mut self, enc: &mut dyn Encoder) -> CatResult<()> {
+        if self.n_done == 0 {
+            return Err(Error::NoFrames);
+        }
+        if let (Some((_, callback)), Some(screen)) = (self.poster.take(), &self.screen) {
+            callback(screen.pixels.clone());
+        }
+        let start = self.timings.start_lzw();
+        enc.finish()?;
+        self.timings.add_lzw(start);
+        Ok(())
+    }
+}
+
+#[test]
+fn histogram_difference_ignores_unimportant_pixels() {
+    let mut a = Vec::new();
+    let mut b = Vec::new();
+    let image = ImgVec::new(vec![RGBA8::new(255, 0, 0, 255), RGBA8::new(0, 0, 255, 255), RGBA8::new(0, 255, 0, 0)], 3, 1);
+    color_histogram(image.as_ref(), &[100, 0, 255], &mut a);
+    color_histogram(image.as_ref(), &[50, 0, 0], &mut b);
+    assert_eq!(0., histogram_difference(&a, &b));
+
+    color_histogram(image.as_ref(), &[50, 50, 0], &mut b);
+    assert_eq!(0.5, histogram_difference(&a, &b));
+    assert_eq!(1., histogram_difference(&a, &vec![0; a.len()]));
+}
+
+#[test]
+fn small_new_color_needs_new_palette() {
+    let settings = Settings { reuse_palettes: true, ..Settings::default() };
+    let mut stage = QuantizeStage::new(settings, Vec::new(), Vec::new(), Arc::new(Timings::default()), Pools::new());
+    let gray = RGBA8::new(100, 100, 100, 255);
+    let frame = |ordinal_frame_number, pixels: Vec<RGBA8>| DiffMessage {
+        ordinal_frame_number,
+        end_pts: 0.,
+        dispose: gif::DisposalMethod::Keep,
+        importance_map: vec![255; pixels.len()],
+        image: ImgVec::new(pixels, 20, 20),
+        reuse_palette: false,
+    };
+    assert!(!stage.select(frame(2, vec![gray; 400])).unwrap().reuse_palette);
+    assert!(stage.select(frame(3, vec![gray; 400])).unwrap().reuse_palette);
+
+    // one red pixel is 0.25% of the frame
+    let mut with_cursor = vec![gray; 400];
+    with_cursor[210] = RGBA8::new(255, 0, 0, 255);
+    assert!(!stage.select(frame(4, with_cursor)).unwrap().reuse_palette);
+
+    let mut stage = QuantizeStage::new(Settings::default(), Vec::new(), Vec::new(), Arc::new(Timings::default()), Pools::new());
+    assert!(!stage.select(frame(2, vec![gray; 400])).unwrap().reuse_palette);
+    assert!(!stage.select(frame(3, vec![gray; 400])).unwrap().reuse_palette, "reuse is opt-in");
+}
+
+#[test]
+fn remapping_in_bands_keeps_colors() {
+    let settings = Settings { dithering: crate::Dithering::None, ..Settings::default() };
+    let pools = Pools::new();
+    let (width, height) = (64, 48);
+    let pixels = (0..width * height).map(|i| RGBA8::new((i % width * 4) as u8, (i / width * 5) as u8, (i * 7) as u8, 255)).collect();
+    let image = ImgVec::new(pixels, width, height);
+    let (liq, mut res, image) = Writer::quantize(image, &vec![255; width * height], false, &settings, &[], &[], &pools).unwrap();
+
+    let (whole, whole_pal) = Writer::remap_in_bands(&liq, &mut res, image.as_ref(), None, &settings, &pools, usize::MAX).unwrap();
+    let (banded, banded_pal) = Writer::remap_in_bands(&liq, &mut res, image.as_ref(), None, &settings, &pools, width * 5).unwrap();
+    assert_eq!((width, height), (banded.width(), banded.height()));
+    // the whole frame's palette may have been adjusted slightly during remapping
+    let close = |a: u8, b: u8| (i16::from(a) - i16::from(b)).abs() <= 8;
+    for (w, b) in whole.pixels().zip(banded.pixels()) {
+        let (w, b) = (whole_pal[w as usize], banded_pal[b as usize]);
+        assert!(close(w.r, b.r) && close(w.g, b.g) && close(w.b, b.b) && w.a == b.a, "{:?} {:?}", w, b);
+    }
+}
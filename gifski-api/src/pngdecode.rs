@@ -0,0 +1,65 @@
+//! With the `png` feature, PNG frames are decoded with the `png` crate, which is faster than lodepng
+//! for large frames. Otherwise lodepng is used.
+
+use crate::error::*;
+use imgref::*;
+use rgb::*;
+use std::path::Path;
+
+/// Decodes a PNG file to 8-bit RGBA
+pub(crate) fn decode_png_file(path: &Path) -> CatResult<ImgVec<RGBA8>> {
+    decode_file(path).map_err(|err| Error::PNG(format!("Can't load {}: {}", path.display(), err)))
+}
+
+#[cfg(not(feature = "png"))]
+fn decode_file(path: &Path) -> Result<ImgVec<RGBA8>, lodepng::Error> {
+    let image = lodepng::decode32_file(path)?;
+    Ok(Img::new(image.buffer, image.width, image.height))
+}
+
+#[cfg(feature = "png")]
+fn decode_file(path: &Path) -> Result<ImgVec<RGBA8>, png::DecodingError> {
+    decode(std::io::BufReader::new(std::fs::File::open(path)?))
+}
+
+#[cfg(feature = "png")]
+fn decode(reader: impl std::io::Read) -> Result<ImgVec<RGBA8>, png::DecodingError> {
+    let mut decoder = png::Decoder::new(reader);
+    // palettes and low bit depths become 8-bit channels, and tRNS becomes alpha
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (info, mut reader) = decoder.read_info()?;
+    let (width, height) = (info.width as usize, info.height as usize);
+
+    let pixels = if info.color_type == png::ColorType::RGBA {
+        // decoded in place, because most frames are RGBA
+        let mut pixels = vec![RGBA8::new(0, 0, 0, 0); width * height];
+        reader.next_frame(pixels.as_bytes_mut())?;
+        pixels
+    } else {
+        let mut buf = vec![0; info.buffer_size()];
+        reader.next_frame(&mut buf)?;
+        match info.color_type {
+            png::ColorType::RGB => buf.as_rgb().iter().map(|px| px.alpha(255)).collect(),
+            png::ColorType::GrayscaleAlpha => buf.chunks_exact(2).map(|ga| RGBA8::new(ga[0], ga[0], ga[0], ga[1])).collect(),
+            _ => buf.iter().map(|&g| RGBA8::new(g, g, g, 255)).collect(),
+        }
+    };
+    Ok(Img::new(pixels, width, height))
+}
+
+#[test]
+#[cfg(feature = "png")]
+fn decodes_to_rgba() {
+    let mut file = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut file, 3, 2);
+        encoder.set_color(png::ColorType::GrayscaleAlpha);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(&[0, 255, 50, 128, 100, 0, 150, 255, 200, 255, 250, 1]).unwrap();
+    }
+    let image = decode(&file[..]).unwrap();
+    assert_eq!((3, 2), (image.width(), image.height()));
+    assert_eq!(RGBA8::new(50, 50, 50, 128), image[(1_usize, 0_usize)]);
+    assert_eq!(RGBA8::new(250, 250, 250, 1), image[(2_usize, 1_usize)]);
+}
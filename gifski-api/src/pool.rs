@@ -0,0 +1,88 @@
+use rgb::RGBA8;
+use std::sync::{Arc, Mutex};
+
+/// Buffers kept for reuse. All frames have the same size, so a few are enough for steady-state encoding.
+const MAX_POOLED: usize = 4;
+
+/// Recycles frame-sized buffers between pipeline stages, so that encoding doesn't allocate for every frame.
+///
+/// Clones share the buffers, so a buffer can be given back on a different thread than the one that took it.
+pub(crate) struct BufferPool<T> {
+    free: Arc<Mutex<Vec<Vec<T>>>>,
+}
+
+impl<T> Clone for BufferPool<T> {
+    fn clone(&self) -> Self {
+        Self { free: self.free.clone() }
+    }
+}
+
+impl<T> BufferPool<T> {
+    pub fn new() -> Self {
+        Self { free: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    /// Empty buffer with room for at least `capacity` elements
+    pub fn take(&self, capacity: usize) -> Vec<T> {
+        let mut buf = self.free.lock().ok().and_then(|mut free| free.pop()).unwrap_or_default();
+        buf.clear();
+        buf.reserve(capacity);
+        buf
+    }
+
+    /// The buffer is dropped if enough are pooled already
+    pub fn give(&self, buf: Vec<T>) {
+        if buf.capacity() == 0 {
+            return;
+        }
+        if let Ok(mut free) = self.free.lock() {
+            if free.len() < MAX_POOLED {
+                free.push(buf);
+            }
+        }
+    }
+}
+
+impl<T: Clone> BufferPool<T> {
+    /// Buffer of `len` copies of `value`
+    pub fn take_filled(&self, len: usize, value: T) -> Vec<T> {
+        let mut buf = self.take(len);
+        buf.resize(len, value);
+        buf
+    }
+}
+
+/// Pools for pixels, and for importance maps and palette indices
+#[derive(Clone)]
+pub(crate) struct Pools {
+    pub rgba: BufferPool<RGBA8>,
+    pub bytes: BufferPool<u8>,
+}
+
+impl Pools {
+    pub fn new() -> Self {
+        Self {
+            rgba: BufferPool::new(),
+            bytes: BufferPool::new(),
+        }
+    }
+}
+
+#[test]
+fn reuses_buffers() {
+    let pool = BufferPool::new();
+    let mut buf = pool.take_filled(100, 1_u8);
+    assert_eq!(100, buf.len());
+    buf[0] = 2;
+    let ptr = buf.as_ptr();
+    pool.clone().give(buf);
+
+    let buf = pool.take(50);
+    assert!(buf.is_empty());
+    assert_eq!(ptr, buf.as_ptr());
+
+    for _ in 0..MAX_POOLED + 2 {
+        pool.give(vec![0; 10]);
+    }
+    assert_eq!(MAX_POOLED, pool.free.lock().unwrap().len());
+}
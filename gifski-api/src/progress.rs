@@ -1,16 +1,137 @@
 pub use pbr::ProgressBar;
 use std::io::Stdout;
 use std::os::raw::{c_int, c_void};
+use std::time::Duration;
 
 /// A trait that is used to report progress to some consumer.
 pub trait ProgressReporter: Send {
     /// Increase the progress counter. Return `false` to abort processing.
     fn increase(&mut self) -> bool;
 
+    /// Same as `increase()`, but with details of the frame: its number (1..), presentation timestamp in seconds,
+    /// and the size of the GIF written so far. Skipped frames are reported with the timestamp of the next written frame.
+    fn frame_written(&mut self, ordinal_frame_number: usize, presentation_timestamp: f64, bytes_written: u64) -> bool {
+        let _ = (ordinal_frame_number, presentation_timestamp, bytes_written);
+        self.increase()
+    }
+
+    /// Called while no frame has been written for a while, e.g. while the first frames are decoded and quantized.
+    /// Return `false` to abort processing. Not called with the `threadless` feature, or when frames are small enough
+    /// to be encoded on the writer's thread.
+    fn stage_progress(&mut self, progress: &StageProgress) -> bool {
+        let _ = progress;
+        true
+    }
+
+    /// Called after a frame has been compressed and written. Frames that have been skipped aren't reported.
+    fn frame_stats(&mut self, stats: &FrameStats) {
+        let _ = stats;
+    }
+
+    /// Called once after all frames have been written, with time spent in each stage of encoding.
+    fn stage_times(&mut self, times: &StageTimes) {
+        let _ = times;
+    }
+
+    /// Called after a frame has been written, with time it spent in each stage. Only called with the `metrics` feature.
+    fn frame_times(&mut self, times: &FrameTimes) {
+        let _ = times;
+    }
+
+    /// Called when the GIF is over `Writer::set_max_size()`, before it's encoded again with `next_quality`.
+    /// Frames of the next attempt are numbered from 1 again.
+    fn retry(&mut self, size: u64, next_quality: u8) {
+        let _ = (size, next_quality);
+    }
+
     /// Mark the progress as done.
     fn done(&mut self, msg: &str);
 }
 
+/// Details of a written frame, see `ProgressReporter::frame_stats`
+#[repr(C)]
+#[derive(Debug, Copy, Clone)]
+pub struct FrameStats {
+    /// 1..
+    pub ordinal_frame_number: u32,
+    /// In 1/100ths of a second
+    pub delay: u16,
+    /// Number of colors, including transparency
+    pub palette_size: u16,
+    /// Bytes added to the file by this frame
+    pub compressed_size: u64,
+}
+
+/// Frames that went through the stages before writing, see `ProgressReporter::stage_progress`
+#[derive(Debug, Copy, Clone, Default)]
+pub struct StageProgress {
+    /// Frames added to the `Collector`, and decoded and resized
+    pub decoded: usize,
+    /// Frames that got a palette. Frames that don't change anything on screen aren't counted.
+    pub quantized: usize,
+}
+
+/// Time spent in each stage of encoding, see `ProgressReporter::stage_times`.
+///
+/// Stages run on separate threads at the same time, so the times add up to more than the total time.
+/// Time spent waiting for other stages isn't counted.
+#[derive(Debug, Clone, Default)]
+pub struct StageTimes {
+    /// Number of frames added to the `Collector`
+    pub frames: usize,
+    /// Decoding of PNG files and conversion of YUV frames. Frames decoded by the caller aren't included.
+    pub decode: Duration,
+    /// Cropping and resizing
+    pub resize: Duration,
+    pub denoise: Duration,
+    pub quantize: Duration,
+    pub remap: Duration,
+    /// Making frames cheaper to encode by reusing the previous frame's pixels
+    pub optimize: Duration,
+    /// LZW compression of frames
+    pub lzw: Duration,
+    /// Writing to the output
+    pub write: Duration,
+    /// Time spent waiting on the queues between stages. Always zero without the `metrics` feature.
+    pub waits: QueueWaits,
+}
+
+/// Time one frame spent in each stage, see `ProgressReporter::frame_times`
+#[derive(Debug, Clone, Default)]
+pub struct FrameTimes {
+    /// 1..
+    pub ordinal_frame_number: usize,
+    pub denoise: Duration,
+    pub quantize: Duration,
+    pub remap: Duration,
+    pub optimize: Duration,
+    /// LZW compression and writing to the output
+    pub write: Duration,
+}
+
+/// Time stages spent waiting on each queue, see `StageTimes::waits`.
+///
+/// Encoding on the caller's thread (`sync::Encoder` or the `threadless` feature) doesn't use the queues.
+#[derive(Debug, Clone, Default)]
+pub struct QueueWaits {
+    /// Frames added to the `Collector`, waiting to be decoded and compared with the next frame
+    pub input: QueueWait,
+    /// Frames waiting for a quantization thread
+    pub quantize: QueueWait,
+    pub remap: QueueWait,
+    /// Frames waiting to be optimized and written
+    pub write: QueueWait,
+}
+
+/// See `QueueWaits`
+#[derive(Debug, Clone, Default)]
+pub struct QueueWait {
+    /// The stage taking frames out of the queue waited for the next frame
+    pub empty: Duration,
+    /// The stage putting frames into the queue waited for room in it
+    pub full: Duration,
+}
+
 /// No-op progress reporter
 pub struct NoProgress {}
 
@@ -28,6 +149,20 @@ impl ProgressCallback {
     }
 }
 
+/// For C, with frame details
+pub struct ProgressCallback2 {
+    callback: unsafe extern "C" fn(u32, f64, u64, *mut c_void) -> c_int,
+    arg: *mut c_void,
+}
+
+unsafe impl Send for ProgressCallback2 {}
+
+impl ProgressCallback2 {
+    pub fn new(callback: unsafe extern "C" fn(u32, f64, u64, *mut c_void) -> c_int, arg: *mut c_void) -> Self {
+        Self { callback, arg }
+    }
+}
+
 impl ProgressReporter for NoProgress {
     fn increase(&mut self) -> bool {
         true
@@ -42,6 +177,19 @@ impl ProgressReporter for ProgressCallback {
     fn done(&mut self, _msg: &str) {}
 }
 
+impl ProgressReporter for ProgressCallback2 {
+    /// Progress is reported only via `frame_written`
+    fn increase(&mut self) -> bool {
+        true
+    }
+
+    fn frame_written(&mut self, ordinal_frame_number: usize, presentation_timestamp: f64, bytes_written: u64) -> bool {
+        unsafe { (self.callback)(ordinal_frame_number as u32, presentation_timestamp, bytes_written, self.arg) == 1 }
+    }
+
+    fn done(&mut self, _msg: &str) {}
+}
+
 /// Implement the progress reporter trait for a progress bar,
 /// to make it usable for frame processing reporting.
 impl ProgressReporter for ProgressBar<Stdout> {
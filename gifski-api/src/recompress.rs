@@ -0,0 +1,207 @@
+use crate::error::*;
+use crate::trim_image;
+use crate::{GifsicleWriter, IndexedFrame, Repeat};
+use gif::DisposalMethod;
+use imgref::*;
+use rgb::*;
+use std::io::{Read, Write};
+
+/// Make an existing GIF smaller without decoding it to RGBA and quantizing it again.
+///
+/// Palettes and pixels of all frames are kept as they are. Rows that don't change anything on screen are trimmed,
+/// frames that don't change anything are merged with the previous frame, and LZW compression is redone
+/// with the given amount of `loss` (same as gifsicle's `--lossy`, 0 is lossless).
+///
+/// If `repeat` is `None`, the output loops as many times as the input.
+pub fn recompress<R: Read, W: Write>(mut reader: R, mut writer: W, loss: u32, repeat: Option<Repeat>) -> CatResult<()> {
+    let mut data = Vec::new();
+    // like the gif crate's own read errors, so that they're not mistaken for errors writing the output
+    reader.read_to_end(&mut data).map_err(|e| Error::GifDecode(e.to_string()))?;
+    let repeat = repeat.unwrap_or_else(|| loop_count(&data).unwrap_or(Repeat::Finite(0)));
+
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(&data[..])?;
+
+    let screen_width = decoder.width();
+    let screen_height = decoder.height();
+    let global_pal = decoder.global_palette().map(rgba_palette);
+
+    let mut screen = gif_dispose::Screen::new(screen_width.into(), screen_height.into(), RGBA8::new(0, 0, 0, 0), None);
+    let mut out = GifsicleWriter::new(&mut writer, screen_width, screen_height, repeat, loss);
+
+    // Held back, because the next frame may turn out to be a no-op that only extends its delay
+    let mut pending: Option<IndexedFrame> = None;
+    while let Some(frame) = decoder.read_next_frame()? {
+        let mut palette = frame.palette.as_deref().map(rgba_palette)
+            .or_else(|| global_pal.clone())
+            .ok_or_else(|| Error::GifDecode("frame without a palette".into()))?;
+        if let Some(p) = frame.transparent.and_then(|idx| palette.get_mut(idx as usize)) {
+            p.a = 0;
+        }
+
+        let (left, top) = (frame.left, frame.top);
+        let image = ImgVec::new(frame.buffer.to_vec(), frame.width.into(), frame.height.into());
+        if left as usize + image.width() > screen_width as usize || top as usize + image.height() > screen_height as usize {
+            return Err(Error::WrongSize(format!("Frame {}×{} at {},{} doesn't fit in {}×{} screen",
+                image.width(), image.height(), left, top, screen_width, screen_height)));
+        }
+
+        let mut screen_after_dispose = screen.dispose();
+
+        // Other disposal methods depend on the frame's area, so it can't shrink
+        let can_trim = pending.is_some() && matches!(frame.dispose, DisposalMethod::Keep | DisposalMethod::Any);
+        let trimmed = if can_trim {
+            let bg = screen_after_dispose.pixels().sub_image(left.into(), top.into(), image.width(), image.height());
+            trim_image(image.clone(), &palette, frame.transparent, bg)
+        } else {
+            Some((0, 0, image.clone()))
+        };
+
+        screen_after_dispose.then_blit(Some(&palette), frame.dispose, left, top, image.as_ref(), frame.transparent)?;
+
+        match trimmed {
+            Some((trim_left, trim_top, image)) => {
+                if let Some(prev) = pending.replace(IndexedFrame {
+                    left: left + trim_left,
+                    top: top + trim_top,
+                    image,
+                    palette,
+                    transparent_index: frame.transparent,
+                    dispose: frame.dispose,
+                    delay: frame.delay,
+                }) {
+                    out.write_frame(prev)?;
+                }
+            },
+            None => {
+                let prev = pending.as_mut().expect("first frame is never trimmed");
+                if matches!(prev.dispose, DisposalMethod::Keep | DisposalMethod::Any) {
+                    prev.delay = prev.delay.saturating_add(frame.delay);
+                } else {
+                    // Previous frame is disposed before this one is shown, so this one has to stay
+                    let prev = pending.replace(IndexedFrame {
+                        left, top,
+                        image,
+                        palette,
+                        transparent_index: frame.transparent,
+                        dispose: frame.dispose,
+                        delay: frame.delay,
+                    });
+                    out.write_frame(prev.expect("pending"))?;
+                }
+            },
+        }
+    }
+
+    out.write_frame(pending.ok_or(Error::NoFrames)?)?;
+    out.finish()
+}
+
+/// Loop count from the NETSCAPE2.0 extension. The gif crate skips it, so this walks the blocks of the file.
+/// `None` if the file doesn't have it, which means it plays once.
+fn loop_count(gif: &[u8]) -> Option<Repeat> {
+    fn color_table_len(flags: u8) -> usize {
+        if flags & 0x80 != 0 { 3 << ((flags & 7) + 1) } else { 0 }
+    }
+    fn skip_sub_blocks(gif: &[u8], mut pos: usize) -> Option<usize> {
+        loop {
+            let len = *gif.get(pos)? as usize;
+            pos += 1 + len;
+            if len == 0 {
+                return Some(pos);
+            }
+        }
+    }
+
+    let mut pos = 13 + color_table_len(*gif.get(10)?);
+    loop {
+        match *gif.get(pos)? {
+            0x21 => {
+                let label = *gif.get(pos + 1)?;
+                pos += 2;
+                if label == 0xFF && gif.get(pos..pos + 12) == Some(b"\x0BNETSCAPE2.0") {
+                    let sub_block = gif.get(pos + 12..pos + 17)?;
+                    if sub_block[0] == 3 && sub_block[1] == 1 {
+                        return Some(match u16::from_le_bytes([sub_block[2], sub_block[3]]) {
+                            0 => Repeat::Infinite,
+                            n => Repeat::Finite(n),
+                        });
+                    }
+                }
+                pos = skip_sub_blocks(gif, pos)?;
+            },
+            0x2C => {
+                // descriptor, color table, and LZW code size
+                pos += 10 + color_table_len(*gif.get(pos + 9)?) + 1;
+                pos = skip_sub_blocks(gif, pos)?;
+            },
+            _ => return None,
+        }
+    }
+}
+
+fn rgba_palette(pal: &[u8]) -> Vec<RGBA8> {
+    pal.chunks_exact(3).map(|c| RGBA8::new(c[0], c[1], c[2], 255)).collect()
+}
+
+#[test]
+fn recompress_merges_identical_frames() {
+    let mut gif = Vec::new();
+    {
+        let mut enc = gif::Encoder::new(&mut gif, 2, 2, &[0, 0, 0, 255, 255, 255]).unwrap();
+        for (buf, delay) in [([0, 1, 1, 0], 10), ([0, 1, 1, 0], 20), ([1, 1, 1, 0], 30)].iter() {
+            enc.write_frame(&gif::Frame {
+                width: 2, height: 2,
+                delay: *delay,
+                buffer: buf[..].into(),
+                ..gif::Frame::default()
+            }).unwrap();
+        }
+    }
+
+    let mut out = Vec::new();
+    recompress(&gif[..], &mut out, 0, None).unwrap();
+
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut dec = options.read_info(&out[..]).unwrap();
+    let first = dec.read_next_frame().unwrap().unwrap();
+    assert_eq!(30, first.delay);
+    let second = dec.read_next_frame().unwrap().unwrap();
+    assert_eq!((2, 1), (second.width, second.height));
+    assert_eq!(30, second.delay);
+    assert!(dec.read_next_frame().unwrap().is_none());
+}
+
+#[test]
+fn recompress_keeps_loop_count() {
+    let encode = |repeat: Option<gif::Repeat>| {
+        let mut gif = Vec::new();
+        {
+            let mut enc = gif::Encoder::new(&mut gif, 2, 2, &[0, 0, 0, 255, 255, 255]).unwrap();
+            if let Some(repeat) = repeat {
+                enc.set_repeat(repeat).unwrap();
+            }
+            for buf in [[0, 1, 1, 0], [1, 1, 1, 0]].iter() {
+                enc.write_frame(&gif::Frame {
+                    width: 2, height: 2,
+                    delay: 10,
+                    buffer: buf[..].into(),
+                    ..gif::Frame::default()
+                }).unwrap();
+            }
+        }
+        gif
+    };
+    let recompressed = |gif: Vec<u8>, repeat| {
+        let mut out = Vec::new();
+        recompress(&gif[..], &mut out, 0, repeat).unwrap();
+        loop_count(&out)
+    };
+
+    assert!(matches!(recompressed(encode(Some(gif::Repeat::Finite(3))), None), Some(Repeat::Finite(3))));
+    assert!(matches!(recompressed(encode(Some(gif::Repeat::Infinite)), None), Some(Repeat::Infinite)));
+    assert!(recompressed(encode(None), None).is_none(), "plays once");
+    assert!(matches!(recompressed(encode(Some(gif::Repeat::Finite(3))), Some(Repeat::Infinite)), Some(Repeat::Infinite)));
+}
@@ -0,0 +1,408 @@
+//! Per-pixel loops that the compiler doesn't vectorize on its own.
+//! SSE2 is always available on x86-64. Other architectures use the scalar code.
+
+use crate::c_api::ARGB8;
+use rgb::alt::BGRA8;
+use rgb::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+/// No channel differs by more than `threshold`, except in pixels that are transparent in both rows
+pub(crate) fn rows_close(a: &[RGBA8], b: &[RGBA8], threshold: u8) -> bool {
+    debug_assert_eq!(a.len(), b.len());
+    let mut done = 0;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        let limit = _mm_set1_epi8(threshold as i8);
+        let zero = _mm_setzero_si128();
+        for (a4, b4) in a.chunks_exact(4).zip(b.chunks_exact(4)) {
+            let va = _mm_loadu_si128(a4.as_ptr() as *const __m128i);
+            let vb = _mm_loadu_si128(b4.as_ptr() as *const __m128i);
+            let diff = _mm_or_si128(_mm_subs_epu8(va, vb), _mm_subs_epu8(vb, va));
+            // non-zero where the difference is above the threshold
+            let over = _mm_subs_epu8(diff, limit);
+            if _mm_movemask_epi8(_mm_cmpeq_epi8(over, zero)) != 0xFFFF && !rows_close_scalar(a4, b4, threshold) {
+                return false;
+            }
+            done += 4;
+        }
+    }
+    rows_close_scalar(&a[done..], &b[done..], threshold)
+}
+
+fn rows_close_scalar(a: &[RGBA8], b: &[RGBA8], threshold: u8) -> bool {
+    let close = |a: u8, b: u8| (i16::from(a) - i16::from(b)).abs() <= i16::from(threshold);
+    a.iter().zip(b).all(|(a, b)| {
+        (a.a == 0 && b.a == 0) || (close(a.r, b.r) && close(a.g, b.g) && close(a.b, b.b) && close(a.a, b.a))
+    })
+}
+
+/// Appends the row with `offsets` added to RGB channels, clamped to 0-255.
+/// Offsets repeat every 8 pixels, starting from the first pixel of the row.
+pub(crate) fn add_offsets(row: &[RGBA8], offsets: &[i16; 8], out: &mut Vec<RGBA8>) {
+    out.reserve(row.len());
+    let mut done = 0;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        // saturating add of the positive part, and then subtract of the negative part
+        let lanes = |first: usize, sign: i16| {
+            let mut bytes = [0u8; 16];
+            for (px, &offset) in bytes.chunks_exact_mut(4).zip(&offsets[first..first + 4]) {
+                let offset = (offset * sign).clamp(0, 255) as u8;
+                px[..3].copy_from_slice(&[offset; 3]);
+            }
+            _mm_loadu_si128(bytes.as_ptr() as *const __m128i)
+        };
+        let (add_lo, add_hi, sub_lo, sub_hi) = (lanes(0, 1), lanes(4, 1), lanes(0, -1), lanes(4, -1));
+        let mut tmp = [RGBA8::default(); 8];
+        for px8 in row.chunks_exact(8) {
+            let lo = _mm_loadu_si128(px8.as_ptr() as *const __m128i);
+            let hi = _mm_loadu_si128(px8[4..].as_ptr() as *const __m128i);
+            _mm_storeu_si128(tmp.as_mut_ptr() as *mut __m128i, _mm_subs_epu8(_mm_adds_epu8(lo, add_lo), sub_lo));
+            _mm_storeu_si128(tmp[4..].as_mut_ptr() as *mut __m128i, _mm_subs_epu8(_mm_adds_epu8(hi, add_hi), sub_hi));
+            out.extend_from_slice(&tmp);
+            done += 8;
+        }
+    }
+    let shift = |c: u8, offset: i16| (i16::from(c) + offset).clamp(0, 255) as u8;
+    out.extend(row[done..].iter().enumerate().map(|(x, px)| {
+        let offset = offsets[(done + x) & 7];
+        RGBA8::new(shift(px.r, offset), shift(px.g, offset), shift(px.b, offset), px.a)
+    }));
+}
+
+/// Calls `f` with the index of every pixel that isn't fully opaque. Opaque pixels are skipped 4 at a time.
+pub(crate) fn for_each_translucent(row: &mut [RGBA8], mut f: impl FnMut(usize, &mut RGBA8)) {
+    let mut done = 0;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        let opaque = _mm_set1_epi8(-1);
+        for px4 in row.chunks_exact_mut(4) {
+            let v = _mm_loadu_si128(px4.as_ptr() as *const __m128i);
+            // bits of alpha bytes
+            if _mm_movemask_epi8(_mm_cmpeq_epi8(v, opaque)) & 0x8888 != 0x8888 {
+                for (i, px) in px4.iter_mut().enumerate() {
+                    if px.a < 255 {
+                        f(done + i, px);
+                    }
+                }
+            }
+            done += 4;
+        }
+    }
+    for (i, px) in row[done..].iter_mut().enumerate() {
+        if px.a < 255 {
+            f(done + i, px);
+        }
+    }
+}
+
+/// Every pixel is fully opaque. Stops at the first 4 pixels that aren't.
+pub(crate) fn all_opaque(row: &[RGBA8]) -> bool {
+    let mut done = 0;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        let opaque = _mm_set1_epi8(-1);
+        for px4 in row.chunks_exact(4) {
+            let v = _mm_loadu_si128(px4.as_ptr() as *const __m128i);
+            if _mm_movemask_epi8(_mm_cmpeq_epi8(v, opaque)) & 0x8888 != 0x8888 {
+                return false;
+            }
+            done += 4;
+        }
+    }
+    row[done..].iter().all(|px| px.a == 255)
+}
+
+/// Any byte is non-zero
+pub(crate) fn any_nonzero(bytes: &[u8]) -> bool {
+    let mut done = 0;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        let zero = _mm_setzero_si128();
+        for chunk in bytes.chunks_exact(16) {
+            let v = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+            if _mm_movemask_epi8(_mm_cmpeq_epi8(v, zero)) != 0xFFFF {
+                return true;
+            }
+            done += 16;
+        }
+    }
+    bytes[done..].iter().any(|&b| b != 0)
+}
+
+/// Appends ARGB pixels converted to RGBA
+pub(crate) fn extend_from_argb(row: &[ARGB8], out: &mut Vec<RGBA8>) {
+    out.reserve(row.len());
+    let mut done = 0;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        let mut tmp = [RGBA8::default(); 4];
+        for px4 in row.chunks_exact(4) {
+            let v = _mm_loadu_si128(px4.as_ptr() as *const __m128i);
+            // alpha is the lowest byte of each little-endian pixel, and moves to the top
+            _mm_storeu_si128(tmp.as_mut_ptr() as *mut __m128i, _mm_or_si128(_mm_srli_epi32(v, 8), _mm_slli_epi32(v, 24)));
+            out.extend_from_slice(&tmp);
+            done += 4;
+        }
+    }
+    out.extend(row[done..].iter().map(|px| RGBA8::new(px.r, px.g, px.b, px.a)));
+}
+
+/// Appends BGRA pixels converted to RGBA
+pub(crate) fn extend_from_bgra(row: &[BGRA8], out: &mut Vec<RGBA8>) {
+    out.reserve(row.len());
+    let mut done = 0;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        let green_alpha = _mm_set1_epi32(0xFF00FF00_u32 as i32);
+        let mut tmp = [RGBA8::default(); 4];
+        for px4 in row.chunks_exact(4) {
+            let v = _mm_loadu_si128(px4.as_ptr() as *const __m128i);
+            // swaps the lowest and the third byte of each pixel
+            let blue_red = _mm_andnot_si128(green_alpha, v);
+            let red_blue = _mm_or_si128(_mm_slli_epi32(blue_red, 16), _mm_srli_epi32(blue_red, 16));
+            _mm_storeu_si128(tmp.as_mut_ptr() as *mut __m128i, _mm_or_si128(_mm_and_si128(green_alpha, v), red_blue));
+            out.extend_from_slice(&tmp);
+            done += 4;
+        }
+    }
+    out.extend(row[done..].iter().map(|px| RGBA8::new(px.r, px.g, px.b, px.a)));
+}
+
+/// Palette as integers, for comparing rows of palette indices with screen pixels without a branch per pixel
+pub(crate) struct PaletteLookup {
+    /// Colors in the low 32 bits. Indices past the end of the palette have bit 32 set, so they never match.
+    colors: [u64; 256],
+    /// Zero for the transparent index, which matches any color
+    masks: [u64; 256],
+    transparent_index: Option<u8>,
+}
+
+impl PaletteLookup {
+    pub fn new(palette: &[RGBA8], transparent_index: Option<u8>) -> Self {
+        let mut colors = [1 << 32; 256];
+        for (color, &px) in colors.iter_mut().zip(palette) {
+            *color = pixel_bits(px).into();
+        }
+        let mut masks = [!0; 256];
+        if let Some(index) = transparent_index {
+            masks[index as usize] = 0;
+        }
+        Self { colors, masks, transparent_index }
+    }
+
+    /// Every pixel is either transparent or the same color as the screen
+    pub fn row_matches(&self, indices: &[u8], screen: &[RGBA8]) -> bool {
+        debug_assert_eq!(indices.len(), screen.len());
+        indices.chunks(16).zip(screen.chunks(16)).all(|(indices, screen)| {
+            self.all_transparent(indices) || self.colors_match(indices, screen)
+        })
+    }
+
+    fn colors_match(&self, indices: &[u8], screen: &[RGBA8]) -> bool {
+        let diff = indices.iter().zip(screen).fold(0, |diff, (&index, &px)| {
+            diff | ((self.colors[index as usize] ^ u64::from(pixel_bits(px))) & self.masks[index as usize])
+        });
+        diff == 0
+    }
+
+    /// Diffed frames are mostly transparent, and that is checked 16 pixels at a time
+    fn all_transparent(&self, indices: &[u8]) -> bool {
+        let index = match self.transparent_index {
+            Some(index) => index,
+            None => return false,
+        };
+        #[cfg(target_arch = "x86_64")]
+        unsafe {
+            if indices.len() == 16 {
+                let v = _mm_loadu_si128(indices.as_ptr() as *const __m128i);
+                return _mm_movemask_epi8(_mm_cmpeq_epi8(v, _mm_set1_epi8(index as i8))) == 0xFFFF;
+            }
+        }
+        indices.iter().all(|&i| i == index)
+    }
+}
+
+fn pixel_bits(px: RGBA8) -> u32 {
+    u32::from_ne_bytes([px.r, px.g, px.b, px.a])
+}
+
+#[cfg(test)]
+fn test_pixels(seed: u32, len: usize) -> Vec<RGBA8> {
+    let mut state = seed;
+    let mut next = move || {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (state >> 16) as u8
+    };
+    (0..len).map(|_| {
+        let a = match next() % 4 { 0 => 0, 1 => 255, _ => next() };
+        RGBA8::new(next(), next(), next(), a)
+    }).collect()
+}
+
+#[test]
+fn rows_close_matches_scalar() {
+    let a = test_pixels(1, 103);
+    for threshold in [0, 3, 40, 255].iter().copied() {
+        let mut b = a.clone();
+        assert!(rows_close(&a, &b, threshold));
+        for i in (0..b.len()).step_by(7) {
+            b[i].g = b[i].g.saturating_add((i % 50) as u8);
+            assert_eq!(rows_close_scalar(&a, &b, threshold), rows_close(&a, &b, threshold));
+        }
+    }
+    // color of transparent pixels doesn't matter
+    let a = vec![RGBA8::new(0, 0, 0, 0); 9];
+    let b = vec![RGBA8::new(255, 255, 255, 0); 9];
+    assert!(rows_close(&a, &b, 0));
+}
+
+#[test]
+fn add_offsets_matches_scalar() {
+    let row = test_pixels(2, 45);
+    let offsets = [-16, 16, -3, 0, 5, -1, 15, -9];
+    let mut out = Vec::new();
+    add_offsets(&row, &offsets, &mut out);
+    assert_eq!(row.len(), out.len());
+    for (x, (px, out)) in row.iter().zip(&out).enumerate() {
+        let shift = |c: u8| (i16::from(c) + offsets[x & 7]).clamp(0, 255) as u8;
+        assert_eq!(RGBA8::new(shift(px.r), shift(px.g), shift(px.b), px.a), *out);
+    }
+}
+
+#[test]
+fn skips_only_opaque() {
+    let mut row = test_pixels(3, 37);
+    let expected: Vec<_> = row.iter().enumerate().filter(|(_, px)| px.a < 255).map(|(i, _)| i).collect();
+    let mut seen = Vec::new();
+    for_each_translucent(&mut row, |i, _| seen.push(i));
+    assert_eq!(expected, seen);
+}
+
+#[test]
+fn opaque() {
+    let mut row = vec![RGBA8::new(1, 2, 3, 255); 39];
+    assert!(all_opaque(&row));
+    for i in [0, 3, 4, 35, 36, 38].iter().copied() {
+        row[i].a = 254;
+        assert!(!all_opaque(&row));
+        row[i].a = 255;
+    }
+}
+
+#[test]
+fn nonzero() {
+    let mut bytes = vec![0; 40];
+    assert!(!any_nonzero(&bytes));
+    for i in [0, 15, 16, 31, 39].iter().copied() {
+        bytes[i] = 1;
+        assert!(any_nonzero(&bytes));
+        bytes[i] = 0;
+    }
+}
+
+#[test]
+fn palette_rows_match_like_scalar() {
+    let palette = test_pixels(4, 200);
+    let screen = test_pixels(5, 70);
+    for transparent_index in [None, Some(3), Some(250)].iter().copied() {
+        let lookup = PaletteLookup::new(&palette, transparent_index);
+        let scalar = |indices: &[u8], screen: &[RGBA8]| indices.iter().zip(screen).all(|(&i, bg)| {
+            Some(i) == transparent_index || palette.get(i as usize) == Some(bg)
+        });
+        // indices past the end of the palette, and runs of the transparent index
+        let mut indices: Vec<u8> = (0..screen.len()).map(|x| [3, 250, 199, 255][x % 4]).collect();
+        assert!(!lookup.row_matches(&indices, &screen));
+        indices.iter_mut().filter(|i| **i == 255).for_each(|i| *i = 7);
+        indices[20..40].iter_mut().for_each(|i| *i = 3);
+        for x in 0..screen.len() {
+            let mut screen = screen.clone();
+            for (i, px) in indices.iter().zip(screen.iter_mut()) {
+                if let Some(&color) = palette.get(*i as usize) {
+                    *px = color;
+                }
+            }
+            assert_eq!(scalar(&indices, &screen), lookup.row_matches(&indices, &screen));
+            screen[x].b ^= 1;
+            assert_eq!(scalar(&indices, &screen), lookup.row_matches(&indices, &screen));
+        }
+    }
+}
+
+#[test]
+fn swizzles_to_rgba() {
+    let rgba = test_pixels(6, 23);
+    let argb: Vec<_> = rgba.iter().map(|px| ARGB8 { a: px.a, r: px.r, g: px.g, b: px.b }).collect();
+    let bgra: Vec<_> = rgba.iter().map(|px| BGRA8 { b: px.b, g: px.g, r: px.r, a: px.a }).collect();
+    let mut out = Vec::new();
+    extend_from_argb(&argb, &mut out);
+    assert_eq!(rgba, out);
+    out.clear();
+    extend_from_bgra(&bgra, &mut out);
+    assert_eq!(rgba, out);
+}
+
+/// Compares the kernels with their scalar versions on a 1080p frame.
+/// Run with `cargo test --release simd_speed -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn simd_speed() {
+    use std::hint::black_box;
+    use std::time::{Duration, Instant};
+
+    let len = 1920 * 1080;
+    let frame = test_pixels(7, len);
+    let similar = frame.clone();
+    let opaque = vec![RGBA8::new(10, 20, 30, 255); len];
+    let unimportant = vec![0u8; len];
+    let bgra: Vec<_> = frame.iter().map(|px| BGRA8 { b: px.b, g: px.g, r: px.r, a: px.a }).collect();
+    let offsets = [-16, 16, -3, 0, 5, -1, 15, -9];
+    let palette = test_pixels(8, 255);
+    let lookup = PaletteLookup::new(&palette, Some(255));
+    let indices: Vec<u8> = (0..len).map(|i| (i % 256) as u8).collect();
+    let screen: Vec<_> = indices.iter().map(|&i| palette.get(i as usize).copied().unwrap_or_default()).collect();
+
+    let time = |f: &dyn Fn()| {
+        let mut best = Duration::from_secs(1000);
+        for _ in 0..30 {
+            let start = Instant::now();
+            f();
+            best = best.min(start.elapsed());
+        }
+        best
+    };
+    let compare = |name: &str, simd: &dyn Fn(), scalar: &dyn Fn()| {
+        let (simd, scalar) = (time(simd), time(scalar));
+        println!("{:<16} {:>10.2?} SIMD {:>10.2?} scalar {:>5.1}x", name, simd, scalar, scalar.as_secs_f64() / simd.as_secs_f64());
+    };
+
+    compare("rows_close", &|| assert!(rows_close(black_box(&frame), black_box(&similar), 3)),
+        &|| assert!(rows_close_scalar(black_box(&frame), black_box(&similar), 3)));
+    compare("add_offsets", &|| {
+        let mut out = Vec::with_capacity(len);
+        add_offsets(black_box(&frame), &offsets, &mut out);
+        black_box(out);
+    }, &|| {
+        let shift = |c: u8, offset: i16| (i16::from(c) + offset).clamp(0, 255) as u8;
+        let out: Vec<_> = black_box(&frame).iter().enumerate().map(|(x, px)| {
+            let offset = offsets[x & 7];
+            RGBA8::new(shift(px.r, offset), shift(px.g, offset), shift(px.b, offset), px.a)
+        }).collect();
+        black_box(out);
+    });
+    compare("all_opaque", &|| assert!(all_opaque(black_box(&opaque))),
+        &|| assert!(black_box(&opaque).iter().all(|px| px.a == 255)));
+    compare("any_nonzero", &|| assert!(!any_nonzero(black_box(&unimportant))),
+        &|| assert!(!black_box(&unimportant).iter().any(|&b| b != 0)));
+    compare("extend_from_bgra", &|| {
+        let mut out = Vec::with_capacity(len);
+        extend_from_bgra(black_box(&bgra), &mut out);
+        black_box(out);
+    }, &|| {
+        let out: Vec<_> = black_box(&bgra).iter().map(|px| RGBA8::new(px.r, px.g, px.b, px.a)).collect();
+        black_box(out);
+    });
+    compare("row_matches", &|| assert!(lookup.row_matches(black_box(&indices), black_box(&screen))),
+        &|| assert!(black_box(&indices).iter().zip(black_box(&screen)).all(|(&i, bg)| i == 255 || palette.get(i as usize) == Some(bg))));
+}
@@ -0,0 +1,186 @@
+use crate::error::*;
+use crate::DecodedFrame;
+use imgref::*;
+use rgb::*;
+
+/// Edges cropped off each side, as a fraction of the frame size. The frame can move within them.
+const MARGIN_DIVISOR: usize = 20;
+/// How slowly the virtual camera follows the real one (0-1). Higher removes slower movements too.
+const SMOOTHING: f32 = 0.9;
+/// Average luma difference after alignment above which frames are assumed to be of different scenes
+const SCENE_CUT_DIFF: f32 = 40.;
+/// The coarsest level of the motion search is about this size
+const COARSEST_SIZE: usize = 64;
+
+/// Removes camera shake by estimating global motion between frames,
+/// and cropping each frame so that the content moves only smoothly.
+pub(crate) struct Stabilize<I> {
+    inputs: I,
+    prev: Option<Pyramid>,
+    /// Where the camera has moved since the start, in pixels
+    position: (f32, f32),
+    /// Where it would be without shaking
+    smoothed: (f32, f32),
+}
+
+impl<I> Stabilize<I> {
+    pub fn new(inputs: I) -> Self {
+        Self { inputs, prev: None, position: (0., 0.), smoothed: (0., 0.) }
+    }
+
+    fn stabilize(&mut self, (image, pts, importance_map): DecodedFrame) -> DecodedFrame {
+        let (width, height) = (image.width(), image.height());
+        let (margin_x, margin_y) = (width / MARGIN_DIVISOR, height / MARGIN_DIVISOR);
+        if margin_x == 0 || margin_y == 0 {
+            return (image, pts, importance_map);
+        }
+
+        let curr = Pyramid::new(image.as_ref());
+        match self.prev.as_ref().and_then(|prev| prev.motion_to(&curr)) {
+            Some((dx, dy, diff)) if diff <= SCENE_CUT_DIFF => {
+                self.position.0 += dx as f32;
+                self.position.1 += dy as f32;
+            },
+            _ => {
+                self.position = (0., 0.);
+                self.smoothed = (0., 0.);
+            },
+        }
+        self.prev = Some(curr);
+        self.smoothed.0 = self.smoothed.0 * SMOOTHING + self.position.0 * (1. - SMOOTHING);
+        self.smoothed.1 = self.smoothed.1 * SMOOTHING + self.position.1 * (1. - SMOOTHING);
+
+        // the crop window moves with the shake, so that the content inside it doesn't
+        let left = (margin_x as f32 + self.position.0 - self.smoothed.0).round().max(0.).min((2 * margin_x) as f32);
+        let top = (margin_y as f32 + self.position.1 - self.smoothed.1).round().max(0.).min((2 * margin_y) as f32);
+        // when the shake is larger than the margin, the virtual camera has to follow
+        self.smoothed.0 = self.position.0 - (left - margin_x as f32);
+        self.smoothed.1 = self.position.1 - (top - margin_y as f32);
+
+        let (left, top) = (left as usize, top as usize);
+        let (crop_width, crop_height) = (width - 2 * margin_x, height - 2 * margin_y);
+        let image = owned(image.sub_image(left, top, crop_width, crop_height));
+        let importance_map = importance_map.map(|map| owned(map.sub_image(left, top, crop_width, crop_height)));
+        (image, pts, importance_map)
+    }
+}
+
+impl<I: Iterator<Item = CatResult<DecodedFrame>>> Iterator for Stabilize<I> {
+    type Item = CatResult<DecodedFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.inputs.next()?.map(|frame| self.stabilize(frame)))
+    }
+}
+
+/// Luma of the frame at progressively halved resolutions, finest first
+struct Pyramid {
+    levels: Vec<ImgVec<u8>>,
+}
+
+impl Pyramid {
+    fn new(image: ImgRef<'_, RGBA8>) -> Self {
+        let luma = image.pixels().map(|px| {
+            let y = (u32::from(px.r) * 77 + u32::from(px.g) * 150 + u32::from(px.b) * 29) >> 8;
+            (y * u32::from(px.a) / 255) as u8
+        }).collect();
+        let mut levels = vec![ImgVec::new(luma, image.width(), image.height())];
+        loop {
+            let last = levels.last().unwrap();
+            if last.width().max(last.height()) <= COARSEST_SIZE || last.width().min(last.height()) < 16 {
+                break;
+            }
+            let half = downsample(last.as_ref());
+            levels.push(half);
+        }
+        Self { levels }
+    }
+
+    /// How far the content has moved from this frame to the next one, and the average difference after moving it back
+    fn motion_to(&self, next: &Self) -> Option<(isize, isize, f32)> {
+        if self.levels.len() != next.levels.len() || self.levels[0].width() != next.levels[0].width() || self.levels[0].height() != next.levels[0].height() {
+            return None;
+        }
+        let mut best = (0, 0, f32::INFINITY);
+        for (level, (prev, curr)) in self.levels.iter().zip(&next.levels).enumerate().rev() {
+            let coarsest = level + 1 == self.levels.len();
+            // the coarsest level searches widely, and finer ones only refine it
+            let (center_x, center_y, range) = if coarsest { (0, 0, 4) } else { (best.0 * 2, best.1 * 2, 1) };
+            // large frames don't need every pixel compared
+            let step = if prev.width() * prev.height() > 500_000 { 2 } else { 1 };
+            best = (center_x, center_y, f32::INFINITY);
+            for dy in center_y - range..=center_y + range {
+                for dx in center_x - range..=center_x + range {
+                    let diff = average_difference(prev.as_ref(), curr.as_ref(), dx, dy, step);
+                    if diff < best.2 {
+                        best = (dx, dy, diff);
+                    }
+                }
+            }
+        }
+        if best.2.is_finite() { Some(best) } else { None }
+    }
+}
+
+fn downsample(image: ImgRef<'_, u8>) -> ImgVec<u8> {
+    let (width, height) = (image.width() / 2, image.height() / 2);
+    let mut out = Vec::with_capacity(width * height);
+    for y in 0..height {
+        let row1 = &image[y * 2];
+        let row2 = &image[y * 2 + 1];
+        out.extend((0..width).map(|x| {
+            ((u16::from(row1[x * 2]) + u16::from(row1[x * 2 + 1]) + u16::from(row2[x * 2]) + u16::from(row2[x * 2 + 1]) + 2) / 4) as u8
+        }));
+    }
+    ImgVec::new(out, width, height)
+}
+
+/// Compares `prev` with `curr` moved back by `dx`,`dy`. Doesn't count shifts that leave less than half of the frame overlapping.
+fn average_difference(prev: ImgRef<'_, u8>, curr: ImgRef<'_, u8>, dx: isize, dy: isize, step: usize) -> f32 {
+    let (width, height) = (prev.width() as isize, prev.height() as isize);
+    let (x_start, x_end) = ((-dx).max(0), (width - dx).min(width));
+    let (y_start, y_end) = ((-dy).max(0), (height - dy).min(height));
+    if (x_end - x_start) * 2 < width || (y_end - y_start) * 2 < height {
+        return f32::INFINITY;
+    }
+    let mut sum = 0u64;
+    let mut count = 0u64;
+    for y in (y_start..y_end).step_by(step) {
+        let prev_row = &prev[y as usize][x_start as usize..x_end as usize];
+        let curr_row = &curr[(y + dy) as usize][(x_start + dx) as usize..(x_end + dx) as usize];
+        for (&a, &b) in prev_row.iter().zip(curr_row).step_by(step) {
+            sum += u64::from(a.max(b) - a.min(b));
+            count += 1;
+        }
+    }
+    sum as f32 / count.max(1) as f32
+}
+
+fn owned<T: Copy>(image: ImgRef<'_, T>) -> ImgVec<T> {
+    let (buf, width, height) = image.to_contiguous_buf();
+    ImgVec::new(buf.into_owned(), width, height)
+}
+
+#[test]
+fn follows_shake() {
+    // a blob on a gradient, shaking by a few pixels
+    let scene = |shift_x: usize, shift_y: usize| {
+        let pixels = (0..120).flat_map(|y| (0..160).map(move |x| {
+            let (x, y) = (x + 10 - shift_x, y + 10 - shift_y);
+            let blob = if (x as isize - 80).pow(2) + (y as isize - 60).pow(2) < 400 { 100 } else { 0 };
+            let v = ((x + y) / 4) as u8 + blob;
+            RGBA8::new(v, v, v, 255)
+        })).collect();
+        ImgVec::new(pixels, 160, 120)
+    };
+    let shakes = [(0, 0), (3, 1), (0, 2), (4, 4), (1, 0)];
+    let stabilize = Stabilize::new(shakes.iter().enumerate().map(|(n, &(x, y))| Ok((scene(x + 5, y + 5), n as f64, None))));
+    let frames: Vec<_> = stabilize.map(|frame| frame.unwrap().0).collect();
+    assert_eq!(shakes.len(), frames.len());
+    assert_eq!((144, 108), (frames[0].width(), frames[0].height()));
+    let motion = |frame: &ImgVec<RGBA8>| Pyramid::new(frames[0].as_ref()).motion_to(&Pyramid::new(frame.as_ref())).unwrap();
+    for frame in &frames[1..] {
+        let (dx, dy, _) = motion(frame);
+        assert!(dx.abs() <= 1 && dy.abs() <= 1, "{},{}", dx, dy);
+    }
+}
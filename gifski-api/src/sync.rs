@@ -0,0 +1,137 @@
+//! Encoding that doesn't use any threads or channels, for hosts that don't allow spawning threads,
+//! such as audio plugins and game engines.
+//!
+//! Each frame is resized, denoised, quantized and written during the call that adds it.
+
+use crate::error::*;
+use crate::filter::Filters;
+use crate::pipeline::*;
+use crate::pool::Pools;
+use crate::progress::NoProgress;
+use crate::{encoderust, Collector, InputFrame, Instant, Matte, MatteMode, Settings, Timings};
+use imgref::*;
+use rgb::*;
+use std::borrow::Cow;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+
+/// Encodes frames on the thread that adds them
+///
+/// The GIF is complete only after `finish()`.
+pub struct Encoder<'w> {
+    settings: Settings,
+    filters: Filters,
+    /// Background to blend semi-transparent pixels with
+    matte: Option<Matte>,
+    timings: Arc<Timings>,
+    pools: Pools,
+    input: InputStage,
+    diffs: DiffStage,
+    quantize: QuantizeStage,
+    remap: RemapStage,
+    optimize: Option<OptimizeStage>,
+    write: WriteStage,
+    enc: Box<dyn crate::Encoder + 'w>,
+    bytes_written: AtomicU64,
+}
+
+impl<'w> Encoder<'w> {
+    /// The GIF is written to `out` as frames are added.
+    ///
+    /// Stabilization, reversing, loop crossfade and bounce need the whole sequence of frames, so they can't be used here.
+    /// With the `openmp` feature, quantization still uses OpenMP's threads.
+    pub fn new(settings: Settings, out: &'w mut dyn Write) -> CatResult<Self> {
+        if uses_whole_sequence(&settings) {
+            return Err(Error::InvalidInput("stabilize, reverse, loop crossfade and bounce can't be used when encoding frame by frame".into()));
+        }
+        let timings = Arc::new(Timings::default());
+        let pools = Pools::new();
+
+        let enc: Box<dyn crate::Encoder + 'w> = {
+            #[cfg(feature = "gifsicle")]
+            {
+                if settings.gifsicle_loss() > 0 {
+                    Box::new(crate::encodegifsicle::Gifsicle::new(settings.gifsicle_loss(), out).without_threads())
+                } else {
+                    Box::new(encoderust::RustEncoder::new(out))
+                }
+            }
+            #[cfg(not(feature = "gifsicle"))]
+            {
+                Box::new(encoderust::RustEncoder::new(out))
+            }
+        };
+
+        Ok(Self {
+            settings,
+            filters: Filters::new(&settings),
+            matte: None,
+            input: InputStage::new(settings, timings.clone(), pools.clone()),
+            diffs: DiffStage::new(settings, 0, None, Default::default(), timings.clone(), pools.clone()),
+            quantize: QuantizeStage::new(settings, Vec::new(), Vec::new(), timings.clone(), pools.clone()),
+            remap: RemapStage::new(settings, timings.clone(), pools.clone()),
+            optimize: if settings.optimize_frames { Some(OptimizeStage::new(timings.clone())) } else { None },
+            write: WriteStage::new(settings, None, None, timings.clone(), pools.clone()),
+            enc,
+            bytes_written: AtomicU64::new(0),
+            timings,
+            pools,
+        })
+    }
+
+    /// Add frames in order of their timestamps.
+    ///
+    /// Presentation timestamp is time in seconds (since file start at 0) when this frame is to be displayed.
+    ///
+    /// A frame is written once the next frame is known, and the denoiser keeps a few frames, so writing lags behind adding.
+    pub fn add_frame_rgba(&mut self, image: ImgVec<RGBA8>, presentation_timestamp: f64) -> CatResult<()> {
+        let image = self.resize(image.into())?;
+        self.push(InputFrame::Rgba(image), presentation_timestamp)
+    }
+
+    /// Read and decode a PNG file from disk. Same as `add_frame_rgba` otherwise.
+    pub fn add_frame_png_file(&mut self, path: PathBuf, presentation_timestamp: f64) -> CatResult<()> {
+        let start = Instant::now();
+        let image = crate::pngdecode::decode_png_file(&path)?;
+        self.timings.add(&self.timings.decode, start);
+
+        let (buf, width, height) = image.into_contiguous_buf();
+        let image = self.resize(Img::new(buf.into(), width, height))?;
+        self.push(InputFrame::Rgba(image), presentation_timestamp)
+    }
+
+    /// Same as `Collector::set_matte`. Applies to frames added afterwards.
+    pub fn set_matte(&mut self, color: RGBA8, mode: MatteMode) {
+        self.matte = Some((color, mode));
+    }
+
+    /// Writes the remaining frames and the end of the file.
+    pub fn finish(mut self) -> CatResult<()> {
+        let Self { diffs, quantize, remap, optimize, write, enc, bytes_written, .. } = &mut self;
+        let mut write_frame = |msg| optimized(optimize, msg, &mut |msg| write.push(msg, &mut **enc, bytes_written, &mut NoProgress {}));
+        diffs.finish(&mut |msg| {
+            quantize.push(msg, &mut |msg| remap.push(msg, &mut write_frame))
+        })?;
+        remap.finish(&mut write_frame)?;
+        write.finish(&mut **enc)
+    }
+
+    fn resize(&self, image: Img<Cow<'_, [RGBA8]>>) -> CatResult<ImgVec<RGBA8>> {
+        let start = Instant::now();
+        let settings = &self.settings;
+        let image = Collector::resized_binary_alpha(image, settings.width, settings.height, settings.scale, settings.crop, self.filters, self.matte, &self.pools);
+        self.timings.add(&self.timings.resize, start);
+        image
+    }
+
+    fn push(&mut self, frame: InputFrame, presentation_timestamp: f64) -> CatResult<()> {
+        let frame = self.input.decode((frame, presentation_timestamp))?;
+        let Self { diffs, quantize, remap, optimize, write, enc, bytes_written, .. } = self;
+        let mut write_frame = |msg| optimized(optimize, msg, &mut |msg| write.push(msg, &mut **enc, bytes_written, &mut NoProgress {}));
+        diffs.push(frame, &mut |msg| {
+            quantize.push(msg, &mut |msg| remap.push(msg, &mut write_frame))
+        })
+    }
+}
@@ -0,0 +1,266 @@
+//! Encoding on the caller's thread, for platforms that can't spawn threads, such as WebAssembly in browsers.
+//!
+//! Frames go through the same stages as with threads, but only when `Writer::pump()` or `Writer::write()` is called.
+//! Once the output is known, pumped frames are written to it right away.
+
+use crate::error::*;
+use crate::pipeline::*;
+use crate::progress::*;
+use crate::{maxsize, CountingWriter, DecodedFrame, Encoder, FrameMessage, HeaderSkipper, Writer};
+use std::io::Write;
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+
+/// Stages that pumped frames have gone through
+pub(crate) struct Pumped {
+    input: InputStage,
+    stages: Stages,
+    /// Finished frames, kept until the output to write them to is known
+    frames: Vec<FrameMessage>,
+    output: Option<Output>,
+}
+
+/// Stages after decoding
+struct Stages {
+    diffs: DiffStage,
+    quantize: QuantizeStage,
+    remap: RemapStage,
+    optimize: Option<OptimizeStage>,
+}
+
+/// Set by `Writer::set_output()`
+struct Output {
+    encoder: Box<dyn Encoder + Send>,
+    write: WriteStage,
+    /// Shared with the `CountingWriter` in the encoder
+    bytes_written: Arc<AtomicU64>,
+    reporter: Box<dyn ProgressReporter>,
+}
+
+impl Output {
+    fn push(&mut self, msg: FrameMessage) -> CatResult<()> {
+        self.write.push(msg, &mut *self.encoder, &self.bytes_written, &mut *self.reporter)
+    }
+}
+
+impl Pumped {
+    fn new(writer: &mut Writer) -> Self {
+        let settings = writer.settings;
+        Self {
+            input: InputStage::new(settings, writer.timings.clone(), writer.pools.clone()),
+            stages: Stages {
+                diffs: DiffStage::new(settings, writer.resume_after, writer.memory_limit.take(), writer.last_frame_duration.clone(), writer.timings.clone(), writer.pools.clone()),
+                quantize: QuantizeStage::new(settings, std::mem::take(&mut writer.fixed_palette), std::mem::take(&mut writer.fixed_colors), writer.timings.clone(), writer.pools.clone()),
+                remap: RemapStage::new(settings, writer.timings.clone(), writer.pools.clone()),
+                optimize: if settings.optimize_frames { Some(OptimizeStage::new(writer.timings.clone())) } else { None },
+            },
+            frames: Vec::new(),
+            output: None,
+        }
+    }
+}
+
+impl Stages {
+    fn push(&mut self, frame: DecodedFrame, done: Emit<'_, FrameMessage>) -> CatResult<()> {
+        let Self { diffs, quantize, remap, optimize } = self;
+        diffs.push(frame, &mut |msg| {
+            quantize.push(msg, &mut |msg| {
+                remap.push(msg, &mut |msg| optimized(optimize, msg, &mut *done))
+            })
+        })
+    }
+
+    fn finish(&mut self, done: Emit<'_, FrameMessage>) -> CatResult<()> {
+        let Self { diffs, quantize, remap, optimize } = self;
+        diffs.finish(&mut |msg| {
+            quantize.push(msg, &mut |msg| {
+                remap.push(msg, &mut |msg| optimized(optimize, msg, &mut *done))
+            })
+        })?;
+        remap.finish(&mut |msg| optimized(optimize, msg, &mut *done))
+    }
+}
+
+/// Writes the frame if the output is known already, or keeps it until it is
+fn keep_or_write(frames: &mut Vec<FrameMessage>, output: &mut Option<Output>, msg: FrameMessage) -> CatResult<()> {
+    match output {
+        Some(output) => output.push(msg),
+        None => {
+            frames.push(msg);
+            Ok(())
+        },
+    }
+}
+
+impl Writer {
+    /// Encode the frames that have been added so far, on the current thread.
+    ///
+    /// Call it after adding frames, so that they don't pile up in memory as full-size images.
+    /// Only available with the `threadless` feature, which doesn't use any threads.
+    /// Finished frames are written to the output given to `set_output()`, or kept until `write()` if there's none.
+    ///
+    /// Stabilization, reversing, loop crossfade and bounce need the whole sequence of frames,
+    /// so with these settings frames are kept until `write()` or `finish()`.
+    pub fn pump(&mut self) -> CatResult<()> {
+        if uses_whole_sequence(&self.settings) {
+            return Ok(());
+        }
+        if self.pumped.is_none() {
+            self.pumped = Some(Pumped::new(self));
+        }
+        let queue = self.queue_iter.as_mut().ok_or(Error::Aborted)?;
+        let Pumped { input, stages, frames, output } = self.pumped.as_mut().ok_or(Error::Aborted)?;
+        while let Some(frame) = queue.try_next() {
+            let frame = input.decode(frame?)?;
+            if let Some(recording) = &self.recording {
+                recording.lock().unwrap().push(frame.clone())?;
+            }
+            stages.push(frame, &mut |msg| keep_or_write(frames, output, msg))?;
+        }
+        Ok(())
+    }
+
+    /// Makes `pump()` write frames to `writer` as soon as they're finished, instead of keeping them in memory.
+    /// Frames pumped before are written now. `reporter` gets progress of the frames written.
+    ///
+    /// Drop the `Collector` and call `finish()` (not `write()`) to write the rest. It can't be used with `set_max_size()`.
+    pub fn set_output(&mut self, writer: impl Write + Send + 'static, reporter: impl ProgressReporter + 'static) -> CatResult<()> {
+        if self.max_size.is_some() {
+            return Err(Error::InvalidInput("set_max_size() needs the whole GIF, so it works only with write()".into()));
+        }
+        if self.pumped.is_none() {
+            self.pumped = Some(Pumped::new(self));
+        }
+        let settings = self.settings;
+        let bytes_written = Arc::new(AtomicU64::new(0));
+        let writer = CountingWriter { writer, bytes_written: bytes_written.clone(), timings: self.timings.clone() };
+        let writer = HeaderSkipper { writer, header: if self.resume_after > 0 { Some(Vec::new()) } else { None } };
+        let encoder: Box<dyn Encoder + Send> = {
+            #[cfg(feature = "gifsicle")]
+            {
+                if settings.gifsicle_loss() > 0 {
+                    Box::new(crate::encodegifsicle::Gifsicle::new(settings.gifsicle_loss(), writer))
+                } else {
+                    Box::new(crate::encoderust::RustEncoder::new(writer))
+                }
+            }
+            #[cfg(not(feature = "gifsicle"))]
+            {
+                Box::new(crate::encoderust::RustEncoder::new(writer))
+            }
+        };
+        let mut output = Output {
+            encoder,
+            write: WriteStage::new(settings, self.output_frame_callback.take(), self.poster.take(), self.timings.clone(), self.pools.clone()),
+            bytes_written,
+            reporter: Box::new(reporter),
+        };
+        let pumped = self.pumped.as_mut().ok_or(Error::Aborted)?;
+        if pumped.output.is_some() {
+            return Err(Error::InvalidInput("the output has been set already".into()));
+        }
+        for msg in pumped.frames.drain(..) {
+            output.push(msg)?;
+        }
+        pumped.output = Some(output);
+        Ok(())
+    }
+
+    /// Encodes the remaining frames, and completes the GIF in the output given to `set_output()`.
+    ///
+    /// Waits for frames until the `Collector` is dropped, so drop it first.
+    pub fn finish(mut self) -> CatResult<()> {
+        let mut pumped = self.pumped.take().ok_or_else(|| Error::InvalidInput("set_output() hasn't been called".into()))?;
+        let Pumped { input, stages, output, .. } = &mut pumped;
+        let output = output.as_mut().ok_or_else(|| Error::InvalidInput("set_output() hasn't been called".into()))?;
+        self.encode_remaining(input, stages, &mut |msg| output.push(msg))?;
+        output.write.finish(&mut *output.encoder)?;
+        output.reporter.stage_times(&self.timings.stage_times());
+        Ok(())
+    }
+
+    pub(crate) fn write_with_encoder(mut self, encoder: &mut dyn Encoder, bytes_written: &AtomicU64, reporter: &mut dyn ProgressReporter) -> CatResult<()> {
+        let mut pumped = match self.pumped.take() {
+            Some(pumped) => pumped,
+            None => Pumped::new(&mut self),
+        };
+        if pumped.output.is_some() {
+            return Err(Error::InvalidInput("the output has been set with set_output(), so use finish()".into()));
+        }
+        let mut write = WriteStage::new(self.settings, self.output_frame_callback.take(), self.poster.take(), self.timings.clone(), self.pools.clone());
+        // frames pumped so far, and then the rest as they're finished
+        for msg in pumped.frames.drain(..) {
+            write.push(msg, encoder, bytes_written, reporter)?;
+        }
+        let Pumped { input, stages, .. } = &mut pumped;
+        self.encode_remaining(input, stages, &mut |msg| write.push(msg, encoder, bytes_written, reporter))?;
+        write.finish(encoder)?;
+        reporter.stage_times(&self.timings.stage_times());
+        Ok(())
+    }
+
+    /// Frames that haven't been pumped yet
+    fn encode_remaining(&mut self, input: &mut InputStage, stages: &mut Stages, done: Emit<'_, FrameMessage>) -> CatResult<()> {
+        let settings = self.settings;
+        let frames = match self.replay.take() {
+            Some(replay) => Box::new(replay),
+            None => {
+                let queue = self.queue_iter.take().ok_or(Error::Aborted)?;
+                // waits for frames until the `Collector` is dropped
+                let inputs = queue.map(|res| res.and_then(|frame| input.decode(frame)));
+                sequence_effects(Box::new(inputs), &settings)?
+            },
+        };
+        for frame in maxsize::recorded(frames, self.recording.take()) {
+            stages.push(frame?, &mut *done)?;
+        }
+        stages.finish(done)
+    }
+}
+
+/// Output that can be checked while it's being written
+#[cfg(test)]
+#[derive(Clone, Default)]
+struct SharedVec(Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl Write for SharedVec {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn pumped_frames_are_written_before_finish() {
+    use imgref::ImgVec;
+    use rgb::RGBA8;
+
+    let (mut collector, mut writer) = crate::new(crate::Settings::default()).unwrap();
+    let out = SharedVec::default();
+    writer.set_output(out.clone(), NoProgress {}).unwrap();
+    assert!(writer.set_output(SharedVec::default(), NoProgress {}).is_err());
+    // more frames than the denoiser's lookahead
+    for n in 0..12u8 {
+        let frame = ImgVec::new(vec![RGBA8::new(n * 20, 0, 255 - n * 20, 255); 64 * 48], 64, 48);
+        collector.add_frame_rgba(n.into(), frame, f64::from(n) / 10.).unwrap();
+        writer.pump().unwrap();
+    }
+    let written = out.0.lock().unwrap().len();
+    assert!(written > 0);
+    drop(collector);
+    writer.finish().unwrap();
+
+    let gif = out.0.lock().unwrap().clone();
+    assert!(gif.len() > written);
+    let mut decoder = gif::DecodeOptions::new().read_info(&gif[..]).unwrap();
+    let mut frames = 0;
+    while decoder.read_next_frame().unwrap().is_some() {
+        frames += 1;
+    }
+    assert_eq!(12, frames);
+}
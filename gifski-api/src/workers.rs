@@ -0,0 +1,147 @@
+//! Quantization is the slowest stage, and frames don't depend on each other there,
+//! so it gets more threads when frames pile up in front of it, and gives them back when they're idle.
+
+use crate::error::*;
+use crate::ordqueue::OrdQueue;
+use crate::pipeline::QuantizeStage;
+use crate::{DiffMessage, RemapMessage, Settings, Timings, Waiting};
+use crossbeam_channel::{Receiver, Sender};
+use crossbeam_channel::RecvTimeoutError;
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Threads used by the other stages, and by the caller adding frames
+const OTHER_THREADS: usize = 3;
+
+/// A thread that had no frame for this long exits, unless it's the last one
+const IDLE_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// Quantizes frames on as many threads as it takes to keep up, and passes them on in order
+pub(crate) struct QuantizeWorkers {
+    stage: QuantizeStage,
+    /// Frames waiting for a free thread
+    queue: Sender<(usize, DiffMessage)>,
+    queue_recv: Receiver<(usize, DiffMessage)>,
+    /// Frames finish out of order, and are put back in order for remapping
+    done: OrdQueue<RemapMessage>,
+    threads: Vec<JoinHandle<CatResult<()>>>,
+    /// Threads that haven't exited after being idle
+    running: Arc<AtomicUsize>,
+    max_threads: usize,
+    /// For `Settings::deterministic`, all threads are started for the first frame, and never exit early
+    fixed: bool,
+    spawned: usize,
+    next_index: usize,
+    timings: Arc<Timings>,
+}
+
+impl QuantizeWorkers {
+    pub fn new(stage: QuantizeStage, done: OrdQueue<RemapMessage>, settings: &Settings, timings: Arc<Timings>) -> Self {
+        let max_threads = max_threads(settings);
+        // one frame waiting per thread is enough to tell that quantization is behind
+        let (queue, queue_recv) = crossbeam_channel::bounded(max_threads);
+        Self { stage, queue, queue_recv, done, threads: Vec::with_capacity(max_threads), running: Arc::new(AtomicUsize::new(0)), max_threads, fixed: settings.deterministic, spawned: 0, next_index: 0, timings }
+    }
+
+    pub fn push(&mut self, msg: DiffMessage) -> CatResult<()> {
+        let msg = match self.stage.select(msg) {
+            Some(msg) => msg,
+            None => return Ok(()),
+        };
+        if self.fixed {
+            while self.spawned < self.max_threads {
+                self.spawn()?;
+            }
+        } else {
+            // If remapping is behind, more threads here wouldn't help
+            let running = self.running.load(SeqCst);
+            let behind = self.queue.len() >= running && !self.done.is_full();
+            if running == 0 || (behind && running < self.max_threads) {
+                self.spawn()?;
+            }
+        }
+        let queue = &self.queue;
+        let index = self.next_index;
+        self.timings.wait(Waiting::QuantizeFull, || queue.send((index, msg)))?;
+        self.next_index += 1;
+        Ok(())
+    }
+
+    fn spawn(&mut self) -> CatResult<()> {
+        self.join_exited()?;
+        let stage = self.stage.clone();
+        let queue = self.queue_recv.clone();
+        let mut done = self.done.clone();
+        let timings = self.timings.clone();
+        let running = self.running.clone();
+        let fixed = self.fixed;
+        running.fetch_add(1, SeqCst);
+        let thread = thread::Builder::new().name(format!("quant{}", self.spawned)).spawn(move || -> CatResult<()> {
+            let mut res = Ok(());
+            loop {
+                let (index, msg) = match timings.wait(Waiting::QuantizeEmpty, || queue.recv_timeout(IDLE_TIMEOUT)) {
+                    Ok(msg) => msg,
+                    // After an error it keeps taking frames, so that adding frames doesn't wait for it forever.
+                    // With a fixed number of threads, none of them exit early.
+                    Err(RecvTimeoutError::Timeout) if res.is_err() || fixed => continue,
+                    Err(RecvTimeoutError::Timeout) => {
+                        // the last thread stays, so that there's always one to take frames
+                        if running.fetch_update(SeqCst, SeqCst, |n| if n > 1 { Some(n - 1) } else { None }).is_ok() {
+                            return res;
+                        }
+                        continue;
+                    },
+                    Err(RecvTimeoutError::Disconnected) => break,
+                };
+                if res.is_ok() {
+                    res = stage.quantize(msg).and_then(|msg| {
+                        let bytes = msg.memory_size();
+                        timings.wait(Waiting::RemapFull, || done.push_sized(index, msg, bytes))
+                    });
+                }
+            }
+            running.fetch_sub(1, SeqCst);
+            res
+        })?;
+        self.threads.push(thread);
+        self.spawned += 1;
+        Ok(())
+    }
+
+    /// Threads that exited when idle, so that their errors aren't left until the end
+    fn join_exited(&mut self) -> CatResult<()> {
+        let mut i = 0;
+        while i < self.threads.len() {
+            if self.threads[i].is_finished() {
+                self.threads.swap_remove(i).join().map_err(|_| Error::ThreadSend)??;
+            } else {
+                i += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until all frames have been quantized
+    pub fn finish(self) -> CatResult<()> {
+        let Self { queue, done, threads, .. } = self;
+        drop(queue);
+        drop(done);
+        for thread in threads {
+            thread.join().map_err(|_| Error::ThreadSend)??;
+        }
+        Ok(())
+    }
+}
+
+/// With OpenMP, quantization of a single frame already uses all the threads
+fn max_threads(settings: &Settings) -> usize {
+    if cfg!(feature = "openmp") {
+        return 1;
+    }
+    if settings.threads > 0 {
+        return settings.threads.into();
+    }
+    num_cpus::get().saturating_sub(OTHER_THREADS).max(1)
+}
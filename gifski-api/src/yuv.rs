@@ -0,0 +1,108 @@
+//! Planar YUV 4:2:0 input, as produced by video decoders.
+use crate::error::*;
+use imgref::*;
+use rgb::*;
+
+/// Coefficients used to convert YUV to RGB
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum YuvMatrix {
+    /// Standard-definition video
+    BT601,
+    /// HD video
+    BT709,
+    /// UHD/HDR video (colors are converted as-is, without tone mapping)
+    BT2020,
+}
+
+impl YuvMatrix {
+    /// (Kr, Kb)
+    fn coefficients(self) -> (f32, f32) {
+        match self {
+            Self::BT601 => (0.299, 0.114),
+            Self::BT709 => (0.2126, 0.0722),
+            Self::BT2020 => (0.2627, 0.0593),
+        }
+    }
+}
+
+/// Y plane at full resolution, U and V planes at half width and half height (rounded up)
+pub(crate) struct Yuv420<'a> {
+    y: ImgRef<'a, u8>,
+    u: ImgRef<'a, u8>,
+    v: ImgRef<'a, u8>,
+    matrix: YuvMatrix,
+    /// JPEG-style 0-255 range, rather than 16-235 "TV" range
+    full_range: bool,
+}
+
+impl<'a> Yuv420<'a> {
+    pub fn new(y: ImgRef<'a, u8>, u: ImgRef<'a, u8>, v: ImgRef<'a, u8>, matrix: YuvMatrix, full_range: bool) -> CatResult<Self> {
+        let chroma_width = (y.width() + 1) / 2;
+        let chroma_height = (y.height() + 1) / 2;
+        if y.width() == 0 || y.height() == 0 {
+            return Err(Error::InvalidInput("YUV frame is empty".into()));
+        }
+        if u.width() < chroma_width || u.height() < chroma_height || v.width() < chroma_width || v.height() < chroma_height {
+            return Err(Error::InvalidInput(format!("chroma planes of {}x{} YUV 4:2:0 frame must be at least {}x{}",
+                y.width(), y.height(), chroma_width, chroma_height)));
+        }
+        Ok(Self {
+            y,
+            u: u.sub_image(0, 0, chroma_width, chroma_height),
+            v: v.sub_image(0, 0, chroma_width, chroma_height),
+            matrix,
+            full_range,
+        })
+    }
+
+    pub fn to_rgba(&self) -> ImgVec<RGBA8> {
+        let (kr, kb) = self.matrix.coefficients();
+        let kg = 1. - kr - kb;
+        let (y_offset, y_scale, c_scale) = if self.full_range {
+            (0., 1., 1.)
+        } else {
+            (16., 255. / 219., 255. / 224.)
+        };
+        let cr_r = 2. * (1. - kr) * c_scale;
+        let cb_g = 2. * kb * (1. - kb) / kg * c_scale;
+        let cr_g = 2. * kr * (1. - kr) / kg * c_scale;
+        let cb_b = 2. * (1. - kb) * c_scale;
+
+        let width = self.y.width();
+        let mut out = Vec::with_capacity(width * self.y.height());
+        // each chroma row is shared by two luma rows
+        let chroma_rows = self.u.rows().zip(self.v.rows()).flat_map(|uv| std::iter::repeat(uv).take(2));
+        for (luma_row, (u_row, v_row)) in self.y.rows().zip(chroma_rows) {
+            out.extend(luma_row.iter().enumerate().map(|(x, &luma)| {
+                let luma = (luma as f32 - y_offset) * y_scale;
+                let cb = u_row[x / 2] as f32 - 128.;
+                let cr = v_row[x / 2] as f32 - 128.;
+                RGBA8::new(
+                    (luma + cr_r * cr).round().clamp(0., 255.) as u8,
+                    (luma - cb_g * cb - cr_g * cr).round().clamp(0., 255.) as u8,
+                    (luma + cb_b * cb).round().clamp(0., 255.) as u8,
+                    255,
+                )
+            }));
+        }
+        ImgVec::new(out, width, self.y.height())
+    }
+}
+
+#[test]
+fn yuv_gray_and_red() {
+    let y = [16u8, 235, 81, 81];
+    let u = [128u8];
+    let v = [128u8];
+    let frame = Yuv420::new(ImgRef::new(&y, 4, 1), ImgRef::new(&u, 1, 1), ImgRef::new(&v, 1, 1), YuvMatrix::BT601, false);
+    assert!(frame.is_err(), "chroma is too narrow");
+
+    let u = [128u8, 90];
+    let v = [128u8, 240];
+    let frame = Yuv420::new(ImgRef::new(&y, 4, 1), ImgRef::new(&u, 2, 1), ImgRef::new(&v, 2, 1), YuvMatrix::BT601, false).unwrap();
+    let rgba = frame.to_rgba();
+    assert_eq!(rgba.buf()[0], RGBA8::new(0, 0, 0, 255));
+    assert_eq!(rgba.buf()[1], RGBA8::new(255, 255, 255, 255));
+    let red = rgba.buf()[2];
+    assert!(red.r > 250 && red.g < 5 && red.b < 5, "{:?}", red);
+}